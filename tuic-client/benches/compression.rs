@@ -0,0 +1,101 @@
+//! CPU cost and size of `relay.stream_compression`, on text and on incompressible data
+//!
+//! Run with `cargo bench -p tuic-client --features compression`
+
+// throughput is measured on the wall clock
+#![allow(clippy::disallowed_types)]
+
+use async_compression::{
+    tokio::{bufread::ZstdDecoder, write::ZstdEncoder},
+    Level,
+};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use std::{
+    fmt::Write as _,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    runtime::Builder,
+};
+
+const SIZE: usize = 16 * 1024 * 1024;
+/// The chunk the relay reads from a socket at a time, each followed by a flush
+const CHUNK: usize = 8 * 1024;
+const LEVELS: [i32; 4] = [1, 3, 9, 19];
+
+fn main() {
+    let rt = Builder::new_current_thread().build().unwrap();
+
+    let inputs = [("json", json()), ("random", random())];
+
+    println!("data    level  ratio   compress    decompress");
+
+    for (name, data) in &inputs {
+        for level in LEVELS {
+            let (compressed, compress) = rt.block_on(compress(data, level));
+            let decompress = rt.block_on(decompress(&compressed, data.len()));
+
+            println!(
+                "{name:<7} {level:>5}  {:>5.2}  {:>6.1} MB/s  {:>6.1} MB/s",
+                data.len() as f64 / compressed.len() as f64,
+                throughput(data.len(), compress),
+                throughput(data.len(), decompress),
+            );
+        }
+    }
+}
+
+/// API responses, the kind of traffic compression is meant for
+fn json() -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut data = String::with_capacity(SIZE);
+
+    while data.len() < SIZE {
+        let _ = writeln!(
+            data,
+            r#"{{"id":{},"user":"user{}","status":"active","score":{},"tags":["alpha","beta"],"updated_at":"2023-0{}-1{}T12:{:02}:00Z"}}"#,
+            rng.next_u32(),
+            rng.next_u32() % 1000,
+            rng.next_u32() % 100,
+            rng.next_u32() % 9 + 1,
+            rng.next_u32() % 10,
+            rng.next_u32() % 60,
+        );
+    }
+
+    data.into_bytes()
+}
+
+/// Already compressed or encrypted traffic, e.g. TLS
+fn random() -> Vec<u8> {
+    let mut data = vec![0; SIZE];
+    StdRng::seed_from_u64(0).fill_bytes(&mut data);
+    data
+}
+
+async fn compress(data: &[u8], level: i32) -> (Vec<u8>, Duration) {
+    let start = Instant::now();
+    let mut encoder = ZstdEncoder::with_quality(Vec::new(), Level::Precise(level));
+
+    for chunk in data.chunks(CHUNK) {
+        encoder.write_all(chunk).await.unwrap();
+        encoder.flush().await.unwrap();
+    }
+
+    encoder.shutdown().await.unwrap();
+    (encoder.into_inner(), start.elapsed())
+}
+
+async fn decompress(data: &[u8], len: usize) -> Duration {
+    let start = Instant::now();
+    let mut decoder = ZstdDecoder::new(BufReader::new(data));
+    let mut buf = Vec::with_capacity(len);
+    decoder.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf.len(), len);
+    start.elapsed()
+}
+
+fn throughput(len: usize, elapsed: Duration) -> f64 {
+    len as f64 / elapsed.as_secs_f64() / 1_000_000.0
+}
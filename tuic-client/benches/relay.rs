@@ -0,0 +1,300 @@
+//! Relays through the client to a mock TUIC server on loopback that echoes every relay: throughput of a single relay through `forward`, setup latency of a relay on a fresh and on an established connection, and the rate of many small relays
+//!
+//! Run with `cargo bench -p tuic-client --bench relay`
+
+// throughput and latency are measured on the wall clock
+#![allow(clippy::disallowed_types)]
+
+use quinn::{Endpoint, ServerConfig};
+use rustls::{Certificate, PrivateKey};
+use socks5_proto::{
+    handshake::{HandshakeRequest, HandshakeResponse},
+    Address, Command, HandshakeMethod, Reply, Request, Response,
+};
+use std::{
+    env, fs,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    process,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    runtime::Builder,
+    sync::Semaphore,
+    task::JoinSet,
+    time,
+};
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tuic_client::Client;
+use tuic_quinn::{side, Connection as Model, Task};
+
+const THROUGHPUT_SIZE: usize = 64 * 1024 * 1024;
+const THROUGHPUT_SAMPLES: usize = 5;
+/// The chunk a socks5 client writes at a time
+const CHUNK: usize = 64 * 1024;
+const SETUP_SAMPLES: usize = 20;
+/// `relay.idle_connection_timeout` of the connection relays to `SETUP_PORT` are routed to, so it is closed before every sample
+const SETUP_IDLE_TIMEOUT: Duration = Duration::from_millis(50);
+/// Relays to this port go through a connection of their own, see `SETUP_IDLE_TIMEOUT`
+const SETUP_PORT: u16 = 9;
+/// Relays to this port go through the shared connection
+const ECHO_PORT: u16 = 7;
+const SMALL_RELAYS: usize = 2000;
+const SMALL_CONCURRENCY: usize = 64;
+const SMALL_SIZE: usize = 64;
+
+fn main() {
+    let rt = Builder::new_multi_thread().enable_all().build().unwrap();
+    let dir = env::temp_dir().join(format!("tuic-client-bench-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let socks5 = rt.block_on(start(&dir));
+
+    rt.block_on(async {
+        // the first relay connects to the server
+        echo(socks5, ECHO_PORT, 1).await;
+
+        let mut throughput = Vec::with_capacity(THROUGHPUT_SAMPLES);
+
+        for _ in 0..THROUGHPUT_SAMPLES {
+            let elapsed = relay_throughput(socks5).await;
+            throughput.push(THROUGHPUT_SIZE as f64 / elapsed.as_secs_f64() / 1_000_000.0);
+        }
+
+        throughput.sort_by(f64::total_cmp);
+        println!(
+            "throughput        {:>8.1} MB/s  (median of {THROUGHPUT_SAMPLES}, {} MiB each way)",
+            throughput[THROUGHPUT_SAMPLES / 2],
+            THROUGHPUT_SIZE / 1024 / 1024,
+        );
+
+        let mut fresh = Vec::with_capacity(SETUP_SAMPLES);
+        let mut established = Vec::with_capacity(SETUP_SAMPLES);
+
+        for _ in 0..SETUP_SAMPLES {
+            time::sleep(SETUP_IDLE_TIMEOUT * 3).await;
+            fresh.push(echo(socks5, SETUP_PORT, 1).await);
+            established.push(echo(socks5, ECHO_PORT, 1).await);
+        }
+
+        print_latency("setup (fresh)", &mut fresh);
+        print_latency("setup (reused)", &mut established);
+
+        let elapsed = small_relays(socks5).await;
+        println!(
+            "small relays      {:>8.0} /s    ({SMALL_RELAYS} relays of {SMALL_SIZE} bytes, {SMALL_CONCURRENCY} at a time)",
+            SMALL_RELAYS as f64 / elapsed.as_secs_f64(),
+        );
+    });
+
+    let _ = fs::remove_dir_all(&dir);
+    process::exit(0);
+}
+
+/// Starts the mock server and the client relaying to it, returning the address of the socks5 server
+async fn start(dir: &Path) -> SocketAddr {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let cert_der = cert.serialize_der().unwrap();
+    let cert_path = dir.join("cert.der");
+    fs::write(&cert_path, &cert_der).unwrap();
+
+    let server_cfg = ServerConfig::with_single_cert(
+        vec![Certificate(cert_der)],
+        PrivateKey(cert.serialize_private_key_der()),
+    )
+    .unwrap();
+    let server = Endpoint::server(server_cfg, SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+    let server_port = server.local_addr().unwrap().port();
+    tokio::spawn(serve(server));
+
+    let port_file = dir.join("port");
+    let cfg_path = dir.join("config.json");
+    fs::write(&cfg_path, config(server_port, &cert_path, &port_file)).unwrap();
+
+    let client = Client::from_file(cfg_path).unwrap();
+
+    tokio::spawn(async move {
+        if let Err(err) = client.run().await {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    });
+
+    loop {
+        if let Some(port) = fs::read_to_string(&port_file)
+            .ok()
+            .and_then(|port| port.trim().parse::<u16>().ok())
+        {
+            return SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        }
+
+        time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+fn config(server_port: u16, cert: &Path, port_file: &Path) -> String {
+    format!(
+        r#"{{
+            "relay": {{
+                "server": "localhost:{server_port}",
+                "ip": "127.0.0.1",
+                "uuid": "00000000-0000-0000-0000-000000000000",
+                "password": "bench",
+                "certificates": [{cert:?}],
+                "disable_native_certs": true,
+                "egress_bindings": {{ "setup": "127.0.0.1" }},
+                "idle_connection_timeout": {{ "secs": 0, "nanos": {idle} }}
+            }},
+            "local": {{
+                "server": "127.0.0.1:0",
+                "port_file": {port_file:?}
+            }},
+            "routing": {{
+                "rules": [{{ "matcher": "port:{SETUP_PORT}", "action": "tunnel", "egress": "setup" }}]
+            }},
+            "log_level": "off"
+        }}"#,
+        idle = SETUP_IDLE_TIMEOUT.as_nanos(),
+    )
+}
+
+/// A TUIC server that echoes every relay, whatever its target. The authentication is not checked
+async fn serve(ep: Endpoint) {
+    while let Some(connecting) = ep.accept().await {
+        tokio::spawn(async move {
+            let Ok(conn) = connecting.await else {
+                return;
+            };
+            let model = Model::<side::Server>::new(conn.clone());
+
+            let uni = {
+                let conn = conn.clone();
+                let model = model.clone();
+
+                async move {
+                    while let Ok(recv) = conn.accept_uni().await {
+                        let _ = model.accept_uni_stream(recv).await;
+                    }
+                }
+            };
+            tokio::spawn(uni);
+
+            while let Ok((send, recv)) = conn.accept_bi().await {
+                let model = model.clone();
+
+                tokio::spawn(async move {
+                    if let Ok(Task::Connect(relay)) = model.accept_bi_stream(send, recv).await {
+                        let (mut recv, mut send) = io::split(relay.compat());
+                        let _ = io::copy(&mut recv, &mut send).await;
+                        let _ = send.shutdown().await;
+                    }
+                });
+            }
+        });
+    }
+}
+
+/// Opens a relay to `port` through the socks5 server
+async fn connect(socks5: SocketAddr, port: u16) -> TcpStream {
+    let mut stream = TcpStream::connect(socks5).await.unwrap();
+    stream.set_nodelay(true).unwrap();
+
+    HandshakeRequest::new(vec![HandshakeMethod::None])
+        .write_to(&mut stream)
+        .await
+        .unwrap();
+    let resp = HandshakeResponse::read_from(&mut stream).await.unwrap();
+    assert_eq!(resp.method, HandshakeMethod::None);
+
+    let target = Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+    Request::new(Command::Connect, target)
+        .write_to(&mut stream)
+        .await
+        .unwrap();
+    let resp = Response::read_from(&mut stream).await.unwrap();
+    assert_eq!(resp.reply, Reply::Succeeded);
+
+    stream
+}
+
+/// Opens a relay to `port` and waits for `len` bytes to be echoed, returning how long that took
+async fn echo(socks5: SocketAddr, port: u16, len: usize) -> Duration {
+    let start = Instant::now();
+    let mut stream = connect(socks5, port).await;
+
+    let data = vec![0x42; len];
+    stream.write_all(&data).await.unwrap();
+
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).await.unwrap();
+    let elapsed = start.elapsed();
+
+    stream.shutdown().await.unwrap();
+    elapsed
+}
+
+/// Sends `THROUGHPUT_SIZE` bytes through a single relay while reading the echo, returning how long until the echo is complete
+async fn relay_throughput(socks5: SocketAddr) -> Duration {
+    let stream = connect(socks5, ECHO_PORT).await;
+    let (mut recv, mut send) = stream.into_split();
+
+    let start = Instant::now();
+
+    let write = tokio::spawn(async move {
+        let chunk = vec![0x42; CHUNK];
+
+        for _ in 0..THROUGHPUT_SIZE / CHUNK {
+            send.write_all(&chunk).await.unwrap();
+        }
+
+        send.shutdown().await.unwrap();
+    });
+
+    let mut buf = vec![0; CHUNK];
+    let mut received = 0;
+
+    while received < THROUGHPUT_SIZE {
+        let n = recv.read(&mut buf).await.unwrap();
+        assert_ne!(n, 0, "relay closed early");
+        received += n;
+    }
+
+    let elapsed = start.elapsed();
+    write.await.unwrap();
+    elapsed
+}
+
+/// Opens `SMALL_RELAYS` relays, `SMALL_CONCURRENCY` at a time, each echoing `SMALL_SIZE` bytes, returning how long they took
+async fn small_relays(socks5: SocketAddr) -> Duration {
+    let permits = Arc::new(Semaphore::new(SMALL_CONCURRENCY));
+    let mut relays = JoinSet::new();
+    let start = Instant::now();
+
+    for _ in 0..SMALL_RELAYS {
+        let permit = permits.clone().acquire_owned().await.unwrap();
+
+        relays.spawn(async move {
+            echo(socks5, ECHO_PORT, SMALL_SIZE).await;
+            drop(permit);
+        });
+    }
+
+    while let Some(res) = relays.join_next().await {
+        res.unwrap();
+    }
+
+    start.elapsed()
+}
+
+fn print_latency(name: &str, samples: &mut [Duration]) {
+    samples.sort();
+    println!(
+        "{name:<17} {:>8.2} ms    (median of {}, p90 {:.2} ms)",
+        samples[samples.len() / 2].as_secs_f64() * 1000.0,
+        samples.len(),
+        samples[samples.len() * 9 / 10].as_secs_f64() * 1000.0,
+    );
+}
@@ -0,0 +1,282 @@
+//! Access log of the CONNECT requests, one line per request in a format log analyzers read
+
+use crate::{metrics, utils::AccessLogFormat, Error};
+use once_cell::sync::OnceCell;
+use socks5_proto::Reply;
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+static ACCESS_LOG: OnceCell<AccessLog> = OnceCell::new();
+
+/// Lines waiting for the writer thread at most. Lines beyond are dropped rather than held in memory while the disk stalls
+const QUEUE_SIZE: usize = 4096;
+
+const W3C_HEADER: &str = "#Version: 1.0\n\
+                          #Fields: date time c-ip cs-username cs-method cs-uri sc-status sc-bytes cs-bytes time-taken\n";
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    /// Lines waiting for the writer thread
+    tx: SyncSender<String>,
+    /// Whether lines were dropped since the queue last had room, so a stall is warned about once
+    dropping: AtomicBool,
+}
+
+/// A CONNECT request that ended
+pub struct Entry<'a> {
+    pub peer: SocketAddr,
+    pub user: Option<&'a str>,
+    pub target: &'a str,
+    pub reply: Reply,
+    /// When the request was received
+    pub started: SystemTime,
+    /// From the request until the relay closed, `None` for requests that were refused
+    pub duration: Option<Duration>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+impl AccessLog {
+    pub fn set_config(path: Option<PathBuf>, format: AccessLogFormat) -> Result<(), Error> {
+        let Some(path) = path else {
+            return Ok(());
+        };
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        if let AccessLogFormat::W3c = format {
+            if file.metadata()?.len() == 0 {
+                file.write_all(W3C_HEADER.as_bytes())?;
+            }
+        }
+
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+
+        thread::Builder::new()
+            .name(String::from("access-log"))
+            .spawn(move || write_lines(file, path, rx))?;
+
+        let access_log = Self {
+            format,
+            tx,
+            dropping: AtomicBool::new(false),
+        };
+
+        ACCESS_LOG
+            .set(access_log)
+            .map_err(|_| "access log already initialized")
+            .unwrap();
+
+        Ok(())
+    }
+
+    /// Queues the line of `entry`. Never blocks on the file
+    pub fn record(entry: Entry) {
+        let Some(access_log) = ACCESS_LOG.get() else {
+            return;
+        };
+
+        let line = match access_log.format {
+            AccessLogFormat::Common => format_common(&entry),
+            AccessLogFormat::W3c => format_w3c(&entry),
+        };
+
+        match access_log.tx.try_send(line) {
+            Ok(()) => access_log.dropping.store(false, Ordering::Relaxed),
+            Err(TrySendError::Full(_)) => {
+                metrics::ACCESS_LOG_DROPPED_TOTAL.inc();
+
+                if !access_log.dropping.swap(true, Ordering::Relaxed) {
+                    log::warn!("[access_log] writing falls behind, dropping lines");
+                }
+            }
+            // the writer thread is gone, which already logged why
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+/// Writes what is queued in one go, flushing once the queue is empty
+fn write_lines(file: File, path: PathBuf, rx: Receiver<String>) {
+    let mut writer = BufWriter::new(file);
+
+    while let Ok(line) = rx.recv() {
+        let res = writer
+            .write_all(line.as_bytes())
+            .and_then(|()| {
+                rx.try_iter()
+                    .try_for_each(|line| writer.write_all(line.as_bytes()))
+            })
+            .and_then(|()| writer.flush());
+
+        if let Err(err) = res {
+            log::warn!("[access_log] failed to write to {}: {err}", path.display());
+        }
+    }
+}
+
+/// `client_ip - user [10/Oct/2000:13:55:36 +0000] "CONNECT host:port" reply bytes_down bytes_up duration`
+fn format_common(entry: &Entry) -> String {
+    // e.g. `2000-10-10T13:55:36Z`
+    let time = humantime::format_rfc3339_seconds(entry.started).to_string();
+    let month = match &time[5..7] {
+        "01" => "Jan",
+        "02" => "Feb",
+        "03" => "Mar",
+        "04" => "Apr",
+        "05" => "May",
+        "06" => "Jun",
+        "07" => "Jul",
+        "08" => "Aug",
+        "09" => "Sep",
+        "10" => "Oct",
+        "11" => "Nov",
+        _ => "Dec",
+    };
+
+    let mut line = format!(
+        "{} - {} [{}/{month}/{}:{} +0000] \"CONNECT {}\" {} {} {} ",
+        entry.peer.ip(),
+        field(entry.user),
+        &time[8..10],
+        &time[..4],
+        &time[11..19],
+        field(Some(entry.target)),
+        reply_code(entry.reply),
+        entry.bytes_down,
+        entry.bytes_up,
+    );
+
+    push_duration(&mut line, entry.duration);
+    line
+}
+
+/// `date time c-ip cs-username cs-method cs-uri sc-status sc-bytes cs-bytes time-taken`
+fn format_w3c(entry: &Entry) -> String {
+    let time = humantime::format_rfc3339_seconds(entry.started).to_string();
+
+    let mut line = format!(
+        "{} {} {} {} CONNECT {} {} {} {} ",
+        &time[..10],
+        &time[11..19],
+        entry.peer.ip(),
+        field(entry.user),
+        field(Some(entry.target)),
+        reply_code(entry.reply),
+        entry.bytes_down,
+        entry.bytes_up,
+    );
+
+    push_duration(&mut line, entry.duration);
+    line
+}
+
+/// Fields are separated by spaces, so spaces within one are written as `+`, as the W3C format specifies. Other control characters and `"`, which ends the request of the common format, are percent-encoded, so a target cannot forge lines or fields
+fn field(value: Option<&str>) -> String {
+    let Some(value) = value.filter(|value| !value.is_empty()) else {
+        return String::from("-");
+    };
+
+    let mut field = String::with_capacity(value.len());
+
+    for char in value.chars() {
+        if char.is_whitespace() && !char.is_control() {
+            field.push('+');
+        } else if char.is_control() || char == '"' || char == '%' {
+            let mut buf = [0; 4];
+
+            for byte in char.encode_utf8(&mut buf).bytes() {
+                let _ = write!(field, "%{byte:02X}");
+            }
+        } else {
+            field.push(char);
+        }
+    }
+
+    field
+}
+
+fn push_duration(line: &mut String, duration: Option<Duration>) {
+    match duration {
+        Some(duration) => {
+            let _ = writeln!(line, "{:.3}", duration.as_secs_f64());
+        }
+        None => line.push_str("-\n"),
+    }
+}
+
+/// The reply code of RFC 1928
+fn reply_code(reply: Reply) -> u8 {
+    match reply {
+        Reply::Succeeded => 0x00,
+        Reply::GeneralFailure => 0x01,
+        Reply::ConnectionNotAllowed => 0x02,
+        Reply::NetworkUnreachable => 0x03,
+        Reply::HostUnreachable => 0x04,
+        Reply::ConnectionRefused => 0x05,
+        Reply::TtlExpired => 0x06,
+        Reply::CommandNotSupported => 0x07,
+        Reply::AddressTypeNotSupported => 0x08,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn entry(target: &str) -> Entry<'_> {
+        Entry {
+            peer: SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 50000)),
+            user: Some("alice"),
+            target,
+            reply: Reply::Succeeded,
+            started: SystemTime::UNIX_EPOCH + Duration::from_secs(971_186_136),
+            duration: Some(Duration::from_millis(1500)),
+            bytes_up: 10,
+            bytes_down: 20,
+        }
+    }
+
+    #[test]
+    fn common_format() {
+        assert_eq!(
+            format_common(&entry("example.com:443")),
+            "192.0.2.1 - alice [10/Oct/2000:13:55:36 +0000] \"CONNECT example.com:443\" 0 20 10 1.500\n"
+        );
+    }
+
+    #[test]
+    fn w3c_format() {
+        assert_eq!(
+            format_w3c(&entry("example.com:443")),
+            "2000-10-10 13:55:36 192.0.2.1 alice CONNECT example.com:443 0 20 10 1.500\n"
+        );
+    }
+
+    #[test]
+    fn targets_cannot_forge_lines_or_fields() {
+        let target = "evil.com\r\n192.0.2.2 - - \"CONNECT\x1b[2J 50%\":443";
+
+        let line = format_common(&entry(target));
+        assert_eq!(line.lines().count(), 1);
+        assert!(
+            line.contains("\"CONNECT evil.com%0D%0A192.0.2.2+-+-+%22CONNECT%1B[2J+50%25%22:443\"")
+        );
+
+        let line = format_w3c(&entry(target));
+        assert_eq!(line.lines().count(), 1);
+        assert_eq!(line.split(' ').count(), 10);
+    }
+}
@@ -0,0 +1,234 @@
+//! Control socket for runtime commands. It has no authentication, so it must only be bound to a loopback address
+
+use crate::{
+    config::Config, connection::Connection, diagnostics::Diagnostics, metrics::QuicStats, probe,
+    routing::Router, socks5::Server as Socks5Server, utils,
+};
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tuic::Address;
+
+/// Serves newline-delimited commands: `stats`, `list-relays`, `cancel <id>`, `drain`, `drain-server <server> [deadline]`, `undrain-server <server>`, `reload` and `probe <host:port>`. Every command is answered with its output lines, then `OK` or `ERR <reason>`
+///
+/// `reload` reads the config file at `config_path` again and applies its routing rules and what `Server::reload()` applies of `local` to new requests, listing the changed `local` fields that need a restart. `probe` opens a relay to the target through the tunnel, reports whether it looks reachable with the timings, and closes it
+///
+/// `drain-server` takes a server profile, named `host:port` as in `relay.server`, out of use for maintenance: new relays go through the other profiles, and its connections are closed once their relays ended, or after the deadline (e.g. `30s`) resetting those left. `undrain-server` puts it back in use
+pub async fn serve(addr: SocketAddr, config_path: PathBuf) {
+    if !addr.ip().is_loopback() {
+        log::warn!("[admin] {addr} is not a loopback address, anyone who can reach it controls this client");
+    }
+
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("[admin] failed to listen on {addr}: {err}");
+            return;
+        }
+    };
+
+    log::warn!("[admin] control socket started, listening on {addr}");
+
+    async fn handle(
+        stream: TcpStream,
+        peer: SocketAddr,
+        config_path: Arc<PathBuf>,
+    ) -> std::io::Result<()> {
+        let (recv, mut send) = stream.into_split();
+        let mut lines = BufReader::new(recv).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let line = line.trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            log::info!("[admin] [{peer}] {line}");
+
+            let resp = match execute(line, &config_path).await {
+                Ok(mut output) => {
+                    output.push_str("OK\n");
+                    output
+                }
+                Err(reason) => format!("ERR {reason}\n"),
+            };
+
+            send.write_all(resp.as_bytes()).await?;
+        }
+
+        send.shutdown().await
+    }
+
+    let config_path = Arc::new(config_path);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let config_path = config_path.clone();
+                utils::spawn(format_args!("admin {peer}"), async move {
+                    if let Err(err) = handle(stream, peer, config_path).await {
+                        log::debug!("[admin] [{peer}] {err}");
+                    }
+                });
+            }
+            Err(err) => log::warn!("[admin] failed to accept connection: {err}"),
+        }
+    }
+}
+
+async fn execute(line: &str, config_path: &Path) -> Result<String, String> {
+    let mut args = line.split_whitespace();
+    let cmd = args.next().unwrap_or_default();
+    let mut output = String::new();
+
+    match (cmd, args.next(), args.next()) {
+        ("stats", None, _) => {
+            let stats = Socks5Server::stats();
+            let server = Connection::get_if_open().map(|conn| conn.server());
+
+            let _ = writeln!(output, "connections {}", stats.connections);
+            let _ = writeln!(output, "relays {}", stats.relays);
+            let _ = writeln!(output, "udp_sessions {}", stats.udp_sessions);
+            let _ = writeln!(
+                output,
+                "recent_errors {}",
+                Diagnostics::recent_errors().len()
+            );
+            let _ = writeln!(output, "server {}", server.as_deref().unwrap_or("-"));
+            let _ = writeln!(output, "draining {}", stats.draining);
+
+            for quic in QuicStats::all() {
+                let _ = writeln!(
+                    output,
+                    "quic {} rtt_ms {} cwnd {} sent_packets {} lost_packets {} loss_percent {:.2} congestion_events {}",
+                    quic.server,
+                    quic.rtt.as_millis(),
+                    quic.cwnd,
+                    quic.sent_packets,
+                    quic.lost_packets,
+                    quic.loss_percent(),
+                    quic.congestion_events
+                );
+            }
+        }
+        ("list-relays", None, _) => {
+            for relay in Socks5Server::active_relays() {
+                let started = humantime::format_rfc3339_seconds(relay.started);
+                let _ = writeln!(
+                    output,
+                    "{} {started} {} {} {} {} {}",
+                    relay.id,
+                    relay.peer,
+                    relay.target,
+                    relay.via.as_deref().unwrap_or("-"),
+                    relay.bytes_up,
+                    relay.bytes_down
+                );
+            }
+        }
+        ("cancel", Some(id), None) => {
+            let id = id.parse().map_err(|_| format!("invalid relay ID: {id}"))?;
+
+            if !Socks5Server::cancel_relay(id) {
+                return Err(format!("no such relay: {id}"));
+            }
+        }
+        ("drain", None, _) => {
+            if !Socks5Server::drain() {
+                return Err("already draining".to_owned());
+            }
+        }
+        ("drain-server", Some(server), deadline) => {
+            if args.next().is_some() {
+                return Err(format!("invalid arguments for `{cmd}`"));
+            }
+
+            let deadline = deadline
+                .map(|deadline| {
+                    humantime::parse_duration(deadline)
+                        .map_err(|_| format!("invalid deadline: {deadline}"))
+                })
+                .transpose()?;
+
+            if !Connection::drain_server(server, deadline)
+                .await
+                .map_err(|err| err.to_string())?
+            {
+                return Err(format!("already draining: {server}"));
+            }
+        }
+        ("undrain-server", Some(server), None) => {
+            if !Connection::undrain_server(server) {
+                return Err(format!("not draining: {server}"));
+            }
+        }
+        ("reload", None, _) => {
+            let cfg = Config::read(config_path.to_owned()).map_err(|err| err.to_string())?;
+
+            // both sections are checked before either is applied
+            Router::validate(&cfg.routing).map_err(|err| err.to_string())?;
+            let restart_required =
+                Socks5Server::reload(cfg.local).map_err(|err| err.to_string())?;
+            let count = Router::reload(cfg.routing).map_err(|err| err.to_string())?;
+
+            let _ = writeln!(output, "rules {count}");
+            let _ = writeln!(
+                output,
+                "restart_required {}",
+                if restart_required.is_empty() {
+                    String::from("-")
+                } else {
+                    restart_required.join(",")
+                }
+            );
+        }
+        ("probe", Some(target), None) => {
+            let addr = parse_target(target).ok_or_else(|| format!("invalid target: {target}"))?;
+            let res = probe::probe(addr).await;
+
+            let _ = writeln!(output, "via {}", res.via.as_deref().unwrap_or("-"));
+            let _ = writeln!(output, "connect_ms {}", res.connect_time.as_millis());
+            let _ = writeln!(
+                output,
+                "response_ms {}",
+                res.response_time
+                    .map_or_else(|| "-".to_owned(), |time| time.as_millis().to_string())
+            );
+            let _ = writeln!(output, "reachable {}", res.reachable);
+            let _ = writeln!(output, "error {}", res.error.as_deref().unwrap_or("-"));
+        }
+        (
+            "stats" | "list-relays" | "cancel" | "drain" | "drain-server" | "undrain-server"
+            | "reload" | "probe",
+            ..,
+        ) => {
+            return Err(format!("invalid arguments for `{cmd}`"));
+        }
+        _ => return Err(format!("unknown command: {cmd}")),
+    }
+
+    Ok(output)
+}
+
+/// `host:port`, with IPv6 addresses in brackets
+fn parse_target(target: &str) -> Option<Address> {
+    if let Ok(addr) = target.parse() {
+        return Some(Address::SocketAddress(addr));
+    }
+
+    let (host, port) = target.rsplit_once(':')?;
+
+    if host.is_empty() || host.contains(':') {
+        return None;
+    }
+
+    Some(Address::DomainAddress(host.to_owned(), port.parse().ok()?))
+}
@@ -1,9 +1,21 @@
-use crate::utils::{CongestionControl, UdpRelayMode};
+#[cfg(feature = "tower")]
+use crate::dialer::Dialer;
+use crate::{
+    resolver::Lookup,
+    utils::{
+        AccessLogFormat, Bypass, CongestionControl, DnsUpstream, FailureReply, IpPreference,
+        LogFormat, ProfileSelection, ReplyBindMode, ReplyTiming, RouteAction, RouteMatcher,
+        RuleEvalFailure, StreamCompression, Transport, UdpOversizePolicy, UdpRelayMode,
+    },
+};
 use lexopt::{Arg, Error as ArgumentError, Parser};
 use log::LevelFilter;
+use quinn::TransportConfig;
+use rustls::{Certificate, Error as TlsError, ServerName};
 use serde::{de::Error as DeError, Deserialize, Deserializer};
 use serde_json::Error as SerdeError;
 use std::{
+    collections::HashMap,
     env::ArgsOs,
     fmt::Display,
     fs::File,
@@ -11,9 +23,11 @@ use std::{
     net::{IpAddr, SocketAddr},
     path::PathBuf,
     str::FromStr,
+    sync::Arc,
     time::Duration,
 };
 use thiserror::Error;
+use tuic::Address;
 use uuid::Uuid;
 
 const HELP_MSG: &str = r#"
@@ -30,8 +44,45 @@ Arguments:
 pub struct Config {
     pub relay: Relay,
     pub local: Local,
+    #[serde(default = "default::dns")]
+    pub dns: Dns,
+    #[serde(default = "default::routing")]
+    pub routing: Routing,
     #[serde(default = "default::log_level")]
     pub log_level: LevelFilter,
+    #[serde(
+        default = "default::log_format",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub log_format: LogFormat,
+    pub metrics_server: Option<SocketAddr>,
+    /// Address of the control socket for runtime commands (`stats`, `list-relays`, `cancel <id>`, `drain`, `drain-server <server> [deadline]`, `undrain-server <server>`, `reload`), one per line. `reload` reads the config file again and applies to new requests its `routing` section and, in `local`, the credentials, `reply_bind_mode`, `reply_timing`, `tunnel_failure_reply`, `udp_strict_source`, `relay_linger`, `relay_write_buffer`, `relay_flush_interval`, `max_relay_lifetime`, `max_per_destination`, `relay_warn_bytes`, `relay_warn_ratio`, `relay_warn_ratio_min_bytes`, `quiet_empty_connects` and `abort_on_write_failure`. Requests in progress keep the settings they started with. Every other change needs a restart: `reload` lists those of `server`, `dual_stack`, `listen_backlog`, `tcp_keepalive`, `max_packet_size` and of switching between password and no authentication as `restart_required`, and ignores the rest, e.g. the `relay` and `dns` sections. It has no authentication, so only bind it to a loopback address. Requires the `admin` feature
+    pub admin_addr: Option<SocketAddr>,
+    #[serde(default = "default::recent_errors")]
+    pub recent_errors: usize,
+    /// File the session state is saved to on shutdown (SIGINT or SIGTERM) and restored from at startup, so that a restart reconnects quickly: the address every server was last reached at is tried first, and the saved TLS session tickets let the first connection resume the session, or use 0-RTT with `zero_rtt_handshake`. The quota usage is kept too, unless `local.quota_file` keeps it. A file of another format version or that fails to parse is ignored with a warning
+    ///
+    /// The session tickets carry TLS resumption secrets, so the file is created readable by its owner only on Unix. Unless `state_key` is set, it is not encrypted: keep it on private storage
+    pub state_file: Option<PathBuf>,
+    /// Passphrase the `state_file` is encrypted with, using ChaCha20-Poly1305 under a key derived from it with PBKDF2. A file that fails to decrypt stops the client at startup instead of being ignored, and so does a plaintext file, which anyone able to write the file could have put there, unless `state_migrate_plaintext` is set. When unset, the file is written in plaintext
+    pub state_key: Option<String>,
+    /// File holding the `state_key`, as an alternative to it. A trailing line break is ignored
+    pub state_key_file: Option<PathBuf>,
+    /// Reads a plaintext `state_file` although `state_key` is set, so that an existing file is encrypted on the next save. Meant for a single run after setting `state_key`: unset it afterwards
+    #[serde(default = "default::state_migrate_plaintext")]
+    pub state_migrate_plaintext: bool,
+    /// User and group the client switches to once the socks5 listener is bound and before it accepts any connection, e.g. for a client started as root to listen on a privileged port. Startup fails if the switch fails. The relay socket is bound before too, while `metrics_server` and `admin_addr` are bound after and so cannot use privileged ports. Files written later, e.g. `local.port_file` and `state_file` on shutdown, must be writable by that user. Unix only, requires the `privdrop` feature
+    pub run_as: Option<RunAs>,
+    /// The file this config was read from
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunAs {
+    pub uid: u32,
+    pub gid: u32,
 }
 
 #[derive(Deserialize)]
@@ -40,8 +91,26 @@ pub struct Relay {
     #[serde(deserialize_with = "deserialize_server")]
     pub server: (String, u16),
     pub uuid: Uuid,
-    pub password: String,
+    pub password: Option<String>,
+    /// File holding the password, as an alternative to `password`. It is read again for every new connection, so the secret can be rotated without a restart. A trailing line break is ignored
+    pub password_file: Option<PathBuf>,
+    /// An opaque name of this client (up to 255 bytes) sent along with the authentication, e.g. for the server to tell clients of a fleet apart in its logs. It extends the TUIC v5 `Authenticate` command, so only set it for servers that support it: tuic-server logs it, and versions that predate it skip it, but other TUIC server implementations may fail the authentication and close the connection on it
+    pub client_label: Option<String>,
     pub ip: Option<IpAddr>,
+    /// Discover the servers of `server` and `profiles` through the DNS `SRV` records of `_tuic._udp.<domain>`, trying their targets by priority and weight as RFC 2782 describes. A domain without records, or whose lookup fails, is resolved as usual with the configured port. The TLS server name stays the configured domain, and `ip` overrides the lookup
+    ///
+    /// The records are looked up through `dns.resolver`, which must not be `system`, and never through the tunnel. The address that connected last is still tried first on reconnection. Not applied to `tcp_fallback`
+    #[serde(default = "default::relay::srv")]
+    pub srv: bool,
+    /// Which address family to connect to first when a server resolves to both IPv4 and IPv6, for networks where one of them is broken
+    /// - `system`: the order the resolver returned
+    /// - `v4` / `v6`: every address of that family first, then the others
+    /// - `happy_eyeballs`: connect to the first IPv6 and the first IPv4 address at once, giving IPv6 a 250ms head start that ends early if it fails, and keep whichever connects first. The remaining addresses are tried one by one if both fail. On a host that cannot bind IPv6 sockets, IPv4 is used alone after the first attempt. Not applied through `upstream_proxy`
+    #[serde(
+        default = "default::relay::server_ip_preference",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub server_ip_preference: IpPreference,
     #[serde(default = "default::relay::certificates")]
     pub certificates: Vec<PathBuf>,
     #[serde(
@@ -49,41 +118,361 @@ pub struct Relay {
         deserialize_with = "deserialize_from_str"
     )]
     pub udp_relay_mode: UdpRelayMode,
+    #[serde(
+        default = "default::relay::udp_oversize_policy",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub udp_oversize_policy: UdpOversizePolicy,
+    /// In native mode, drop and count a packet that does not fit in the QUIC datagram send buffer, instead of letting quinn silently evict older queued datagrams (possibly fragments of a packet already partly sent) to make room
+    ///
+    /// Either way native packets are never retransmitted: bursts beyond the path capacity are lost rather than delayed, which suits games and VoIP but gives bulk transfers less throughput than the `quic` mode
+    #[serde(default = "default::relay::udp_drop_on_full")]
+    pub udp_drop_on_full: bool,
+    /// In native mode, the largest UDP payload relayed in a single QUIC datagram, for paths whose MTU is lower than what QUIC assumes, e.g. through a tunnel. At least 512. Larger packets follow `udp_oversize_policy`, with fragments sized to the limit
+    ///
+    /// The limit applied to a packet is the QUIC max datagram size minus the TUIC header, which depends on the length of the destination address, capped at this value. When unset, only the QUIC max datagram size applies
+    #[serde(default = "default::relay::udp_max_payload")]
+    pub udp_max_payload: Option<usize>,
     #[serde(
         default = "default::relay::congestion_control",
         deserialize_with = "deserialize_from_str"
     )]
     pub congestion_control: CongestionControl,
+    /// Initial congestion window of the congestion controller in bytes, e.g. `120000` for about 100 full-sized packets. A larger window speeds up the first round trips of transfers on paths with a high bandwidth-delay product, but bursts that much data into the path before any feedback, causing loss and retransmissions on constrained or shared links. When unset, quinn's default of 10 packets applies
+    pub initial_cwnd: Option<u64>,
     #[serde(default = "default::relay::alpn")]
     pub alpn: Vec<String>,
+    /// Ask the server to compress relayed TCP streams, e.g. `zstd:3`. Requires the `compression` feature
+    ///
+    /// Each new connection first asks the server whether it compresses streams, and every CONNECT then waits one round trip for the server to answer before relaying. The answer applies to that connection only, so a server that declines is asked again on the next connection. A server that rejects the question in any other way, i.e. resets or stops it, does not answer within `timeout`, or closes the connection because it does not know the request, is not asked again for the rest of the process, the connection being used uncompressed, or connected to again if it was closed. Relays only wait for connections that are already answered, so no relay is lost to that close
+    ///
+    /// zstd pays off on text-heavy traffic over slow links. Measured with `benches/compression.rs` on one Xeon core, in the 8 KiB chunks the relay writes, JSON shrinks about 7 times at levels 1 to 9 and compresses at 450 MB/s at level 1, 290 MB/s at the default level 3 and 50 MB/s at level 9. Level 19 shrinks it 10 times but compresses at 1.4 MB/s. Decompressing runs at 600 MB/s or more at every level. Already compressed traffic (TLS, media, archives) does not shrink at all and still costs a core 320 MB/s at level 3 on both ends, so mind the server load before enabling it for all streams
+    #[serde(
+        default = "default::relay::stream_compression",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub stream_compression: StreamCompression,
     #[serde(default = "default::relay::zero_rtt_handshake")]
     pub zero_rtt_handshake: bool,
+    /// Resume earlier TLS sessions to the servers, saving a full handshake on reconnection. 0-RTT handshakes need it. Disabling it makes every handshake complete, e.g. for debugging
+    #[serde(default = "default::relay::session_resumption")]
+    pub session_resumption: bool,
+    /// Number of TLS sessions kept for resumption, across all server profiles. Raise it when connecting to many distinct servers
+    #[serde(default = "default::relay::session_cache_size")]
+    pub session_cache_size: usize,
     #[serde(default = "default::relay::disable_sni")]
     pub disable_sni: bool,
+    /// Append the TLS secrets of every connection to the file named by the `SSLKEYLOGFILE` environment variable, in the NSS key log format, so that captured QUIC and TLS traffic can be decrypted, e.g. in Wireshark
+    ///
+    /// Debugging only: anyone who reads the file can decrypt the recorded sessions, including the TUIC authentication and every relayed byte. Nothing is written unless this is enabled, even if `SSLKEYLOGFILE` is set
+    #[serde(default = "default::relay::keylog")]
+    pub keylog: bool,
+    /// DSCP (0-63) marked on the QUIC packets, in the IPv4 ToS or IPv6 Traffic Class field. e.g. 46 (EF) or 34 (AF41)
+    pub dscp: Option<u8>,
+    /// A socks5 proxy to reach the servers through, for networks that block UDP to the outside but allow it through the proxy. The QUIC packets are carried in its UDP associate, so the proxy must support it and allow connecting without authentication, or every connection attempt fails with the reason
+    pub upstream_proxy: Option<SocketAddr>,
+    /// Watch the local address used to reach the server and move the QUIC connection to a fresh socket when it changes, e.g. on switching between WiFi and cellular. The relays then survive the switch through QUIC connection migration instead of dying with the old path
+    ///
+    /// The server must accept migration, which quinn-based servers do by default. The check runs every second and sends nothing
+    #[serde(default = "default::relay::enable_migration")]
+    pub enable_migration: bool,
     #[serde(default = "default::relay::timeout")]
     pub timeout: Duration,
+    /// Exit with an error once connecting to the servers kept failing for this long, so a supervisor can restart the client or alert. When unset, the client keeps retrying on every request
+    pub fail_fast_after: Option<Duration>,
+    /// While at least one relay is open, a TUIC `Heartbeat` command, a 2-byte QUIC datagram, is sent this often, whether the relays carry data or not. It keeps NAT and firewall mappings of the UDP flow alive for idle relays: middleboxes only see the flow, not the streams in it. With no relay open nothing is sent, and the connection may time out
     #[serde(default = "default::relay::heartbeat")]
     pub heartbeat: Duration,
+    /// Every heartbeat interval is randomly lengthened or shortened by up to this fraction (0 to 1), so that clients started together do not hit the server in step. 0 keeps the timing deterministic
+    #[serde(default = "default::relay::timing_jitter")]
+    pub timing_jitter: f64,
+    /// Idle timeout of the QUIC connection. When unset, the timeout advertised by the server applies
+    pub max_idle_time: Option<Duration>,
+    /// Send a QUIC `PING` frame whenever the connection sent nothing for this long, so that middleboxes dropping idle UDP flows keep the mapping of relays that carry no data, also between heartbeats when `heartbeat` is long. Only the PING frame and its acknowledgement are sent, no TUIC command and no byte on the relay streams, which TUIC has no no-op for. quinn only offers keep-alive per connection, so a connection with no relay open is kept alive too, until `idle_connection_timeout` closes it. Should be below the idle timeout of the connection to be of any use. When unset, nothing is sent beyond the heartbeats
+    pub relay_keepalive_interval: Option<Duration>,
     #[serde(default = "default::relay::disable_native_certs")]
     pub disable_native_certs: bool,
     #[serde(default = "default::relay::gc_interval")]
     pub gc_interval: Duration,
     #[serde(default = "default::relay::gc_lifetime")]
     pub gc_lifetime: Duration,
+    /// How often the path statistics of every QUIC connection are sampled: round-trip time, congestion window, sent and lost packets and congestion events. They are exported by `metrics_server`, listed by the admin `stats` command and logged by the diagnostics dump, so a lossy link can be told apart from a slow target. 0 disables sampling
+    #[serde(default = "default::relay::stats_poll_interval")]
+    pub stats_poll_interval: Duration,
+    #[serde(default = "default::relay::profiles")]
+    pub profiles: Vec<ServerProfile>,
+    #[serde(
+        default = "default::relay::profile_selection",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub profile_selection: ProfileSelection,
+    /// Pin every socks5 client, by username or else by IP address, to one of `server` and `profiles` with a consistent hash, so that its CONNECT relays keep leaving through the same server, e.g. for a stable egress IP. Each pinned server gets a connection of its own, and relays fall back to the shared connection while it is unreachable: once connecting to it failed, it is tried again after 30 seconds. UDP relays and `tcp_fallback` keep using the shared connection and the main server
+    #[serde(default = "default::relay::sticky_routing")]
+    pub sticky_routing: bool,
+    /// Named local addresses to connect to the server from, e.g. `{"wan2": "192.0.2.10"}`, for picking the egress interface of a multi-homed host per routing rule with the rule's `egress`. Each binding gets a QUIC endpoint and a connection of its own, to the server profile active at the time it connects
+    ///
+    /// A relay routed to a binding always goes through it: it does not fall back to the shared connection, and `sticky_routing` does not apply to it. Only the server addresses of the family of the bound address are tried. Not applied to UDP relays, `tcp_fallback` or through `upstream_proxy`
+    #[serde(default = "default::relay::egress_bindings")]
+    pub egress_bindings: HashMap<String, IpAddr>,
+    /// Close a connection of `sticky_routing` or `egress_bindings` once it carried no relay for this long, so clients that went quiet do not hold connections on the server. It is connected again on the next relay routed to it. The shared connection is not closed this way, so one connection always stays open. When unset, these connections stay open until they fail
+    pub idle_connection_timeout: Option<Duration>,
+    /// What CONNECT relays are carried over:
+    ///
+    /// - `quic`: the QUIC connection
+    /// - `tcp_fallback`: TLS over TCP to the same server address and port, for networks that block UDP. The server must enable `tcp_fallback`. Every relay costs a TCP and TLS handshake of its own, and the relays no longer share congestion control, so throughput and latency are worse than over QUIC. UDP relays still use QUIC
+    /// - `auto`: QUIC, switching to TCP when QUIC does not connect within `tcp_fallback_timeout`. QUIC is tried again 5 minutes after a switch
+    #[serde(
+        default = "default::relay::transport",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub transport: Transport,
+    #[serde(default = "default::relay::tcp_fallback_timeout")]
+    pub tcp_fallback_timeout: Duration,
+    /// Send priorities of CONNECT relays by target, e.g. to keep SSH and DNS responsive next to bulk transfers. The first matching rule applies, other relays get priority 0. Rules use the matchers of `routing.rules`, but are checked against the requested target only, so IP-based rules never match domain targets
+    ///
+    /// Whenever the QUIC connection can send, quinn picks the stream with pending data and the highest priority, and sends from it until it has nothing left or is blocked by flow control. Streams of the same priority take turns. This only orders what the client sends: the server schedules the downloads, and relays over `tcp_fallback` are not prioritized
+    #[serde(default = "default::relay::priority_rules")]
+    pub priority_rules: Vec<PriorityRule>,
+    /// Called with the QUIC transport config after every option above is applied and before the endpoint is built, so it runs last and can override any of them. Not read from the config file, it is an escape hatch for builds that embed the client and need a quinn setting without its own option, set through `Client::transport_config_hook()`
+    #[serde(skip)]
+    pub transport_config_hook: Option<TransportConfigHook>,
+    /// Called in the TLS handshake of every connection to the server, over QUIC or `tcp_fallback`, with the server certificate, the name it was verified against and the result of the normal verification, e.g. for a GUI to ask whether to trust a certificate on first use. Not read from the config file, it is for builds that embed the client, set through `Client::cert_verifier_hook()`
+    ///
+    /// The hook takes over the security of the connection: accepting a certificate that failed verification lets anyone on the path impersonate the server, so it should only accept a certificate the user pinned or explicitly approved. It runs inside the handshake on a runtime thread, so it must not block for long, e.g. answer from the decisions the user made earlier and reject until the user decided
+    #[serde(skip)]
+    pub cert_verifier_hook: Option<CertVerifierHook>,
+}
+
+pub type TransportConfigHook = Arc<dyn Fn(&mut TransportConfig) + Send + Sync>;
+
+pub type CertVerifierHook =
+    Arc<dyn Fn(&Certificate, &ServerName, Result<(), &TlsError>) -> VerifyDecision + Send + Sync>;
+
+/// What `relay.cert_verifier_hook` decides about a server certificate
+#[derive(Clone, Copy)]
+pub enum VerifyDecision {
+    /// Keep the result of the normal verification
+    Default,
+    /// Accept the certificate, even if the normal verification failed
+    Accept,
+    /// Reject the certificate, even if the normal verification passed
+    Reject,
+}
+
+/// An alternative server to connect to, tried after `relay.server`. Unset TLS parameters are inherited from `relay`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerProfile {
+    #[serde(deserialize_with = "deserialize_server")]
+    pub server: (String, u16),
+    pub ip: Option<IpAddr>,
+    pub sni: Option<String>,
+    pub alpn: Option<Vec<String>>,
+    pub disable_sni: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PriorityRule {
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub matcher: RouteMatcher,
+    /// Higher is sent first, negative values below the unmatched relays
+    pub priority: i32,
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Local {
     pub server: SocketAddr,
+    /// File the port the socks5 server listens on is written to once the client started successfully, e.g. for a launcher that sets `server` to port 0 and then points an application at the port the OS picked. It is written atomically and removed on shutdown (SIGINT or SIGTERM) or when every server stays unreachable for `relay.fail_fast_after`
+    pub port_file: Option<PathBuf>,
     pub username: Option<String>,
     pub password: Option<String>,
+    /// More socks5 credentials as `username: password`, accepted alongside `username` and `password`. Also read as `credentials`
+    #[serde(default = "default::local::users", alias = "credentials")]
+    pub users: HashMap<String, String>,
+    /// Traffic quotas in bytes, uploaded plus downloaded, by socks5 username. Once a user reaches the quota, new CONNECT requests are refused with `connection not allowed` while open relays carry on. UDP is not counted
+    #[serde(default = "default::local::user_quotas")]
+    pub user_quotas: HashMap<String, u64>,
+    /// Resets the usage of every user once this long has passed since the last reset, e.g. 30 days. When unset, usage only grows
+    pub quota_period: Option<Duration>,
+    /// JSON file the quota usage is loaded from at startup and saved to every minute, so it survives restarts
+    pub quota_file: Option<PathBuf>,
+    /// File every CONNECT request is appended to as one line once it ends, for log analyzers. Lines are written by a thread of their own, so a slow disk never holds up a relay: once 4096 lines are waiting, further lines are dropped and counted in `access_log_dropped_total`
+    pub access_log: Option<PathBuf>,
+    /// The line format of `access_log`:
+    ///
+    /// - `common`: the Common Log Format, `client_ip - user [time] "CONNECT host:port" reply bytes_down`, followed by `bytes_up` and the duration in seconds
+    /// - `w3c`: the W3C Extended Log File Format, with the fields `date time c-ip cs-username cs-method cs-uri sc-status sc-bytes cs-bytes time-taken`, declared at the start of the file
+    ///
+    /// `reply` is the socks5 reply code, 0 for relays that were opened. The time is when the request was received, in UTC. Missing values are written as `-`, e.g. the user of clients that did not authenticate, or the duration of requests that failed. Spaces within a value are written as `+`, control characters, `"` and `%` percent-encoded
+    #[serde(
+        default = "default::local::access_log_format",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub access_log_format: AccessLogFormat,
+    /// Refuse to start without socks5 credentials, so the listener can never serve clients that skip authentication. With credentials, a client offering both no authentication and username / password is always made to use the password, and one offering only no authentication is rejected and counted in `auth_downgrade_total`
+    #[serde(default = "default::local::reject_no_auth_clients")]
+    pub reject_no_auth_clients: bool,
     pub dual_stack: Option<bool>,
+    /// Backlog of the socks5 listener, i.e. how many incoming connections the OS queues while the client has not accepted them yet. Beyond it, new connections are refused or dropped depending on the OS
+    ///
+    /// Connections are accepted as fast as they come in normal operation, so the queue only fills under bursts or overload. A queue too small refuses clients during short bursts, one too large hides overload behind growing connection latency
+    ///
+    /// When unset, the OS maximum is used: `net.core.somaxconn` on Linux (4096 since Linux 5.4, 128 before), `kern.ipc.somaxconn` on macOS and the BSDs (128 by default), and a provider-chosen maximum on Windows. A value set here is capped by the same limits
+    pub listen_backlog: Option<u32>,
     #[serde(default = "default::local::max_packet_size")]
     pub max_packet_size: usize,
+    /// Enables TCP keepalive on accepted socks5 connections, probing after this much idle time
+    ///
+    /// The tunnel hop is covered by QUIC instead: heartbeats keep the connection busy, and once the server stops answering the idle timeout closes it along with every local stream relayed over it
+    pub tcp_keepalive: Option<Duration>,
+    /// How long a CONNECT relay keeps passing on the remote's response after the socks5 client closed its side. When unset, the relay waits for the remote to close as well
+    pub relay_linger: Option<Duration>,
+    /// Gather what a CONNECT relay receives from the remote into a buffer of this many bytes before writing it to the socks5 client, so a download takes fewer and larger writes. When unset, every read from the remote is written on its own, at most 8 KiB at a time. At most 1 MiB, as every relay allocates it
+    ///
+    /// The buffer is written out as soon as it is full, or as soon as no more data is immediately available, unless `relay_flush_interval` is set. Interactive traffic, which arrives in small bursts, is therefore written right away
+    pub relay_write_buffer: Option<usize>,
+    /// With `relay_write_buffer`, how long a partly filled buffer waits for more data before it is written. Larger values save more writes on slow downloads, at the cost of up to this much added latency. When unset, it does not wait
+    pub relay_flush_interval: Option<Duration>,
+    /// How long a CONNECT relay may stay open, however busy. Once exceeded, both sides are finished gracefully and the relay is logged as closed with `lifetime_exceeded`, leaving the socks5 client to reconnect. Unset or zero for no limit
+    ///
+    /// It counts from when the relay is opened and runs alongside `relay_linger`, whichever ends the relay first is reported
+    pub max_relay_lifetime: Option<Duration>,
+    /// Most CONNECT relays open at once to the same destination, by the host and port the socks5 client requested, to spare a rate-sensitive backend. Requests beyond it are replied `connection not allowed`. When unset, there is no limit and relays are not counted, so a limit set by `reload` only counts the relays opened after it
+    pub max_per_destination: Option<usize>,
+    /// Log a warning with the peer and the destination for every CONNECT relay that sent or received more than this many bytes in either direction, as a hint of e.g. exfiltration or traffic reflection. The bytes of each direction are logged, so a lopsided relay stands out. This is an advisory heuristic only: it enforces nothing and large downloads trip it too. When unset, no warning is logged
+    pub relay_warn_bytes: Option<u64>,
+    /// Log a warning with the peer and the destination for every CONNECT relay of which one direction carried more than this many times the bytes of the other, e.g. 100. Relays that moved less than `relay_warn_ratio_min_bytes` in total are left out, so short exchanges such as a request answered by a page do not trip it. Advisory only like `relay_warn_bytes`, as downloads and uploads are lopsided by nature. Must be at least 1, when unset no warning is logged
+    pub relay_warn_ratio: Option<f64>,
+    /// Bytes a CONNECT relay must move in both directions together before `relay_warn_ratio` applies to it
+    #[serde(default = "default::local::relay_warn_ratio_min_bytes")]
+    pub relay_warn_ratio_min_bytes: u64,
+    /// Log CONNECT relays that the socks5 client closes before sending anything, typically port scanners, at debug level instead of info, and their errors at debug instead of warn. As such a relay is only told apart when it closes, the `relay_started` line of every relay is then logged at debug level too
+    #[serde(default = "default::local::quiet_empty_connects")]
+    pub quiet_empty_connects: bool,
+    /// Close a CONNECT connection whose reply fails to write, as the client went away, with a TCP RST instead of a FIN, by setting `SO_LINGER` to 0 on the listening socket, which accepted connections inherit, and clearing it once the reply is written. No socket is then left behind waiting for the close to complete, which adds up under port scanners. A connection closed before its reply, e.g. on a failed handshake, is reset too
+    #[serde(default = "default::local::abort_on_write_failure")]
+    pub abort_on_write_failure: bool,
+    /// What the BND address of a successful CONNECT reply is set to:
+    ///
+    /// - `zero`: `0.0.0.0:0`. Accepted by every client, but tells them nothing
+    /// - `echo_port`: `0.0.0.0` with the target port, for clients that expect a meaningful BND.PORT. Clients that check BND.PORT against their own bookkeeping may be confused by it
+    /// - `relay_socket`: the local address of the socks5 connection, i.e. the proxy address the client connected to. Suits clients that validate the reply against the proxy they configured
+    /// - `server`: the address of the TUIC server the relay goes through, or `0.0.0.0:0` for relays routed directly. Reveals the server to the local application
+    /// - a socket address, e.g. `127.0.0.1:1080`, replied as is
+    #[serde(
+        default = "default::local::reply_bind_mode",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub reply_bind_mode: ReplyBindMode,
+    /// When a CONNECT request is replied to:
+    ///
+    /// - `after_connect`: once the relay is opened. A failure to open it is replied to the socks5 client as such, at the cost of a round trip to the server before the client can send anything
+    /// - `immediate`: right away, so that the client can send its first bytes, e.g. a TLS ClientHello, while the relay is still being opened. They wait in the socket buffer until the relay is open. A failure to open it can only be told by closing the connection, and `reply_bind_mode` `server` replies `0.0.0.0:0` as the server is not known yet
+    #[serde(
+        default = "default::local::reply_timing",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub reply_timing: ReplyTiming,
+    /// Deprecated, same as `reply_bind_mode` `echo_port`
+    #[serde(default = "default::local::reply_echo_port")]
+    pub reply_echo_port: bool,
+    #[serde(default = "default::local::bypass_on_failure")]
+    pub bypass_on_failure: bool,
+    /// The reply to a CONNECT request that could not be relayed because the tunnel is unavailable. Over `tcp_fallback`, the server tells when it fails to reach the target, which is always replied with `host_unreachable`
+    #[serde(
+        default = "default::local::tunnel_failure_reply",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub tunnel_failure_reply: FailureReply,
+    /// Require UDP packets to come from the port declared in the associate request, not only from its IP. Disable for clients that send from another port, e.g. behind NAT
+    #[serde(default = "default::local::udp_strict_source")]
+    pub udp_strict_source: bool,
+    #[serde(
+        default = "default::local::bypass",
+        deserialize_with = "deserialize_vec_from_str"
+    )]
+    pub bypass: Vec<Bypass>,
+    /// Called with the target of every CONNECT request before it is dialed, to pick settings of that relay other than the ones above, e.g. a short lifetime for some destinations. Not read from the config file, it is for builds that embed the client, set through `Client::relay_overrides_hook()`. It runs on a runtime thread for every request, so it must not block
+    #[serde(skip)]
+    pub relay_overrides_hook: Option<RelayOverridesHook>,
+    /// Opens the relays of CONNECT requests through the server in place of `relay.transport`, e.g. with tower middleware around `TuicService`. Not read from the config file, it is for builds that embed the client, set through `Client::connect_service()`. Requires the `tower` feature
+    #[cfg(feature = "tower")]
+    #[serde(skip)]
+    pub connect_service: Option<Box<dyn Dialer>>,
+}
+
+pub type RelayOverridesHook = Arc<dyn Fn(&Address) -> RelayOverrides + Send + Sync>;
+
+/// Settings of a single relay that `local.relay_overrides_hook` picks for its target. Every `None` keeps what `local` sets
+#[derive(Clone, Copy, Default)]
+pub struct RelayOverrides {
+    /// How long opening the stream may take, on top of the timeouts of the dialer itself
+    pub connect_timeout: Option<Duration>,
+    /// Replaces `local.relay_linger`
+    pub relay_linger: Option<Duration>,
+    /// Replaces `local.max_relay_lifetime`
+    pub max_relay_lifetime: Option<Duration>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dns {
+    #[serde(default = "default::dns::resolve_locally")]
+    pub resolve_locally: bool,
+    #[serde(
+        default = "default::dns::resolver",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub resolver: DnsUpstream,
+    #[serde(default = "default::dns::tunnel")]
+    pub tunnel: bool,
+    #[serde(default = "default::dns::timeout")]
+    pub timeout: Duration,
+    /// Most domains whose answers are cached, the least recently used being dropped first. Answers are cached for their TTL, except those of the `system` resolver, which caches on its own. 0 disables the cache
+    #[serde(default = "default::dns::cache_size")]
+    pub cache_size: usize,
+    /// How long a failed lookup, e.g. `NXDOMAIN` or a timeout, is cached, so a name that keeps failing is not looked up again on every request. 0 disables negative caching
+    #[serde(default = "default::dns::neg_ttl")]
+    pub neg_ttl: Duration,
+    /// Looks up target domains and `SRV` records in place of `resolver`, with the cache of `cache_size` and `neg_ttl` in front of it. Not read from the config file, it is for builds that embed the client and integrate a resolver of their own, set through `Client::lookup()`
+    #[serde(skip)]
+    pub lookup: Option<Arc<dyn Lookup>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Routing {
+    #[serde(default = "default::routing::rules")]
+    pub rules: Vec<RoutingRule>,
+    #[serde(
+        default = "default::routing::default",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub default: RouteAction,
+    /// What happens when a rule cannot be evaluated, e.g. an IP-based rule for a domain that failed to resolve, or a GeoIP rule while the database is missing: `fail_open` sends the connection through the tunnel, `fail_closed` rejects it with `connection not allowed`
+    #[serde(
+        default = "default::routing::rule_eval_failure",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub rule_eval_failure: RuleEvalFailure,
+    pub geoip_path: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingRule {
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub matcher: RouteMatcher,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub action: RouteAction,
+    /// For `tunnel` rules, the name of the `relay.egress_bindings` entry to connect to the server from
+    pub egress: Option<String>,
 }
 
 impl Config {
+    #[allow(clippy::collapsible_match)]
     pub fn parse(args: ArgsOs) -> Result<Self, ConfigError> {
         let mut parser = Parser::from_iter(args);
         let mut path = None;
@@ -109,17 +498,36 @@ impl Config {
             return Err(ConfigError::NoConfig);
         }
 
-        let file = File::open(path.unwrap())?;
-        Ok(serde_json::from_reader(file)?)
+        Self::read(PathBuf::from(path.unwrap()))
+    }
+
+    /// Reads and validates the config file at `path`
+    pub fn read(path: PathBuf) -> Result<Self, ConfigError> {
+        let file = File::open(&path)?;
+        let mut cfg: Self = serde_json::from_reader(file)?;
+        cfg.path = path;
+        Ok(cfg)
     }
 }
 
 mod default {
+    use super::{Dns, Routing};
+    use crate::utils::LogFormat;
     use log::LevelFilter;
 
     pub mod relay {
-        use crate::utils::{CongestionControl, UdpRelayMode};
-        use std::{path::PathBuf, time::Duration};
+        use crate::{
+            config::{PriorityRule, ServerProfile},
+            utils::{
+                CongestionControl, IpPreference, ProfileSelection, StreamCompression, Transport,
+                UdpOversizePolicy, UdpRelayMode,
+            },
+        };
+        use std::{collections::HashMap, net::IpAddr, path::PathBuf, time::Duration};
+
+        pub fn srv() -> bool {
+            false
+        }
 
         pub fn certificates() -> Vec<PathBuf> {
             Vec::new()
@@ -129,6 +537,54 @@ mod default {
             UdpRelayMode::Native
         }
 
+        pub fn udp_oversize_policy() -> UdpOversizePolicy {
+            UdpOversizePolicy::Fragment
+        }
+
+        pub fn udp_drop_on_full() -> bool {
+            false
+        }
+
+        pub fn udp_max_payload() -> Option<usize> {
+            None
+        }
+
+        pub fn profiles() -> Vec<ServerProfile> {
+            Vec::new()
+        }
+
+        pub fn profile_selection() -> ProfileSelection {
+            ProfileSelection::Failover
+        }
+
+        pub fn keylog() -> bool {
+            false
+        }
+
+        pub fn sticky_routing() -> bool {
+            false
+        }
+
+        pub fn egress_bindings() -> HashMap<String, IpAddr> {
+            HashMap::new()
+        }
+
+        pub fn server_ip_preference() -> IpPreference {
+            IpPreference::System
+        }
+
+        pub fn transport() -> Transport {
+            Transport::Quic
+        }
+
+        pub fn tcp_fallback_timeout() -> Duration {
+            Duration::from_secs(3)
+        }
+
+        pub fn priority_rules() -> Vec<PriorityRule> {
+            Vec::new()
+        }
+
         pub fn congestion_control() -> CongestionControl {
             CongestionControl::Cubic
         }
@@ -137,14 +593,30 @@ mod default {
             Vec::new()
         }
 
+        pub fn stream_compression() -> StreamCompression {
+            StreamCompression::None
+        }
+
         pub fn zero_rtt_handshake() -> bool {
             false
         }
 
+        pub fn session_resumption() -> bool {
+            true
+        }
+
+        pub fn session_cache_size() -> usize {
+            32
+        }
+
         pub fn disable_sni() -> bool {
             false
         }
 
+        pub fn enable_migration() -> bool {
+            false
+        }
+
         pub fn timeout() -> Duration {
             Duration::from_secs(8)
         }
@@ -153,6 +625,10 @@ mod default {
             Duration::from_secs(3)
         }
 
+        pub fn timing_jitter() -> f64 {
+            0.1
+        }
+
         pub fn disable_native_certs() -> bool {
             false
         }
@@ -164,17 +640,162 @@ mod default {
         pub fn gc_lifetime() -> Duration {
             Duration::from_secs(15)
         }
+
+        pub fn stats_poll_interval() -> Duration {
+            Duration::from_secs(10)
+        }
     }
 
     pub mod local {
+        use crate::utils::{AccessLogFormat, Bypass, FailureReply, ReplyBindMode, ReplyTiming};
+        use socks5_proto::Reply;
+        use std::collections::HashMap;
+
+        pub fn users() -> HashMap<String, String> {
+            HashMap::new()
+        }
+
+        pub fn user_quotas() -> HashMap<String, u64> {
+            HashMap::new()
+        }
+
+        pub fn reject_no_auth_clients() -> bool {
+            false
+        }
+
+        pub fn access_log_format() -> AccessLogFormat {
+            AccessLogFormat::Common
+        }
+
         pub fn max_packet_size() -> usize {
             1500
         }
+
+        pub fn abort_on_write_failure() -> bool {
+            false
+        }
+
+        pub fn relay_warn_ratio_min_bytes() -> u64 {
+            1024 * 1024
+        }
+
+        pub fn quiet_empty_connects() -> bool {
+            true
+        }
+
+        pub fn reply_bind_mode() -> ReplyBindMode {
+            ReplyBindMode::Zero
+        }
+
+        pub fn reply_timing() -> ReplyTiming {
+            ReplyTiming::AfterConnect
+        }
+
+        pub fn reply_echo_port() -> bool {
+            false
+        }
+
+        pub fn bypass_on_failure() -> bool {
+            false
+        }
+
+        pub fn bypass() -> Vec<Bypass> {
+            Vec::new()
+        }
+
+        pub fn tunnel_failure_reply() -> FailureReply {
+            FailureReply(Reply::GeneralFailure)
+        }
+
+        pub fn udp_strict_source() -> bool {
+            true
+        }
+    }
+
+    pub mod dns {
+        use crate::utils::DnsUpstream;
+        use std::time::Duration;
+
+        pub fn resolve_locally() -> bool {
+            false
+        }
+
+        pub fn resolver() -> DnsUpstream {
+            DnsUpstream::System
+        }
+
+        pub fn tunnel() -> bool {
+            false
+        }
+
+        pub fn timeout() -> Duration {
+            Duration::from_secs(5)
+        }
+
+        pub fn cache_size() -> usize {
+            4096
+        }
+
+        pub fn neg_ttl() -> Duration {
+            Duration::from_secs(10)
+        }
+    }
+
+    pub fn dns() -> Dns {
+        Dns {
+            resolve_locally: dns::resolve_locally(),
+            resolver: dns::resolver(),
+            tunnel: dns::tunnel(),
+            timeout: dns::timeout(),
+            cache_size: dns::cache_size(),
+            neg_ttl: dns::neg_ttl(),
+            lookup: None,
+        }
+    }
+
+    pub mod routing {
+        use crate::{
+            config::RoutingRule,
+            utils::{RouteAction, RuleEvalFailure},
+        };
+
+        pub fn rules() -> Vec<RoutingRule> {
+            Vec::new()
+        }
+
+        pub fn default() -> RouteAction {
+            RouteAction::Tunnel
+        }
+
+        pub fn rule_eval_failure() -> RuleEvalFailure {
+            RuleEvalFailure::FailOpen
+        }
+    }
+
+    pub fn routing() -> Routing {
+        Routing {
+            rules: routing::rules(),
+            default: routing::default(),
+            rule_eval_failure: routing::rule_eval_failure(),
+            geoip_path: None,
+        }
     }
 
     pub fn log_level() -> LevelFilter {
         LevelFilter::Warn
     }
+
+    pub fn log_format() -> LogFormat {
+        LogFormat::Text
+    }
+
+    pub fn recent_errors() -> usize {
+        32
+    }
+
+    pub fn state_migrate_plaintext() -> bool {
+        false
+    }
 }
 
 pub fn deserialize_from_str<'de, T, D>(deserializer: D) -> Result<T, D::Error>
@@ -187,6 +808,18 @@ where
     T::from_str(&s).map_err(DeError::custom)
 }
 
+pub fn deserialize_vec_from_str<'de, T, D>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    T: FromStr,
+    <T as FromStr>::Err: Display,
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| T::from_str(s).map_err(DeError::custom))
+        .collect()
+}
+
 pub fn deserialize_server<'de, D>(deserializer: D) -> Result<(String, u16), D::Error>
 where
     D: Deserializer<'de>,
@@ -1,72 +1,139 @@
 use crate::{
-    config::Relay,
-    socks5::Server as Socks5Server,
-    utils::{self, CongestionControl, ServerAddr, UdpRelayMode},
+    config::{CertVerifierHook, PriorityRule, Relay, VerifyDecision},
+    diagnostics::Diagnostics,
+    forward::QuicError,
+    metrics::QuicStats,
+    state::State,
+    tcp::TcpTransport,
+    udp,
+    upstream::UdpRelay,
+    utils::{
+        self, CongestionControl, IpPreference, ProfileSelection, ServerAddr, StreamCompression,
+        Transport, UdpOversizePolicy, UdpRelayMode,
+    },
     Error,
 };
 use bytes::Bytes;
 use crossbeam_utils::atomic::AtomicCell;
-use once_cell::sync::OnceCell;
+use once_cell::sync::{Lazy, OnceCell};
 use parking_lot::Mutex;
 use quinn::{
     congestion::{BbrConfig, CubicConfig, NewRenoConfig},
-    ClientConfig, Connection as QuinnConnection, Endpoint as QuinnEndpoint, EndpointConfig,
-    RecvStream, SendStream, TokioRuntime, TransportConfig, VarInt,
+    ClientConfig, Connection as QuinnConnection, ConnectionError, Endpoint as QuinnEndpoint,
+    EndpointConfig, IdleTimeout, RecvStream, SendStream, TokioRuntime, TransportConfig, VarInt,
 };
 use register_count::{Counter, Register};
-use rustls::{version, ClientConfig as RustlsClientConfig};
-use socks5_proto::Address as Socks5Address;
+use rustls::{
+    client::{
+        ClientSessionMemoryCache, NoClientSessionStorage, ServerCertVerified, ServerCertVerifier,
+        StoresClientSessions, WebPkiVerifier,
+    },
+    version, Certificate, ClientConfig as RustlsClientConfig, Error as TlsError, KeyLog,
+    KeyLogFile, ServerName,
+};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::{
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    collections::{HashMap, HashSet},
+    env, fs,
+    future::Future,
+    io::{Error as IoError, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 use tokio::{
-    sync::{Mutex as AsyncMutex, OnceCell as AsyncOnceCell},
-    time,
+    sync::{Mutex as AsyncMutex, Notify, OnceCell as AsyncOnceCell},
+    time::{self, Instant},
 };
-use tuic::Address;
+#[cfg(feature = "compression")]
+use tuic::Connect as ConnectHeader;
+use tuic::{Address, Packet as PacketHeader};
 use tuic_quinn::{side, Connect, Connection as Model, Task};
 use uuid::Uuid;
 
-static ENDPOINT: OnceCell<Mutex<Endpoint>> = OnceCell::new();
+/// Locked while a connection is being established, so that connections are made one at a time
+static ENDPOINT: OnceCell<AsyncMutex<Endpoint>> = OnceCell::new();
 static CONNECTION: AsyncOnceCell<AsyncMutex<Connection>> = AsyncOnceCell::const_new();
+/// The address every server was last connected at, by server name and port
+static KNOWN_ADDRS: Lazy<Mutex<HashMap<String, SocketAddr>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 static TIMEOUT: AtomicCell<Duration> = AtomicCell::new(Duration::from_secs(0));
+static UDP_OVERSIZE_POLICY: AtomicCell<UdpOversizePolicy> =
+    AtomicCell::new(UdpOversizePolicy::Fragment);
+static UDP_DROP_ON_FULL: AtomicCell<bool> = AtomicCell::new(false);
+static UDP_MAX_PAYLOAD: AtomicCell<Option<usize>> = AtomicCell::new(None);
+static STREAM_COMPRESSION: AtomicCell<StreamCompression> = AtomicCell::new(StreamCompression::None);
+/// Servers that did not answer when asked whether they compress streams, by server name and port. They are not asked again
+#[cfg(feature = "compression")]
+static NO_STREAM_COMPRESSION: Lazy<Mutex<HashSet<String>>> =
+    Lazy::new(|| Mutex::new(HashSet::new()));
+/// Relays opened through `local.connect_service`. Their streams are handed out without the connection they belong to, so every connection counts them as its tasks to send heartbeats and not to be closed as idle. Draining a server does not wait for them
+#[cfg(feature = "tower")]
+pub static SERVICE_RELAYS: Lazy<Counter> = Lazy::new(Counter::new);
+static FAIL_FAST_AFTER: AtomicCell<Option<Duration>> = AtomicCell::new(None);
+static IDLE_CONNECTION_TIMEOUT: AtomicCell<Option<Duration>> = AtomicCell::new(None);
+static UNREACHABLE_SINCE: AtomicCell<Option<Instant>> = AtomicCell::new(None);
+static UNREACHABLE: Lazy<Notify> = Lazy::new(Notify::new);
+static CLIENT_LABEL: OnceCell<Arc<str>> = OnceCell::new();
+static PRIORITY_RULES: OnceCell<Vec<PriorityRule>> = OnceCell::new();
+/// Connections of `sticky_routing`, by profile index
+static STICKY_CONNECTIONS: Lazy<Mutex<HashMap<usize, ConnectionSlot>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+/// Profiles of `sticky_routing` that failed to connect, by profile index, with the time until which their relays use the shared connection
+static STICKY_UNAVAILABLE: Lazy<Mutex<HashMap<usize, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static EGRESS_BINDINGS: OnceCell<HashMap<String, IpAddr>> = OnceCell::new();
+/// Connections of `egress_bindings`, by binding name
+static EGRESS_CONNECTIONS: Lazy<Mutex<HashMap<String, ConnectionSlot>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+/// Server profiles taken out of use by `Connection::drain_server()`, by server name and port
+static DRAINING: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+/// Set once binding an IPv6 socket failed for another reason than port exhaustion, so the host is taken to have no IPv6 and later connects skip it
+static NO_IPV6: AtomicCell<bool> = AtomicCell::new(false);
+
+/// A connection kept for reuse, empty until one is made
+type ConnectionSlot = Arc<AsyncMutex<Option<Connection>>>;
 
 const DEFAULT_CONCURRENT_STREAMS: usize = 32;
+const MIGRATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a pinned server that failed to connect is skipped, so its relays do not each wait for the connection timeout
+const STICKY_RETRY_AFTER: Duration = Duration::from_secs(30);
+/// How long IPv6 is raced alone before IPv4 joins, the "Connection Attempt Delay" recommended in RFC 8305
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+/// Keeps a 64 KiB packet within the 255 fragments a TUIC packet can be split into
+const MIN_UDP_MAX_PAYLOAD: usize = 512;
 
 pub struct Endpoint {
     ep: QuinnEndpoint,
-    server: ServerAddr,
+    profiles: Vec<Profile>,
+    profile_selection: ProfileSelection,
+    active_profile: usize,
     uuid: Uuid,
-    password: Arc<[u8]>,
+    password: Password,
     udp_relay_mode: UdpRelayMode,
     zero_rtt_handshake: bool,
+    dscp: Option<u8>,
+    upstream_proxy: Option<SocketAddr>,
+    ip_preference: IpPreference,
+    sticky_routing: bool,
+    /// The endpoints of `egress_bindings`, bound on first use
+    egress_eps: HashMap<String, QuinnEndpoint>,
+    enable_migration: bool,
     heartbeat: Duration,
+    timing_jitter: f64,
     gc_interval: Duration,
     gc_lifetime: Duration,
+    stats_poll_interval: Duration,
 }
 
 impl Endpoint {
     pub fn set_config(cfg: Relay) -> Result<(), Error> {
         let certs = utils::load_certs(cfg.certificates, cfg.disable_native_certs)?;
-
-        let mut crypto = RustlsClientConfig::builder()
-            .with_safe_default_cipher_suites()
-            .with_safe_default_kx_groups()
-            .with_protocol_versions(&[&version::TLS13])
-            .unwrap()
-            .with_root_certificates(certs)
-            .with_no_client_auth();
-
-        crypto.alpn_protocols = cfg.alpn.into_iter().map(|alpn| alpn.into_bytes()).collect();
-        crypto.enable_early_data = true;
-        crypto.enable_sni = !cfg.disable_sni;
-
-        let mut config = ClientConfig::new(Arc::new(crypto));
         let mut tp_cfg = TransportConfig::default();
 
         tp_cfg
@@ -74,70 +141,327 @@ impl Endpoint {
             .max_concurrent_uni_streams(VarInt::from(DEFAULT_CONCURRENT_STREAMS as u32))
             .max_idle_timeout(None);
 
-        match cfg.congestion_control {
-            CongestionControl::Cubic => {
-                tp_cfg.congestion_controller_factory(Arc::new(CubicConfig::default()))
+        if let Some(max_idle_time) = cfg.max_idle_time {
+            tp_cfg.max_idle_timeout(Some(
+                IdleTimeout::try_from(max_idle_time).map_err(|_| Error::InvalidMaxIdleTime)?,
+            ));
+        }
+
+        tp_cfg.keep_alive_interval(cfg.relay_keepalive_interval);
+
+        set_congestion_control(&mut tp_cfg, cfg.congestion_control, cfg.initial_cwnd);
+
+        if let Some(hook) = &cfg.transport_config_hook {
+            hook(&mut tp_cfg);
+        }
+
+        let tp_cfg = Arc::new(tp_cfg);
+
+        if !cfg.session_resumption && cfg.zero_rtt_handshake {
+            log::warn!(
+                "[connection] 0-RTT handshake requires session resumption, which is disabled"
+            );
+        }
+
+        let session_storage = Self::session_storage(cfg.session_resumption, cfg.session_cache_size);
+
+        let key_log: Option<Arc<dyn KeyLog>> = if cfg.keylog {
+            match env::var_os("SSLKEYLOGFILE") {
+                Some(path) => log::warn!(
+                    "[connection] writing TLS secrets to {}, anyone reading it can decrypt the traffic. Disable `keylog` once done debugging",
+                    PathBuf::from(path).display()
+                ),
+                None => log::warn!(
+                    "[connection] `keylog` is enabled but `SSLKEYLOGFILE` is not set, no TLS secrets will be written"
+                ),
             }
-            CongestionControl::NewReno => {
-                tp_cfg.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+
+            Some(Arc::new(KeyLogFile::new()))
+        } else {
+            None
+        };
+
+        let tls_config = |alpn: Vec<String>, disable_sni: bool| {
+            let builder = RustlsClientConfig::builder()
+                .with_safe_default_cipher_suites()
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[&version::TLS13])
+                .unwrap();
+
+            let mut crypto = match &cfg.cert_verifier_hook {
+                Some(hook) => builder
+                    .with_custom_certificate_verifier(Arc::new(HookedVerifier {
+                        inner: WebPkiVerifier::new(certs.clone(), None),
+                        hook: hook.clone(),
+                    }))
+                    .with_no_client_auth(),
+                None => builder
+                    .with_root_certificates(certs.clone())
+                    .with_no_client_auth(),
+            };
+
+            crypto.alpn_protocols = alpn.into_iter().map(|alpn| alpn.into_bytes()).collect();
+            crypto.enable_early_data = true;
+            crypto.enable_sni = !disable_sni;
+            crypto.enable_tickets = cfg.session_resumption;
+            crypto.session_storage = session_storage.clone();
+
+            if let Some(key_log) = &key_log {
+                crypto.key_log = key_log.clone();
             }
-            CongestionControl::Bbr => {
-                tp_cfg.congestion_controller_factory(Arc::new(BbrConfig::default()))
+
+            crypto
+        };
+
+        let client_config = |alpn: Vec<String>, disable_sni: bool| {
+            let mut config = ClientConfig::new(Arc::new(tls_config(alpn, disable_sni)));
+            config.transport_config(tp_cfg.clone());
+            config
+        };
+
+        let server = ServerAddr::new(cfg.server.0.clone(), cfg.server.1, cfg.ip, cfg.srv);
+        let mut profiles = vec![Profile {
+            server_name: server.server_name().to_owned(),
+            server,
+            config: client_config(cfg.alpn.clone(), cfg.disable_sni),
+        }];
+
+        for profile in cfg.profiles {
+            let server = ServerAddr::new(profile.server.0, profile.server.1, profile.ip, cfg.srv);
+
+            profiles.push(Profile {
+                server_name: profile
+                    .sni
+                    .unwrap_or_else(|| server.server_name().to_owned()),
+                server,
+                config: client_config(
+                    profile.alpn.unwrap_or_else(|| cfg.alpn.clone()),
+                    profile.disable_sni.unwrap_or(cfg.disable_sni),
+                ),
+            });
+        }
+
+        let password = match (cfg.password, cfg.password_file) {
+            (Some(password), None) => Password::Inline(Arc::from(password.into_bytes())),
+            (None, Some(path)) => {
+                let password = Password::File(path);
+                password.load()?;
+                password
             }
+            _ => return Err(Error::InvalidPassword),
         };
 
-        config.transport_config(Arc::new(tp_cfg));
+        // only the main server is reached over TCP, the profiles are QUIC alternatives
+        if !matches!(cfg.transport, Transport::Quic) {
+            let mut tls = tls_config(cfg.alpn.clone(), cfg.disable_sni);
+            tls.enable_early_data = false;
 
-        let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
-        let mut ep = QuinnEndpoint::new(EndpointConfig::default(), None, socket, TokioRuntime)?;
-        ep.set_default_client_config(config);
+            TcpTransport::set_config(
+                cfg.transport,
+                cfg.tcp_fallback_timeout,
+                ServerAddr::new(cfg.server.0, cfg.server.1, cfg.ip, false),
+                tls,
+                cfg.uuid,
+                password.clone(),
+                cfg.timeout,
+            )?;
+        }
+
+        if !(0.0..=1.0).contains(&cfg.timing_jitter) {
+            return Err(Error::InvalidTimingJitter);
+        }
+
+        if cfg.dscp.is_some_and(|dscp| dscp > 63) {
+            return Err(Error::InvalidDscp);
+        }
+
+        if cfg
+            .udp_max_payload
+            .is_some_and(|max| max < MIN_UDP_MAX_PAYLOAD)
+        {
+            return Err(Error::InvalidUdpMaxPayload);
+        }
+
+        if let Some(label) = cfg.client_label {
+            if label.len() > u8::MAX as usize {
+                return Err(Error::InvalidClientLabel);
+            }
+
+            CLIENT_LABEL
+                .set(Arc::from(label))
+                .map_err(|_| "client label already initialized")
+                .unwrap();
+        }
+
+        PRIORITY_RULES
+            .set(cfg.priority_rules)
+            .map_err(|_| "priority rules already initialized")
+            .unwrap();
+
+        EGRESS_BINDINGS
+            .set(cfg.egress_bindings)
+            .map_err(|_| "egress bindings already initialized")
+            .unwrap();
+
+        let socket = bind_socket(SocketAddr::from(([0, 0, 0, 0], 0)), cfg.dscp)?;
+        let ep = QuinnEndpoint::new(EndpointConfig::default(), None, socket, TokioRuntime)?;
 
         let ep = Self {
             ep,
-            server: ServerAddr::new(cfg.server.0, cfg.server.1, cfg.ip),
+            profiles,
+            profile_selection: cfg.profile_selection,
+            active_profile: 0,
             uuid: cfg.uuid,
-            password: Arc::from(cfg.password.into_bytes().into_boxed_slice()),
+            password,
             udp_relay_mode: cfg.udp_relay_mode,
             zero_rtt_handshake: cfg.zero_rtt_handshake,
+            dscp: cfg.dscp,
+            upstream_proxy: cfg.upstream_proxy,
+            ip_preference: cfg.server_ip_preference,
+            sticky_routing: cfg.sticky_routing,
+            egress_eps: HashMap::new(),
+            enable_migration: cfg.enable_migration,
             heartbeat: cfg.heartbeat,
+            timing_jitter: cfg.timing_jitter,
             gc_interval: cfg.gc_interval,
             gc_lifetime: cfg.gc_lifetime,
+            stats_poll_interval: cfg.stats_poll_interval,
         };
 
+        *KNOWN_ADDRS.lock() = State::take_servers();
+
         ENDPOINT
-            .set(Mutex::new(ep))
+            .set(AsyncMutex::new(ep))
             .map_err(|_| "endpoint already initialized")
             .unwrap();
 
         TIMEOUT.store(cfg.timeout);
+        UDP_OVERSIZE_POLICY.store(cfg.udp_oversize_policy);
+        UDP_DROP_ON_FULL.store(cfg.udp_drop_on_full);
+        UDP_MAX_PAYLOAD.store(cfg.udp_max_payload);
+        STREAM_COMPRESSION.store(cfg.stream_compression);
+        FAIL_FAST_AFTER.store(cfg.fail_fast_after);
+        IDLE_CONNECTION_TIMEOUT.store(
+            cfg.idle_connection_timeout
+                .filter(|timeout| !timeout.is_zero()),
+        );
 
         Ok(())
     }
 
-    async fn connect(&mut self) -> Result<Connection, Error> {
+    /// The TLS session cache shared by all profiles, so `cache_size` bounds the memory used for every server. Stores nothing when `resumption` is off
+    fn session_storage(resumption: bool, cache_size: usize) -> Arc<dyn StoresClientSessions> {
+        if resumption {
+            match State::session_cache(cache_size) {
+                Some(cache) => cache,
+                None => ClientSessionMemoryCache::new(cache_size),
+            }
+        } else {
+            Arc::new(NoClientSessionStorage {})
+        }
+    }
+
+    /// The address every server was last connected at, for the state file
+    pub fn known_servers() -> HashMap<String, SocketAddr> {
+        KNOWN_ADDRS.lock().clone()
+    }
+
+    /// With `sticky_routing` and several profiles, the profile `key` is pinned to. Rendezvous hashing keeps every key on its profile when other profiles are added or removed
+    fn sticky_profile(&self, key: &str) -> Option<usize> {
+        if !self.sticky_routing || self.profiles.len() < 2 {
+            return None;
+        }
+
+        // a draining profile hands its keys over to the others until it is back
+        let draining = DRAINING.lock();
+
+        let servers = self
+            .profiles
+            .iter()
+            .map(|profile| profile.server.to_string())
+            .enumerate()
+            .filter(|(_, server)| !draining.contains(server));
+
+        rendezvous(key, servers)
+    }
+
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    fn has_profile(&self, name: &str) -> bool {
+        self.profiles
+            .iter()
+            .any(|profile| profile.server.to_string() == name)
+    }
+
+    /// Whether `name` is an entry of `egress_bindings`
+    pub fn has_egress(name: &str) -> bool {
+        EGRESS_BINDINGS
+            .get()
+            .is_some_and(|bindings| bindings.contains_key(name))
+    }
+
+    /// The address and the endpoint of the `egress_bindings` entry `name`
+    fn egress_endpoint(&mut self, name: &str) -> Result<(IpAddr, QuinnEndpoint), Error> {
+        let Some(ip) = EGRESS_BINDINGS
+            .get()
+            .and_then(|bindings| bindings.get(name))
+            .copied()
+        else {
+            return Err(Error::UnknownEgress(name.to_owned()));
+        };
+
+        if let Some(ep) = self.egress_eps.get(name) {
+            return Ok((ip, ep.clone()));
+        }
+
+        let socket = bind_socket(SocketAddr::new(ip, 0), self.dscp)?;
+        let ep = QuinnEndpoint::new(EndpointConfig::default(), None, socket, TokioRuntime)?;
+        self.egress_eps.insert(name.to_owned(), ep.clone());
+
+        log::info!("[connection] [{name}] egress endpoint bound to {ip}");
+
+        Ok((ip, ep))
+    }
+
+    /// Connects to every profile in turn, starting from the active one. With `only`, just that profile is tried and the rotation is left as is. With `egress`, the connection is made from the endpoint of that `egress_bindings` entry, and the rotation of the shared connection is left as is too
+    ///
+    /// With `stream_compression`, the connection is only returned once the server answered whether it compresses streams. A server that closes the connection on that question is connected to again without it
+    async fn connect(
+        &mut self,
+        only: Option<usize>,
+        egress: Option<&str>,
+    ) -> Result<Connection, Error> {
+        // a server that closed the connection on the question is not asked again, so this ends
+        loop {
+            if let Some(conn) = self
+                .connect_once(only, egress)
+                .await?
+                .negotiate_compression()
+                .await
+            {
+                return Ok(conn);
+            }
+        }
+    }
+
+    async fn connect_once(
+        &mut self,
+        only: Option<usize>,
+        egress: Option<&str>,
+    ) -> Result<Connection, Error> {
+        #[allow(clippy::too_many_arguments)]
         async fn connect_to(
             ep: &mut QuinnEndpoint,
             addr: SocketAddr,
-            server_name: &str,
+            profile: &Profile,
             uuid: Uuid,
             password: Arc<[u8]>,
             udp_relay_mode: UdpRelayMode,
             zero_rtt_handshake: bool,
+            dscp: Option<u8>,
         ) -> Result<Connection, Error> {
-            let match_ipv4 = addr.is_ipv4() && ep.local_addr().map_or(false, |addr| addr.is_ipv4());
-            let match_ipv6 = addr.is_ipv6() && ep.local_addr().map_or(false, |addr| addr.is_ipv6());
-
-            if !match_ipv4 && !match_ipv6 {
-                let bind_addr = if addr.is_ipv4() {
-                    SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
-                } else {
-                    SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
-                };
+            bind_family(ep, addr, dscp)?;
 
-                ep.rebind(UdpSocket::bind(bind_addr)?)?;
-            }
-
-            let conn = ep.connect(addr, server_name)?;
+            let conn = ep.connect_with(profile.config.clone(), addr, &profile.server_name)?;
             let conn = if zero_rtt_handshake {
                 match conn.into_0rtt() {
                     Ok((conn, _)) => conn,
@@ -145,52 +469,625 @@ impl Endpoint {
                         log::info!(
                             "[connection] 0-RTT handshake failed, fallback to 1-RTT handshake"
                         );
-                        conn.await?
+                        conn.await.map_err(handshake_error)?
                     }
                 }
             } else {
-                conn.await?
+                conn.await.map_err(handshake_error)?
             };
 
-            Ok(Connection::new(conn, udp_relay_mode, uuid, password))
+            let server = Arc::from(profile.server.to_string());
+            Ok(Connection::new(
+                conn,
+                server,
+                udp_relay_mode,
+                uuid,
+                password,
+            ))
         }
 
+        let password = self.password.load()?;
         let mut last_err = None;
 
-        for addr in self.server.resolve().await? {
-            let res = connect_to(
-                &mut self.ep,
-                addr,
-                self.server.server_name(),
-                self.uuid,
-                self.password.clone(),
-                self.udp_relay_mode,
-                self.zero_rtt_handshake,
-            )
-            .await;
-
-            match res {
-                Ok(conn) => {
-                    log::info!("[connection] established");
-                    tokio::spawn(conn.clone().init(
-                        self.heartbeat,
-                        self.gc_interval,
-                        self.gc_lifetime,
-                    ));
-                    return Ok(conn);
+        // only the shared connection moves the rotation on
+        let rotate = only.is_none() && egress.is_none();
+
+        // through a proxy, the endpoint only talks to the loopback end of the UDP associate
+        let egress = match egress.filter(|_| self.upstream_proxy.is_none()) {
+            Some(name) => Some(self.egress_endpoint(name)?),
+            None => None,
+        };
+
+        let (first, count) = match only {
+            Some(idx) => (idx, 1),
+            None => (self.active_profile, self.profiles.len()),
+        };
+
+        // starting from the first profile, every profile is tried once
+        for offset in 0..count {
+            let idx = (first + offset) % self.profiles.len();
+            let profile = &self.profiles[idx];
+            let server = profile.server.to_string();
+
+            if DRAINING.lock().contains(&server) {
+                last_err = Some(Error::ServerDraining(server));
+                continue;
+            }
+
+            if offset > 0 {
+                log::warn!("[connection] rotating to server profile {}", profile.server);
+            }
+
+            // the whole attempt may be cancelled by the connection timeout, so the next profile is made active up front
+            if rotate {
+                self.active_profile = (idx + 1) % self.profiles.len();
+            }
+
+            let mut addrs = match profile.server.resolve().await {
+                Ok(addrs) => addrs.collect::<Vec<_>>(),
+                Err(err) => {
+                    log::warn!("[connection] [{}] {err}", profile.server);
+                    last_err = Some(err);
+                    continue;
+                }
+            };
+
+            self.ip_preference.sort(&mut addrs);
+
+            if NO_IPV6.load() && addrs.iter().any(SocketAddr::is_ipv4) {
+                addrs.retain(SocketAddr::is_ipv4);
+            }
+
+            // a bound endpoint cannot switch to the other family
+            if let Some((ip, _)) = &egress {
+                addrs.retain(|addr| addr.is_ipv4() == ip.is_ipv4());
+            }
+
+            // the address that worked last goes first within its family
+            if let Some(pos) = KNOWN_ADDRS
+                .lock()
+                .get(&profile.server.to_string())
+                .and_then(|known| addrs.iter().position(|addr| addr == known))
+            {
+                let addr = addrs.remove(pos);
+                let first_of_family = addrs
+                    .iter()
+                    .position(|other| other.is_ipv4() == addr.is_ipv4())
+                    .unwrap_or(addrs.len());
+                addrs.insert(first_of_family.min(pos), addr);
+            }
+
+            // every attempt of the race needs a socket of its own family, the loopback socket of the upstream proxy only has one
+            let race = match (self.ip_preference, self.upstream_proxy, &egress) {
+                (IpPreference::HappyEyeballs, None, None) => addrs
+                    .iter()
+                    .copied()
+                    .find(SocketAddr::is_ipv6)
+                    .zip(addrs.iter().copied().find(SocketAddr::is_ipv4)),
+                _ => None,
+            };
+
+            if let Some((v6, v4)) = race {
+                let (uuid, udp_relay_mode, zero_rtt_handshake, dscp) = (
+                    self.uuid,
+                    self.udp_relay_mode,
+                    self.zero_rtt_handshake,
+                    self.dscp,
+                );
+
+                // each attempt gets an endpoint of its own, bound once the attempt starts, the one that connects replaces the current endpoint
+                let attempt = |addr: SocketAddr| {
+                    let password = password.clone();
+
+                    async move {
+                        let socket = bind_race_socket(addr, dscp)?;
+                        let mut ep = QuinnEndpoint::new(
+                            EndpointConfig::default(),
+                            None,
+                            socket,
+                            TokioRuntime,
+                        )?;
+
+                        let conn = connect_to(
+                            &mut ep,
+                            addr,
+                            profile,
+                            uuid,
+                            password,
+                            udp_relay_mode,
+                            zero_rtt_handshake,
+                            dscp,
+                        )
+                        .await?;
+
+                        Ok::<_, Error>((ep, conn))
+                    }
+                };
+
+                // dropped before the endpoint is replaced, as they borrow the profile
+                let res = happy_eyeballs(
+                    (v6, attempt(v6)),
+                    (v4, attempt(v4)),
+                    &profile.server.to_string(),
+                )
+                .await;
+
+                match res {
+                    Ok((ep, conn)) => {
+                        self.ep = ep;
+                        return Ok(self.established(idx, conn, rotate));
+                    }
+                    // without free ports for endpoints of their own, the addresses are left to the shared endpoint
+                    Err((addr, err @ Error::PortExhaustion(_))) => {
+                        log::warn!(
+                            "[connection] [{}] [{addr}] {err}, connecting from the shared endpoint",
+                            profile.server
+                        );
+                        last_err = Some(err);
+                    }
+                    Err((addr, err)) => {
+                        log::warn!("[connection] [{}] [{addr}] {err}", profile.server);
+                        last_err = Some(err);
+                        addrs.retain(|addr| *addr != v6 && *addr != v4);
+                    }
+                }
+            }
+
+            let mut ep = egress
+                .as_ref()
+                .map_or_else(|| self.ep.clone(), |(_, ep)| ep.clone());
+
+            for addr in addrs {
+                let relay = match self.upstream_proxy {
+                    Some(proxy) => match open_relay(&mut ep, proxy, addr, self.dscp).await {
+                        Ok(relay) => Some(Arc::new(relay)),
+                        Err(err) => {
+                            log::warn!(
+                                "[connection] [{}] [{addr}] [{proxy}] {err}",
+                                profile.server
+                            );
+                            last_err = Some(err);
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+
+                // through a proxy, the endpoint sends to the local end of the UDP associate instead of the server
+                let quic_addr = relay.as_ref().map_or(addr, |relay| relay.local_addr());
+
+                let res = connect_to(
+                    &mut ep,
+                    quic_addr,
+                    profile,
+                    self.uuid,
+                    password.clone(),
+                    self.udp_relay_mode,
+                    self.zero_rtt_handshake,
+                    self.dscp,
+                )
+                .await
+                .map(|mut conn| {
+                    conn._upstream = relay;
+                    conn
+                });
+
+                match res {
+                    Ok(conn) => return Ok(self.established(idx, conn, rotate)),
+                    Err(err) => {
+                        log::warn!("[connection] [{}] [{addr}] {err}", profile.server);
+                        last_err = Some(err);
+                    }
                 }
-                Err(err) => last_err = Some(err),
             }
         }
 
         Err(last_err.unwrap_or(Error::DnsResolve))
     }
+
+    fn established(&mut self, idx: usize, conn: Connection, rotate: bool) -> Connection {
+        log::info!("[connection] [{}] established", self.profiles[idx].server);
+
+        // through a proxy, the remote address is the local end of the UDP associate
+        if conn._upstream.is_none() {
+            KNOWN_ADDRS
+                .lock()
+                .insert(self.profiles[idx].server.to_string(), conn.remote_addr());
+        }
+
+        if rotate {
+            self.active_profile = match self.profile_selection {
+                ProfileSelection::Failover => idx,
+                ProfileSelection::RoundRobin => (idx + 1) % self.profiles.len(),
+            };
+        }
+
+        utils::spawn(
+            format_args!("connection"),
+            conn.clone().init(
+                self.heartbeat,
+                self.timing_jitter,
+                self.gc_interval,
+                self.gc_lifetime,
+                self.enable_migration,
+                self.stats_poll_interval,
+            ),
+        );
+
+        conn
+    }
+
+    /// Moves every connection to a new socket, so that they continue from the current local address
+    fn rebind(&mut self) -> Result<SocketAddr, Error> {
+        let bind_addr = if self.ep.local_addr()?.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+
+        self.ep.rebind(bind_socket(bind_addr, self.dscp)?)?;
+        Ok(self.ep.local_addr()?)
+    }
+}
+
+/// Sets the congestion controller, starting at `initial_cwnd` bytes if set, quinn's default otherwise
+fn set_congestion_control(
+    tp_cfg: &mut TransportConfig,
+    congestion_control: CongestionControl,
+    initial_cwnd: Option<u64>,
+) {
+    match congestion_control {
+        CongestionControl::Cubic => {
+            let mut cc = CubicConfig::default();
+
+            if let Some(window) = initial_cwnd {
+                cc.initial_window(window);
+            }
+
+            tp_cfg.congestion_controller_factory(Arc::new(cc))
+        }
+        CongestionControl::NewReno => {
+            let mut cc = NewRenoConfig::default();
+
+            if let Some(window) = initial_cwnd {
+                cc.initial_window(window);
+            }
+
+            tp_cfg.congestion_controller_factory(Arc::new(cc))
+        }
+        CongestionControl::Bbr => {
+            let mut cc = BbrConfig::default();
+
+            if let Some(window) = initial_cwnd {
+                cc.initial_window(window);
+            }
+
+            tp_cfg.congestion_controller_factory(Arc::new(cc))
+        }
+    };
+}
+
+/// Verifies the server certificate as usual, then lets `relay.cert_verifier_hook` make the final decision
+struct HookedVerifier {
+    inner: WebPkiVerifier,
+    hook: CertVerifierHook,
+}
+
+impl ServerCertVerifier for HookedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        let res = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        );
+
+        match (self.hook)(end_entity, server_name, res.as_ref().map(|_| ())) {
+            VerifyDecision::Default => res,
+            VerifyDecision::Accept => {
+                if let Err(err) = &res {
+                    log::warn!("[connection] server certificate accepted by the verifier hook despite failing verification: {err}");
+                }
+
+                Ok(ServerCertVerified::assertion())
+            }
+            VerifyDecision::Reject => Err(TlsError::General(String::from(
+                "server certificate rejected by the verifier hook",
+            ))),
+        }
+    }
+}
+
+/// Tells apart the common reasons of a failed handshake, e.g. dialing a server that is not a TUIC server
+fn handshake_error(err: ConnectionError) -> Error {
+    // TLS alerts are carried in QUIC `CRYPTO_ERROR` codes, `0x0100` + the alert
+    const CRYPTO_ERROR: u64 = 0x0100;
+    const CONNECTION_REFUSED: u64 = 0x02;
+    const ALERT_NO_APPLICATION_PROTOCOL: u64 = 120;
+    // bad_certificate, unsupported_certificate, certificate_revoked, certificate_expired, certificate_unknown, unknown_ca
+    const ALERTS_CERT: [u64; 6] = [42, 43, 44, 45, 46, 48];
+
+    let code = match &err {
+        ConnectionError::TransportError(err) => Some(u64::from(err.code)),
+        ConnectionError::ConnectionClosed(close) => Some(u64::from(close.error_code)),
+        _ => None,
+    };
+
+    let alert = code
+        .filter(|code| (CRYPTO_ERROR..CRYPTO_ERROR + 0x0100).contains(code))
+        .map(|code| code - CRYPTO_ERROR);
+
+    match err {
+        ConnectionError::TimedOut => Error::Timeout,
+        ConnectionError::VersionMismatch => Error::ProtocolMismatch(err),
+        err if alert == Some(ALERT_NO_APPLICATION_PROTOCOL) => Error::ProtocolMismatch(err),
+        err if alert.is_some_and(|alert| ALERTS_CERT.contains(&alert)) => Error::CertInvalid(err),
+        err if alert.is_some() => Error::TlsHandshakeFailed(err),
+        ConnectionError::Reset => Error::Refused(err),
+        err if code == Some(CONNECTION_REFUSED) => Error::Refused(err),
+        err => Error::Connection(err),
+    }
+}
+
+/// Where the password comes from
+#[derive(Clone)]
+pub enum Password {
+    Inline(Arc<[u8]>),
+    /// Read again for every new connection, so the secret can be rotated without a restart
+    File(PathBuf),
+}
+
+impl Password {
+    pub fn load(&self) -> Result<Arc<[u8]>, Error> {
+        match self {
+            Self::Inline(password) => Ok(password.clone()),
+            Self::File(path) => match fs::read_to_string(path) {
+                // secret mounts and editors usually end the file with a line break
+                Ok(password) => Ok(Arc::from(
+                    password.trim_end_matches(['\r', '\n']).as_bytes(),
+                )),
+                Err(err) => {
+                    log::warn!(
+                        "[connection] failed to read password file {}: {err}",
+                        path.display()
+                    );
+                    Err(Error::from(err))
+                }
+            },
+        }
+    }
+}
+
+/// The largest UDP payload relayed to `addr` in a single QUIC datagram in native mode: what is left of `max_datagram_size` after the TUIC header, capped at `udp_max_payload`
+fn udp_payload_limit(
+    max_datagram_size: usize,
+    addr: &Address,
+    udp_max_payload: Option<usize>,
+) -> usize {
+    // `VERSION` and command type bytes and the packet header
+    let overhead = 2 + PacketHeader::len_without_addr() + addr.len();
+    let limit = max_datagram_size.saturating_sub(overhead);
+    udp_max_payload.map_or(limit, |max| limit.min(max))
+}
+
+/// The index of the server `key` is pinned to among `servers`, by rendezvous hashing: the server scoring highest for the key wins, so removing a server only moves the keys it had
+fn rendezvous(key: &str, servers: impl Iterator<Item = (usize, String)>) -> Option<usize> {
+    servers
+        .max_by_key(|(_, server)| stable_hash(&[key.as_bytes(), server.as_bytes()]))
+        .map(|(idx, _)| idx)
+}
+
+/// FNV-1a over `parts`, followed by the 64-bit finalizer of MurmurHash3 to spread the bits. Unlike `DefaultHasher`, whose algorithm may change between Rust releases, it maps keys the same way across restarts and upgrades
+fn stable_hash(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+
+    for part in parts {
+        // 0xff never occurs in UTF-8, so the boundary between parts cannot be forged
+        for byte in part.iter().chain(&[0xff]) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Rebinds `ep` to a socket of the family of `addr`, unless it already has one
+fn bind_family(ep: &mut QuinnEndpoint, addr: SocketAddr, dscp: Option<u8>) -> Result<(), Error> {
+    let match_ipv4 = addr.is_ipv4() && ep.local_addr().is_ok_and(|addr| addr.is_ipv4());
+    let match_ipv6 = addr.is_ipv6() && ep.local_addr().is_ok_and(|addr| addr.is_ipv6());
+
+    if !match_ipv4 && !match_ipv6 {
+        let bind_addr = if addr.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+
+        ep.rebind(bind_socket(bind_addr, dscp)?)?;
+    }
+
+    Ok(())
+}
+
+/// Races the attempt to connect to `v6` against the one to `v4`, started `HAPPY_EYEBALLS_DELAY` later, or as soon as the attempt to `v6` failed. The first attempt to connect wins, a failed one leaves the race to the other. If both fail, returns the error of the one that failed last along with its address
+async fn happy_eyeballs<T>(
+    (v6, v6_attempt): (SocketAddr, impl Future<Output = Result<T, Error>>),
+    (v4, v4_attempt): (SocketAddr, impl Future<Output = Result<T, Error>>),
+    server: &str,
+) -> Result<T, (SocketAddr, Error)> {
+    tokio::pin!(v6_attempt, v4_attempt);
+
+    tokio::select! {
+        res = &mut v6_attempt => return match res {
+            Ok(res) => Ok(res),
+            Err(err) => {
+                log::warn!("[connection] [{server}] [{v6}] {err}");
+                v4_attempt.await.map_err(|err| (v4, err))
+            }
+        },
+        () = time::sleep(HAPPY_EYEBALLS_DELAY) => {}
+    }
+
+    tokio::select! {
+        res = &mut v6_attempt => match res {
+            Ok(res) => Ok(res),
+            Err(err) => {
+                log::warn!("[connection] [{server}] [{v6}] {err}");
+                v4_attempt.await.map_err(|err| (v4, err))
+            }
+        },
+        res = &mut v4_attempt => match res {
+            Ok(res) => Ok(res),
+            Err(err) => {
+                log::warn!("[connection] [{server}] [{v4}] {err}");
+                v6_attempt.await.map_err(|err| (v6, err))
+            }
+        },
+    }
+}
+
+/// Opens a UDP associate on `proxy` to reach `server`, relaying the packets of `ep` only. The endpoint is moved to IPv4 first if needed, as the relay listens on the IPv4 loopback
+async fn open_relay(
+    ep: &mut QuinnEndpoint,
+    proxy: SocketAddr,
+    server: SocketAddr,
+    dscp: Option<u8>,
+) -> Result<UdpRelay, Error> {
+    let loopback = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    bind_family(ep, loopback, dscp)?;
+
+    let endpoint = SocketAddr::from((Ipv4Addr::LOCALHOST, ep.local_addr()?.port()));
+    UdpRelay::open(proxy, server, endpoint).await
+}
+
+/// Binds the socket of the endpoint of a happy eyeballs attempt to `addr`. Failing to bind an IPv6 socket for another reason than port exhaustion is taken as the host having no IPv6, which is warned about once and skipped from then on
+fn bind_race_socket(addr: SocketAddr, dscp: Option<u8>) -> Result<UdpSocket, Error> {
+    let bind_addr = if addr.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+
+    let res = bind_socket(bind_addr, dscp);
+
+    match &res {
+        Err(Error::PortExhaustion(_)) => {}
+        Err(err) if addr.is_ipv6() && !NO_IPV6.swap(true) => {
+            log::warn!("[connection] IPv6 is unavailable on this host ({err}), connecting over IPv4 from now on");
+        }
+        _ => {}
+    }
+
+    res
+}
+
+/// Binds a UDP socket for the QUIC endpoint, marking outgoing packets with `dscp` if set
+///
+/// A mark rejected by the OS is logged, the socket is still used unmarked. Failing to get an ephemeral port is reported as `Error::PortExhaustion`
+fn bind_socket(addr: SocketAddr, dscp: Option<u8>) -> Result<UdpSocket, Error> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket
+        .bind(&SockAddr::from(addr))
+        .map_err(|err| bind_error(addr, err))?;
+
+    if let Some(dscp) = dscp {
+        // DSCP is the upper 6 bits of the IPv4 ToS / IPv6 Traffic Class byte, the lower 2 bits are left to ECN
+        let tos = u32::from(dscp) << 2;
+
+        let res = if addr.is_ipv4() {
+            socket.set_tos_v4(tos)
+        } else {
+            set_tclass_v6(&socket, tos)
+        };
+
+        match res {
+            Ok(()) => log::debug!("[connection] QUIC socket {addr} marked with DSCP {dscp}"),
+            Err(err) => {
+                log::warn!("[connection] failed to set DSCP {dscp} on the QUIC socket: {err}")
+            }
+        }
+    }
+
+    Ok(UdpSocket::from(socket))
+}
+
+/// An ephemeral port that cannot be had is `Error::PortExhaustion`, logged along with how to remedy it
+fn bind_error(addr: SocketAddr, err: IoError) -> Error {
+    if addr.port() != 0 || !matches!(err.kind(), ErrorKind::AddrInUse | ErrorKind::WouldBlock) {
+        return Error::from(err);
+    }
+
+    log::error!(
+        "[connection] no free local UDP port to bind {addr}: {err}. Widen the ephemeral port range of the OS (`net.ipv4.ip_local_port_range` on Linux), close other UDP sockets of this host, or use fewer `relay.egress_bindings`"
+    );
+    Error::PortExhaustion(addr)
+}
+
+/// Sets the IPv6 Traffic Class, which not every platform lets a socket set
+fn set_tclass_v6(socket: &Socket, tclass: u32) -> std::io::Result<()> {
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos"
+    ))]
+    return socket.set_tclass_v6(tclass);
+
+    #[cfg(not(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos"
+    )))]
+    {
+        let _ = (socket, tclass);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "IPv6 Traffic Class is not supported on this platform",
+        ))
+    }
+}
+
+/// Returns the local IP the OS would send packets to `remote` from, without sending anything
+fn route_source(remote: SocketAddr) -> Option<IpAddr> {
+    let bind_addr = if remote.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+
+    let socket = UdpSocket::bind(bind_addr).ok()?;
+    socket.connect(remote).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+/// A server together with the TLS parameters used to reach it
+struct Profile {
+    server: ServerAddr,
+    server_name: String,
+    config: ClientConfig,
 }
 
 #[derive(Clone)]
 pub struct Connection {
     conn: QuinnConnection,
     model: Model<side::Client>,
+    server: Arc<str>,
     uuid: Uuid,
     password: Arc<[u8]>,
     udp_relay_mode: UdpRelayMode,
@@ -198,11 +1095,21 @@ pub struct Connection {
     remote_bi_stream_cnt: Counter,
     max_concurrent_uni_streams: Arc<AtomicUsize>,
     max_concurrent_bi_streams: Arc<AtomicUsize>,
+    /// When the connection was last handed out or seen carrying a relay, for `idle_connection_timeout`
+    last_used: Arc<AtomicCell<Instant>>,
+    /// Set when closed by `idle_connection_timeout`, as quinn reports every local close alike
+    closed_idle: Arc<AtomicBool>,
+    established: Instant,
+    /// What the server agreed to compress relayed streams with, see `negotiate_compression()`
+    compression: StreamCompression,
+    /// Keeps the UDP associate on the upstream proxy open for as long as the connection is in use
+    _upstream: Option<Arc<UdpRelay>>,
 }
 
 impl Connection {
     fn new(
         conn: QuinnConnection,
+        server: Arc<str>,
         udp_relay_mode: UdpRelayMode,
         uuid: Uuid,
         password: Arc<[u8]>,
@@ -210,6 +1117,7 @@ impl Connection {
         Self {
             conn: conn.clone(),
             model: Model::<side::Client>::new(conn),
+            server,
             uuid,
             password,
             udp_relay_mode,
@@ -217,6 +1125,11 @@ impl Connection {
             remote_bi_stream_cnt: Counter::new(),
             max_concurrent_uni_streams: Arc::new(AtomicUsize::new(DEFAULT_CONCURRENT_STREAMS)),
             max_concurrent_bi_streams: Arc::new(AtomicUsize::new(DEFAULT_CONCURRENT_STREAMS)),
+            last_used: Arc::new(AtomicCell::new(Instant::now())),
+            closed_idle: Arc::new(AtomicBool::new(false)),
+            established: Instant::now(),
+            compression: StreamCompression::None,
+            _upstream: None,
         }
     }
 
@@ -226,7 +1139,8 @@ impl Connection {
                 .get()
                 .unwrap()
                 .lock()
-                .connect()
+                .await
+                .connect(None, None)
                 .await
                 .map(AsyncMutex::new)
         };
@@ -238,32 +1152,549 @@ impl Connection {
                 .lock()
                 .await;
 
-            if conn.is_closed() {
-                let new_conn = ENDPOINT.get().unwrap().lock().connect().await?;
+            // a draining connection is left to the relays it carries
+            if conn.is_closed() || conn.is_draining() {
+                let new_conn = ENDPOINT
+                    .get()
+                    .unwrap()
+                    .lock()
+                    .await
+                    .connect(None, None)
+                    .await?;
                 *conn = new_conn;
             }
 
             Ok::<_, Error>(conn.clone())
         };
 
-        let conn = time::timeout(TIMEOUT.load(), try_get_conn)
-            .await
-            .map_err(|_| Error::Timeout)??;
+        let res = time::timeout(TIMEOUT.load(), try_get_conn)
+            .await
+            .map_err(|_| Error::Timeout)
+            .and_then(|res| res);
+
+        match res {
+            Ok(conn) => {
+                if UNREACHABLE_SINCE.take().is_some() {
+                    log::warn!("[connection] servers reachable again");
+                }
+
+                Ok(conn)
+            }
+            Err(err) => {
+                Self::record_unreachable();
+                Err(err)
+            }
+        }
+    }
+
+    /// With `sticky_routing`, the connection to the server profile pinned to `key`, so that a socks5 client keeps leaving through the same server. Falls back to `get()` while that server cannot be reached
+    pub async fn get_sticky(key: &str) -> Result<Connection, Error> {
+        let idx = ENDPOINT.get().unwrap().lock().await.sticky_profile(key);
+
+        let Some(idx) = idx else {
+            return Self::get().await;
+        };
+
+        let is_unavailable = || {
+            STICKY_UNAVAILABLE
+                .lock()
+                .get(&idx)
+                .is_some_and(|until| Instant::now() < *until)
+        };
+
+        if is_unavailable() {
+            return Self::get().await;
+        }
+
+        let slot = STICKY_CONNECTIONS.lock().entry(idx).or_default().clone();
+
+        let try_get_conn = async {
+            let mut conn = slot.lock().await;
+
+            if let Some(conn) = conn.as_ref().filter(|conn| !conn.is_closed()) {
+                conn.last_used.store(Instant::now());
+                return Ok(Some(conn.clone()));
+            }
+
+            // relays that waited for an attempt that failed skip the server too
+            if is_unavailable() {
+                return Ok(None);
+            }
+
+            let new_conn = ENDPOINT
+                .get()
+                .unwrap()
+                .lock()
+                .await
+                .connect(Some(idx), None)
+                .await?;
+            *conn = Some(new_conn.clone());
+            new_conn.close_when_idle(slot.clone());
+            Ok::<_, Error>(Some(new_conn))
+        };
+
+        let res = time::timeout(TIMEOUT.load(), try_get_conn)
+            .await
+            .map_err(|_| Error::Timeout)
+            .and_then(|res| res);
+
+        match res {
+            Ok(Some(conn)) => Ok(conn),
+            Ok(None) => Self::get().await,
+            Err(err) => {
+                log::warn!(
+                    "[connection] pinned server unavailable ({err}), using the shared connection for {}",
+                    humantime::format_duration(STICKY_RETRY_AFTER)
+                );
+                STICKY_UNAVAILABLE
+                    .lock()
+                    .insert(idx, Instant::now() + STICKY_RETRY_AFTER);
+                Self::get().await
+            }
+        }
+    }
+
+    /// The connection of the `relay.egress_bindings` entry `name`. Unlike `get_sticky()`, it never falls back to the shared connection, which leaves through another interface
+    pub async fn get_egress(name: &str) -> Result<Connection, Error> {
+        let slot = EGRESS_CONNECTIONS
+            .lock()
+            .entry(name.to_owned())
+            .or_default()
+            .clone();
+
+        let try_get_conn = async {
+            let mut conn = slot.lock().await;
+
+            if let Some(conn) = conn.as_ref().filter(|conn| !conn.is_closed()) {
+                conn.last_used.store(Instant::now());
+                return Ok(conn.clone());
+            }
+
+            let new_conn = ENDPOINT
+                .get()
+                .unwrap()
+                .lock()
+                .await
+                .connect(None, Some(name))
+                .await?;
+            *conn = Some(new_conn.clone());
+            new_conn.close_when_idle(slot.clone());
+            Ok::<_, Error>(new_conn)
+        };
+
+        time::timeout(TIMEOUT.load(), try_get_conn)
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    /// With `idle_connection_timeout`, closes this connection, held in `slot`, once it carried no relay for that long
+    fn close_when_idle(&self, slot: ConnectionSlot) {
+        let Some(timeout) = IDLE_CONNECTION_TIMEOUT.load() else {
+            return;
+        };
+
+        log::debug!("[connection] [{}] dedicated connection opened", self.server);
+
+        utils::spawn(
+            format_args!("idle connection check"),
+            self.clone().check_idle(slot, timeout),
+        );
+    }
+
+    async fn check_idle(self, slot: ConnectionSlot, timeout: Duration) {
+        let is_idle = |conn: &Self| conn.tasks() == 0 && conn.last_used.load().elapsed() >= timeout;
+
+        loop {
+            time::sleep_until(self.last_used.load() + timeout).await;
+
+            if self.is_closed() {
+                break;
+            }
+
+            if self.tasks() > 0 {
+                self.last_used.store(Instant::now());
+                continue;
+            }
+
+            if !is_idle(&self) {
+                continue;
+            }
+
+            let mut slot = slot.lock().await;
+
+            if slot
+                .as_ref()
+                .is_none_or(|conn| conn.conn.stable_id() != self.conn.stable_id())
+            {
+                break;
+            }
+
+            // checked again under the lock, as a relay may have been handed the connection meanwhile. Once out of the slot, no relay gets it any more
+            if !is_idle(&self) {
+                continue;
+            }
+
+            *slot = None;
+            drop(slot);
+
+            self.close_idle();
+            log::debug!(
+                "[connection] [{}] dedicated connection closed after {} idle",
+                self.server,
+                humantime::format_duration(timeout)
+            );
+
+            break;
+        }
+    }
+
+    fn close_idle(&self) {
+        self.closed_idle.store(true, Ordering::Relaxed);
+        self.conn.close(VarInt::from_u32(0), b"idle");
+    }
+
+    /// Whether `err` ended the connection because `idle_connection_timeout` closed it, which is not an error. Other local closes still are
+    fn is_closed_idle(&self, err: &Error) -> bool {
+        matches!(err, Error::Connection(ConnectionError::LocallyClosed))
+            && self.closed_idle.load(Ordering::Relaxed)
+    }
+
+    /// Takes the server profile `name` out of use, e.g. for maintenance: its connections are no longer handed out, so new relays go through the other profiles, and each is closed once the relays and UDP sessions it carries ended, or at `deadline`, resetting those left. The server stays out of use until `undrain_server()`. Returns `false` if it is already draining
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub async fn drain_server(name: &str, deadline: Option<Duration>) -> Result<bool, Error> {
+        if !ENDPOINT.get().unwrap().lock().await.has_profile(name) {
+            return Err(Error::UnknownServer(name.to_owned()));
+        }
+
+        if !DRAINING.lock().insert(name.to_owned()) {
+            return Ok(false);
+        }
+
+        let of_server = |conn: &Connection| &*conn.server == name && !conn.is_closed();
+        let mut conns = Vec::new();
+
+        // left in place, `get()` replaces it on the next request
+        if let Some(conn) = CONNECTION.get() {
+            conns.extend(Some(conn.lock().await.clone()).filter(of_server));
+        }
+
+        let slots = STICKY_CONNECTIONS
+            .lock()
+            .values()
+            .chain(EGRESS_CONNECTIONS.lock().values())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for slot in slots {
+            let mut slot = slot.lock().await;
+
+            if slot.as_ref().is_some_and(of_server) {
+                conns.extend(slot.take());
+            }
+        }
+
+        log::warn!(
+            event = "server_drain_started",
+            server = name,
+            connections = conns.len();
+            "[connection] [{name}] draining {} connection(s){}",
+            conns.len(),
+            deadline.map_or_else(String::new, |deadline| format!(
+                ", resetting what is left after {}",
+                humantime::format_duration(deadline)
+            ))
+        );
+
+        utils::spawn(
+            format_args!("server drain"),
+            Self::finish_drain(
+                Arc::from(name),
+                conns,
+                deadline.map(|deadline| Instant::now() + deadline),
+            ),
+        );
+
+        Ok(true)
+    }
+
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    async fn finish_drain(name: Arc<str>, conns: Vec<Connection>, deadline: Option<Instant>) {
+        let mut reset = 0;
+
+        for conn in conns {
+            let tasks = || conn.model.task_connect_count() + conn.model.task_associate_count();
+
+            let drained = async {
+                while !conn.is_closed() && tasks() > 0 {
+                    time::sleep(DRAIN_CHECK_INTERVAL).await;
+                }
+            };
+
+            if let Some(deadline) = deadline {
+                if time::timeout_at(deadline, drained).await.is_err() {
+                    reset += tasks();
+                }
+            } else {
+                drained.await;
+            }
+
+            conn.conn.close(VarInt::from_u32(0), b"drained");
+        }
+
+        log::warn!(
+            event = "server_drain_completed",
+            server = &*name,
+            relays_reset = reset;
+            "[connection] [{name}] drained, {reset} relay(s) reset"
+        );
+    }
+
+    /// Puts the server profile `name` back in use after `drain_server()`. Connections that are still draining are closed all the same. Returns `false` if it is not draining
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn undrain_server(name: &str) -> bool {
+        let removed = DRAINING.lock().remove(name);
+
+        if removed {
+            log::warn!("[connection] [{name}] back in use");
+        }
+
+        removed
+    }
+
+    fn is_draining(&self) -> bool {
+        DRAINING.lock().contains(&*self.server)
+    }
+
+    /// Resolves once every server has stayed unreachable for `relay.fail_fast_after`, i.e. connection attempts kept failing that long without any succeeding in between. Never resolves if the option is unset
+    pub async fn unreachable() -> Error {
+        UNREACHABLE.notified().await;
+        Error::Unreachable(FAIL_FAST_AFTER.load().unwrap_or_default())
+    }
+
+    fn record_unreachable() {
+        let Some(fail_fast_after) = FAIL_FAST_AFTER.load() else {
+            return;
+        };
+
+        match UNREACHABLE_SINCE.load() {
+            None => {
+                UNREACHABLE_SINCE.store(Some(Instant::now()));
+                log::warn!(
+                    "[connection] all servers unreachable, shutting down if this lasts for {}",
+                    humantime::format_duration(fail_fast_after)
+                );
+            }
+            Some(since) if since.elapsed() >= fail_fast_after => UNREACHABLE.notify_one(),
+            Some(_) => {}
+        }
+    }
+
+    /// Returns the current connection if it is still open, without connecting or waiting for a reconnection in progress
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn get_if_open() -> Option<Connection> {
+        let conn = CONNECTION.get()?.try_lock().ok()?;
+        (!conn.is_closed()).then(|| conn.clone())
+    }
+
+    /// Returns the server of the profile this connection was established with
+    pub fn server(&self) -> Arc<str> {
+        self.server.clone()
+    }
+
+    /// Returns when the handshake of this connection completed, or with 0-RTT, when it was sent
+    pub fn established_at(&self) -> Instant {
+        self.established
+    }
+
+    /// Returns the address of the server this connection goes to
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.conn.remote_address()
+    }
+
+    /// Opens a relay to `addr`, returning it together with the compression the server agreed to apply
+    pub async fn connect(&self, addr: Address) -> Result<(Connect, StreamCompression), Error> {
+        let priority = Self::priority(&addr);
+
+        let (relay, compression) = self.connect_inner(addr).await?;
+
+        if priority != 0 {
+            relay.set_priority(priority);
+        }
+
+        Ok((relay, compression))
+    }
+
+    /// Opens a relay to `addr` as `connect()` does, without compression, and returns its streams. The relay is no longer a task of the connection, see `SERVICE_RELAYS`
+    #[cfg(feature = "tower")]
+    pub async fn connect_streams(&self, addr: Address) -> Result<(SendStream, RecvStream), Error> {
+        let priority = Self::priority(&addr);
+        let relay = self.model.connect(addr).await?;
+
+        if priority != 0 {
+            relay.set_priority(priority);
+        }
+
+        Ok(relay.into_streams())
+    }
+
+    /// The relays and UDP sessions on the connection, and those of `local.connect_service` on any connection, see `SERVICE_RELAYS`
+    fn tasks(&self) -> usize {
+        let tasks = self.model.task_connect_count() + self.model.task_associate_count();
+
+        #[cfg(feature = "tower")]
+        let tasks = tasks + SERVICE_RELAYS.count();
+
+        tasks
+    }
+
+    /// The priority of the first rule matching `addr`, 0 if none does
+    fn priority(addr: &Address) -> i32 {
+        PRIORITY_RULES
+            .get()
+            .and_then(|rules| {
+                rules
+                    .iter()
+                    .find(|rule| rule.matcher.matches(addr, addr) == Ok(true))
+            })
+            .map_or(0, |rule| rule.priority)
+    }
+
+    async fn connect_inner(&self, addr: Address) -> Result<(Connect, StreamCompression), Error> {
+        match self.compression {
+            StreamCompression::None => {
+                Ok((self.model.connect(addr).await?, StreamCompression::None))
+            }
+            #[cfg(feature = "compression")]
+            StreamCompression::Zstd(level) => {
+                let mut relay = AbortOnDrop(Some(
+                    self.model
+                        .connect_compressed(addr, ConnectHeader::COMPRESSION_ZSTD, level)
+                        .await?,
+                ));
+
+                // the server still answers for each stream, and may decline a single one
+                let compression = match relay.get().recv_compression().await? {
+                    ConnectHeader::COMPRESSION_ZSTD => StreamCompression::Zstd(level),
+                    _ => StreamCompression::None,
+                };
+
+                Ok((relay.into_inner(), compression))
+            }
+        }
+    }
+
+    /// Opens a relay to `addr` that is never compressed, for streams the client itself reads, e.g. DNS queries
+    pub async fn connect_plain(&self, addr: Address) -> Result<Connect, Error> {
+        Ok(self.model.connect(addr).await?)
+    }
+
+    /// With `stream_compression`, asks the server whether it compresses streams, see `probe_compression()`
+    ///
+    /// The server only answers once the connection is authenticated, so this waits for `init()` to send the authentication
+    async fn negotiate_compression(self) -> Option<Self> {
+        #[cfg(feature = "compression")]
+        if let StreamCompression::Zstd(level) = STREAM_COMPRESSION.load() {
+            if NO_STREAM_COMPRESSION.lock().contains(&*self.server) {
+                return Some(self);
+            }
+
+            return self.probe_compression(level, TIMEOUT.load()).await;
+        }
+
+        Some(self)
+    }
+
+    /// Asks the server whether it compresses streams with zstd at `level`, with a compressed `Connect` to an unspecified address that is aborted once answered. The server answers before it dials, and fails at once to dial that address. Returns the connection with the answer applied to its relays
+    ///
+    /// Any other outcome of the question, i.e. the stream being reset, stopped or finished, the connection being closed or no answer within `timeout`, means the server does not support it, and it is not asked again. Returns `None` if the connection was closed on the question, as servers that do not know the compressed `Connect` do, the connection being kept uncompressed otherwise
+    #[cfg(feature = "compression")]
+    async fn probe_compression(mut self, level: i8, timeout: Duration) -> Option<Self> {
+        let probe = async {
+            let addr = Address::SocketAddress(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+            let mut relay = self
+                .model
+                .connect_compressed(addr, ConnectHeader::COMPRESSION_ZSTD, level)
+                .await?;
+            let res = relay.recv_compression().await;
+            relay.abort(VarInt::from_u32(0));
+            res
+        };
+
+        match time::timeout(timeout, probe).await {
+            Ok(Ok(ConnectHeader::COMPRESSION_ZSTD)) => {
+                self.compression = StreamCompression::Zstd(level);
+            }
+            Ok(Ok(_)) => log::info!(
+                "[connection] [{}] server declined stream compression",
+                self.server
+            ),
+            res => {
+                let reason = match res {
+                    Ok(Err(err)) => err.to_string(),
+                    _ => String::from("timed out"),
+                };
+                NO_STREAM_COMPRESSION.lock().insert(self.server.to_string());
+
+                if self.conn.close_reason().is_some() {
+                    log::warn!(
+                        "[connection] [{}] server does not support stream compression ({reason}), connecting again without it",
+                        self.server
+                    );
+                    return None;
+                }
 
-        Ok(conn)
-    }
+                log::warn!(
+                    "[connection] [{}] server does not support stream compression ({reason}), relaying uncompressed",
+                    self.server
+                );
+            }
+        }
 
-    pub async fn connect(&self, addr: Address) -> Result<Connect, Error> {
-        Ok(self.model.connect(addr).await?)
+        Some(self)
     }
 
-    pub async fn packet(&self, pkt: Bytes, addr: Address, assoc_id: u16) -> Result<(), Error> {
+    /// Relays a UDP packet to the server, returning `false` if it was dropped because the datagram send buffer is full
+    pub async fn packet(&self, pkt: Bytes, addr: Address, assoc_id: u16) -> Result<bool, Error> {
         match self.udp_relay_mode {
-            UdpRelayMode::Native => self.model.packet_native(pkt, addr, assoc_id)?,
+            UdpRelayMode::Native => {
+                // `VERSION` and command type bytes and the packet header
+                let overhead = 2 + PacketHeader::len_without_addr() + addr.len();
+                let size = overhead + pkt.len();
+
+                let limit = self
+                    .conn
+                    .max_datagram_size()
+                    .map(|max| udp_payload_limit(max, &addr, UDP_MAX_PAYLOAD.load()));
+                let over = limit.filter(|limit| pkt.len() > *limit);
+
+                if let (Some(limit), UdpOversizePolicy::Stream) = (over, UDP_OVERSIZE_POLICY.load())
+                {
+                    log::debug!("[connection] [packet] [{assoc_id:#06x}] [{addr}] {} bytes exceeds UDP payload limit {limit}, relaying over a stream", pkt.len());
+                    self.model.packet_quic(pkt, addr, assoc_id).await?;
+                    return Ok(true);
+                }
+
+                if UDP_DROP_ON_FULL.load() && self.conn.datagram_send_buffer_space() < size {
+                    log::debug!("[connection] [packet] [{assoc_id:#06x}] [{addr}] datagram send buffer full, dropping {size} bytes");
+                    return Ok(false);
+                }
+
+                if let Some(limit) = over {
+                    log::debug!("[connection] [packet] [{assoc_id:#06x}] [{addr}] {} bytes exceeds UDP payload limit {limit}, fragmenting", pkt.len());
+                }
+
+                match limit {
+                    Some(limit) => {
+                        self.model
+                            .packet_native_clamped(pkt, addr, assoc_id, overhead + limit)?
+                    }
+                    None => self.model.packet_native(pkt, addr, assoc_id)?,
+                }
+            }
             UdpRelayMode::Quic => self.model.packet_quic(pkt, addr, assoc_id).await?,
         }
 
-        Ok(())
+        Ok(true)
     }
 
     pub async fn dissociate(&self, assoc_id: u16) -> Result<(), Error> {
@@ -271,10 +1702,15 @@ impl Connection {
         Ok(())
     }
 
-    fn is_closed(&self) -> bool {
+    pub fn is_closed(&self) -> bool {
         self.conn.close_reason().is_some()
     }
 
+    /// Whether `other` is a handle on the same connection
+    pub fn is_same(&self, other: &Self) -> bool {
+        self.conn.stable_id() == other.conn.stable_id()
+    }
+
     async fn accept_uni_stream(&self) -> Result<(RecvStream, Register), Error> {
         let max = self.max_concurrent_uni_streams.load(Ordering::Relaxed);
 
@@ -318,14 +1754,7 @@ impl Connection {
             Ok(Task::Packet(pkt)) => match self.udp_relay_mode {
                 UdpRelayMode::Quic => match pkt.accept().await {
                     Ok(Some((pkt, addr, assoc_id))) => {
-                        let addr = match addr {
-                            Address::None => unreachable!(),
-                            Address::DomainAddress(domain, port) => {
-                                Socks5Address::DomainAddress(domain, port)
-                            }
-                            Address::SocketAddress(addr) => Socks5Address::SocketAddress(addr),
-                        };
-                        Socks5Server::recv_pkt(pkt, addr, assoc_id).await;
+                        udp::deliver(pkt, addr, assoc_id);
                         Ok(())
                     }
                     Ok(None) => Ok(()),
@@ -362,14 +1791,7 @@ impl Connection {
             Ok(Task::Packet(pkt)) => match self.udp_relay_mode {
                 UdpRelayMode::Native => match pkt.accept().await {
                     Ok(Some((pkt, addr, assoc_id))) => {
-                        let addr = match addr {
-                            Address::None => unreachable!(),
-                            Address::DomainAddress(domain, port) => {
-                                Socks5Address::DomainAddress(domain, port)
-                            }
-                            Address::SocketAddress(addr) => Socks5Address::SocketAddress(addr),
-                        };
-                        Socks5Server::recv_pkt(pkt, addr, assoc_id).await;
+                        udp::deliver(pkt, addr, assoc_id);
                         Ok(())
                     }
                     Ok(None) => Ok(()),
@@ -387,25 +1809,37 @@ impl Connection {
     }
 
     async fn authenticate(self) {
-        match self
-            .model
-            .authenticate(self.uuid, self.password.clone())
-            .await
-        {
+        let res = match CLIENT_LABEL.get() {
+            Some(label) => {
+                self.model
+                    .authenticate_labeled(self.uuid, self.password.clone(), label.as_bytes())
+                    .await
+            }
+            None => {
+                self.model
+                    .authenticate(self.uuid, self.password.clone())
+                    .await
+            }
+        };
+
+        match res {
             Ok(()) => log::info!("[connection] authentication sent"),
-            Err(err) => log::warn!("[connection] authentication failed: {err}"),
+            Err(err) => {
+                log::warn!("[connection] authentication failed: {err}");
+                Diagnostics::record(None, None, &err);
+            }
         }
     }
 
-    async fn heartbeat(self, heartbeat: Duration) {
+    async fn heartbeat(self, heartbeat: Duration, jitter: f64) {
         loop {
-            time::sleep(heartbeat).await;
+            time::sleep(utils::jitter(heartbeat, jitter)).await;
 
             if self.is_closed() {
                 break;
             }
 
-            if self.model.task_connect_count() + self.model.task_associate_count() == 0 {
+            if self.tasks() == 0 {
                 continue;
             }
 
@@ -416,6 +1850,47 @@ impl Connection {
         }
     }
 
+    /// Migrates the connection to a new socket whenever the local address the OS routes the server through changes
+    async fn follow_network(self) {
+        let remote = self.conn.remote_address();
+        let mut local = route_source(remote);
+
+        loop {
+            time::sleep(MIGRATION_CHECK_INTERVAL).await;
+
+            if self.is_closed() {
+                break;
+            }
+
+            let current = route_source(remote);
+
+            if current == local {
+                continue;
+            }
+
+            let Some(ip) = current else {
+                log::warn!("[connection] no route to {remote}, waiting for the network");
+                local = None;
+                continue;
+            };
+
+            // held while a new connection is being established, try again on the next check
+            let Ok(mut ep) = ENDPOINT.get().unwrap().try_lock() else {
+                continue;
+            };
+
+            match ep.rebind() {
+                Ok(addr) => log::warn!(
+                    "[connection] local address changed from {} to {ip}, migrated to {addr}",
+                    local.map_or_else(|| "none".to_owned(), |ip| ip.to_string())
+                ),
+                Err(err) => log::warn!("[connection] failed to migrate to {ip}: {err}"),
+            }
+
+            local = current;
+        }
+    }
+
     async fn collect_garbage(self, gc_interval: Duration, gc_lifetime: Duration) {
         loop {
             time::sleep(gc_interval).await;
@@ -429,28 +1904,708 @@ impl Connection {
         }
     }
 
-    async fn init(self, heartbeat: Duration, gc_interval: Duration, gc_lifetime: Duration) {
-        tokio::spawn(self.clone().authenticate());
-        tokio::spawn(self.clone().heartbeat(heartbeat));
-        tokio::spawn(self.clone().collect_garbage(gc_interval, gc_lifetime));
+    async fn sample_stats(self, interval: Duration) {
+        let id = self.conn.stable_id();
+
+        loop {
+            QuicStats::record(id, self.server.clone(), &self.conn);
+            time::sleep(interval).await;
+
+            if self.is_closed() {
+                break;
+            }
+        }
+
+        QuicStats::remove(id);
+    }
+
+    async fn init(
+        self,
+        heartbeat: Duration,
+        jitter: f64,
+        gc_interval: Duration,
+        gc_lifetime: Duration,
+        enable_migration: bool,
+        stats_poll_interval: Duration,
+    ) {
+        utils::spawn(format_args!("authenticate"), self.clone().authenticate());
+        utils::spawn(
+            format_args!("heartbeat"),
+            self.clone().heartbeat(heartbeat, jitter),
+        );
+        utils::spawn(
+            format_args!("packet garbage collection"),
+            self.clone().collect_garbage(gc_interval, gc_lifetime),
+        );
+
+        if enable_migration {
+            utils::spawn(format_args!("migration"), self.clone().follow_network());
+        }
+
+        if !stats_poll_interval.is_zero() {
+            utils::spawn(
+                format_args!("connection statistics"),
+                self.clone().sample_stats(stats_poll_interval),
+            );
+        }
 
         let err = loop {
             tokio::select! {
                 res = self.accept_uni_stream() => match res {
-                    Ok((recv, reg)) => tokio::spawn(self.clone().handle_uni_stream(recv, reg)),
+                    Ok((recv, reg)) => utils::spawn(format_args!("incoming unidirectional stream"), self.clone().handle_uni_stream(recv, reg)),
                     Err(err) => break err,
                 },
                 res = self.accept_bi_stream() => match res {
-                    Ok((send, recv, reg)) => tokio::spawn(self.clone().handle_bi_stream(send, recv, reg)),
+                    Ok((send, recv, reg)) => utils::spawn(format_args!("incoming bidirectional stream"), self.clone().handle_bi_stream(send, recv, reg)),
                     Err(err) => break err,
                 },
                 res = self.accept_datagram() => match res {
-                    Ok(dg) => tokio::spawn(self.clone().handle_datagram(dg)),
+                    Ok(dg) => utils::spawn(format_args!("incoming datagram"), self.clone().handle_datagram(dg)),
                     Err(err) => break err,
                 },
             };
         };
 
-        log::error!("[connection] {err}");
+        if self.is_closed_idle(&err) {
+            return;
+        }
+
+        let quic = match &err {
+            Error::Connection(err) => Some(QuicError::from_connection(err)),
+            _ => None,
+        };
+
+        log::error!(
+            event = "connection_closed",
+            quic_error = quic.as_ref().map(|quic| quic.kind),
+            quic_code = quic.as_ref().and_then(|quic| quic.code),
+            quic_reason = quic.as_ref().and_then(|quic| quic.reason.as_deref());
+            "[connection] {err}"
+        );
+        Diagnostics::record(None, None, &err);
+    }
+}
+
+/// Aborts a relay stream dropped before it is handed out, e.g. when the dial waiting on the server is cancelled, so the server gives up the target instead of seeing the stream finished
+#[cfg_attr(not(feature = "compression"), allow(dead_code))]
+struct AbortOnDrop(Option<Connect>);
+
+#[cfg_attr(not(feature = "compression"), allow(dead_code))]
+impl AbortOnDrop {
+    fn get(&mut self) -> &mut Connect {
+        self.0.as_mut().unwrap()
+    }
+
+    fn into_inner(mut self) -> Connect {
+        self.0.take().unwrap()
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if let Some(relay) = &mut self.0 {
+            relay.abort(VarInt::from_u32(0));
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::utils::tests::{assert_pending_for, timed};
+    use quinn::ServerConfig;
+    use rustls::{PrivateKey, RootCertStore, ServerConfig as RustlsServerConfig};
+    use std::{future, pin::pin};
+    use tuic_quinn::Task;
+
+    /// Settings of a QUIC server with a self-signed certificate for `localhost` and the ALPN protocols `alpn`, along with the certificate
+    pub(crate) fn server_config(alpn: &[&[u8]]) -> (ServerConfig, Certificate) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_der = Certificate(cert.serialize_der().unwrap());
+        let key = PrivateKey(cert.serialize_private_key_der());
+
+        let mut crypto = RustlsServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key)
+            .unwrap();
+        crypto.alpn_protocols = alpn.iter().map(|alpn| alpn.to_vec()).collect();
+        crypto.max_early_data_size = u32::MAX;
+
+        (ServerConfig::with_crypto(Arc::new(crypto)), cert_der)
+    }
+
+    /// A QUIC server on loopback, see [`server_config`]
+    pub(crate) fn server(alpn: &[&[u8]]) -> (QuinnEndpoint, Certificate) {
+        let (cfg, cert) = server_config(alpn);
+        let server =
+            QuinnEndpoint::server(cfg, SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        (server, cert)
+    }
+
+    /// TLS settings of a client that only trusts `cert`
+    pub(crate) fn client_crypto(cert: &Certificate) -> RustlsClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+
+        RustlsClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    }
+
+    /// Connects to `server` as `localhost`
+    pub(crate) async fn handshake(
+        server: &QuinnEndpoint,
+        crypto: RustlsClientConfig,
+    ) -> Result<QuinnConnection, ConnectionError> {
+        let client = QuinnEndpoint::client(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+
+        client
+            .connect_with(
+                ClientConfig::new(Arc::new(crypto)),
+                server.local_addr().unwrap(),
+                "localhost",
+            )
+            .unwrap()
+            .await
+    }
+
+    /// A connection to a loopback server, along with the server side of it
+    pub(crate) async fn connect_loopback() -> (Connection, Model<side::Server>, QuinnConnection) {
+        let (server, cert) = server(&[]);
+
+        let (client_conn, server_conn) = tokio::join!(
+            async { handshake(&server, client_crypto(&cert)).await.unwrap() },
+            async { server.accept().await.unwrap().await.unwrap() },
+        );
+
+        let conn = Connection::new(
+            client_conn,
+            Arc::from(server.local_addr().unwrap().to_string()),
+            UdpRelayMode::Native,
+            Uuid::nil(),
+            Arc::from(&b"password"[..]),
+        );
+
+        (
+            conn,
+            Model::<side::Server>::new(server_conn.clone()),
+            server_conn,
+        )
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn compression_probe_reset_by_server_falls_back() {
+        let (conn, _server, server_conn) = connect_loopback().await;
+
+        let server_task = tokio::spawn(async move {
+            let (mut send, _recv) = server_conn.accept_bi().await.unwrap();
+            send.reset(VarInt::from_u32(1)).unwrap();
+            server_conn
+        });
+
+        let conn = conn
+            .probe_compression(3, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(matches!(conn.compression, StreamCompression::None));
+        assert!(conn.conn.close_reason().is_none());
+        assert!(NO_STREAM_COMPRESSION.lock().contains(&*conn.server));
+        drop(server_task.await.unwrap());
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn compression_probe_without_answer_falls_back() {
+        let (conn, _server, server_conn) = connect_loopback().await;
+
+        let conn = conn
+            .probe_compression(3, Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert!(matches!(conn.compression, StreamCompression::None));
+        assert!(NO_STREAM_COMPRESSION.lock().contains(&*conn.server));
+        drop(server_conn);
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn compression_probe_closing_the_connection_reconnects() {
+        let (conn, _server, server_conn) = connect_loopback().await;
+
+        let server_task = tokio::spawn(async move {
+            let _ = server_conn.accept_bi().await.unwrap();
+            server_conn.close(VarInt::from_u32(0), b"");
+        });
+
+        let server = conn.server.clone();
+        assert!(conn
+            .probe_compression(3, Duration::from_secs(5))
+            .await
+            .is_none());
+        assert!(NO_STREAM_COMPRESSION.lock().contains(&*server));
+        server_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn idle_close_is_not_an_error() {
+        let (conn, _, _) = connect_loopback().await;
+        conn.close_idle();
+
+        let err = conn.accept_bi_stream().await.err().unwrap();
+        assert!(conn.is_closed_idle(&err));
+    }
+
+    #[tokio::test]
+    async fn other_local_close_is_an_error() {
+        let (conn, _, _) = connect_loopback().await;
+        conn.conn.close(VarInt::from_u32(0), b"");
+
+        let err = conn.accept_bi_stream().await.err().unwrap();
+        assert!(matches!(
+            err,
+            Error::Connection(ConnectionError::LocallyClosed)
+        ));
+        assert!(!conn.is_closed_idle(&err));
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate_is_cert_invalid() {
+        let (server, _) = server(&[]);
+        let (_, other) = self::server(&[]);
+
+        let err = handshake(&server, client_crypto(&other)).await.unwrap_err();
+        assert!(matches!(handshake_error(err), Error::CertInvalid(_)));
+    }
+
+    /// TLS settings of a client that trusts `trusted` and leaves the final decision to `hook`, counting its calls
+    fn hooked_crypto(
+        trusted: &Certificate,
+        hook: impl Fn(Result<(), &TlsError>) -> VerifyDecision + Send + Sync + 'static,
+    ) -> (RustlsClientConfig, Arc<AtomicUsize>) {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted).unwrap();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let hook_calls = calls.clone();
+
+        let crypto = RustlsClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&version::TLS13])
+            .unwrap()
+            .with_custom_certificate_verifier(Arc::new(HookedVerifier {
+                inner: WebPkiVerifier::new(roots, None),
+                hook: Arc::new(move |_, _, res| {
+                    hook_calls.fetch_add(1, Ordering::Relaxed);
+                    hook(res)
+                }),
+            }))
+            .with_no_client_auth();
+
+        (crypto, calls)
+    }
+
+    #[tokio::test]
+    async fn rejecting_verifier_hook_fails_a_trusted_certificate() {
+        let (server, cert) = server(&[]);
+        let (crypto, calls) = hooked_crypto(&cert, |res| {
+            assert!(res.is_ok());
+            VerifyDecision::Reject
+        });
+
+        let err = handshake(&server, crypto).await.unwrap_err();
+        assert!(matches!(handshake_error(err), Error::CertInvalid(_)));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn accepting_verifier_hook_passes_an_untrusted_certificate() {
+        let (server, _) = server(&[]);
+        let (_, other) = self::server(&[]);
+        let (crypto, calls) = hooked_crypto(&other, |res| {
+            assert!(res.is_err());
+            VerifyDecision::Accept
+        });
+
+        handshake(&server, crypto).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn default_verifier_hook_decision_keeps_the_verification() {
+        let (server, _) = server(&[]);
+        let (_, other) = self::server(&[]);
+        let (crypto, _) = hooked_crypto(&other, |_| VerifyDecision::Default);
+
+        let err = handshake(&server, crypto).await.unwrap_err();
+        assert!(matches!(handshake_error(err), Error::CertInvalid(_)));
+    }
+
+    #[tokio::test]
+    async fn alpn_mismatch_is_protocol_mismatch() {
+        let (server, cert) = server(&[b"h3"]);
+        let mut crypto = client_crypto(&cert);
+        crypto.alpn_protocols = vec![b"other".to_vec()];
+
+        let err = handshake(&server, crypto).await.unwrap_err();
+        assert!(matches!(handshake_error(err), Error::ProtocolMismatch(_)));
+    }
+
+    #[tokio::test]
+    async fn server_refusing_connections_is_refused() {
+        let (mut cfg, cert) = server_config(&[]);
+        cfg.concurrent_connections(0);
+        let server =
+            QuinnEndpoint::server(cfg, SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+
+        let err = handshake(&server, client_crypto(&cert)).await.unwrap_err();
+        assert!(matches!(handshake_error(err), Error::Refused(_)));
+    }
+
+    #[tokio::test]
+    async fn client_label_follows_the_authentication() {
+        let (conn, server, server_conn) = connect_loopback().await;
+
+        tokio::join!(
+            conn.model
+                .authenticate_labeled(Uuid::nil(), b"password", b"laptop"),
+            async {
+                let recv = server_conn.accept_uni().await.unwrap();
+                match server.accept_uni_stream(recv).await.unwrap() {
+                    Task::Authenticate(mut auth) => {
+                        assert!(auth.validate(b"password"));
+                        assert_eq!(auth.label().await.unwrap().as_deref(), Some(&b"laptop"[..]));
+                    }
+                    _ => panic!("expecting an authentication"),
+                }
+            },
+        )
+        .0
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn authentication_without_label_has_none() {
+        let (conn, server, server_conn) = connect_loopback().await;
+        conn.model
+            .authenticate(Uuid::nil(), b"password")
+            .await
+            .unwrap();
+
+        let recv = server_conn.accept_uni().await.unwrap();
+        match server.accept_uni_stream(recv).await.unwrap() {
+            Task::Authenticate(mut auth) => {
+                assert!(auth.validate(b"password"));
+                assert_eq!(auth.label().await.unwrap(), None);
+            }
+            _ => panic!("expecting an authentication"),
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_dropped_before_handed_out_is_reset() {
+        let (conn, _server, server_conn) = connect_loopback().await;
+        let addr = Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 80)));
+
+        let relay = AbortOnDrop(Some(conn.model.connect(addr).await.unwrap()));
+        drop(relay);
+
+        let (_send, recv) = server_conn.accept_bi().await.unwrap();
+        let err = recv.read_to_end(1024).await.unwrap_err();
+        assert!(matches!(
+            err,
+            quinn::ReadToEndError::Read(quinn::ReadError::Reset(code)) if code == VarInt::from_u32(0)
+        ));
+    }
+
+    #[tokio::test]
+    async fn relay_handed_out_is_kept() {
+        let (conn, _server, server_conn) = connect_loopback().await;
+        let addr = Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 80)));
+
+        let relay = AbortOnDrop(Some(conn.model.connect(addr).await.unwrap())).into_inner();
+
+        let (_send, mut recv) = server_conn.accept_bi().await.unwrap();
+        let mut buf = [0; 2];
+        recv.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0x05, 0x01]);
+        drop(relay);
+    }
+
+    /// The congestion window of a fresh connection to a loopback server
+    async fn initial_window(
+        congestion_control: CongestionControl,
+        initial_cwnd: Option<u64>,
+    ) -> u64 {
+        let (server, cert) = server(&[]);
+        let mut tp_cfg = TransportConfig::default();
+        set_congestion_control(&mut tp_cfg, congestion_control, initial_cwnd);
+
+        let mut config = ClientConfig::new(Arc::new(client_crypto(&cert)));
+        config.transport_config(Arc::new(tp_cfg));
+
+        let client = QuinnEndpoint::client(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        let (conn, _server_conn) = tokio::join!(
+            async {
+                client
+                    .connect_with(config, server.local_addr().unwrap(), "localhost")
+                    .unwrap()
+                    .await
+                    .unwrap()
+            },
+            async { server.accept().await.unwrap().await.unwrap() },
+        );
+
+        conn.stats().path.cwnd
+    }
+
+    #[tokio::test]
+    async fn initial_cwnd_is_applied() {
+        for cc in [
+            CongestionControl::Cubic,
+            CongestionControl::NewReno,
+            CongestionControl::Bbr,
+        ] {
+            // the handshake acknowledgements may already have grown it a little, and bbr starts larger
+            assert!(initial_window(cc, None).await < 1_000_000);
+            assert!(initial_window(cc, Some(2_000_000)).await >= 2_000_000);
+        }
+    }
+
+    /// Whether the second of two connections to the same server can send 0-RTT data, which takes a ticket from the first
+    async fn reuses_ticket(resumption: bool) -> bool {
+        let (server, cert) = server(&[]);
+        let mut crypto = client_crypto(&cert);
+        crypto.enable_early_data = true;
+        crypto.enable_tickets = resumption;
+        crypto.session_storage = Endpoint::session_storage(resumption, 32);
+        let config = ClientConfig::new(Arc::new(crypto));
+
+        let client = QuinnEndpoint::client(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        let connect = || {
+            client
+                .connect_with(config.clone(), server.local_addr().unwrap(), "localhost")
+                .unwrap()
+        };
+
+        let (first, server_conn) = tokio::join!(async { connect().await.unwrap() }, async {
+            server.accept().await.unwrap().await.unwrap()
+        },);
+
+        // the tickets follow the handshake, a round trip later they have arrived
+        let mut send = first.open_uni().await.unwrap();
+        send.write_all(b"ping").await.unwrap();
+        send.finish().await.unwrap();
+        server_conn.accept_uni().await.unwrap();
+
+        connect().into_0rtt().is_ok()
+    }
+
+    #[tokio::test]
+    async fn session_resumption_reuses_tickets() {
+        assert!(reuses_ticket(true).await);
+    }
+
+    #[tokio::test]
+    async fn session_resumption_disabled_stores_no_tickets() {
+        assert!(!reuses_ticket(false).await);
+    }
+
+    fn race_addrs() -> (SocketAddr, SocketAddr) {
+        (
+            SocketAddr::from((Ipv6Addr::LOCALHOST, 443)),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 443)),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn happy_eyeballs_falls_back_to_v4_when_v6_is_blackholed() {
+        let (v6, v4) = race_addrs();
+
+        let mut race = pin!(happy_eyeballs(
+            (v6, future::pending::<Result<&str, Error>>()),
+            (v4, async { Ok("v4") }),
+            "server",
+        ));
+
+        // IPv4 is held back for the head start of IPv6 ...
+        assert_pending_for(&mut race, HAPPY_EYEBALLS_DELAY - Duration::from_millis(1)).await;
+
+        // ... and wins right after it
+        let (res, elapsed) = timed(race).await;
+        assert_eq!(res.unwrap(), "v4");
+        assert_eq!(elapsed, Duration::from_millis(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn happy_eyeballs_prefers_v6_within_its_head_start() {
+        let (v6, v4) = race_addrs();
+
+        let (res, elapsed) = timed(happy_eyeballs(
+            (v6, async {
+                time::sleep(HAPPY_EYEBALLS_DELAY / 2).await;
+                Ok("v6")
+            }),
+            (v4, async { Ok("v4") }),
+            "server",
+        ))
+        .await;
+
+        assert_eq!(res.unwrap(), "v6");
+        assert_eq!(elapsed, HAPPY_EYEBALLS_DELAY / 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn happy_eyeballs_hands_over_to_v4_when_v6_fails() {
+        let (v6, v4) = race_addrs();
+
+        // e.g. no IPv6 socket can be bound
+        let (res, elapsed) = timed(happy_eyeballs(
+            (v6, async {
+                Err::<&str, _>(Error::from(IoError::from(ErrorKind::AddrNotAvailable)))
+            }),
+            (v4, async { Ok("v4") }),
+            "server",
+        ))
+        .await;
+
+        assert_eq!(res.unwrap(), "v4");
+        assert_eq!(elapsed, Duration::ZERO);
+    }
+
+    #[test]
+    fn ephemeral_port_bind_failure_is_port_exhaustion() {
+        let any = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+
+        for kind in [ErrorKind::AddrInUse, ErrorKind::WouldBlock] {
+            let err = bind_error(any, IoError::from(kind));
+            assert!(matches!(err, Error::PortExhaustion(addr) if addr == any));
+        }
+
+        let err = bind_error(any, IoError::from(ErrorKind::PermissionDenied));
+        assert!(matches!(err, Error::Io(_)));
+    }
+
+    #[test]
+    fn port_in_use_is_not_port_exhaustion() {
+        let taken = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        let addr = taken.local_addr().unwrap();
+
+        match bind_socket(addr, None) {
+            Err(Error::Io(err)) => assert_eq!(err.kind(), ErrorKind::AddrInUse),
+            res => panic!("{:?}", res.map(|_| ())),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn happy_eyeballs_reports_the_last_failure() {
+        let (v6, v4) = race_addrs();
+
+        let res = happy_eyeballs(
+            (v6, async { Err::<(), _>(Error::Timeout) }),
+            (v4, async { Err(Error::DnsResolve) }),
+            "server",
+        )
+        .await;
+
+        assert!(matches!(res, Err((addr, Error::DnsResolve)) if addr == v4));
+    }
+
+    #[test]
+    fn udp_payload_limit_leaves_room_for_the_header() {
+        let v4 = Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 53)));
+        let v6 = Address::SocketAddress(SocketAddr::from((Ipv6Addr::LOCALHOST, 53)));
+        let domain = Address::DomainAddress(String::from("example.com"), 53);
+
+        // 2 bytes of version and command type, 8 of packet header, then the address
+        assert_eq!(udp_payload_limit(1200, &v4, None), 1200 - 2 - 8 - 7);
+        assert_eq!(udp_payload_limit(1200, &v6, None), 1200 - 2 - 8 - 19);
+        assert_eq!(udp_payload_limit(1200, &domain, None), 1200 - 2 - 8 - 15);
+    }
+
+    #[test]
+    fn udp_payload_limit_is_capped_by_udp_max_payload() {
+        let v4 = Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 53)));
+
+        assert_eq!(udp_payload_limit(1200, &v4, Some(512)), 512);
+        assert_eq!(udp_payload_limit(1200, &v4, Some(1500)), 1183);
+        // a datagram too small for the header leaves no room at all
+        assert_eq!(udp_payload_limit(10, &v4, None), 0);
+    }
+
+    fn servers(names: &[&str]) -> impl Iterator<Item = (usize, String)> {
+        names
+            .iter()
+            .map(|name| name.to_string())
+            .enumerate()
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn sticky_key_keeps_its_server() {
+        let names = ["a.example:443", "b.example:443", "c.example:443"];
+
+        for key in ["alice", "bob", "192.0.2.1"] {
+            let idx = rendezvous(key, servers(&names));
+            assert!(idx.is_some());
+            assert_eq!(rendezvous(key, servers(&names)), idx);
+        }
+
+        // fixed across releases and restarts
+        assert_eq!(
+            stable_hash(&[b"alice", b"a.example:443"]),
+            0xad34_eebc_7ac3_267f
+        );
+    }
+
+    #[test]
+    fn sticky_keys_only_move_off_a_removed_server() {
+        let names = ["a.example:443", "b.example:443", "c.example:443"];
+        let mut used = HashSet::new();
+
+        for key in (0..100).map(|n| format!("user{n}")) {
+            let before = rendezvous(&key, servers(&names)).unwrap();
+            used.insert(before);
+
+            // without "c.example:443", whose index is 2
+            let after = rendezvous(&key, servers(&names).filter(|(idx, _)| *idx != 2)).unwrap();
+
+            if before != 2 {
+                assert_eq!(after, before);
+            }
+        }
+
+        // the keys are spread over every server
+        assert_eq!(used.len(), names.len());
+    }
+
+    #[test]
+    fn other_errors_keep_their_kind() {
+        assert!(matches!(
+            handshake_error(ConnectionError::TimedOut),
+            Error::Timeout
+        ));
+        assert!(matches!(
+            handshake_error(ConnectionError::VersionMismatch),
+            Error::ProtocolMismatch(_)
+        ));
+        assert!(matches!(
+            handshake_error(ConnectionError::Reset),
+            Error::Refused(_)
+        ));
+        assert!(matches!(
+            handshake_error(ConnectionError::LocallyClosed),
+            Error::Connection(_)
+        ));
     }
 }
@@ -0,0 +1,125 @@
+use crate::{metrics::QuicStats, socks5::Server as Socks5Server};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use std::{collections::VecDeque, fmt::Display, net::SocketAddr, time::SystemTime};
+
+static DIAGNOSTICS: OnceCell<Diagnostics> = OnceCell::new();
+
+pub struct Diagnostics {
+    capacity: usize,
+    errors: Mutex<VecDeque<ErrorRecord>>,
+}
+
+#[cfg_attr(not(unix), allow(dead_code))]
+#[derive(Clone)]
+pub struct ErrorRecord {
+    pub time: SystemTime,
+    pub peer: Option<SocketAddr>,
+    pub target: Option<String>,
+    pub error: String,
+}
+
+impl Diagnostics {
+    pub fn set_config(capacity: usize) {
+        let diagnostics = Self {
+            capacity,
+            errors: Mutex::new(VecDeque::with_capacity(capacity)),
+        };
+
+        DIAGNOSTICS
+            .set(diagnostics)
+            .map_err(|_| "diagnostics already initialized")
+            .unwrap();
+    }
+
+    /// Remembers an error, evicting the oldest one if the buffer is full
+    pub fn record(peer: Option<SocketAddr>, target: Option<String>, err: &dyn Display) {
+        let Some(diagnostics) = DIAGNOSTICS.get() else {
+            return;
+        };
+
+        if diagnostics.capacity == 0 {
+            return;
+        }
+
+        let record = ErrorRecord {
+            time: SystemTime::now(),
+            peer,
+            target,
+            error: err.to_string(),
+        };
+
+        let mut errors = diagnostics.errors.lock();
+
+        if errors.len() == diagnostics.capacity {
+            errors.pop_front();
+        }
+
+        errors.push_back(record);
+    }
+
+    /// Returns the remembered errors, oldest first
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn recent_errors() -> Vec<ErrorRecord> {
+        DIAGNOSTICS.get().map_or_else(Vec::new, |diagnostics| {
+            diagnostics.errors.lock().iter().cloned().collect()
+        })
+    }
+
+    /// Logs the recent errors and the active relays every time the process receives `SIGUSR1`
+    #[cfg(unix)]
+    pub async fn dump_on_signal() {
+        use tokio::signal::unix::{self, SignalKind};
+
+        let mut signal = match unix::signal(SignalKind::user_defined1()) {
+            Ok(signal) => signal,
+            Err(err) => {
+                log::warn!("[diagnostics] failed to listen for SIGUSR1: {err}");
+                return;
+            }
+        };
+
+        while signal.recv().await.is_some() {
+            let errors = Self::recent_errors();
+            log::warn!("[diagnostics] {} recent errors", errors.len());
+
+            for record in errors {
+                let time = humantime::format_rfc3339_seconds(record.time);
+                let peer = record
+                    .peer
+                    .map_or_else(|| "-".to_owned(), |peer| peer.to_string());
+                let target = record.target.as_deref().unwrap_or("-");
+                log::warn!("[diagnostics] {time} [{peer}] [{target}] {}", record.error);
+            }
+
+            let relays = Socks5Server::active_relays();
+            log::warn!("[diagnostics] {} active relays", relays.len());
+
+            for relay in relays {
+                let started = humantime::format_rfc3339_seconds(relay.started);
+                log::warn!(
+                    "[diagnostics] #{} since {started} [{}] [{}] via {}, {} bytes up, {} bytes down",
+                    relay.id,
+                    relay.peer,
+                    relay.target,
+                    relay.via.as_deref().unwrap_or("-"),
+                    relay.bytes_up,
+                    relay.bytes_down
+                );
+            }
+
+            for quic in QuicStats::all() {
+                log::warn!(
+                    "[diagnostics] QUIC connection to {}: rtt {}ms, cwnd {} bytes, {} of {} packets lost ({:.2}%), {} congestion events",
+                    quic.server,
+                    quic.rtt.as_millis(),
+                    quic.cwnd,
+                    quic.lost_packets,
+                    quic.sent_packets,
+                    quic.loss_percent(),
+                    quic.congestion_events
+                );
+            }
+        }
+    }
+}
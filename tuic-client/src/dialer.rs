@@ -0,0 +1,375 @@
+use crate::{
+    connection::Connection as TuicConnection,
+    metrics,
+    tcp::TcpTransport,
+    utils::{Bypass, StreamCompression, Transport},
+    Error,
+};
+use async_trait::async_trait;
+use crossbeam_utils::atomic::AtomicCell;
+use quinn::VarInt;
+use std::{
+    io::{Error as IoError, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time::{self, Instant},
+};
+use tokio_rustls::client::TlsStream;
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
+use tuic::Address;
+use tuic_quinn::Connect as TuicConnect;
+
+/// The error code relays are aborted with
+pub const ABORT_CODE: VarInt = VarInt::from_u32(0);
+
+/// A bidirectional byte stream to a relay target
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {
+    /// Tells the remote end that the relay was aborted rather than finished. Streams that cannot tell apart an abort are left to be dropped
+    fn abort(&mut self) {}
+}
+
+impl Stream for Compat<TuicConnect> {
+    fn abort(&mut self) {
+        self.get_mut().abort(ABORT_CODE);
+    }
+}
+
+impl Stream for TlsStream<TcpStream> {}
+
+impl Stream for TcpStream {}
+
+/// A stream opened by a dialer
+pub struct Dialed {
+    pub stream: Box<dyn Stream>,
+    /// The server the stream goes through, or `direct`
+    pub via: Arc<str>,
+    /// The address of the server the stream goes through, `None` for direct streams
+    pub server_addr: Option<SocketAddr>,
+    /// The compression the remote end applies to the stream
+    pub compression: StreamCompression,
+    pub timings: DialTimings,
+}
+
+/// Where the time opening a stream went, taken from a few `Instant`s so it costs next to nothing
+///
+/// TUIC over QUIC does not acknowledge the authentication nor the server connecting to the target, so neither can be timed here. The time until the target's first byte covers the latter. Over `tcp_fallback`, `open` includes both
+#[derive(Clone, Copy)]
+pub struct DialTimings {
+    /// Getting a connection to the server, `None` for direct streams
+    pub acquire: Option<Duration>,
+    /// Whether that connection was established for this stream, i.e. `acquire` includes the handshake, rather than reused
+    pub handshake: bool,
+    /// Opening the stream on the connection, or connecting to the target for direct streams
+    pub open: Duration,
+}
+
+impl DialTimings {
+    /// Feeds the `connect_phase_seconds` histograms
+    pub fn observe(&self) {
+        match (self.acquire, self.handshake) {
+            (Some(acquire), false) => metrics::CONNECT_PHASE_ACQUIRE_SECONDS.observe(acquire),
+            (Some(acquire), true) => metrics::CONNECT_PHASE_HANDSHAKE_SECONDS.observe(acquire),
+            (None, _) => {}
+        }
+
+        metrics::CONNECT_PHASE_OPEN_SECONDS.observe(self.open);
+    }
+}
+
+/// How long `auto` keeps using TCP after QUIC failed to connect, before trying QUIC again
+const QUIC_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Opens streams to relay targets on behalf of the socks5 front-end
+#[async_trait]
+pub trait Dialer: Send + Sync {
+    /// `sticky_key` identifies the socks5 client for `relay.sticky_routing`, `egress` names the `relay.egress_bindings` entry to tunnel from
+    async fn connect(
+        &self,
+        addr: Address,
+        sticky_key: Option<&str>,
+        egress: Option<&str>,
+    ) -> Result<Dialed, Error>;
+}
+
+/// The dialer through the server, over the configured transport
+pub fn tunnel() -> Box<dyn Dialer> {
+    match TcpTransport::mode() {
+        (Transport::Quic, _) => Box::new(TuicDialer),
+        (Transport::TcpFallback, _) => Box::new(TcpDialer),
+        (Transport::Auto, timeout) => Box::new(AutoDialer::new(
+            Box::new(TuicDialer),
+            Box::new(TcpDialer),
+            timeout,
+        )),
+    }
+}
+
+/// Dials through the TUIC connection
+pub struct TuicDialer;
+
+#[async_trait]
+impl Dialer for TuicDialer {
+    async fn connect(
+        &self,
+        addr: Address,
+        sticky_key: Option<&str>,
+        egress: Option<&str>,
+    ) -> Result<Dialed, Error> {
+        let start = Instant::now();
+
+        let conn = match (egress, sticky_key) {
+            (Some(egress), _) => TuicConnection::get_egress(egress).await?,
+            (None, Some(key)) => TuicConnection::get_sticky(key).await?,
+            (None, None) => TuicConnection::get().await?,
+        };
+
+        let acquired = Instant::now();
+        let (relay, compression) = conn.connect(addr).await?;
+
+        Ok(Dialed {
+            stream: Box::new(relay.compat()),
+            via: conn.server(),
+            server_addr: Some(conn.remote_addr()),
+            compression,
+            timings: DialTimings {
+                acquire: Some(acquired - start),
+                handshake: conn.established_at() >= start,
+                open: acquired.elapsed(),
+            },
+        })
+    }
+}
+
+/// Dials through the server over TLS over TCP
+pub struct TcpDialer;
+
+#[async_trait]
+impl Dialer for TcpDialer {
+    async fn connect(
+        &self,
+        addr: Address,
+        _sticky_key: Option<&str>,
+        _egress: Option<&str>,
+    ) -> Result<Dialed, Error> {
+        TcpTransport::connect(addr).await
+    }
+}
+
+/// Dials through `quic`, switching to `tcp` for a while when `quic` does not connect within `timeout`
+pub struct AutoDialer {
+    quic: Box<dyn Dialer>,
+    tcp: Box<dyn Dialer>,
+    timeout: Duration,
+    quic_blocked_until: AtomicCell<Option<Instant>>,
+}
+
+impl AutoDialer {
+    pub fn new(quic: Box<dyn Dialer>, tcp: Box<dyn Dialer>, timeout: Duration) -> Self {
+        Self {
+            quic,
+            tcp,
+            timeout,
+            quic_blocked_until: AtomicCell::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl Dialer for AutoDialer {
+    async fn connect(
+        &self,
+        addr: Address,
+        sticky_key: Option<&str>,
+        egress: Option<&str>,
+    ) -> Result<Dialed, Error> {
+        if let Some(until) = self.quic_blocked_until.load() {
+            if Instant::now() < until {
+                return self.tcp.connect(addr, sticky_key, egress).await;
+            }
+        }
+
+        let quic = self.quic.connect(addr.clone(), sticky_key, egress);
+
+        let err = match time::timeout(self.timeout, quic).await {
+            Ok(Ok(stream)) => {
+                self.quic_blocked_until.store(None);
+                return Ok(stream);
+            }
+            Ok(Err(err)) => err,
+            Err(_) => Error::Timeout,
+        };
+
+        log::warn!("[dialer] [{addr}] QUIC unavailable ({err}), switching to TCP");
+        self.quic_blocked_until
+            .store(Some(Instant::now() + QUIC_RETRY_AFTER));
+
+        self.tcp.connect(addr, sticky_key, egress).await
+    }
+}
+
+/// Connects to the target directly, bypassing the tunnel
+pub struct DirectDialer;
+
+#[async_trait]
+impl Dialer for DirectDialer {
+    async fn connect(
+        &self,
+        addr: Address,
+        _sticky_key: Option<&str>,
+        _egress: Option<&str>,
+    ) -> Result<Dialed, Error> {
+        let start = Instant::now();
+
+        let stream = match addr {
+            Address::DomainAddress(domain, port) => TcpStream::connect((domain, port)).await?,
+            Address::SocketAddress(addr) => TcpStream::connect(addr).await?,
+            Address::None => Err(IoError::new(ErrorKind::InvalidInput, "empty address"))?,
+        };
+
+        Ok(Dialed {
+            stream: Box::new(stream),
+            via: Arc::from("direct"),
+            server_addr: None,
+            compression: StreamCompression::None,
+            timings: DialTimings {
+                acquire: None,
+                handshake: false,
+                open: start.elapsed(),
+            },
+        })
+    }
+}
+
+/// Dials through the tunnel, falling back to a direct connection for bypassed targets if the tunnel fails
+pub struct FailoverDialer {
+    tunnel: Box<dyn Dialer>,
+    bypass: Vec<Bypass>,
+}
+
+impl FailoverDialer {
+    pub fn new(tunnel: Box<dyn Dialer>, bypass: Vec<Bypass>) -> Self {
+        Self { tunnel, bypass }
+    }
+}
+
+#[async_trait]
+impl Dialer for FailoverDialer {
+    async fn connect(
+        &self,
+        addr: Address,
+        sticky_key: Option<&str>,
+        egress: Option<&str>,
+    ) -> Result<Dialed, Error> {
+        match self.tunnel.connect(addr.clone(), sticky_key, egress).await {
+            Ok(stream) => Ok(stream),
+            Err(err) if self.bypass.iter().any(|rule| rule.matches(&addr)) => {
+                log::warn!(
+                    "[dialer] [{addr}] tunnel unavailable ({err}), connecting directly without the tunnel"
+                );
+                DirectDialer.connect(addr, None, None).await
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        future,
+        net::Ipv4Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use tokio::net::TcpListener;
+
+    /// A dialer that counts its calls, and either never connects or connects through `via` to a loopback listener
+    struct MockDialer {
+        via: Option<&'static str>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl MockDialer {
+        fn boxed(via: Option<&'static str>) -> (Box<dyn Dialer>, Arc<AtomicUsize>) {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let dialer = Self {
+                via,
+                calls: calls.clone(),
+            };
+            (Box::new(dialer), calls)
+        }
+    }
+
+    #[async_trait]
+    impl Dialer for MockDialer {
+        async fn connect(
+            &self,
+            _addr: Address,
+            _sticky_key: Option<&str>,
+            _egress: Option<&str>,
+        ) -> Result<Dialed, Error> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+
+            let Some(via) = self.via else {
+                return future::pending().await;
+            };
+
+            let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await?;
+            let mut dialed = DirectDialer
+                .connect(Address::SocketAddress(listener.local_addr()?), None, None)
+                .await?;
+            dialed.via = Arc::from(via);
+
+            Ok(dialed)
+        }
+    }
+
+    fn target() -> Address {
+        Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 80)))
+    }
+
+    #[tokio::test]
+    async fn auto_switches_to_tcp_when_quic_does_not_connect() {
+        let (quic, quic_calls) = MockDialer::boxed(None);
+        let (tcp, tcp_calls) = MockDialer::boxed(Some("tcp"));
+        let dialer = AutoDialer::new(quic, tcp, Duration::from_millis(50));
+
+        let dialed = dialer.connect(target(), None, None).await.unwrap();
+        assert_eq!(&*dialed.via, "tcp");
+        assert_eq!(quic_calls.load(Ordering::Relaxed), 1);
+        assert_eq!(tcp_calls.load(Ordering::Relaxed), 1);
+
+        // QUIC is not tried again until `QUIC_RETRY_AFTER` passed
+        let dialed = dialer.connect(target(), None, None).await.unwrap();
+        assert_eq!(&*dialed.via, "tcp");
+        assert_eq!(quic_calls.load(Ordering::Relaxed), 1);
+        assert_eq!(tcp_calls.load(Ordering::Relaxed), 2);
+
+        dialer
+            .quic_blocked_until
+            .store(Some(Instant::now() - Duration::from_secs(1)));
+        dialer.connect(target(), None, None).await.unwrap();
+        assert_eq!(quic_calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn auto_keeps_quic_while_it_connects() {
+        let (quic, quic_calls) = MockDialer::boxed(Some("quic"));
+        let (tcp, tcp_calls) = MockDialer::boxed(Some("tcp"));
+        let dialer = AutoDialer::new(quic, tcp, Duration::from_secs(5));
+
+        for _ in 0..2 {
+            let dialed = dialer.connect(target(), None, None).await.unwrap();
+            assert_eq!(&*dialed.via, "quic");
+        }
+
+        assert_eq!(quic_calls.load(Ordering::Relaxed), 2);
+        assert_eq!(tcp_calls.load(Ordering::Relaxed), 0);
+        assert!(dialer.quic_blocked_until.load().is_none());
+    }
+}
@@ -0,0 +1,520 @@
+use crate::{dialer::Stream, utils::StreamCompression};
+#[cfg(feature = "compression")]
+use async_compression::{
+    tokio::{bufread::ZstdDecoder, write::ZstdEncoder},
+    Level,
+};
+use once_cell::sync::OnceCell;
+use quinn::{ConnectionError, ReadError, WriteError};
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    future::Future,
+    io::{Error as IoError, ErrorKind},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+#[cfg(feature = "compression")]
+use tokio::io::BufReader;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{self, Instant},
+};
+
+const BUFFER_SIZE: usize = 8 * 1024;
+/// The largest `relay_write_buffer`, allocated for every relay. Writes are already few and large well below it
+pub const MAX_WRITE_BUFFER: usize = 1024 * 1024;
+
+/// Why a relay ended
+#[derive(Clone, Copy)]
+pub enum CloseReason {
+    /// The socks5 client closed its side first, then the remote finished its response
+    LocalEof,
+    /// The remote closed its side first, then the socks5 client followed
+    RemoteEof,
+    /// Reading from or writing to the socks5 client failed
+    LocalError,
+    /// The remote stream was reset or failed
+    RemoteReset,
+    /// The remote did not finish within the linger time, or the connection timed out
+    IdleTimeout,
+    /// Cancelled through the admin socket
+    Canceled,
+    /// The user ran out of quota
+    QuotaExceeded,
+    /// The relay was open for longer than `max_relay_lifetime`
+    LifetimeExceeded,
+    /// Too many relays to the same destination were open, see `max_per_destination`
+    DestinationLimit,
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(match self {
+            Self::LocalEof => "local_eof",
+            Self::RemoteEof => "remote_eof",
+            Self::LocalError => "local_error",
+            Self::RemoteReset => "remote_reset",
+            Self::IdleTimeout => "idle_timeout",
+            Self::Canceled => "canceled",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::LifetimeExceeded => "lifetime_exceeded",
+            Self::DestinationLimit => "destination_limit",
+        })
+    }
+}
+
+/// How the data from the remote is gathered before it is written to the socks5 client
+#[derive(Clone, Copy)]
+pub struct WriteBuffer {
+    pub capacity: usize,
+    /// How long a partly filled buffer waits for more data, `None` to write it once no more data is immediately available
+    pub flush_interval: Option<Duration>,
+}
+
+/// The QUIC-level cause of a failed relay, telling apart what the server closed or reset, with its error code and reason, from network failures
+pub struct QuicError {
+    /// e.g. `stream_reset` or `timed_out`
+    pub kind: &'static str,
+    /// The error code sent by the peer, or the local transport error code
+    pub code: Option<u64>,
+    pub reason: Option<String>,
+}
+
+impl QuicError {
+    /// Finds the QUIC error behind an error of the remote stream. `None` for other errors, e.g. of relays over TCP
+    pub fn from_io(err: &IoError) -> Option<Self> {
+        let inner = err.get_ref()?;
+
+        if let Some(err) = inner.downcast_ref::<ReadError>() {
+            return match err {
+                ReadError::Reset(code) => Some(Self::new("stream_reset", u64::from(*code), None)),
+                ReadError::ConnectionLost(err) => Some(Self::from_connection(err)),
+                _ => None,
+            };
+        }
+
+        if let Some(err) = inner.downcast_ref::<WriteError>() {
+            return match err {
+                WriteError::Stopped(code) => {
+                    Some(Self::new("stream_stopped", u64::from(*code), None))
+                }
+                WriteError::ConnectionLost(err) => Some(Self::from_connection(err)),
+                _ => None,
+            };
+        }
+
+        inner
+            .downcast_ref::<ConnectionError>()
+            .map(Self::from_connection)
+    }
+
+    pub fn from_connection(err: &ConnectionError) -> Self {
+        let reason = |reason: &[u8]| {
+            (!reason.is_empty()).then(|| String::from_utf8_lossy(reason).into_owned())
+        };
+
+        match err {
+            ConnectionError::ApplicationClosed(close) => Self::new(
+                "application_closed",
+                u64::from(close.error_code),
+                reason(&close.reason),
+            ),
+            ConnectionError::ConnectionClosed(close) => Self::new(
+                "connection_closed",
+                u64::from(close.error_code),
+                reason(&close.reason),
+            ),
+            ConnectionError::TransportError(err) => Self::new(
+                "transport_error",
+                u64::from(err.code),
+                reason(err.reason.as_bytes()),
+            ),
+            ConnectionError::Reset => Self::without_code("stateless_reset"),
+            ConnectionError::TimedOut => Self::without_code("timed_out"),
+            ConnectionError::LocallyClosed => Self::without_code("locally_closed"),
+            ConnectionError::VersionMismatch => Self::without_code("version_mismatch"),
+        }
+    }
+
+    fn new(kind: &'static str, code: u64, reason: Option<String>) -> Self {
+        Self {
+            kind,
+            code: Some(code),
+            reason,
+        }
+    }
+
+    fn without_code(kind: &'static str) -> Self {
+        Self {
+            kind,
+            code: None,
+            reason: None,
+        }
+    }
+
+    /// Names of the application error codes TUIC implementations close connections and reset streams with
+    fn code_name(&self) -> Option<&'static str> {
+        match (self.kind, self.code?) {
+            ("stream_reset" | "stream_stopped" | "application_closed", 0) => Some("tuic_closed"),
+            _ => None,
+        }
+    }
+}
+
+impl Display for QuicError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(self.kind)?;
+
+        if let Some(code) = self.code {
+            write!(f, " {code:#x}")?;
+        }
+
+        if let Some(name) = self.code_name() {
+            write!(f, " ({name})")?;
+        }
+
+        if let Some(reason) = &self.reason {
+            write!(f, ": {reason}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Relays data between the socks5 client and the remote stream until both directions are closed, returning why the relay ended along with the error that ended it, if any
+///
+/// `up_bytes` and `down_bytes` are updated as data flows, so the progress of a running relay can be observed. They count uncompressed bytes
+///
+/// A direction is shut down exactly once, as soon as its source reaches EOF, so a half-closed peer still receives the rest of the response. On error, the remaining direction is dropped without being shut down. If the socks5 client reset its connection, the remote stream is aborted in both directions instead
+///
+/// If `linger` is set, the remote is given at most that long to finish its response once the socks5 client reached EOF. The relay then ends without waiting for the remote any longer
+///
+/// With `write_buffer`, what is read from the remote is gathered and written to the socks5 client in larger chunks
+///
+/// With `compression`, the remote stream is wrapped in an encoder and a decoder, so it carries one compressed frame in each direction
+///
+/// `first_byte` is set when the first data from the remote is read
+#[allow(clippy::too_many_arguments)]
+pub async fn forward<L, R>(
+    local: &mut L,
+    remote: &mut R,
+    linger: Option<Duration>,
+    write_buffer: Option<WriteBuffer>,
+    compression: StreamCompression,
+    up_bytes: &AtomicU64,
+    down_bytes: &AtomicU64,
+    first_byte: &OnceCell<Instant>,
+) -> (CloseReason, Result<(), IoError>)
+where
+    L: AsyncRead + AsyncWrite + Unpin,
+    R: Stream + ?Sized,
+{
+    let (reason, res) = {
+        let (mut local_recv, mut local_send) = io::split(local);
+        let (mut remote_recv, mut remote_send) = io::split(&mut *remote);
+
+        match compression {
+            StreamCompression::None => {
+                let up = copy(
+                    &mut local_recv,
+                    &mut remote_send,
+                    up_bytes,
+                    None,
+                    None,
+                    false,
+                );
+                let down = copy(
+                    &mut remote_recv,
+                    &mut local_send,
+                    down_bytes,
+                    write_buffer,
+                    Some(first_byte),
+                    false,
+                );
+                relay(up, down, linger).await
+            }
+            #[cfg(feature = "compression")]
+            StreamCompression::Zstd(level) => {
+                let mut remote_recv = ZstdDecoder::new(BufReader::new(remote_recv));
+                let mut remote_send =
+                    ZstdEncoder::with_quality(remote_send, Level::Precise(i32::from(level)));
+
+                let up = copy(
+                    &mut local_recv,
+                    &mut remote_send,
+                    up_bytes,
+                    None,
+                    None,
+                    true,
+                );
+                let down = copy(
+                    &mut remote_recv,
+                    &mut local_send,
+                    down_bytes,
+                    write_buffer,
+                    Some(first_byte),
+                    false,
+                );
+                relay(up, down, linger).await
+            }
+        }
+    };
+
+    // the socks5 client reset its connection, which must not reach the remote as a clean end of the request
+    if let (CloseReason::LocalError, Err(err)) = (reason, &res) {
+        if err.kind() == ErrorKind::ConnectionReset {
+            remote.abort();
+        }
+    }
+
+    (reason, res)
+}
+
+/// Drives both directions of a relay, see `forward()`
+async fn relay<U, D>(up: U, down: D, linger: Option<Duration>) -> (CloseReason, Result<(), IoError>)
+where
+    U: Future<Output = Result<(), CopyError>>,
+    D: Future<Output = Result<(), CopyError>>,
+{
+    tokio::pin!(up, down);
+
+    tokio::select! {
+        res = &mut up => {
+            if let Err(err) = res {
+                return err.close(Direction::Up);
+            }
+
+            let res = match linger {
+                Some(linger) => match time::timeout(linger, down).await {
+                    Ok(res) => res,
+                    Err(_) => return (CloseReason::IdleTimeout, Ok(())),
+                },
+                None => down.await,
+            };
+
+            match res {
+                Ok(()) => (CloseReason::LocalEof, Ok(())),
+                Err(err) => err.close(Direction::Down),
+            }
+        }
+        res = &mut down => {
+            if let Err(err) = res {
+                return err.close(Direction::Down);
+            }
+
+            match up.await {
+                Ok(()) => (CloseReason::RemoteEof, Ok(())),
+                Err(err) => err.close(Direction::Up),
+            }
+        }
+    }
+}
+
+enum Direction {
+    /// From the socks5 client to the remote
+    Up,
+    /// From the remote to the socks5 client
+    Down,
+}
+
+/// A failed copy, telling apart the side read from and the side written to
+enum CopyError {
+    Read(IoError),
+    Write(IoError),
+}
+
+impl CopyError {
+    fn close(self, direction: Direction) -> (CloseReason, Result<(), IoError>) {
+        let (is_local, err) = match (direction, self) {
+            (Direction::Up, Self::Read(err)) | (Direction::Down, Self::Write(err)) => (true, err),
+            (Direction::Up, Self::Write(err)) | (Direction::Down, Self::Read(err)) => (false, err),
+        };
+
+        let reason = match err.kind() {
+            ErrorKind::TimedOut => CloseReason::IdleTimeout,
+            _ if is_local => CloseReason::LocalError,
+            _ => CloseReason::RemoteReset,
+        };
+
+        (reason, Err(err))
+    }
+}
+
+/// Copies until `reader` reaches EOF, then shuts `writer` down. `copied` is kept up to date, so the count is right even if the copy is cancelled, and `first_read` is set on the first data read
+///
+/// With `flush`, `writer` is flushed after every write, so that a compressing writer does not hold back data the peer waits for. With `buffer`, every read is followed by more reads as long as data is available and the buffer has room, and what they gathered is written at once
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    copied: &AtomicU64,
+    buffer: Option<WriteBuffer>,
+    first_read: Option<&OnceCell<Instant>>,
+    flush: bool,
+) -> Result<(), CopyError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; buffer.map_or(BUFFER_SIZE, |buffer| buffer.capacity)];
+
+    loop {
+        let mut n = reader.read(&mut buf).await.map_err(CopyError::Read)?;
+
+        if n == 0 {
+            break;
+        }
+
+        if let Some(first_read) = first_read {
+            first_read.get_or_init(Instant::now);
+        }
+
+        let mut is_eof = false;
+
+        if let Some(buffer) = buffer {
+            // a zero timeout still polls the read once, taking what is already available
+            let deadline = Instant::now() + buffer.flush_interval.unwrap_or_default();
+
+            while n < buf.len() {
+                let Ok(res) = time::timeout_at(deadline, reader.read(&mut buf[n..])).await else {
+                    break;
+                };
+
+                match res.map_err(CopyError::Read)? {
+                    0 => {
+                        is_eof = true;
+                        break;
+                    }
+                    read => n += read,
+                }
+            }
+        }
+
+        writer
+            .write_all(&buf[..n])
+            .await
+            .map_err(CopyError::Write)?;
+
+        if flush {
+            writer.flush().await.map_err(CopyError::Write)?;
+        }
+
+        copied.fetch_add(n as u64, Ordering::Relaxed);
+
+        if is_eof {
+            break;
+        }
+    }
+
+    writer.shutdown().await.map_err(CopyError::Write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Result as IoResult,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    /// A large download, every segment of which is available right away
+    struct Download {
+        left: usize,
+        segment: usize,
+    }
+
+    impl AsyncRead for Download {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut io::ReadBuf<'_>,
+        ) -> Poll<IoResult<()>> {
+            let n = self.left.min(self.segment).min(buf.remaining());
+            buf.put_slice(&vec![0; n]);
+            self.left -= n;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Counts the writes a socket would make a syscall for
+    #[derive(Default)]
+    struct Writes {
+        count: usize,
+        bytes: usize,
+    }
+
+    impl AsyncWrite for Writes {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<IoResult<usize>> {
+            self.count += 1;
+            self.bytes += buf.len();
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Downloads 16 MiB in segments of a TCP packet, returning the writes to the socks5 client
+    async fn download(buffer: Option<WriteBuffer>) -> Writes {
+        const SIZE: usize = 16 * 1024 * 1024;
+
+        let mut reader = Download {
+            left: SIZE,
+            segment: 1460,
+        };
+        let mut writer = Writes::default();
+        let copied = AtomicU64::new(0);
+
+        let res = copy(&mut reader, &mut writer, &copied, buffer, None, false).await;
+        assert!(res.is_ok());
+        assert_eq!(copied.load(Ordering::Relaxed), SIZE as u64);
+        assert_eq!(writer.bytes, SIZE);
+
+        writer
+    }
+
+    #[tokio::test]
+    async fn write_buffer_saves_writes_on_a_large_download() {
+        let unbuffered = download(None).await;
+        let buffered = download(Some(WriteBuffer {
+            capacity: 64 * 1024,
+            flush_interval: None,
+        }))
+        .await;
+
+        // one write per segment, against one per 64 KiB
+        assert_eq!(unbuffered.count, 11_492);
+        assert_eq!(buffered.count, 256);
+    }
+
+    #[tokio::test]
+    async fn write_buffer_writes_small_transfers_right_away() {
+        let (mut remote, mut reader) = io::duplex(1024);
+        let mut writer = Writes::default();
+        let copied = AtomicU64::new(0);
+
+        remote.write_all(b"interactive").await.unwrap();
+
+        let buffer = WriteBuffer {
+            capacity: 64 * 1024,
+            flush_interval: None,
+        };
+
+        // the remote stays open without sending more
+        tokio::select! {
+            _ = copy(&mut reader, &mut writer, &copied, Some(buffer), None, false) => unreachable!(),
+            () = time::sleep(Duration::from_millis(50)) => {}
+        }
+
+        assert_eq!((writer.count, writer.bytes), (1, 11));
+    }
+}
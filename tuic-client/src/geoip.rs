@@ -0,0 +1,69 @@
+use maxminddb::{geoip2::Country, Reader};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use std::{collections::HashMap, net::IpAddr, path::PathBuf};
+
+static GEOIP: OnceCell<GeoIp> = OnceCell::new();
+
+const MAX_CACHED_LOOKUPS: usize = 4096;
+
+pub struct GeoIp {
+    /// `None` if the database failed to load
+    reader: Option<Reader<Vec<u8>>>,
+    cache: Mutex<HashMap<IpAddr, Option<String>>>,
+}
+
+impl GeoIp {
+    /// Loads the database, at startup so that reading the file does not block a runtime thread while routing
+    pub fn set_config(path: PathBuf) {
+        let reader = match Reader::open_readfile(&path) {
+            Ok(reader) => Some(reader),
+            Err(err) => {
+                log::error!("[geoip] failed to load database {}: {err}", path.display());
+                None
+            }
+        };
+
+        let geoip = Self {
+            reader,
+            cache: Mutex::new(HashMap::new()),
+        };
+
+        GEOIP
+            .set(geoip)
+            .map_err(|_| "geoip database already initialized")
+            .unwrap();
+    }
+
+    /// Whether the database is configured and loaded
+    pub fn is_available() -> bool {
+        GEOIP.get().is_some_and(|geoip| geoip.reader.is_some())
+    }
+
+    /// Returns the ISO 3166 country code of `ip`
+    pub fn country(ip: IpAddr) -> Option<String> {
+        let geoip = GEOIP.get()?;
+
+        if let Some(code) = geoip.cache.lock().get(&ip) {
+            return code.clone();
+        }
+
+        let code = geoip
+            .reader
+            .as_ref()?
+            .lookup::<Country>(ip)
+            .ok()
+            .and_then(|country| country.country)
+            .and_then(|country| country.iso_code)
+            .map(str::to_owned);
+
+        let mut cache = geoip.cache.lock();
+
+        if cache.len() >= MAX_CACHED_LOOKUPS {
+            cache.clear();
+        }
+
+        cache.insert(ip, code.clone());
+        code
+    }
+}
@@ -0,0 +1,330 @@
+//! The TUIC client of the `tuic-client` binary. See [`Client`] to embed it
+
+pub use self::{
+    config::{ConfigError, RelayOverrides, VerifyDecision},
+    resolver::{Lookup, Srv},
+};
+pub use tuic::Address;
+
+#[cfg(feature = "tower")]
+pub use self::service::{BoxError, ConnectRequest, RecvStream, SendStream, TuicService};
+
+use self::{
+    config::Config,
+    connection::{Connection, Endpoint},
+    diagnostics::Diagnostics,
+    quota::Quotas,
+    resolver::Resolver,
+    routing::Router,
+    socks5::Server as Socks5Server,
+    state::State,
+    utils::LogFormat,
+};
+use env_logger::Builder as LoggerBuilder;
+use quinn::{ConnectError, ConnectionError, TransportConfig};
+use rustls::{Certificate, Error as TlsError, ServerName};
+use serde_json::Error as SerdeError;
+use std::{
+    env::ArgsOs, io::Error as IoError, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration,
+};
+use thiserror::Error;
+#[cfg(feature = "tower")]
+use tower_service::Service;
+use tuic_quinn::Error as ModelError;
+use webpki::Error as WebpkiError;
+
+mod access_log;
+#[cfg(feature = "admin")]
+mod admin;
+mod config;
+mod connection;
+mod diagnostics;
+mod dialer;
+mod forward;
+#[cfg(feature = "geoip")]
+mod geoip;
+mod metrics;
+#[cfg(all(unix, feature = "privdrop"))]
+mod privilege;
+#[cfg(feature = "admin")]
+mod probe;
+mod quota;
+mod resolver;
+mod routing;
+#[cfg(feature = "tower")]
+mod service;
+mod socks5;
+mod state;
+mod tcp;
+pub mod udp;
+mod upstream;
+mod utils;
+
+/// A TUIC client, as run by the `tuic-client` binary, for builds that embed it
+///
+/// The client keeps its state in process-wide statics, so it can run once per process
+pub struct Client {
+    cfg: Config,
+}
+
+impl Client {
+    /// Reads the config file given on the command line, see `tuic-client --help`
+    pub fn from_args(args: ArgsOs) -> Result<Self, ConfigError> {
+        Ok(Self {
+            cfg: Config::parse(args)?,
+        })
+    }
+
+    /// Reads the config file at `path`
+    pub fn from_file(path: PathBuf) -> Result<Self, ConfigError> {
+        Ok(Self {
+            cfg: Config::read(path)?,
+        })
+    }
+
+    /// Sets `relay.transport_config_hook`, called with the QUIC transport config once every option of the config file is applied, so it can override any of them
+    pub fn transport_config_hook(
+        mut self,
+        hook: impl Fn(&mut TransportConfig) + Send + Sync + 'static,
+    ) -> Self {
+        self.cfg.relay.transport_config_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets `relay.cert_verifier_hook`, called in the TLS handshake of every connection to the server with its certificate, the name it was verified against and the result of the normal verification, to keep, override or reject that result. Accepting a certificate that failed verification lets anyone on the path impersonate the server, see the config documentation
+    pub fn cert_verifier_hook(
+        mut self,
+        hook: impl Fn(&Certificate, &ServerName, Result<(), &TlsError>) -> VerifyDecision
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.cfg.relay.cert_verifier_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets `local.relay_overrides_hook`, called with the target of every CONNECT request to pick its connect timeout, `relay_linger` or `max_relay_lifetime`, e.g. by destination. Whatever it leaves unset comes from the config
+    pub fn relay_overrides_hook(
+        mut self,
+        hook: impl Fn(&Address) -> RelayOverrides + Send + Sync + 'static,
+    ) -> Self {
+        self.cfg.local.relay_overrides_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets `local.connect_service`, which opens the relays of CONNECT requests through the server, e.g. tower middleware around [`TuicService`]. Requires the `tower` feature
+    #[cfg(feature = "tower")]
+    pub fn connect_service<S>(mut self, service: S) -> Self
+    where
+        S: Service<ConnectRequest, Response = (SendStream, RecvStream)>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        S::Error: Into<BoxError>,
+        S::Future: Send,
+    {
+        self.cfg.local.connect_service = Some(Box::new(service::ServiceDialer::new(service)));
+        self
+    }
+
+    /// Sets `dns.lookup`, which looks up target domains and `SRV` records in place of `dns.resolver`, e.g. to integrate a resolver of the embedding build. Domains are only resolved by the client with `dns.resolve_locally`, otherwise just the `SRV` records of `relay.srv` are
+    pub fn lookup(mut self, lookup: impl Lookup + 'static) -> Self {
+        self.cfg.dns.lookup = Some(Arc::new(lookup));
+        self
+    }
+
+    /// Installs the logger of `log_level` and `log_format`, as the binary does. Builds with a logger of their own skip it
+    pub fn init_logger(&self) {
+        let mut logger = LoggerBuilder::new();
+        logger
+            .filter_level(self.cfg.log_level)
+            .format_module_path(false);
+
+        if let LogFormat::Json = self.cfg.log_format {
+            logger.format(utils::format_json_log);
+        }
+
+        logger.init();
+    }
+
+    /// Starts the client and runs it until it is shut down by a signal, or fails to start. Returns `Error::Unreachable` if every server stays unreachable for `relay.fail_fast_after`
+    pub async fn run(self) -> Result<(), Error> {
+        let cfg = self.cfg;
+
+        State::set_config(
+            cfg.state_file,
+            cfg.state_key,
+            cfg.state_key_file,
+            cfg.state_migrate_plaintext,
+        )?;
+
+        let srv = cfg.relay.srv;
+        Endpoint::set_config(cfg.relay)?;
+
+        Diagnostics::set_config(cfg.recent_errors);
+
+        #[cfg(unix)]
+        utils::spawn(format_args!("diagnostics"), Diagnostics::dump_on_signal());
+
+        Router::set_config(cfg.routing)?;
+        Resolver::set_config(cfg.dns)?;
+
+        if srv && !Resolver::supports_srv() {
+            return Err(Error::SrvUnsupported);
+        }
+
+        Socks5Server::set_config(cfg.local)?;
+
+        // before anything is accepted, while only the listeners that need the privileges are bound
+        if let Some(run_as) = cfg.run_as {
+            #[cfg(all(unix, feature = "privdrop"))]
+            privilege::drop_privileges(run_as)?;
+
+            #[cfg(not(all(unix, feature = "privdrop")))]
+            return Err(Error::PrivilegeDropUnsupported {
+                uid: run_as.uid,
+                gid: run_as.gid,
+            });
+        }
+
+        utils::spawn(format_args!("quota"), Quotas::persist());
+
+        if let Some(addr) = cfg.metrics_server {
+            #[cfg(feature = "metrics")]
+            utils::spawn(format_args!("metrics"), metrics::serve(addr));
+
+            #[cfg(not(feature = "metrics"))]
+            log::warn!(
+                "[metrics] built without the `metrics` feature, ignoring exporter on {addr}"
+            );
+        }
+
+        if let Some(addr) = cfg.admin_addr {
+            #[cfg(feature = "admin")]
+            utils::spawn(format_args!("admin"), admin::serve(addr, cfg.path));
+
+            #[cfg(not(feature = "admin"))]
+            log::warn!(
+                "[admin] built without the `admin` feature, ignoring control socket on {addr}"
+            );
+        }
+
+        Socks5Server::write_port_file()?;
+
+        // without anything to clean up, the signals keep ending the process right away
+        let on_shutdown = State::is_enabled() || Socks5Server::has_port_file();
+
+        tokio::select! {
+            () = Socks5Server::start() => {}
+            err = Connection::unreachable() => {
+                log::error!("[connection] {err}, shutting down");
+                Socks5Server::remove_port_file();
+                return Err(err);
+            }
+            () = utils::shutdown_signal(), if on_shutdown => {}
+        }
+
+        State::save_on_shutdown();
+        Socks5Server::remove_port_file();
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] IoError),
+    #[error(transparent)]
+    Connect(#[from] ConnectError),
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+    #[error(transparent)]
+    Model(#[from] ModelError),
+    #[error(transparent)]
+    Webpki(#[from] WebpkiError),
+    #[error("failed to listen on {addr}: {source}")]
+    ListenBind { addr: SocketAddr, source: IoError },
+    #[error("timeout establishing connection")]
+    Timeout,
+    #[error("TLS handshake failed: {0}")]
+    TlsHandshakeFailed(ConnectionError),
+    #[error("protocol mismatch, not a TUIC server or ALPN differs: {0}")]
+    ProtocolMismatch(ConnectionError),
+    #[error("connection refused by the server: {0}")]
+    Refused(ConnectionError),
+    #[error("invalid server certificate: {0}")]
+    CertInvalid(ConnectionError),
+    #[error("cannot resolve the server name")]
+    DnsResolve,
+    #[error("invalid DNS message: {0}")]
+    DnsMessage(&'static str),
+    #[error("DNS query failed with rcode {0}")]
+    DnsRcode(u8),
+    #[error("server {0} the target")]
+    TargetUnreachable(&'static str),
+    #[cfg(feature = "tower")]
+    #[error("connect service failed: {0}")]
+    ConnectService(BoxError),
+    #[error("relay cancelled")]
+    Cancelled,
+    #[error("received packet from an unexpected source")]
+    WrongPacketSource,
+    #[error("invalid socks5 authentication")]
+    InvalidSocks5Auth,
+    #[error("socks5 client offered no acceptable authentication method, expecting {0}")]
+    NoAcceptableAuthMethod(&'static str),
+    #[error("`reject_no_auth_clients` requires socks5 credentials")]
+    MissingSocks5Credentials,
+    #[error("`reply_echo_port` conflicts with `reply_bind_mode`")]
+    ConflictingReplyBindMode,
+    #[error("`relay.srv` requires a `dns.resolver` other than `system`")]
+    SrvUnsupported,
+    #[error("routing rule with unknown egress binding `{0}`")]
+    UnknownEgress(String),
+    #[error("quota set for unknown socks5 user `{0}`")]
+    UnknownQuotaUser(String),
+    #[error("invalid quota file: {0}")]
+    InvalidQuotaFile(SerdeError),
+    #[error("invalid max idle time")]
+    InvalidMaxIdleTime,
+    #[error("exactly one of `password` and `password_file` must be set")]
+    InvalidPassword,
+    #[error("invalid timing jitter, expecting 0 to 1")]
+    InvalidTimingJitter,
+    #[error("invalid DSCP, expecting 0-63")]
+    InvalidDscp,
+    #[error("invalid UDP max payload, expecting at least 512")]
+    InvalidUdpMaxPayload,
+    #[error("client label longer than 255 bytes")]
+    InvalidClientLabel,
+    #[error("invalid relay write buffer, expecting at most 1 MiB")]
+    InvalidRelayWriteBuffer,
+    #[error("invalid relay warn ratio, expecting at least 1")]
+    InvalidRelayWarnRatio,
+    #[error("unknown server `{0}`")]
+    UnknownServer(String),
+    #[error("server {0} is draining")]
+    ServerDraining(String),
+    #[error("upstream proxy cannot carry UDP: {0}")]
+    UpstreamProxy(String),
+    #[error("no free local UDP port to bind {0}")]
+    PortExhaustion(SocketAddr),
+    #[error("all UDP association IDs are in use")]
+    AssociationsExhausted,
+    #[error("at most one of `state_key` and `state_key_file` may be set")]
+    InvalidStateKey,
+    #[error("cannot decrypt {}: wrong or missing `state_key`, or the file is corrupted", .0.display())]
+    StateDecrypt(PathBuf),
+    #[error("{} is not encrypted although `state_key` is set, set `state_migrate_plaintext` once to encrypt it", .0.display())]
+    StatePlaintext(PathBuf),
+    #[cfg(all(unix, feature = "privdrop"))]
+    #[error("failed to switch to uid {uid} and gid {gid}: {source}")]
+    PrivilegeDrop { uid: u32, gid: u32, source: IoError },
+    #[cfg(not(all(unix, feature = "privdrop")))]
+    #[error("`run_as` (uid {uid}, gid {gid}) requires the `privdrop` feature on Unix")]
+    PrivilegeDropUnsupported { uid: u32, gid: u32 },
+    #[error("all servers unreachable for {}", humantime::format_duration(*.0))]
+    Unreachable(Duration),
+}
@@ -0,0 +1,706 @@
+//! Counters for dashboards. Without the `metrics` feature, counters compile to no-ops
+
+#[cfg(feature = "metrics")]
+use crate::utils;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use quinn::Connection;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+#[cfg(feature = "metrics")]
+use std::{fmt::Write as _, net::SocketAddr};
+#[cfg(feature = "metrics")]
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+pub static AUTH_NONE_TOTAL: Counter = Counter::new(
+    "auth_none_total",
+    "SOCKS5 handshakes negotiated without authentication",
+);
+pub static AUTH_PASSWORD_TOTAL: Counter = Counter::new(
+    "auth_password_total",
+    "SOCKS5 handshakes authenticated with username / password",
+);
+pub static AUTH_PASSWORD_FAIL_TOTAL: Counter = Counter::new(
+    "auth_password_fail_total",
+    "SOCKS5 handshakes with rejected username / password",
+);
+pub static AUTH_UNACCEPTABLE_TOTAL: Counter = Counter::new(
+    "auth_unacceptable_total",
+    "SOCKS5 handshakes where the client did not offer the required method",
+);
+pub static AUTH_DOWNGRADE_TOTAL: Counter = Counter::new(
+    "auth_downgrade_total",
+    "SOCKS5 handshakes rejected for offering no username / password while it is required",
+);
+
+const REQUESTS_HELP: &str = "SOCKS5 requests received, by command";
+
+pub static REQUESTS_CONNECT_TOTAL: Counter =
+    Counter::labeled("requests_total", REQUESTS_HELP, "command=\"connect\"");
+pub static REQUESTS_BIND_TOTAL: Counter =
+    Counter::labeled("requests_total", REQUESTS_HELP, "command=\"bind\"");
+pub static REQUESTS_ASSOCIATE_TOTAL: Counter =
+    Counter::labeled("requests_total", REQUESTS_HELP, "command=\"udp_associate\"");
+
+const REPLIES_HELP: &str = "SOCKS5 replies sent, by reply code";
+
+pub static REPLIES_SUCCEEDED_TOTAL: Counter =
+    Counter::labeled("replies_total", REPLIES_HELP, "reply=\"succeeded\"");
+pub static REPLIES_GENERAL_FAILURE_TOTAL: Counter =
+    Counter::labeled("replies_total", REPLIES_HELP, "reply=\"general_failure\"");
+pub static REPLIES_CONNECTION_NOT_ALLOWED_TOTAL: Counter = Counter::labeled(
+    "replies_total",
+    REPLIES_HELP,
+    "reply=\"connection_not_allowed\"",
+);
+pub static REPLIES_NETWORK_UNREACHABLE_TOTAL: Counter = Counter::labeled(
+    "replies_total",
+    REPLIES_HELP,
+    "reply=\"network_unreachable\"",
+);
+pub static REPLIES_HOST_UNREACHABLE_TOTAL: Counter =
+    Counter::labeled("replies_total", REPLIES_HELP, "reply=\"host_unreachable\"");
+pub static REPLIES_CONNECTION_REFUSED_TOTAL: Counter = Counter::labeled(
+    "replies_total",
+    REPLIES_HELP,
+    "reply=\"connection_refused\"",
+);
+pub static REPLIES_TTL_EXPIRED_TOTAL: Counter =
+    Counter::labeled("replies_total", REPLIES_HELP, "reply=\"ttl_expired\"");
+pub static REPLIES_COMMAND_NOT_SUPPORTED_TOTAL: Counter = Counter::labeled(
+    "replies_total",
+    REPLIES_HELP,
+    "reply=\"command_not_supported\"",
+);
+pub static REPLIES_ADDRESS_TYPE_NOT_SUPPORTED_TOTAL: Counter = Counter::labeled(
+    "replies_total",
+    REPLIES_HELP,
+    "reply=\"address_type_not_supported\"",
+);
+
+pub static UDP_PACKETS_SENT_TOTAL: Counter = Counter::new(
+    "udp_packets_sent_total",
+    "UDP packets relayed from socks5 clients to the server",
+);
+pub static UDP_PACKETS_RECEIVED_TOTAL: Counter = Counter::new(
+    "udp_packets_received_total",
+    "UDP packets relayed from the server to socks5 clients",
+);
+pub static UDP_PACKETS_DROPPED_TOTAL: Counter = Counter::new(
+    "udp_packets_dropped_total",
+    "UDP packets dropped in either direction",
+);
+
+pub static ACCESS_LOG_DROPPED_TOTAL: Counter = Counter::new(
+    "access_log_dropped_total",
+    "Access log lines dropped because writing them fell behind",
+);
+
+const DNS_CACHE_HELP: &str = "Local resolutions of target domains, by how the DNS cache answered";
+
+pub static DNS_CACHE_HIT_TOTAL: Counter =
+    Counter::labeled("dns_cache_total", DNS_CACHE_HELP, "result=\"hit\"");
+pub static DNS_CACHE_NEGATIVE_HIT_TOTAL: Counter =
+    Counter::labeled("dns_cache_total", DNS_CACHE_HELP, "result=\"negative_hit\"");
+pub static DNS_CACHE_MISS_TOTAL: Counter =
+    Counter::labeled("dns_cache_total", DNS_CACHE_HELP, "result=\"miss\"");
+
+pub static RELAYS_ACTIVE: Gauge = Gauge::new(
+    "relays_active",
+    "CONNECT relays forwarding data between a socks5 client and the target",
+);
+
+// getting a connection is `acquire` when an open one was reused and `handshake` when one was established for the relay
+const CONNECT_PHASE_HELP: &str = "Time spent opening CONNECT relays, by phase";
+
+pub static CONNECT_PHASE_ACQUIRE_SECONDS: Histogram = Histogram::labeled(
+    "connect_phase_seconds",
+    CONNECT_PHASE_HELP,
+    "phase=\"acquire\"",
+);
+pub static CONNECT_PHASE_HANDSHAKE_SECONDS: Histogram = Histogram::labeled(
+    "connect_phase_seconds",
+    CONNECT_PHASE_HELP,
+    "phase=\"handshake\"",
+);
+pub static CONNECT_PHASE_OPEN_SECONDS: Histogram = Histogram::labeled(
+    "connect_phase_seconds",
+    CONNECT_PHASE_HELP,
+    "phase=\"open\"",
+);
+pub static CONNECT_PHASE_FIRST_BYTE_SECONDS: Histogram = Histogram::labeled(
+    "connect_phase_seconds",
+    CONNECT_PHASE_HELP,
+    "phase=\"first_byte\"",
+);
+
+#[cfg(feature = "metrics")]
+static COUNTERS: &[&Counter] = &[
+    &AUTH_NONE_TOTAL,
+    &AUTH_PASSWORD_TOTAL,
+    &AUTH_PASSWORD_FAIL_TOTAL,
+    &AUTH_UNACCEPTABLE_TOTAL,
+    &AUTH_DOWNGRADE_TOTAL,
+    &REQUESTS_CONNECT_TOTAL,
+    &REQUESTS_BIND_TOTAL,
+    &REQUESTS_ASSOCIATE_TOTAL,
+    &REPLIES_SUCCEEDED_TOTAL,
+    &REPLIES_GENERAL_FAILURE_TOTAL,
+    &REPLIES_CONNECTION_NOT_ALLOWED_TOTAL,
+    &REPLIES_NETWORK_UNREACHABLE_TOTAL,
+    &REPLIES_HOST_UNREACHABLE_TOTAL,
+    &REPLIES_CONNECTION_REFUSED_TOTAL,
+    &REPLIES_TTL_EXPIRED_TOTAL,
+    &REPLIES_COMMAND_NOT_SUPPORTED_TOTAL,
+    &REPLIES_ADDRESS_TYPE_NOT_SUPPORTED_TOTAL,
+    &UDP_PACKETS_SENT_TOTAL,
+    &UDP_PACKETS_RECEIVED_TOTAL,
+    &UDP_PACKETS_DROPPED_TOTAL,
+    &DNS_CACHE_HIT_TOTAL,
+    &DNS_CACHE_NEGATIVE_HIT_TOTAL,
+    &DNS_CACHE_MISS_TOTAL,
+    &ACCESS_LOG_DROPPED_TOTAL,
+];
+
+#[cfg(feature = "metrics")]
+static GAUGES: &[&Gauge] = &[&RELAYS_ACTIVE];
+
+#[cfg(feature = "metrics")]
+static HISTOGRAMS: &[&Histogram] = &[
+    &CONNECT_PHASE_ACQUIRE_SECONDS,
+    &CONNECT_PHASE_HANDSHAKE_SECONDS,
+    &CONNECT_PHASE_OPEN_SECONDS,
+    &CONNECT_PHASE_FIRST_BYTE_SECONDS,
+];
+
+/// Upper bounds of the histogram buckets in seconds, followed by the implicit `+Inf`
+#[cfg(feature = "metrics")]
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[cfg(feature = "metrics")]
+static UDP_ASSOCIATIONS: Lazy<Mutex<BTreeMap<u16, Arc<UdpStats>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// The latest sample of every open QUIC connection, by `stable_id()`
+static QUIC_CONNECTIONS: Lazy<Mutex<BTreeMap<usize, QuicStats>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+pub struct Counter {
+    #[cfg(feature = "metrics")]
+    name: &'static str,
+    #[cfg(feature = "metrics")]
+    help: &'static str,
+    /// Prometheus labels, e.g. `command="connect"`. Counters sharing a name must be listed next to each other in `COUNTERS`
+    #[cfg(feature = "metrics")]
+    labels: &'static str,
+    #[cfg(feature = "metrics")]
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self::labeled(name, help, "")
+    }
+
+    const fn labeled(name: &'static str, help: &'static str, labels: &'static str) -> Self {
+        #[cfg(not(feature = "metrics"))]
+        let _ = (name, help, labels);
+
+        Self {
+            #[cfg(feature = "metrics")]
+            name,
+            #[cfg(feature = "metrics")]
+            help,
+            #[cfg(feature = "metrics")]
+            labels,
+            #[cfg(feature = "metrics")]
+            value: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn inc(&self) {
+        #[cfg(feature = "metrics")]
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Gauge {
+    #[cfg(feature = "metrics")]
+    name: &'static str,
+    #[cfg(feature = "metrics")]
+    help: &'static str,
+    #[cfg(feature = "metrics")]
+    value: AtomicU64,
+}
+
+impl Gauge {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        #[cfg(not(feature = "metrics"))]
+        let _ = (name, help);
+
+        Self {
+            #[cfg(feature = "metrics")]
+            name,
+            #[cfg(feature = "metrics")]
+            help,
+            #[cfg(feature = "metrics")]
+            value: AtomicU64::new(0),
+        }
+    }
+
+    /// Increments the gauge until the returned guard is dropped, so every increment is undone on any path out, including cancellation
+    #[inline]
+    pub fn track(&'static self) -> GaugeGuard {
+        #[cfg(feature = "metrics")]
+        self.value.fetch_add(1, Ordering::Relaxed);
+
+        GaugeGuard(self)
+    }
+}
+
+#[must_use]
+pub struct GaugeGuard(&'static Gauge);
+
+impl Drop for GaugeGuard {
+    #[inline]
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        self.0.value.fetch_sub(1, Ordering::Relaxed);
+
+        #[cfg(not(feature = "metrics"))]
+        let _ = self.0;
+    }
+}
+
+pub struct Histogram {
+    #[cfg(feature = "metrics")]
+    name: &'static str,
+    #[cfg(feature = "metrics")]
+    help: &'static str,
+    /// Like the labels of `Counter`, histograms sharing a name must be listed next to each other in `HISTOGRAMS`
+    #[cfg(feature = "metrics")]
+    labels: &'static str,
+    /// Observations per bucket, not cumulative yet, the last one for those above every bound
+    #[cfg(feature = "metrics")]
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    #[cfg(feature = "metrics")]
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn labeled(name: &'static str, help: &'static str, labels: &'static str) -> Self {
+        #[cfg(not(feature = "metrics"))]
+        let _ = (name, help, labels);
+
+        #[cfg(feature = "metrics")]
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            #[cfg(feature = "metrics")]
+            name,
+            #[cfg(feature = "metrics")]
+            help,
+            #[cfg(feature = "metrics")]
+            labels,
+            #[cfg(feature = "metrics")]
+            buckets: [ZERO; BUCKETS.len() + 1],
+            #[cfg(feature = "metrics")]
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn observe(&self, value: Duration) {
+        #[cfg(feature = "metrics")]
+        {
+            let secs = value.as_secs_f64();
+            let idx = BUCKETS
+                .iter()
+                .position(|bound| secs <= *bound)
+                .unwrap_or(BUCKETS.len());
+
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+            self.sum_micros
+                .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+        }
+
+        #[cfg(not(feature = "metrics"))]
+        let _ = value;
+    }
+}
+
+/// Packet counters of a UDP association. They are kept without the `metrics` feature too, to be logged when the association closes
+#[derive(Default)]
+pub struct UdpStats {
+    sent: AtomicU64,
+    received: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl UdpStats {
+    /// Creates the counters of a new association, exported with an `assoc_id` label until `unregister()` is called
+    pub fn register(assoc_id: u16) -> Arc<Self> {
+        let stats = Arc::new(Self::default());
+
+        #[cfg(feature = "metrics")]
+        UDP_ASSOCIATIONS.lock().insert(assoc_id, stats.clone());
+
+        #[cfg(not(feature = "metrics"))]
+        let _ = assoc_id;
+
+        stats
+    }
+
+    pub fn unregister(assoc_id: u16) {
+        #[cfg(feature = "metrics")]
+        UDP_ASSOCIATIONS.lock().remove(&assoc_id);
+
+        #[cfg(not(feature = "metrics"))]
+        let _ = assoc_id;
+    }
+
+    pub fn inc_sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        UDP_PACKETS_SENT_TOTAL.inc();
+    }
+
+    pub fn inc_received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        UDP_PACKETS_RECEIVED_TOTAL.inc();
+    }
+
+    pub fn inc_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        UDP_PACKETS_DROPPED_TOTAL.inc();
+    }
+
+    /// Returns the sent, received and dropped packet counts
+    pub fn get(&self) -> (u64, u64, u64) {
+        (
+            self.sent.load(Ordering::Relaxed),
+            self.received.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Path statistics of a QUIC connection, as sampled every `relay.stats_poll_interval`. The counts are totals since the connection was established. They are kept without the `metrics` feature too, for the admin socket and the diagnostics dump
+#[derive(Clone)]
+pub struct QuicStats {
+    pub server: Arc<str>,
+    pub rtt: Duration,
+    /// Congestion window in bytes
+    pub cwnd: u64,
+    pub sent_packets: u64,
+    /// Packets declared lost. Their frames are sent again in new packets, so this also counts the retransmissions
+    pub lost_packets: u64,
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub lost_bytes: u64,
+    /// Times the congestion controller reduced the window
+    pub congestion_events: u64,
+}
+
+impl QuicStats {
+    /// Replaces the sample of connection `id`
+    pub fn record(id: usize, server: Arc<str>, conn: &Connection) {
+        let stats = conn.stats();
+        let stats = Self {
+            server,
+            rtt: stats.path.rtt,
+            cwnd: stats.path.cwnd,
+            sent_packets: stats.path.sent_packets,
+            lost_packets: stats.path.lost_packets,
+            lost_bytes: stats.path.lost_bytes,
+            congestion_events: stats.path.congestion_events,
+        };
+
+        QUIC_CONNECTIONS.lock().insert(id, stats);
+    }
+
+    /// Forgets connection `id`, once it is closed
+    pub fn remove(id: usize) {
+        QUIC_CONNECTIONS.lock().remove(&id);
+    }
+
+    /// The latest sample of every open connection, oldest connection first
+    #[cfg_attr(not(any(unix, feature = "admin")), allow(dead_code))]
+    pub fn all() -> Vec<Self> {
+        QUIC_CONNECTIONS.lock().values().cloned().collect()
+    }
+
+    /// Share of the sent packets that were lost, in percent
+    #[cfg_attr(not(any(unix, feature = "admin")), allow(dead_code))]
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent_packets == 0 {
+            0.0
+        } else {
+            self.lost_packets as f64 * 100.0 / self.sent_packets as f64
+        }
+    }
+}
+
+/// Renders all counters in the Prometheus text exposition format
+#[cfg(feature = "metrics")]
+pub fn render() -> String {
+    let mut buf = String::new();
+    let mut last_name = "";
+
+    for counter in COUNTERS {
+        if counter.name != last_name {
+            let _ = writeln!(buf, "# HELP tuic_client_{} {}", counter.name, counter.help);
+            let _ = writeln!(buf, "# TYPE tuic_client_{} counter", counter.name);
+            last_name = counter.name;
+        }
+
+        let value = counter.value.load(Ordering::Relaxed);
+
+        if counter.labels.is_empty() {
+            let _ = writeln!(buf, "tuic_client_{} {value}", counter.name);
+        } else {
+            let _ = writeln!(
+                buf,
+                "tuic_client_{}{{{}}} {value}",
+                counter.name, counter.labels
+            );
+        }
+    }
+
+    for gauge in GAUGES {
+        let _ = writeln!(buf, "# HELP tuic_client_{} {}", gauge.name, gauge.help);
+        let _ = writeln!(buf, "# TYPE tuic_client_{} gauge", gauge.name);
+        let _ = writeln!(
+            buf,
+            "tuic_client_{} {}",
+            gauge.name,
+            gauge.value.load(Ordering::Relaxed)
+        );
+    }
+
+    for histogram in HISTOGRAMS {
+        if histogram.name != last_name {
+            let _ = writeln!(
+                buf,
+                "# HELP tuic_client_{} {}",
+                histogram.name, histogram.help
+            );
+            let _ = writeln!(buf, "# TYPE tuic_client_{} histogram", histogram.name);
+            last_name = histogram.name;
+        }
+
+        let (name, labels) = (histogram.name, histogram.labels);
+        let mut count = 0;
+
+        for (idx, bucket) in histogram.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = BUCKETS
+                .get(idx)
+                .map_or_else(|| String::from("+Inf"), f64::to_string);
+
+            let _ = writeln!(
+                buf,
+                "tuic_client_{name}_bucket{{{labels},le=\"{le}\"}} {count}"
+            );
+        }
+
+        let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(buf, "tuic_client_{name}_sum{{{labels}}} {sum}");
+        let _ = writeln!(buf, "tuic_client_{name}_count{{{labels}}} {count}");
+    }
+
+    let _ = writeln!(
+        buf,
+        "# HELP tuic_client_udp_association_packets_total UDP packets of alive associations"
+    );
+    let _ = writeln!(
+        buf,
+        "# TYPE tuic_client_udp_association_packets_total counter"
+    );
+
+    for (assoc_id, stats) in UDP_ASSOCIATIONS.lock().iter() {
+        let (sent, received, dropped) = stats.get();
+
+        for (kind, value) in [("sent", sent), ("received", received), ("dropped", dropped)] {
+            let _ = writeln!(
+                buf,
+                "tuic_client_udp_association_packets_total{{assoc_id=\"{assoc_id}\",kind=\"{kind}\"}} {value}"
+            );
+        }
+    }
+
+    let connections = QUIC_CONNECTIONS.lock();
+
+    // name, type, help and the value of a connection
+    type Family = (
+        &'static str,
+        &'static str,
+        &'static str,
+        fn(&QuicStats) -> f64,
+    );
+
+    let families: [Family; 6] = [
+        (
+            "quic_rtt_seconds",
+            "gauge",
+            "Smoothed round-trip time of open QUIC connections",
+            |stats| stats.rtt.as_secs_f64(),
+        ),
+        (
+            "quic_cwnd_bytes",
+            "gauge",
+            "Congestion window of open QUIC connections",
+            |stats| stats.cwnd as f64,
+        ),
+        (
+            "quic_sent_packets_total",
+            "counter",
+            "Packets sent on open QUIC connections",
+            |stats| stats.sent_packets as f64,
+        ),
+        (
+            "quic_lost_packets_total",
+            "counter",
+            "Packets of open QUIC connections declared lost and retransmitted",
+            |stats| stats.lost_packets as f64,
+        ),
+        (
+            "quic_lost_bytes_total",
+            "counter",
+            "Bytes of open QUIC connections declared lost",
+            |stats| stats.lost_bytes as f64,
+        ),
+        (
+            "quic_congestion_events_total",
+            "counter",
+            "Congestion window reductions of open QUIC connections",
+            |stats| stats.congestion_events as f64,
+        ),
+    ];
+
+    for (name, kind, help, value) in families {
+        let _ = writeln!(buf, "# HELP tuic_client_{name} {help}");
+        let _ = writeln!(buf, "# TYPE tuic_client_{name} {kind}");
+
+        for (id, stats) in connections.iter() {
+            let _ = writeln!(
+                buf,
+                "tuic_client_{name}{{connection=\"{id}\",server=\"{}\"}} {}",
+                stats.server,
+                value(stats)
+            );
+        }
+    }
+
+    buf
+}
+
+/// Serves `render()` over plain HTTP to anything connecting to `addr`
+#[cfg(feature = "metrics")]
+pub async fn serve(addr: SocketAddr) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("[metrics] failed to listen on {addr}: {err}");
+            return;
+        }
+    };
+
+    log::warn!("[metrics] exporter started, listening on {addr}");
+
+    async fn handle(mut stream: TcpStream) -> std::io::Result<()> {
+        let mut buf = [0; 1024];
+        let _ = stream.read(&mut buf).await?;
+
+        let body = render();
+        let resp = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n\
+             {body}",
+            body.len()
+        );
+
+        stream.write_all(resp.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                utils::spawn(format_args!("metrics {peer}"), async move {
+                    if let Err(err) = handle(stream).await {
+                        log::debug!("[metrics] [{peer}] {err}");
+                    }
+                });
+            }
+            Err(err) => log::warn!("[metrics] failed to accept connection: {err}"),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use std::future;
+
+    /// A relay that ends once `fail` is known, holding the gauge like `handle_connect` does
+    async fn relay(gauge: &'static Gauge, fail: bool) -> Result<(), &'static str> {
+        let _active = gauge.track();
+        assert_eq!(gauge.value.load(Ordering::Relaxed), 1);
+
+        if fail {
+            return Err("target reset");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn relay_end_is_counted_on_every_path() {
+        static GAUGE: Gauge = Gauge::new("relays_active", "test");
+
+        relay(&GAUGE, false).await.unwrap();
+        assert_eq!(GAUGE.value.load(Ordering::Relaxed), 0);
+
+        relay(&GAUGE, true).await.unwrap_err();
+        assert_eq!(GAUGE.value.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_relay_ends() {
+        static GAUGE: Gauge = Gauge::new("relays_active", "test");
+
+        let relays = (0..3)
+            .map(|_| {
+                tokio::spawn(async {
+                    let _active = GAUGE.track();
+                    future::pending::<()>().await;
+                })
+            })
+            .collect::<Vec<_>>();
+
+        while GAUGE.value.load(Ordering::Relaxed) < 3 {
+            tokio::task::yield_now().await;
+        }
+
+        for relay in relays {
+            relay.abort();
+            assert!(relay.await.unwrap_err().is_cancelled());
+        }
+
+        assert_eq!(GAUGE.value.load(Ordering::Relaxed), 0);
+    }
+}
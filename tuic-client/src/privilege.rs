@@ -0,0 +1,35 @@
+//! Switching to an unprivileged user once the privileged ports are bound
+
+use crate::{config::RunAs, Error};
+use std::io::Error as IoError;
+
+/// Sets the supplementary groups, the group and the user of the process to `run_as`, in that order, as the groups can no longer be changed once root is given up. The libc wrappers apply them to every thread, including the runtime's workers
+pub fn drop_privileges(run_as: RunAs) -> Result<(), Error> {
+    let RunAs { uid, gid } = run_as;
+    let err = |source| Error::PrivilegeDrop { uid, gid, source };
+    let groups = [gid as libc::gid_t];
+
+    // SAFETY: plain syscall wrappers, `groups` outlives the call
+    unsafe {
+        if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0 {
+            return Err(err(IoError::last_os_error()));
+        }
+
+        if libc::setgid(gid as libc::gid_t) != 0 {
+            return Err(err(IoError::last_os_error()));
+        }
+
+        if libc::setuid(uid as libc::uid_t) != 0 {
+            return Err(err(IoError::last_os_error()));
+        }
+
+        // a process able to switch back never gave up root
+        if uid != 0 && libc::setuid(0) == 0 {
+            return Err(err(IoError::other("root privileges can still be regained")));
+        }
+    }
+
+    log::info!("[privilege] switched to uid {uid} and gid {gid}");
+
+    Ok(())
+}
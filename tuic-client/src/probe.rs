@@ -0,0 +1,70 @@
+//! Reachability checks of a target through the tunnel, without a socks5 client
+
+use crate::dialer;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{self, Instant},
+};
+use tuic::Address;
+
+/// How long the probe waits for the relay to fail after it is opened. A relay still open by then counts as reachable
+const PROBE_WAIT: Duration = Duration::from_secs(3);
+
+pub struct ProbeResult {
+    /// The server the relay went through, `None` if it could not be opened
+    pub via: Option<Arc<str>>,
+    /// Time to open the relay, including connecting to the server if there was no connection
+    pub connect_time: Duration,
+    /// Time from opening the relay until the target sent data or the server ended the relay, if either happened within the wait
+    pub response_time: Option<Duration>,
+    pub reachable: bool,
+    pub error: Option<String>,
+}
+
+/// Opens a relay to `addr` through the tunnel and closes it again
+///
+/// TUIC does not confirm that the server connected to the target. When it cannot connect, the server ends the relay instead, so the target is reported unreachable if the relay ends before the target sent anything, and reachable if data arrives or the relay is still open after a few seconds
+pub async fn probe(addr: Address) -> ProbeResult {
+    let start = Instant::now();
+
+    let mut relay = match dialer::tunnel().connect(addr, None, None).await {
+        Ok(relay) => relay,
+        Err(err) => {
+            return ProbeResult {
+                via: None,
+                connect_time: start.elapsed(),
+                response_time: None,
+                reachable: false,
+                error: Some(err.to_string()),
+            }
+        }
+    };
+
+    let connect_time = start.elapsed();
+    let opened = Instant::now();
+    let mut buf = [0; 1];
+
+    let res = time::timeout(PROBE_WAIT, relay.stream.read(&mut buf)).await;
+    let response_time = res.is_ok().then(|| opened.elapsed());
+
+    let (reachable, error) = match res {
+        Ok(Ok(0)) => (
+            false,
+            Some(String::from("relay ended by the server before any data")),
+        ),
+        Ok(Ok(_)) => (true, None),
+        Ok(Err(err)) => (false, Some(err.to_string())),
+        Err(_) => (true, None),
+    };
+
+    let _ = relay.stream.shutdown().await;
+
+    ProbeResult {
+        via: Some(relay.via),
+        connect_time,
+        response_time,
+        reachable,
+        error,
+    }
+}
@@ -0,0 +1,174 @@
+//! Traffic quotas of the socks5 users, keyed by the authenticated username
+
+use crate::{state::State, Error};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+static QUOTAS: OnceCell<Quotas> = OnceCell::new();
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Quotas {
+    limits: HashMap<String, u64>,
+    period: Option<Duration>,
+    file: Option<PathBuf>,
+    usage: Mutex<Usage>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Usage {
+    /// Start of the current period, in seconds since the Unix epoch
+    since: u64,
+    bytes: HashMap<String, u64>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl Quotas {
+    pub fn set_config(
+        limits: HashMap<String, u64>,
+        period: Option<Duration>,
+        file: Option<PathBuf>,
+    ) -> Result<(), Error> {
+        let usage = match &file {
+            Some(path) => match fs::read(path) {
+                Ok(buf) => serde_json::from_slice(&buf).map_err(Error::InvalidQuotaFile)?,
+                Err(err) if err.kind() == ErrorKind::NotFound => Usage::new(),
+                Err(err) => return Err(Error::from(err)),
+            },
+            None => State::take_quota_usage().unwrap_or_else(Usage::new),
+        };
+
+        let quotas = Self {
+            limits,
+            period,
+            file,
+            usage: Mutex::new(usage),
+        };
+
+        QUOTAS
+            .set(quotas)
+            .map_err(|_| "quotas already initialized")
+            .unwrap();
+
+        Ok(())
+    }
+
+    /// Whether `user` has used up the quota of the current period
+    pub fn is_exceeded(user: &str) -> bool {
+        let Some(quotas) = QUOTAS.get() else {
+            return false;
+        };
+
+        let Some(limit) = quotas.limits.get(user) else {
+            return false;
+        };
+
+        let mut usage = quotas.usage.lock();
+        quotas.roll_over(&mut usage);
+        usage.bytes.get(user).is_some_and(|bytes| bytes >= limit)
+    }
+
+    /// Counts `bytes` against the quota of `user`, if the user has one
+    pub fn add(user: &str, bytes: u64) {
+        let Some(quotas) = QUOTAS.get() else {
+            return;
+        };
+
+        if !quotas.limits.contains_key(user) || bytes == 0 {
+            return;
+        }
+
+        let mut usage = quotas.usage.lock();
+        quotas.roll_over(&mut usage);
+        *usage.bytes.entry(user.to_owned()).or_default() += bytes;
+        usage.dirty = true;
+    }
+
+    /// The usage to keep in the state file. `None` when there are no quotas, or when the quota file keeps it
+    pub fn usage() -> Option<Usage> {
+        let quotas = QUOTAS.get()?;
+
+        if quotas.limits.is_empty() || quotas.file.is_some() {
+            return None;
+        }
+
+        let mut usage = quotas.usage.lock();
+        quotas.roll_over(&mut usage);
+        Some(usage.clone())
+    }
+
+    /// Saves the usage to the quota file every minute, if one is configured and the usage changed
+    pub async fn persist() {
+        let Some(quotas) = QUOTAS.get() else {
+            return;
+        };
+
+        let Some(path) = &quotas.file else {
+            return;
+        };
+
+        loop {
+            time::sleep(SAVE_INTERVAL).await;
+
+            let buf = {
+                let mut usage = quotas.usage.lock();
+
+                if !usage.dirty {
+                    continue;
+                }
+
+                usage.dirty = false;
+                serde_json::to_vec(&*usage).unwrap()
+            };
+
+            // written aside and renamed, so a crash never leaves a truncated file behind
+            let tmp = path.with_extension("tmp");
+
+            if let Err(err) = fs::write(&tmp, buf).and_then(|()| fs::rename(&tmp, path)) {
+                log::warn!("[quota] failed to save usage to {}: {err}", path.display());
+                quotas.usage.lock().dirty = true;
+            }
+        }
+    }
+
+    fn roll_over(&self, usage: &mut Usage) {
+        let Some(period) = self.period else {
+            return;
+        };
+
+        let now = unix_now();
+
+        if now.saturating_sub(usage.since) >= period.as_secs() {
+            log::info!("[quota] new quota period started, usage reset");
+            usage.since = now;
+            usage.bytes.clear();
+            usage.dirty = true;
+        }
+    }
+}
+
+impl Usage {
+    fn new() -> Self {
+        Self {
+            since: unix_now(),
+            bytes: HashMap::new(),
+            dirty: false,
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
@@ -0,0 +1,209 @@
+//! Cache of looked up domains, holding failed lookups too so a failing name is not looked up on every request
+
+use crate::Error;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Answers of looked up domains, kept until their TTL expires, bounded to `size` domains by dropping the least recently used first
+pub struct Cache {
+    size: usize,
+    entries: Mutex<Entries>,
+}
+
+struct Entries {
+    values: HashMap<String, Entry>,
+    /// Domains by the tick they were last used at, least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+struct Entry {
+    answer: Answer,
+    expire: Instant,
+    used: u64,
+}
+
+#[derive(Clone)]
+pub enum Answer {
+    Addrs(Vec<IpAddr>),
+    /// A failed lookup, with the response code if the server answered with an error, e.g. `3` for `NXDOMAIN`
+    Failed(Option<u8>),
+}
+
+impl Answer {
+    pub fn into_result(self) -> Result<Vec<IpAddr>, Error> {
+        match self {
+            Self::Addrs(addrs) => Ok(addrs),
+            Self::Failed(Some(rcode)) => Err(Error::DnsRcode(rcode)),
+            Self::Failed(None) => Err(Error::DnsResolve),
+        }
+    }
+
+    pub fn failed(err: &Error) -> Self {
+        match err {
+            Error::DnsRcode(rcode) => Self::Failed(Some(*rcode)),
+            _ => Self::Failed(None),
+        }
+    }
+}
+
+impl Cache {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            entries: Mutex::new(Entries {
+                values: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// The answer for `domain` if it has not expired yet, which makes it the most recently used
+    pub fn get(&self, domain: &str) -> Option<Answer> {
+        let mut entries = self.entries.lock();
+        let Entries {
+            values,
+            order,
+            tick,
+        } = &mut *entries;
+
+        let entry = values.get_mut(domain)?;
+
+        if entry.expire <= Instant::now() {
+            return None;
+        }
+
+        *tick += 1;
+        let domain = order.remove(&entry.used).unwrap();
+        order.insert(*tick, domain);
+        entry.used = *tick;
+
+        Some(entry.answer.clone())
+    }
+
+    pub fn insert(&self, domain: &str, answer: Answer, ttl: Duration) {
+        if self.size == 0 || ttl.is_zero() {
+            return;
+        }
+
+        let mut entries = self.entries.lock();
+        entries.tick += 1;
+
+        let entry = Entry {
+            answer,
+            expire: Instant::now() + ttl,
+            used: entries.tick,
+        };
+
+        if let Some(old) = entries.values.insert(domain.to_owned(), entry) {
+            entries.order.remove(&old.used);
+        }
+
+        let tick = entries.tick;
+        entries.order.insert(tick, domain.to_owned());
+
+        while entries.values.len() > self.size {
+            let Some((_, oldest)) = entries.order.pop_first() else {
+                break;
+            };
+
+            entries.values.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::time;
+
+    fn addrs(last: u8) -> Answer {
+        Answer::Addrs(vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))])
+    }
+
+    fn cached_addrs(cache: &Cache, domain: &str) -> Option<Vec<IpAddr>> {
+        cache
+            .get(domain)
+            .map(|answer| answer.into_result().unwrap())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn answers_expire_with_their_ttl() {
+        let cache = Cache::new(16);
+        cache.insert("a.example.com", addrs(1), Duration::from_secs(60));
+        cache.insert("b.example.com", addrs(2), Duration::from_secs(300));
+
+        time::advance(Duration::from_secs(59)).await;
+        assert!(cached_addrs(&cache, "a.example.com").is_some());
+
+        time::advance(Duration::from_secs(1)).await;
+        assert!(cached_addrs(&cache, "a.example.com").is_none());
+        assert!(cached_addrs(&cache, "b.example.com").is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_lookups_are_cached_for_the_negative_ttl() {
+        let cache = Cache::new(16);
+        let neg_ttl = Duration::from_secs(5);
+
+        cache.insert(
+            "nx.example.com",
+            Answer::failed(&Error::DnsRcode(3)),
+            neg_ttl,
+        );
+        cache.insert(
+            "timeout.example.com",
+            Answer::failed(&Error::DnsResolve),
+            neg_ttl,
+        );
+
+        assert!(matches!(
+            cache.get("nx.example.com").unwrap().into_result(),
+            Err(Error::DnsRcode(3))
+        ));
+        assert!(matches!(
+            cache.get("timeout.example.com").unwrap().into_result(),
+            Err(Error::DnsResolve)
+        ));
+
+        time::advance(neg_ttl).await;
+        assert!(cache.get("nx.example.com").is_none());
+        assert!(cache.get("timeout.example.com").is_none());
+    }
+
+    #[test]
+    fn zero_ttl_is_not_cached() {
+        let cache = Cache::new(16);
+        cache.insert("a.example.com", addrs(1), Duration::ZERO);
+        cache.insert(
+            "nx.example.com",
+            Answer::failed(&Error::DnsRcode(3)),
+            Duration::ZERO,
+        );
+
+        assert!(cache.get("a.example.com").is_none());
+        assert!(cache.get("nx.example.com").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn least_recently_used_is_evicted() {
+        let cache = Cache::new(2);
+        let ttl = Duration::from_secs(60);
+
+        cache.insert("a.example.com", addrs(1), ttl);
+        cache.insert("b.example.com", addrs(2), ttl);
+        assert!(cached_addrs(&cache, "a.example.com").is_some());
+
+        cache.insert("c.example.com", addrs(3), ttl);
+        assert!(cached_addrs(&cache, "b.example.com").is_none());
+        assert!(cached_addrs(&cache, "a.example.com").is_some());
+        assert!(cached_addrs(&cache, "c.example.com").is_some());
+    }
+}
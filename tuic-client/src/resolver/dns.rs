@@ -0,0 +1,335 @@
+//! Lookups through a DNS server, over plain DNS, DNS-over-TLS or DNS-over-HTTPS, optionally through the tunnel
+
+use super::{
+    message::{self, Answer, TYPE_A, TYPE_AAAA, TYPE_SRV},
+    Lookup, Srv,
+};
+use crate::{
+    connection::Connection as TuicConnection,
+    utils::{self, DnsUpstream},
+    Error,
+};
+use async_trait::async_trait;
+use rustls::{version, ClientConfig as RustlsClientConfig, ServerName};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time,
+};
+use tokio_rustls::TlsConnector;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tuic::Address;
+
+const MAX_UDP_RESPONSE_SIZE: usize = 1232;
+
+pub struct DnsLookup {
+    upstream: DnsUpstream,
+    tunnel: bool,
+    timeout: Duration,
+    tls: TlsConnector,
+}
+
+impl DnsLookup {
+    /// `upstream` must not be `system`
+    pub fn new(upstream: DnsUpstream, tunnel: bool, timeout: Duration) -> Result<Self, Error> {
+        let certs = utils::load_certs(Vec::new(), false)?;
+
+        let crypto = RustlsClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&version::TLS13])
+            .unwrap()
+            .with_root_certificates(certs)
+            .with_no_client_auth();
+
+        Ok(Self {
+            upstream,
+            tunnel,
+            timeout,
+            tls: TlsConnector::from(Arc::new(crypto)),
+        })
+    }
+
+    async fn query(&self, domain: &str, qtype: u16, tunnel: bool) -> Result<Answer, Error> {
+        let id = rand::random();
+        let query = message::encode_query(id, domain, qtype)?;
+
+        if let (DnsUpstream::Plain(addr), false) = (&self.upstream, tunnel) {
+            let answer = message::decode_response(&exchange_udp(*addr, &query).await?, id)?;
+
+            if !answer.truncated {
+                return Ok(answer);
+            }
+        }
+
+        let (host, port) = match &self.upstream {
+            DnsUpstream::System => unreachable!(),
+            DnsUpstream::Plain(addr) => (addr.ip().to_string(), addr.port()),
+            DnsUpstream::Https(host, port, _) | DnsUpstream::Tls(host, port) => {
+                (host.clone(), *port)
+            }
+        };
+
+        let resp = if tunnel {
+            let addr = match host.parse::<IpAddr>() {
+                Ok(ip) => Address::SocketAddress(SocketAddr::new(ip, port)),
+                Err(_) => Address::DomainAddress(host.clone(), port),
+            };
+
+            let stream = TuicConnection::get()
+                .await?
+                .connect_plain(addr)
+                .await?
+                .compat();
+            self.exchange_stream(stream, &host, &query).await?
+        } else {
+            let stream = TcpStream::connect((host.as_str(), port)).await?;
+            self.exchange_stream(stream, &host, &query).await?
+        };
+
+        message::decode_response(&resp, id)
+    }
+
+    async fn exchange_stream<S>(
+        &self,
+        stream: S,
+        host: &str,
+        query: &[u8],
+    ) -> Result<Vec<u8>, Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match &self.upstream {
+            DnsUpstream::System => unreachable!(),
+            DnsUpstream::Plain(_) => exchange_tcp(stream, query).await,
+            DnsUpstream::Tls(..) => {
+                let stream = self.tls.connect(server_name(host)?, stream).await?;
+                exchange_tcp(stream, query).await
+            }
+            DnsUpstream::Https(_, _, path) => {
+                let stream = self.tls.connect(server_name(host)?, stream).await?;
+                exchange_https(stream, host, path, query).await
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Lookup for DnsLookup {
+    async fn lookup(&self, domain: &str) -> Result<(Vec<IpAddr>, Option<Duration>), Error> {
+        let (v4, v6) = time::timeout(self.timeout, async {
+            tokio::join!(
+                self.query(domain, TYPE_A, self.tunnel),
+                self.query(domain, TYPE_AAAA, self.tunnel)
+            )
+        })
+        .await
+        .map_err(|_| Error::Timeout)?;
+
+        let mut addrs = Vec::new();
+        let mut ttl = u32::MAX;
+        let mut last_err = None;
+
+        for res in [v4, v6] {
+            match res {
+                Ok(answer) if !answer.addrs.is_empty() => {
+                    addrs.extend(answer.addrs);
+                    ttl = ttl.min(answer.ttl);
+                }
+                Ok(_) => {}
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        if addrs.is_empty() {
+            return Err(last_err.unwrap_or(Error::DnsResolve));
+        }
+
+        log::debug!("[resolver] {domain} resolved to {addrs:?}, ttl {ttl}s");
+
+        Ok((addrs, Some(Duration::from_secs(ttl as u64))))
+    }
+
+    /// Never goes through the tunnel, as the records may be needed to reach the server
+    async fn lookup_srv(&self, name: &str) -> Result<Vec<Srv>, Error> {
+        let answer = time::timeout(self.timeout, self.query(name, TYPE_SRV, false))
+            .await
+            .map_err(|_| Error::Timeout)??;
+
+        Ok(answer.srvs)
+    }
+
+    fn supports_srv(&self) -> bool {
+        true
+    }
+}
+
+fn server_name(host: &str) -> Result<ServerName, Error> {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Ok(ServerName::IpAddress(ip)),
+        Err(_) => ServerName::try_from(host).map_err(|_| Error::DnsMessage("invalid server name")),
+    }
+}
+
+async fn exchange_udp(addr: SocketAddr, query: &[u8]) -> Result<Vec<u8>, Error> {
+    let bind_addr = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+    socket.send(query).await?;
+
+    let mut buf = vec![0; MAX_UDP_RESPONSE_SIZE];
+    let n = socket.recv(&mut buf).await?;
+    buf.truncate(n);
+
+    Ok(buf)
+}
+
+async fn exchange_tcp<S>(mut stream: S, query: &[u8]) -> Result<Vec<u8>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = Vec::with_capacity(2 + query.len());
+    buf.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    buf.extend_from_slice(query);
+    stream.write_all(&buf).await?;
+
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+
+    Ok(buf)
+}
+
+async fn exchange_https<S>(
+    mut stream: S,
+    host: &str,
+    path: &str,
+    query: &[u8],
+) -> Result<Vec<u8>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut req = format!(
+        "POST {path} HTTP/1.1\r\n\
+         Host: {host}\r\n\
+         Content-Type: application/dns-message\r\n\
+         Accept: application/dns-message\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        query.len()
+    )
+    .into_bytes();
+
+    req.extend_from_slice(query);
+    stream.write_all(&req).await?;
+    stream.flush().await?;
+
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+
+    let (header_len, content_len, chunked) = loop {
+        let n = stream.read(&mut chunk).await?;
+
+        if n == 0 {
+            return Err(Error::DnsMessage("incomplete HTTP response"));
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let header = String::from_utf8_lossy(&buf[..pos]);
+            let mut lines = header.split("\r\n");
+
+            let status = lines
+                .next()
+                .and_then(|line| line.split(' ').nth(1))
+                .ok_or(Error::DnsMessage("invalid HTTP response"))?;
+
+            if status != "200" {
+                return Err(Error::DnsMessage("unexpected HTTP status"));
+            }
+
+            let mut content_len = None;
+            let mut chunked = false;
+
+            for line in lines {
+                let Some((name, value)) = line.split_once(':') else {
+                    continue;
+                };
+
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_len = value.trim().parse::<usize>().ok();
+                } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                    chunked = value.trim().eq_ignore_ascii_case("chunked");
+                }
+            }
+
+            break (pos + 4, content_len, chunked);
+        }
+    };
+
+    loop {
+        if let Some(len) = content_len {
+            if buf.len() >= header_len + len {
+                buf.truncate(header_len + len);
+                break;
+            }
+        }
+
+        match stream.read(&mut chunk).await {
+            Ok(0) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(err) if content_len.is_none() => {
+                log::debug!("[resolver] DNS-over-HTTPS stream closed uncleanly: {err}");
+                break;
+            }
+            Err(err) => return Err(Error::from(err)),
+        }
+    }
+
+    let body = buf.split_off(header_len);
+
+    if chunked {
+        decode_chunked(&body)
+    } else {
+        Ok(body)
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+
+    loop {
+        let pos = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or(Error::DnsMessage("invalid chunked encoding"))?;
+
+        let size = std::str::from_utf8(&body[..pos])
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+            .ok_or(Error::DnsMessage("invalid chunked encoding"))?;
+
+        if size == 0 {
+            return Ok(data);
+        }
+
+        body = &body[pos + 2..];
+
+        if body.len() < size + 2 {
+            return Err(Error::DnsMessage("invalid chunked encoding"));
+        }
+
+        data.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
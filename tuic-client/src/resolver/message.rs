@@ -0,0 +1,234 @@
+use crate::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+
+const CLASS_IN: u16 = 1;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const HEADER_LEN: usize = 12;
+
+/// Encodes a recursive query for a single question
+pub fn encode_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&FLAG_RD.to_be_bytes());
+    buf.extend_from_slice(&1u16.to_be_bytes());
+    buf.extend_from_slice(&[0; 6]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::DnsMessage("invalid domain name"));
+        }
+
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(buf)
+}
+
+/// Addresses and the minimum TTL extracted from a response
+pub struct Answer {
+    pub addrs: Vec<IpAddr>,
+    pub srvs: Vec<Srv>,
+    pub ttl: u32,
+    pub truncated: bool,
+}
+
+/// A `SRV` record, RFC 2782
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Decodes a response, keeping only the `A` / `AAAA` / `SRV` records
+pub fn decode_response(buf: &[u8], id: u16) -> Result<Answer, Error> {
+    let mut r = Reader { buf, pos: 0 };
+
+    if r.u16()? != id {
+        return Err(Error::DnsMessage("mismatched ID"));
+    }
+
+    let flags = r.u16()?;
+
+    if flags & FLAG_QR == 0 {
+        return Err(Error::DnsMessage("not a response"));
+    }
+
+    let rcode = (flags & 0x000f) as u8;
+
+    if rcode != 0 {
+        return Err(Error::DnsRcode(rcode));
+    }
+
+    let qd_count = r.u16()?;
+    let an_count = r.u16()?;
+    r.skip(4)?;
+
+    for _ in 0..qd_count {
+        r.skip_name()?;
+        r.skip(4)?;
+    }
+
+    let mut addrs = Vec::new();
+    let mut srvs = Vec::new();
+    let mut ttl = u32::MAX;
+
+    for _ in 0..an_count {
+        r.skip_name()?;
+        let rtype = r.u16()?;
+        let class = r.u16()?;
+        let rttl = r.u32()?;
+        let len = r.u16()? as usize;
+        let start = r.pos;
+        let data = r.take(len)?;
+
+        match (rtype, class, len) {
+            (TYPE_A, CLASS_IN, 4) => {
+                let mut octets = [0; 4];
+                octets.copy_from_slice(data);
+                addrs.push(IpAddr::V4(Ipv4Addr::from(octets)));
+                ttl = ttl.min(rttl);
+            }
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+                ttl = ttl.min(rttl);
+            }
+            (TYPE_SRV, CLASS_IN, 7..) => {
+                // the target may be compressed, pointing anywhere in the message
+                let mut rdata = Reader { buf, pos: start };
+
+                srvs.push(Srv {
+                    priority: rdata.u16()?,
+                    weight: rdata.u16()?,
+                    port: rdata.u16()?,
+                    target: rdata.name()?,
+                });
+
+                ttl = ttl.min(rttl);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Answer {
+        addrs,
+        srvs,
+        ttl: if ttl == u32::MAX { 0 } else { ttl },
+        truncated: flags & FLAG_TC != 0,
+    })
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self.pos + len;
+
+        if end > self.buf.len() {
+            return Err(Error::DnsMessage("unexpected end of message"));
+        }
+
+        let data = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(data)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), Error> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let data = self.take(2)?;
+        Ok(u16::from_be_bytes([data[0], data[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let data = self.take(4)?;
+        Ok(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+    }
+
+    /// Reads a possibly compressed name, in dotted form without the trailing dot
+    fn name(&mut self) -> Result<String, Error> {
+        let mut name = String::new();
+        let mut r = Reader {
+            buf: self.buf,
+            pos: self.pos,
+        };
+        let mut jumped = false;
+
+        // every pointer must go backwards, so a malicious message cannot loop
+        let mut limit = r.pos;
+
+        loop {
+            let len = r.take(1)?[0];
+
+            match len {
+                0 => break,
+                len if len & 0xc0 == 0xc0 => {
+                    let offset = (usize::from(len & 0x3f) << 8) | usize::from(r.take(1)?[0]);
+
+                    if offset >= limit {
+                        return Err(Error::DnsMessage("invalid name pointer"));
+                    }
+
+                    if !jumped {
+                        self.pos = r.pos;
+                        jumped = true;
+                    }
+
+                    limit = offset;
+                    r.pos = offset;
+                }
+                len if len & 0xc0 == 0 => {
+                    let label = r.take(len as usize)?;
+
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+
+                    name.push_str(
+                        std::str::from_utf8(label)
+                            .map_err(|_| Error::DnsMessage("invalid label"))?,
+                    );
+                }
+                _ => return Err(Error::DnsMessage("invalid label")),
+            }
+        }
+
+        if !jumped {
+            self.pos = r.pos;
+        }
+
+        Ok(name)
+    }
+
+    fn skip_name(&mut self) -> Result<(), Error> {
+        loop {
+            let len = self.take(1)?[0];
+
+            match len {
+                0 => return Ok(()),
+                len if len & 0xc0 == 0xc0 => return self.skip(1),
+                len if len & 0xc0 == 0 => self.skip(len as usize)?,
+                _ => return Err(Error::DnsMessage("invalid label")),
+            }
+        }
+    }
+}
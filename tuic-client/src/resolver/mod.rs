@@ -0,0 +1,505 @@
+//! Local resolution of target domains, through the system resolver or a DNS server
+
+pub use self::message::Srv;
+use self::{
+    cache::{Answer, Cache},
+    dns::DnsLookup,
+};
+use crate::{config::Dns, metrics, utils::DnsUpstream, Error};
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{net, sync::OnceCell as AsyncOnceCell};
+use tuic::Address;
+
+mod cache;
+mod dns;
+mod message;
+
+static RESOLVER: OnceCell<Resolver> = OnceCell::new();
+
+/// Where the addresses of domains come from. `Resolver` caches the answers and joins concurrent lookups of the same domain in front of it
+///
+/// Builds that embed the client can supply their own through `Client::lookup()`, implemented with `async_trait`
+#[async_trait]
+pub trait Lookup: Send + Sync {
+    /// Looks up the addresses of `domain`, returning them with how long they may be cached for, `None` for not at all
+    async fn lookup(&self, domain: &str) -> Result<(Vec<IpAddr>, Option<Duration>), Error>;
+
+    /// Looks up the `SRV` records of `name`, in no particular order
+    async fn lookup_srv(&self, _name: &str) -> Result<Vec<Srv>, Error> {
+        Err(Error::SrvUnsupported)
+    }
+
+    /// Whether `lookup_srv()` is supported
+    fn supports_srv(&self) -> bool {
+        false
+    }
+}
+
+/// Looks up through the resolver of the system, which caches on its own
+pub struct SystemLookup;
+
+#[async_trait]
+impl Lookup for SystemLookup {
+    async fn lookup(&self, domain: &str) -> Result<(Vec<IpAddr>, Option<Duration>), Error> {
+        let addrs = net::lookup_host((domain, 0))
+            .await?
+            .map(|addr| addr.ip())
+            .collect::<Vec<_>>();
+
+        if addrs.is_empty() {
+            Err(Error::DnsResolve)
+        } else {
+            Ok((addrs, None))
+        }
+    }
+}
+
+/// The result of a lookup in progress, once it finished
+type InflightLookup = Arc<AsyncOnceCell<Option<Vec<IpAddr>>>>;
+
+pub struct Resolver {
+    /// Whether target domains are resolved. Without, the resolver only serves SRV lookups of the server
+    resolve_locally: bool,
+    source: Arc<dyn Lookup>,
+    cache: Cache,
+    /// How long failed lookups are cached for
+    neg_ttl: Duration,
+    /// Lookups in progress by domain, awaited by every concurrent resolution of the same domain
+    inflight: Mutex<HashMap<String, InflightLookup>>,
+}
+
+impl Resolver {
+    pub fn set_config(cfg: Dns) -> Result<(), Error> {
+        if let Some(resolver) = Self::new(cfg)? {
+            RESOLVER
+                .set(resolver)
+                .map_err(|_| "resolver already initialized")
+                .unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// `None` if there is nothing to resolve, i.e. neither local resolution nor a resolver for `SRV` lookups
+    fn new(cfg: Dns) -> Result<Option<Self>, Error> {
+        let source: Arc<dyn Lookup> = match (cfg.lookup, cfg.resolver) {
+            (Some(lookup), _) => lookup,
+            (None, DnsUpstream::System) if !cfg.resolve_locally => return Ok(None),
+            (None, DnsUpstream::System) => Arc::new(SystemLookup),
+            (None, upstream) => Arc::new(DnsLookup::new(upstream, cfg.tunnel, cfg.timeout)?),
+        };
+
+        Ok(Some(Self {
+            resolve_locally: cfg.resolve_locally,
+            source,
+            cache: Cache::new(cfg.cache_size),
+            neg_ttl: cfg.neg_ttl,
+            inflight: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Resolves the domain in `addr` if local resolution is enabled. Other addresses are returned as-is
+    pub async fn resolve_addr(addr: Address) -> Result<Address, Error> {
+        let Some(resolver) = RESOLVER.get().filter(|resolver| resolver.resolve_locally) else {
+            return Ok(addr);
+        };
+
+        match addr {
+            Address::DomainAddress(domain, port) => {
+                let ip = match domain.parse::<IpAddr>() {
+                    Ok(ip) => ip,
+                    Err(_) => resolver.resolve(&domain).await?[0],
+                };
+
+                Ok(Address::SocketAddress(SocketAddr::new(ip, port)))
+            }
+            addr => Ok(addr),
+        }
+    }
+
+    /// Resolves `domain` with the configured resolver and its cache, or through the system if local resolution is disabled, e.g. for IP-based routing rules
+    pub async fn resolve_domain(domain: &str) -> Result<Vec<IpAddr>, Error> {
+        if let Ok(ip) = domain.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+
+        match RESOLVER.get() {
+            Some(resolver) => resolver.resolve(domain).await,
+            None => SystemLookup.lookup(domain).await.map(|(addrs, _)| addrs),
+        }
+    }
+
+    /// Resolves `domain`, joining the lookup already in progress for it if any, e.g. for the many resources of a page loaded at once
+    async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>, Error> {
+        match self.cache.get(domain) {
+            Some(answer @ Answer::Addrs(_)) => {
+                metrics::DNS_CACHE_HIT_TOTAL.inc();
+                return answer.into_result();
+            }
+            Some(answer @ Answer::Failed(_)) => {
+                metrics::DNS_CACHE_NEGATIVE_HIT_TOTAL.inc();
+                return answer.into_result();
+            }
+            None => metrics::DNS_CACHE_MISS_TOTAL.inc(),
+        }
+
+        let flight = self
+            .inflight
+            .lock()
+            .entry(domain.to_owned())
+            .or_default()
+            .clone();
+
+        // only the resolution running the lookup gets its error, the others get `DnsResolve`
+        let mut err = None;
+        let err_slot = &mut err;
+
+        let addrs = flight
+            .get_or_init(|| async move {
+                self.lookup(domain)
+                    .await
+                    .map_err(|err| *err_slot = Some(err))
+                    .ok()
+            })
+            .await
+            .clone();
+
+        // the next resolution looks up again, or hits the cache
+        {
+            let mut inflight = self.inflight.lock();
+
+            if inflight
+                .get(domain)
+                .is_some_and(|other| Arc::ptr_eq(other, &flight))
+            {
+                inflight.remove(domain);
+            }
+        }
+
+        match (addrs, err) {
+            (Some(addrs), _) => Ok(addrs),
+            (None, Some(err)) => Err(err),
+            (None, None) => Err(Error::DnsResolve),
+        }
+    }
+
+    async fn lookup(&self, domain: &str) -> Result<Vec<IpAddr>, Error> {
+        match self.source.lookup(domain).await {
+            Ok((addrs, ttl)) => {
+                if let Some(ttl) = ttl {
+                    self.cache.insert(domain, Answer::Addrs(addrs.clone()), ttl);
+                }
+
+                Ok(addrs)
+            }
+            Err(err) => {
+                self.cache
+                    .insert(domain, Answer::failed(&err), self.neg_ttl);
+                Err(err)
+            }
+        }
+    }
+
+    /// Whether `lookup_srv()` can be used, i.e. `dns.resolver` is not `system`
+    pub fn supports_srv() -> bool {
+        RESOLVER
+            .get()
+            .is_some_and(|resolver| resolver.source.supports_srv())
+    }
+
+    /// Looks up the `SRV` records of `name` through `dns.resolver`, never through the tunnel as they may be needed to reach the server. The records are returned in the order to try them in, by priority and then by weighted random selection as RFC 2782 describes. Targets of `.`, meaning no service, are left out
+    pub async fn lookup_srv(name: &str) -> Result<Vec<Srv>, Error> {
+        match RESOLVER.get() {
+            Some(resolver) => resolver.srv(name).await,
+            None => Err(Error::SrvUnsupported),
+        }
+    }
+
+    async fn srv(&self, name: &str) -> Result<Vec<Srv>, Error> {
+        let mut records = self.source.lookup_srv(name).await?;
+        records.retain(|record| !record.target.is_empty());
+
+        // weight 0 goes first within a priority, where it is picked only when the random draw is 0
+        records.sort_by_key(|record| (record.priority, record.weight > 0));
+
+        let mut ordered = Vec::with_capacity(records.len());
+        let mut rng = rand::thread_rng();
+
+        while !records.is_empty() {
+            let priority = records[0].priority;
+            let end = records
+                .iter()
+                .position(|record| record.priority != priority)
+                .unwrap_or(records.len());
+
+            let mut group = records.drain(..end).collect::<Vec<_>>();
+
+            while !group.is_empty() {
+                let total = group
+                    .iter()
+                    .map(|record| u32::from(record.weight))
+                    .sum::<u32>();
+                let mut draw = rng.gen_range(0..=total);
+
+                let idx = group
+                    .iter()
+                    .position(|record| {
+                        let weight = u32::from(record.weight);
+                        let is_picked = draw <= weight;
+                        draw = draw.saturating_sub(weight);
+                        is_picked
+                    })
+                    .unwrap_or(0);
+
+                ordered.push(group.remove(idx));
+            }
+        }
+
+        Ok(ordered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::Ipv4Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use tokio::time;
+
+    /// A source answering every domain with `addrs` after a while, counting its lookups
+    struct MockLookup {
+        addrs: Vec<IpAddr>,
+        ttl: Option<Duration>,
+        lookups: AtomicUsize,
+    }
+
+    impl MockLookup {
+        fn new(addrs: Vec<IpAddr>, ttl: Option<Duration>) -> Self {
+            Self {
+                addrs,
+                ttl,
+                lookups: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Lookup for Arc<MockLookup> {
+        async fn lookup(&self, _domain: &str) -> Result<(Vec<IpAddr>, Option<Duration>), Error> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            time::sleep(Duration::from_millis(50)).await;
+
+            if self.addrs.is_empty() {
+                Err(Error::DnsRcode(3))
+            } else {
+                Ok((self.addrs.clone(), self.ttl))
+            }
+        }
+    }
+
+    fn resolver(source: &Arc<MockLookup>) -> Resolver {
+        Resolver {
+            resolve_locally: true,
+            source: Arc::new(source.clone()),
+            cache: Cache::new(16),
+            neg_ttl: Duration::ZERO,
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A source answering every `SRV` lookup with `records` of priority, weight and target
+    struct MockSrvLookup {
+        records: Vec<(u16, u16, &'static str)>,
+    }
+
+    #[async_trait]
+    impl Lookup for MockSrvLookup {
+        async fn lookup(&self, _domain: &str) -> Result<(Vec<IpAddr>, Option<Duration>), Error> {
+            Err(Error::DnsResolve)
+        }
+
+        async fn lookup_srv(&self, _name: &str) -> Result<Vec<Srv>, Error> {
+            Ok(self
+                .records
+                .iter()
+                .map(|(priority, weight, target)| Srv {
+                    priority: *priority,
+                    weight: *weight,
+                    port: 443,
+                    target: target.to_string(),
+                })
+                .collect())
+        }
+
+        fn supports_srv(&self) -> bool {
+            true
+        }
+    }
+
+    fn srv_resolver(records: Vec<(u16, u16, &'static str)>) -> Resolver {
+        Resolver {
+            resolve_locally: false,
+            source: Arc::new(MockSrvLookup { records }),
+            cache: Cache::new(16),
+            neg_ttl: Duration::ZERO,
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn srv_targets(resolver: &Resolver) -> Vec<String> {
+        resolver
+            .srv("_tuic._udp.example.com")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|record| record.target)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn srv_records_are_ordered_by_priority() {
+        // an empty target is `.`, no service
+        let resolver = srv_resolver(vec![
+            (20, 10, "c.example.com"),
+            (10, 0, "b.example.com"),
+            (10, 0, ""),
+            (5, 0, "a.example.com"),
+        ]);
+
+        assert_eq!(
+            srv_targets(&resolver).await,
+            ["a.example.com", "b.example.com", "c.example.com"]
+        );
+    }
+
+    #[tokio::test]
+    async fn srv_records_are_picked_by_weight() {
+        let resolver = srv_resolver(vec![
+            (10, 1, "light.example.com"),
+            (10, 99, "heavy.example.com"),
+        ]);
+        let mut heavy_first = 0;
+
+        for _ in 0..1000 {
+            let targets = srv_targets(&resolver).await;
+            assert_eq!(targets.len(), 2);
+
+            if targets[0] == "heavy.example.com" {
+                heavy_first += 1;
+            }
+        }
+
+        // 98% of the time on average
+        assert!(heavy_first > 900, "{heavy_first}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn supplied_lookup_replaces_the_resolver() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let source = Arc::new(MockLookup::new(vec![ip], Some(Duration::from_secs(60))));
+
+        let mut cfg =
+            serde_json::from_str::<Dns>(r#"{ "resolver": "udp://192.0.2.53:53" }"#).unwrap();
+        cfg.lookup = Some(Arc::new(source.clone()));
+
+        let resolver = Resolver::new(cfg).unwrap().unwrap();
+        assert_eq!(resolver.resolve("example.com").await.unwrap(), [ip]);
+
+        // its answers are cached for the TTL it gave
+        assert_eq!(resolver.resolve("example.com").await.unwrap(), [ip]);
+        assert_eq!(source.lookups.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn system_resolver_without_local_resolution_is_not_needed() {
+        let cfg = serde_json::from_str::<Dns>(r#"{ "resolve_locally": false }"#).unwrap();
+        assert!(Resolver::new(cfg).unwrap().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_lookups_are_not_repeated_for_the_negative_ttl() {
+        let source = Arc::new(MockLookup::new(Vec::new(), None));
+        let mut resolver = resolver(&source);
+        resolver.neg_ttl = Duration::from_secs(5);
+
+        for _ in 0..3 {
+            assert!(matches!(
+                resolver.resolve("nx.example.com").await,
+                Err(Error::DnsRcode(3))
+            ));
+        }
+
+        assert_eq!(source.lookups.load(Ordering::Relaxed), 1);
+
+        time::advance(Duration::from_secs(5)).await;
+        resolver.resolve("nx.example.com").await.unwrap_err();
+        assert_eq!(source.lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_resolutions_share_one_lookup() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let source = Arc::new(MockLookup::new(vec![ip], None));
+        let resolver = resolver(&source);
+
+        let res = tokio::join!(
+            resolver.resolve("example.com"),
+            resolver.resolve("example.com"),
+            resolver.resolve("example.com"),
+            resolver.resolve("example.com"),
+            resolver.resolve("example.com"),
+        );
+
+        for addrs in [res.0, res.1, res.2, res.3, res.4] {
+            assert_eq!(addrs.unwrap(), [ip]);
+        }
+
+        assert_eq!(source.lookups.load(Ordering::Relaxed), 1);
+        assert!(resolver.inflight.lock().is_empty());
+
+        // without a TTL to cache it for, the answer is looked up again once the lookup finished
+        resolver.resolve("example.com").await.unwrap();
+        assert_eq!(source.lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_resolutions_of_other_domains_are_not_joined() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let source = Arc::new(MockLookup::new(vec![ip], None));
+        let resolver = resolver(&source);
+
+        let (a, b) = tokio::join!(
+            resolver.resolve("a.example.com"),
+            resolver.resolve("b.example.com"),
+        );
+
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(source.lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn joined_resolutions_fail_with_the_lookup() {
+        let source = Arc::new(MockLookup::new(Vec::new(), None));
+        let resolver = resolver(&source);
+
+        let (first, joined) = tokio::join!(
+            resolver.resolve("example.com"),
+            resolver.resolve("example.com"),
+        );
+
+        assert!(matches!(first, Err(Error::DnsRcode(3))));
+        assert!(matches!(joined, Err(Error::DnsResolve)));
+        assert_eq!(source.lookups.load(Ordering::Relaxed), 1);
+    }
+}
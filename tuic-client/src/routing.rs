@@ -0,0 +1,285 @@
+#[cfg(feature = "geoip")]
+use crate::geoip::GeoIp;
+use crate::{
+    config::{Routing, RoutingRule},
+    connection::Endpoint,
+    resolver::Resolver,
+    utils::{RouteAction, RuleEvalFailure},
+    Error,
+};
+use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use tuic::Address;
+
+static ROUTER: OnceCell<Router> = OnceCell::new();
+
+pub struct Router {
+    /// Swapped as a whole on reload, so a decision in progress keeps using the rules it started with
+    policy: RwLock<Arc<Policy>>,
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    geoip_path: Option<PathBuf>,
+}
+
+/// Where a relay goes
+pub struct Route {
+    pub action: RouteAction,
+    /// The `relay.egress_bindings` entry to tunnel it from
+    pub egress: Option<String>,
+}
+
+impl From<RouteAction> for Route {
+    fn from(action: RouteAction) -> Self {
+        Self {
+            action,
+            egress: None,
+        }
+    }
+}
+
+struct Policy {
+    rules: Vec<RoutingRule>,
+    default: RouteAction,
+    rule_eval_failure: RuleEvalFailure,
+}
+
+impl Router {
+    pub fn set_config(cfg: Routing) -> Result<(), Error> {
+        check_egress(&cfg.rules)?;
+
+        if let Some(path) = cfg.geoip_path.clone() {
+            #[cfg(feature = "geoip")]
+            GeoIp::set_config(path);
+
+            #[cfg(not(feature = "geoip"))]
+            log::warn!(
+                "[routing] built without the `geoip` feature, ignoring database {}",
+                path.display()
+            );
+        }
+
+        let router = Self {
+            policy: RwLock::new(Arc::new(Policy {
+                rules: cfg.rules,
+                default: cfg.default,
+                rule_eval_failure: cfg.rule_eval_failure,
+            })),
+            geoip_path: cfg.geoip_path,
+        };
+
+        ROUTER
+            .set(router)
+            .map_err(|_| "router already initialized")
+            .unwrap();
+
+        Ok(())
+    }
+
+    /// Returns the route of the first rule matching the target, or the default action if none matches
+    ///
+    /// `requested` is the address sent by the socks5 client, `resolved` is the same address after local resolution. If an IP-based rule is reached while the target is still a domain, the domain is resolved for routing purposes only, with the `dns` resolver and its cache
+    pub async fn route(requested: &Address, resolved: &Address) -> Route {
+        let policy = ROUTER.get().unwrap().policy.read().clone();
+        policy.route(requested, resolved).await
+    }
+
+    /// Checks `cfg` the way `reload()` does, without applying it
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn validate(cfg: &Routing) -> Result<(), Error> {
+        check_egress(&cfg.rules)
+    }
+
+    /// Replaces the routing rules and the default action. Relays already routed are not affected
+    ///
+    /// The GeoIP database cannot be changed at runtime, a different `geoip_path` is ignored with a warning
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn reload(cfg: Routing) -> Result<usize, Error> {
+        let router = ROUTER.get().unwrap();
+        check_egress(&cfg.rules)?;
+
+        if cfg.geoip_path != router.geoip_path {
+            log::warn!("[routing] the GeoIP database only changes on restart, ignoring the new `geoip_path`");
+        }
+
+        let count = cfg.rules.len();
+
+        *router.policy.write() = Arc::new(Policy {
+            rules: cfg.rules,
+            default: cfg.default,
+            rule_eval_failure: cfg.rule_eval_failure,
+        });
+
+        log::warn!("[routing] reloaded {count} rules");
+        Ok(count)
+    }
+}
+
+impl Policy {
+    /// See `Router::route()`
+    async fn route(&self, requested: &Address, resolved: &Address) -> Route {
+        let mut routing_addr = None;
+
+        for rule in &self.rules {
+            let target = match resolved {
+                Address::DomainAddress(domain, port) if rule.matcher.needs_ip() => {
+                    if routing_addr.is_none() {
+                        routing_addr = Some(resolve_for_routing(domain, *port).await);
+                    }
+
+                    routing_addr.as_ref().unwrap()
+                }
+                _ => resolved,
+            };
+
+            match rule.matcher.matches(requested, target) {
+                Ok(true) => {
+                    return Route {
+                        action: rule.action,
+                        egress: rule.egress.clone(),
+                    }
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    let (action, fallback) = match self.rule_eval_failure {
+                        RuleEvalFailure::FailOpen => (RouteAction::Tunnel, "tunneling"),
+                        RuleEvalFailure::FailClosed => (RouteAction::Reject, "rejecting"),
+                    };
+
+                    log::warn!("[routing] [{requested}] cannot evaluate rule ({err}), {fallback}");
+                    return Route::from(action);
+                }
+            }
+        }
+
+        Route::from(self.default)
+    }
+}
+
+fn check_egress(rules: &[RoutingRule]) -> Result<(), Error> {
+    match rules
+        .iter()
+        .filter_map(|rule| rule.egress.as_ref())
+        .find(|egress| !Endpoint::has_egress(egress))
+    {
+        Some(egress) => Err(Error::UnknownEgress(egress.clone())),
+        None => Ok(()),
+    }
+}
+
+async fn resolve_for_routing(domain: &str, port: u16) -> Address {
+    match Resolver::resolve_domain(domain)
+        .await
+        .map(|addrs| addrs.first().copied())
+    {
+        Ok(Some(ip)) => Address::SocketAddress(SocketAddr::new(ip, port)),
+        Ok(None) => Address::DomainAddress(domain.to_owned(), port),
+        Err(err) => {
+            log::debug!("[routing] failed to resolve {domain}: {err}");
+            Address::DomainAddress(domain.to_owned(), port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(matcher: &str, action: RouteAction) -> RoutingRule {
+        RoutingRule {
+            matcher: matcher.parse().unwrap(),
+            action,
+            egress: None,
+        }
+    }
+
+    fn policy(rules: Vec<RoutingRule>, default: RouteAction) -> Policy {
+        Policy {
+            rules,
+            default,
+            rule_eval_failure: RuleEvalFailure::FailClosed,
+        }
+    }
+
+    async fn route(policy: &Policy, addr: Address) -> RouteAction {
+        policy.route(&addr, &addr).await.action
+    }
+
+    fn domain(domain: &str) -> Address {
+        Address::DomainAddress(domain.to_owned(), 443)
+    }
+
+    #[tokio::test]
+    async fn first_matching_rule_wins() {
+        let policy = policy(
+            vec![
+                rule("domain:ads.example.com", RouteAction::Reject),
+                rule("domain-suffix:example.com", RouteAction::Direct),
+                rule("domain-suffix:com", RouteAction::Tunnel),
+            ],
+            RouteAction::Reject,
+        );
+
+        assert!(matches!(
+            route(&policy, domain("ads.example.com")).await,
+            RouteAction::Reject
+        ));
+        assert!(matches!(
+            route(&policy, domain("www.example.com")).await,
+            RouteAction::Direct
+        ));
+        assert!(matches!(
+            route(&policy, domain("example.org.com")).await,
+            RouteAction::Tunnel
+        ));
+    }
+
+    #[tokio::test]
+    async fn default_applies_when_no_rule_matches() {
+        let empty = policy(Vec::new(), RouteAction::Tunnel);
+
+        assert!(matches!(
+            route(&empty, domain("example.com")).await,
+            RouteAction::Tunnel
+        ));
+
+        let policy = policy(
+            vec![rule("domain-suffix:example.com", RouteAction::Direct)],
+            RouteAction::Reject,
+        );
+
+        assert!(matches!(
+            route(&policy, domain("example.org")).await,
+            RouteAction::Reject
+        ));
+        assert!(matches!(
+            route(&policy, domain("notexample.com")).await,
+            RouteAction::Reject
+        ));
+    }
+
+    #[tokio::test]
+    async fn ip_rules_match_socket_addresses() {
+        let policy = policy(
+            vec![
+                rule("port:22", RouteAction::Reject),
+                rule("ip-cidr:10.0.0.0/8", RouteAction::Direct),
+            ],
+            RouteAction::Tunnel,
+        );
+
+        let addr = |addr: &str| Address::SocketAddress(addr.parse::<SocketAddr>().unwrap());
+
+        assert!(matches!(
+            route(&policy, addr("10.1.2.3:22")).await,
+            RouteAction::Reject
+        ));
+        assert!(matches!(
+            route(&policy, addr("10.1.2.3:443")).await,
+            RouteAction::Direct
+        ));
+        assert!(matches!(
+            route(&policy, addr("192.0.2.1:443")).await,
+            RouteAction::Tunnel
+        ));
+    }
+}
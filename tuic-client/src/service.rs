@@ -0,0 +1,268 @@
+//! CONNECT relays as a `tower::Service`, for builds that embed the client and put tower middleware, e.g. timeouts, load shedding or tracing, in front of the TUIC dialer. See [`Client::connect_service()`](crate::Client::connect_service)
+
+use crate::{
+    connection::{Connection as TuicConnection, SERVICE_RELAYS},
+    dialer::{DialTimings, Dialed, Dialer, Stream, ABORT_CODE},
+    utils::StreamCompression,
+    Error,
+};
+use async_trait::async_trait;
+use register_count::Register;
+use std::{
+    error::Error as StdError,
+    future::{self, Future},
+    io::Result as IoResult,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Instant,
+};
+use tower_service::Service;
+use tuic::Address;
+
+pub use quinn::{RecvStream, SendStream};
+
+/// The error of a connect service, turned into [`Error::ConnectService`] unless it is an [`Error`]
+pub type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// A CONNECT request of a socks5 client that is relayed through the server
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct ConnectRequest {
+    /// The target of the relay
+    pub addr: Address,
+    /// Identifies the socks5 client for `relay.sticky_routing`
+    pub sticky_key: Option<String>,
+    /// The `relay.egress_bindings` entry the relay is routed to
+    pub egress: Option<String>,
+}
+
+/// Opens a relay to the target of the request on the TUIC connection, as the client does without a connect service
+///
+/// The relay always goes over QUIC and uncompressed, i.e. `relay.transport` and `relay.stream_compression` do not apply to it
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TuicService;
+
+impl Service<ConnectRequest> for TuicService {
+    type Response = (SendStream, RecvStream);
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: ConnectRequest) -> Self::Future {
+        Box::pin(async move {
+            let conn = match (req.egress, req.sticky_key) {
+                (Some(egress), _) => TuicConnection::get_egress(&egress).await?,
+                (None, Some(key)) => TuicConnection::get_sticky(&key).await?,
+                (None, None) => TuicConnection::get().await?,
+            };
+
+            conn.connect_streams(req.addr).await
+        })
+    }
+}
+
+/// Dials through the service set by `Client::connect_service()`
+pub struct ServiceDialer<S> {
+    service: S,
+}
+
+impl<S> ServiceDialer<S> {
+    pub fn new(service: S) -> Self {
+        Self { service }
+    }
+}
+
+#[async_trait]
+impl<S> Dialer for ServiceDialer<S>
+where
+    S: Service<ConnectRequest, Response = (SendStream, RecvStream)> + Clone + Send + Sync,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+{
+    async fn connect(
+        &self,
+        addr: Address,
+        sticky_key: Option<&str>,
+        egress: Option<&str>,
+    ) -> Result<Dialed, Error> {
+        let start = Instant::now();
+
+        // counted from before the relay is opened, so that no connection is closed as idle under it
+        let reg = SERVICE_RELAYS.reg();
+
+        let req = ConnectRequest {
+            addr,
+            sticky_key: sticky_key.map(str::to_owned),
+            egress: egress.map(str::to_owned),
+        };
+
+        let mut service = self.service.clone();
+        future::poll_fn(|cx| service.poll_ready(cx))
+            .await
+            .map_err(service_error)?;
+        let (send, recv) = service.call(req).await.map_err(service_error)?;
+
+        Ok(Dialed {
+            stream: Box::new(ServiceStream {
+                send,
+                recv,
+                _reg: reg,
+            }),
+            via: Arc::from("connect service"),
+            server_addr: None,
+            compression: StreamCompression::None,
+            // the service is timed as a whole
+            timings: DialTimings {
+                acquire: Some(start.elapsed()),
+                handshake: false,
+                open: start.elapsed(),
+            },
+        })
+    }
+}
+
+fn service_error(err: impl Into<BoxError>) -> Error {
+    match err.into().downcast::<Error>() {
+        Ok(err) => *err,
+        Err(err) => Error::ConnectService(err),
+    }
+}
+
+/// The streams of a relay opened by a connect service, counted in `SERVICE_RELAYS` until dropped
+struct ServiceStream {
+    send: SendStream,
+    recv: RecvStream,
+    _reg: Register,
+}
+
+impl Stream for ServiceStream {
+    fn abort(&mut self) {
+        // only fails once the stream is already finished, reset or stopped
+        let _ = self.send.reset(ABORT_CODE);
+        let _ = self.recv.stop(ABORT_CODE);
+    }
+}
+
+impl AsyncRead for ServiceStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ServiceStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<IoResult<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::tests::connect_loopback;
+    use parking_lot::Mutex;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tokio::io::AsyncWriteExt;
+
+    /// A service that opens every relay on `conn`, recording the requests
+    #[derive(Clone)]
+    struct LoopbackService {
+        conn: TuicConnection,
+        requests: Arc<Mutex<Vec<ConnectRequest>>>,
+    }
+
+    impl Service<ConnectRequest> for LoopbackService {
+        type Response = (SendStream, RecvStream);
+        type Error = Error;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: ConnectRequest) -> Self::Future {
+            self.requests.lock().push(req.clone());
+            let conn = self.conn.clone();
+            Box::pin(async move { conn.connect_streams(req.addr).await })
+        }
+    }
+
+    /// A service that is never ready
+    #[derive(Clone)]
+    struct Overloaded;
+
+    impl Service<ConnectRequest> for Overloaded {
+        type Response = (SendStream, RecvStream);
+        type Error = &'static str;
+        type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Err("overloaded"))
+        }
+
+        fn call(&mut self, _req: ConnectRequest) -> Self::Future {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_goes_through_the_service() {
+        let (conn, _server, server_conn) = connect_loopback().await;
+        let service = LoopbackService {
+            conn,
+            requests: Arc::new(Mutex::new(Vec::new())),
+        };
+        let dialer = ServiceDialer::new(service.clone());
+        let addr = Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 80)));
+
+        let mut dialed = dialer
+            .connect(addr.clone(), Some("alice"), None)
+            .await
+            .unwrap();
+        dialed.stream.write_all(b"ping").await.unwrap();
+        dialed.stream.flush().await.unwrap();
+
+        let (_send, mut recv) = server_conn.accept_bi().await.unwrap();
+        // `VERSION`, command type, address type, IPv4 address and port
+        let mut buf = [0; 9 + 4];
+        recv.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[9..], b"ping");
+
+        let requests = service.requests.lock().clone();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].addr.to_string(), addr.to_string());
+        assert_eq!(requests[0].sticky_key.as_deref(), Some("alice"));
+        assert_eq!(requests[0].egress, None);
+    }
+
+    #[tokio::test]
+    async fn service_errors_fail_the_relay() {
+        let dialer = ServiceDialer::new(Overloaded);
+        let addr = Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 80)));
+
+        let err = dialer.connect(addr, None, None).await.err().unwrap();
+        assert!(matches!(err, Error::ConnectService(err) if err.to_string() == "overloaded"));
+    }
+}
@@ -1,44 +1,160 @@
-use crate::{config::Local, connection::Connection as TuicConnection, Error};
-use bytes::Bytes;
+use crate::{
+    access_log::{AccessLog, Entry as AccessLogEntry},
+    config::{Local, RelayOverrides, RelayOverridesHook},
+    diagnostics::Diagnostics,
+    dialer::{self, Dialed, Dialer, DirectDialer, FailoverDialer},
+    forward::{forward, CloseReason, QuicError, WriteBuffer, MAX_WRITE_BUFFER},
+    metrics::{self, UdpStats},
+    quota::Quotas,
+    resolver::Resolver,
+    routing::Router,
+    udp::{self, PacketSink},
+    utils::{self, ReplyBindMode, ReplyTiming, RouteAction},
+    Error,
+};
+use async_trait::async_trait;
+use log::Level;
 use once_cell::sync::OnceCell;
-use parking_lot::Mutex;
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-use socks5_proto::{Address, Reply};
+use parking_lot::{Mutex, RwLock};
+use register_count::Counter;
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, TcpKeepalive, Type};
+use socks5_proto::{
+    handshake::password::{Request as PasswordRequest, Response as PasswordResponse},
+    Address, HandshakeMethod, Reply,
+};
 use socks5_server::{
-    auth::{NoAuth, Password},
+    auth::NoAuth,
     connection::{associate, bind, connect},
     Associate, AssociatedUdpSocket, Auth, Bind, Connect, Connection, Server as Socks5Server,
 };
 use std::{
     collections::HashMap,
-    io::{Error as IoError, ErrorKind},
-    net::{IpAddr, SocketAddr, TcpListener as StdTcpListener, UdpSocket as StdUdpSocket},
+    fs,
+    future::{self, Future},
+    io::{Error as IoError, ErrorKind, Result as IoResult},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener as StdTcpListener, UdpSocket as StdUdpSocket},
+    path::{Path, PathBuf},
+    process,
     sync::{
-        atomic::{AtomicU16, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
+    time::{Duration, SystemTime},
 };
+use subtle::ConstantTimeEq;
 use tokio::{
-    io::{self, AsyncWriteExt},
-    net::{TcpListener, UdpSocket},
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::Notify,
+    time::{self, Instant},
 };
-use tokio_util::compat::FuturesAsyncReadCompatExt;
 use tuic::Address as TuicAddress;
 
 static SERVER: OnceCell<Server> = OnceCell::new();
 
+/// Upper bound of relays tracked for `Server::active_relays()`. Relays beyond it still work, they are just not listed
+const MAX_TRACKED_RELAYS: usize = 4096;
+
 pub struct Server {
     inner: Socks5Server,
+    dialer: Box<dyn Dialer>,
+    relay_overrides_hook: Option<RelayOverridesHook>,
     addr: SocketAddr,
+    /// `addr` with the port the OS picked if it is 0
+    local_addr: SocketAddr,
+    /// A handle on the listening socket, to change the `SO_LINGER` accepted connections inherit
+    listener: Socket,
+    port_file: Option<PathBuf>,
+    auth_method: &'static str,
     dual_stack: Option<bool>,
+    listen_backlog: Option<u32>,
+    tcp_keepalive: Option<Duration>,
     max_pkt_size: usize,
-    next_assoc_id: AtomicU16,
-    udp_sessions: Mutex<HashMap<u16, Arc<AssociatedUdpSocket>>>,
+    /// Swapped as a whole on reload, so a request in progress keeps using the settings it started with
+    settings: RwLock<Arc<Settings>>,
+    /// The credentials, if authenticating with a password
+    password: Option<Arc<Password>>,
+    next_relay_id: AtomicU64,
+    relays: Mutex<HashMap<u64, Arc<RelayEntry>>>,
+    destinations: Destinations,
+    /// Usernames of the connections authenticated with a password, by peer address
+    sessions: Arc<Mutex<HashMap<SocketAddr, Arc<str>>>>,
+    connections: Counter,
+    draining: AtomicBool,
+    drain: Notify,
+}
+
+/// The settings of `Server` that `Server::reload()` changes
+struct Settings {
+    reply_bind_mode: ReplyBindMode,
+    reply_timing: ReplyTiming,
+    tunnel_failure_reply: Reply,
+    udp_strict_source: bool,
+    relay_linger: Option<Duration>,
+    write_buffer: Option<WriteBuffer>,
+    max_relay_lifetime: Option<Duration>,
+    max_per_destination: Option<usize>,
+    relay_warn_bytes: Option<u64>,
+    relay_warn_ratio: Option<f64>,
+    relay_warn_ratio_min_bytes: u64,
+    quiet_empty_connects: bool,
+    abort_on_write_failure: bool,
+}
+
+impl Settings {
+    fn new(cfg: &Local) -> Result<Self, Error> {
+        let reply_bind_mode = match (cfg.reply_echo_port, &cfg.reply_bind_mode) {
+            (false, mode) => *mode,
+            (true, ReplyBindMode::Zero) => {
+                log::warn!("[socks5] `reply_echo_port` is deprecated, use `reply_bind_mode` `echo_port` instead");
+                ReplyBindMode::EchoPort
+            }
+            (true, _) => return Err(Error::ConflictingReplyBindMode),
+        };
+
+        if cfg
+            .relay_write_buffer
+            .is_some_and(|capacity| capacity > MAX_WRITE_BUFFER)
+        {
+            return Err(Error::InvalidRelayWriteBuffer);
+        }
+
+        if cfg
+            .relay_warn_ratio
+            .is_some_and(|ratio| ratio.is_nan() || ratio < 1.0)
+        {
+            return Err(Error::InvalidRelayWarnRatio);
+        }
+
+        Ok(Self {
+            reply_bind_mode,
+            reply_timing: cfg.reply_timing,
+            tunnel_failure_reply: cfg.tunnel_failure_reply.0,
+            udp_strict_source: cfg.udp_strict_source,
+            relay_linger: cfg.relay_linger,
+            write_buffer: cfg
+                .relay_write_buffer
+                .filter(|capacity| *capacity > 0)
+                .map(|capacity| WriteBuffer {
+                    capacity,
+                    flush_interval: cfg.relay_flush_interval,
+                }),
+            max_relay_lifetime: cfg
+                .max_relay_lifetime
+                .filter(|lifetime| !lifetime.is_zero()),
+            max_per_destination: cfg.max_per_destination,
+            relay_warn_bytes: cfg.relay_warn_bytes,
+            relay_warn_ratio: cfg.relay_warn_ratio,
+            relay_warn_ratio_min_bytes: cfg.relay_warn_ratio_min_bytes,
+            quiet_empty_connects: cfg.quiet_empty_connects,
+            abort_on_write_failure: cfg.abort_on_write_failure,
+        })
+    }
 }
 
 impl Server {
     pub fn set_config(cfg: Local) -> Result<(), Error> {
-        let socket = {
+        let (socket, listener) = {
             let domain = match cfg.server.ip() {
                 IpAddr::V4(_) => Domain::IPV4,
                 IpAddr::V6(_) => Domain::IPV6,
@@ -50,27 +166,87 @@ impl Server {
                 socket.set_only_v6(!dual_stack)?;
             }
 
+            // accepted connections inherit the keepalive settings of the listening socket
+            if let Some(time) = cfg.tcp_keepalive {
+                socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+            }
+
             socket.set_reuse_address(true)?;
-            socket.bind(&SockAddr::from(cfg.server))?;
-            socket.listen(128)?;
-            TcpListener::from_std(StdTcpListener::from(socket))?
+            socket.set_nonblocking(true)?;
+
+            let listen = |source| Error::ListenBind {
+                addr: cfg.server,
+                source,
+            };
+
+            // the OS caps the backlog at its maximum, which is what an unset backlog asks for
+            let backlog = cfg.listen_backlog.map_or(i32::MAX, |backlog| {
+                i32::try_from(backlog).unwrap_or(i32::MAX)
+            });
+
+            socket.bind(&SockAddr::from(cfg.server)).map_err(listen)?;
+            socket.listen(backlog).map_err(listen)?;
+            socket.set_linger(cfg.abort_on_write_failure.then_some(Duration::ZERO))?;
+
+            let listener = socket.try_clone()?;
+            (
+                TcpListener::from_std(StdTcpListener::from(socket))?,
+                listener,
+            )
         };
 
-        let auth: Arc<dyn Auth + Send + Sync> = match (cfg.username, cfg.password) {
-            (Some(username), Some(password)) => {
-                Arc::new(Password::new(username.into_bytes(), password.into_bytes()))
-            }
-            (None, None) => Arc::new(NoAuth),
-            _ => return Err(Error::InvalidSocks5Auth),
+        let local_addr = socket.local_addr()?;
+
+        let credentials = credentials(&cfg)?;
+        let settings = Settings::new(&cfg)?;
+
+        Quotas::set_config(cfg.user_quotas, cfg.quota_period, cfg.quota_file)?;
+        AccessLog::set_config(cfg.access_log, cfg.access_log_format)?;
+
+        let sessions = Arc::new(Mutex::new(HashMap::new()));
+
+        let password = (!credentials.is_empty())
+            .then(|| Arc::new(Password::new(credentials, sessions.clone())));
+
+        let auth: Arc<dyn Auth + Send + Sync> = match &password {
+            Some(password) => password.clone(),
+            None => Arc::new(NoAuth),
         };
 
+        let auth = ObservedAuth::new(auth);
+        let auth_method = auth.method;
+
+        #[cfg(feature = "tower")]
+        let tunnel = cfg.connect_service.unwrap_or_else(dialer::tunnel);
+        #[cfg(not(feature = "tower"))]
+        let tunnel = dialer::tunnel();
+
         let server = Self {
-            inner: Socks5Server::new(socket, auth),
+            inner: Socks5Server::new(socket, Arc::new(auth)),
+            dialer: if cfg.bypass_on_failure {
+                Box::new(FailoverDialer::new(tunnel, cfg.bypass))
+            } else {
+                tunnel
+            },
+            relay_overrides_hook: cfg.relay_overrides_hook,
             addr: cfg.server,
+            local_addr,
+            listener,
+            port_file: cfg.port_file,
+            auth_method,
             dual_stack: cfg.dual_stack,
+            listen_backlog: cfg.listen_backlog,
+            tcp_keepalive: cfg.tcp_keepalive,
             max_pkt_size: cfg.max_packet_size,
-            next_assoc_id: AtomicU16::new(0),
-            udp_sessions: Mutex::new(HashMap::new()),
+            settings: RwLock::new(Arc::new(settings)),
+            password,
+            next_relay_id: AtomicU64::new(0),
+            relays: Mutex::new(HashMap::new()),
+            destinations: Destinations::default(),
+            sessions,
+            connections: Counter::new(),
+            draining: AtomicBool::new(false),
+            drain: Notify::new(),
         };
 
         SERVER
@@ -83,39 +259,204 @@ impl Server {
 
     pub async fn start() {
         let server = SERVER.get().unwrap();
-        log::warn!("[socks5] server started, listening on {}", server.addr);
+        log::warn!(
+            "[socks5] server started, listening on {}",
+            server.local_addr
+        );
 
         loop {
-            match server.inner.accept().await {
+            let accepted = tokio::select! {
+                res = server.inner.accept() => res,
+                () = server.drain.notified() => break,
+            };
+
+            match accepted {
                 Ok((conn, addr)) => {
-                    log::debug!("[socks5] [{addr}] connection established");
-                    tokio::spawn(async move {
+                    log::debug!(
+                        event = "accepted",
+                        peer:% = addr;
+                        "[socks5] [{addr}] connection established"
+                    );
+                    let reg = server.connections.reg();
+                    utils::spawn(format_args!("socks5 {addr}"), async move {
+                        let _reg = reg;
+                        let mut target = None;
+
                         let res = match conn.handshake().await {
-                            Ok(Connection::Associate(associate, addr)) => {
-                                Self::handle_associate(associate, addr).await
+                            Ok(Connection::Associate(associate, target_addr)) => {
+                                target = Some(target_addr.to_string());
+                                Self::handle_associate(associate, addr, target_addr).await
+                            }
+                            Ok(Connection::Bind(bind, target_addr)) => {
+                                target = Some(target_addr.to_string());
+                                Self::handle_bind(bind, addr, target_addr).await
+                            }
+                            Ok(Connection::Connect(connect, target_addr)) => {
+                                target = Some(target_addr.to_string());
+                                Self::handle_connect(connect, addr, target_addr).await
                             }
-                            Ok(Connection::Bind(bind, addr)) => Self::handle_bind(bind, addr).await,
-                            Ok(Connection::Connect(connect, addr)) => {
-                                Self::handle_connect(connect, addr).await
+                            Err(err) if is_aborted_handshake(&err) => {
+                                log::debug!(
+                                    event = "handshake_aborted",
+                                    peer:% = addr,
+                                    error:% = err;
+                                    "[socks5] [{addr}] client went away during the handshake: {err}"
+                                );
+                                Ok(())
+                            }
+                            // a method negotiation failure, unlike rejected credentials, which fail in `Password::execute()`
+                            Err(err) if err.kind() == ErrorKind::Unsupported => {
+                                metrics::AUTH_UNACCEPTABLE_TOTAL.inc();
+                                log_auth(addr, server.auth_method, "unacceptable");
+
+                                // the password method is picked whenever offered, so only clients without it end up here
+                                if server.auth_method == "password" {
+                                    metrics::AUTH_DOWNGRADE_TOTAL.inc();
+                                    log::info!(
+                                        event = "auth_downgrade",
+                                        peer:% = addr;
+                                        "[socks5] [{addr}] [auth] rejected a client that offered no password authentication"
+                                    );
+                                }
+
+                                Err(Error::NoAcceptableAuthMethod(server.auth_method))
                             }
                             Err(err) => Err(Error::from(err)),
                         };
 
                         match res {
-                            Ok(()) => log::debug!("[socks5] [{addr}] connection closed"),
-                            Err(err) => log::warn!("[socks5] [{addr}] {err}"),
+                            Ok(()) => log::debug!(
+                                event = "closed",
+                                peer:% = addr;
+                                "[socks5] [{addr}] connection closed"
+                            ),
+                            Err(err) => {
+                                log::warn!(
+                                    event = "closed",
+                                    peer:% = addr,
+                                    error:% = err;
+                                    "[socks5] [{addr}] {err}"
+                                );
+                                Diagnostics::record(Some(addr), target, &err);
+                            }
                         }
+
+                        server.sessions.lock().remove(&addr);
                     });
                 }
                 Err(err) => log::warn!("[socks5] failed to establish connection: {err}"),
             }
         }
+
+        log::warn!(
+            "[socks5] draining, no longer accepting connections, {} left",
+            server.connections.count()
+        );
+
+        while server.connections.count() > 0 {
+            time::sleep(Duration::from_secs(1)).await;
+        }
+
+        log::warn!("[socks5] drained");
+    }
+
+    /// Writes the port the server listens on to `port_file`, once every fallible step of the startup is done, so a launcher never reads the port of a client that failed to start
+    pub fn write_port_file() -> Result<(), Error> {
+        let server = SERVER.get().unwrap();
+
+        if let Some(path) = &server.port_file {
+            write_port(path, server.local_addr.port())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn has_port_file() -> bool {
+        SERVER
+            .get()
+            .is_some_and(|server| server.port_file.is_some())
+    }
+
+    /// Removes `port_file` on shutdown, so a stale port is never read
+    pub fn remove_port_file() {
+        let Some(path) = SERVER.get().and_then(|server| server.port_file.as_ref()) else {
+            return;
+        };
+
+        if let Err(err) = fs::remove_file(path) {
+            log::warn!("[socks5] failed to remove {}: {err}", path.display());
+        }
+    }
+
+    /// Stops accepting connections and makes `Server::start()` return once the existing ones are closed. Returns `false` if the server is already draining
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn drain() -> bool {
+        let server = SERVER.get().unwrap();
+
+        if server.draining.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+
+        server.drain.notify_one();
+        true
+    }
+
+    /// Applies `cfg` to new requests, leaving the requests in progress with the settings they started with. Nothing is applied if `cfg` is invalid
+    ///
+    /// The credentials, `reply_bind_mode`, `reply_timing`, `tunnel_failure_reply`, `udp_strict_source`, `relay_linger`, `relay_write_buffer`, `relay_flush_interval`, `max_relay_lifetime`, `max_per_destination`, `relay_warn_bytes`, `relay_warn_ratio`, `relay_warn_ratio_min_bytes`, `quiet_empty_connects` and `abort_on_write_failure` are applied. Switching between password and no authentication, and changes to `server`, `dual_stack`, `listen_backlog`, `tcp_keepalive` and `max_packet_size` need a restart, the names of those that changed are returned. Every other field of `local` is only read at startup and ignored here
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn reload(cfg: Local) -> Result<Vec<&'static str>, Error> {
+        let server = SERVER.get().unwrap();
+
+        let credentials = credentials(&cfg)?;
+        let settings = Settings::new(&cfg)?;
+
+        let mut restart_required = Vec::new();
+
+        for (field, is_changed) in [
+            ("server", cfg.server != server.addr),
+            ("dual_stack", cfg.dual_stack != server.dual_stack),
+            (
+                "listen_backlog",
+                cfg.listen_backlog != server.listen_backlog,
+            ),
+            ("tcp_keepalive", cfg.tcp_keepalive != server.tcp_keepalive),
+            (
+                "max_packet_size",
+                cfg.max_packet_size != server.max_pkt_size,
+            ),
+        ] {
+            if is_changed {
+                restart_required.push(field);
+            }
+        }
+
+        match (&server.password, credentials.is_empty()) {
+            (Some(password), false) => password.set_credentials(credentials),
+            (None, true) => {}
+            _ => restart_required.push("users"),
+        }
+
+        server
+            .listener
+            .set_linger(settings.abort_on_write_failure.then_some(Duration::ZERO))?;
+        *server.settings.write() = Arc::new(settings);
+
+        log::warn!("[socks5] reloaded");
+        Ok(restart_required)
+    }
+
+    fn settings() -> Arc<Settings> {
+        SERVER.get().unwrap().settings.read().clone()
     }
 
     async fn handle_associate(
         assoc: Associate<associate::NeedReply>,
-        _addr: Address,
+        peer: SocketAddr,
+        addr: Address,
     ) -> Result<(), Error> {
+        log_handshake(peer, "associate", &addr);
+
         async fn get_assoc_socket() -> Result<(Arc<AssociatedUdpSocket>, SocketAddr), IoError> {
             let domain = match SERVER.get().unwrap().addr.ip() {
                 IpAddr::V4(_) => Domain::IPV4,
@@ -128,6 +469,7 @@ impl Server {
                 socket.set_only_v6(!dual_stack)?;
             }
 
+            socket.set_nonblocking(true)?;
             socket.bind(&SockAddr::from(SERVER.get().unwrap().addr))?;
 
             let socket = AssociatedUdpSocket::from((
@@ -144,94 +486,602 @@ impl Server {
                 let assoc = assoc
                     .reply(Reply::Succeeded, Address::SocketAddress(assoc_addr))
                     .await?;
-                Self::send_pkt(assoc, assoc_socket).await
+                log_reply(peer, "associate", None, None, Reply::Succeeded);
+                Self::send_pkt(assoc, peer, addr, assoc_socket).await
             }
             Err(err) => {
                 log::warn!("[socks5] failed to create associated socket: {err}");
                 let mut assoc = assoc
                     .reply(Reply::GeneralFailure, Address::unspecified())
                     .await?;
+                log_reply(peer, "associate", None, None, Reply::GeneralFailure);
                 let _ = assoc.shutdown().await;
                 Ok(())
             }
         }
     }
 
-    async fn handle_bind(bind: Bind<bind::NeedFirstReply>, _addr: Address) -> Result<(), Error> {
+    async fn handle_bind(
+        bind: Bind<bind::NeedFirstReply>,
+        peer: SocketAddr,
+        addr: Address,
+    ) -> Result<(), Error> {
+        log_handshake(peer, "bind", &addr);
+
         let mut conn = bind
             .reply(Reply::CommandNotSupported, Address::unspecified())
             .await?;
+        log_reply(peer, "bind", Some(&addr), None, Reply::CommandNotSupported);
         let _ = conn.shutdown().await;
         Ok(())
     }
 
-    async fn handle_connect(conn: Connect<connect::NeedReply>, addr: Address) -> Result<(), Error> {
-        let target_addr = match addr {
-            Address::DomainAddress(domain, port) => TuicAddress::DomainAddress(domain, port),
-            Address::SocketAddress(addr) => TuicAddress::SocketAddress(addr),
+    async fn handle_connect(
+        conn: Connect<connect::NeedReply>,
+        peer: SocketAddr,
+        addr: Address,
+    ) -> Result<(), Error> {
+        log_handshake(peer, "connect", &addr);
+        let started = SystemTime::now();
+
+        // logged with every event of the relay and listed by `active_relays()`, to follow one relay through the logs
+        let relay_id = SERVER
+            .get()
+            .unwrap()
+            .next_relay_id
+            .fetch_add(1, Ordering::Relaxed);
+
+        let settings = Self::settings();
+        let user = SERVER.get().unwrap().sessions.lock().get(&peer).cloned();
+
+        if let Some(user) = user.as_deref().filter(|user| Quotas::is_exceeded(user)) {
+            log::info!(
+                event = "relay_rejected",
+                relay_id = relay_id,
+                peer:% = peer,
+                target:% = addr,
+                reason:% = CloseReason::QuotaExceeded;
+                "[socks5] [{peer}] [connect] [{addr}] rejected, {user} exceeded the quota"
+            );
+            let mut conn = write_reply(
+                conn,
+                Reply::ConnectionNotAllowed,
+                Address::unspecified(),
+                settings.abort_on_write_failure,
+            )
+            .await?;
+            log_refused(
+                relay_id,
+                peer,
+                Some(user),
+                &addr,
+                Reply::ConnectionNotAllowed,
+                started,
+            );
+            let _ = conn.shutdown().await;
+            return Ok(());
+        }
+
+        // relays are only counted with a limit, one set by a reload applies to the relays opened after it
+        let _destination = match settings.max_per_destination {
+            Some(limit) => match SERVER.get().unwrap().destinations.acquire(&addr, limit) {
+                Some(guard) => Some(guard),
+                None => {
+                    log::info!(
+                        event = "relay_rejected",
+                        relay_id = relay_id,
+                        peer:% = peer,
+                        target:% = addr,
+                        reason:% = CloseReason::DestinationLimit;
+                        "[socks5] [{peer}] [connect] [{addr}] rejected, too many relays to the destination"
+                    );
+                    let mut conn = write_reply(
+                        conn,
+                        Reply::ConnectionNotAllowed,
+                        Address::unspecified(),
+                        settings.abort_on_write_failure,
+                    )
+                    .await?;
+                    log_refused(
+                        relay_id,
+                        peer,
+                        user.as_deref(),
+                        &addr,
+                        Reply::ConnectionNotAllowed,
+                        started,
+                    );
+                    let _ = conn.shutdown().await;
+                    return Ok(());
+                }
+            },
+            None => None,
         };
 
-        let relay = match TuicConnection::get().await {
-            Ok(conn) => conn.connect(target_addr).await,
-            Err(err) => Err(err),
+        let target_addr = tuic_address(addr.clone());
+
+        let requested_addr = target_addr.clone();
+
+        let target_addr = match Resolver::resolve_addr(target_addr).await {
+            Ok(target_addr) => target_addr,
+            Err(err) => {
+                log::warn!(
+                    relay_id = relay_id;
+                    "[socks5] [{peer}] [connect] [{addr}] relay {relay_id} failed to resolve: {err}"
+                );
+                Diagnostics::record(Some(peer), Some(addr.to_string()), &err);
+                let mut conn = write_reply(
+                    conn,
+                    Reply::HostUnreachable,
+                    Address::unspecified(),
+                    settings.abort_on_write_failure,
+                )
+                .await?;
+                log_refused(
+                    relay_id,
+                    peer,
+                    user.as_deref(),
+                    &addr,
+                    Reply::HostUnreachable,
+                    started,
+                );
+                let _ = conn.shutdown().await;
+                return Ok(());
+            }
         };
 
-        match relay {
-            Ok(relay) => {
-                let mut relay = relay.compat();
+        // listed from here on, so the relay can already be cancelled while dialing
+        let entry = Arc::new(RelayEntry {
+            peer,
+            target: addr.to_string(),
+            via: OnceCell::new(),
+            started,
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            cancel: Notify::new(),
+        });
 
-                match conn.reply(Reply::Succeeded, Address::unspecified()).await {
-                    Ok(mut conn) => match io::copy_bidirectional(&mut conn, &mut relay).await {
-                        Ok(_) => Ok(()),
+        let _guard = RelayGuard::register(relay_id, entry.clone());
+
+        // the user if authenticated, so a user keeps the same server from any address
+        let sticky_key = user
+            .as_deref()
+            .map_or_else(|| peer.ip().to_string(), str::to_owned);
+
+        let route = Router::route(&requested_addr, &target_addr).await;
+
+        match route.action {
+            RouteAction::Tunnel => {}
+            RouteAction::Direct => {
+                log::info!(
+                    relay_id = relay_id;
+                    "[socks5] [{peer}] [connect] [{addr}] relay {relay_id} routed directly"
+                );
+            }
+            RouteAction::Reject => {
+                log::info!(
+                    relay_id = relay_id;
+                    "[socks5] [{peer}] [connect] [{addr}] relay {relay_id} rejected by routing rules"
+                );
+                let mut conn = write_reply(
+                    conn,
+                    Reply::ConnectionNotAllowed,
+                    Address::unspecified(),
+                    settings.abort_on_write_failure,
+                )
+                .await?;
+                log_refused(
+                    relay_id,
+                    peer,
+                    user.as_deref(),
+                    &addr,
+                    Reply::ConnectionNotAllowed,
+                    started,
+                );
+                let _ = conn.shutdown().await;
+                return Ok(());
+            }
+        }
+
+        let overrides = SERVER
+            .get()
+            .unwrap()
+            .relay_overrides_hook
+            .as_ref()
+            .map_or_else(RelayOverrides::default, |hook| hook(&target_addr));
+
+        let relay_linger = overrides.relay_linger.or(settings.relay_linger);
+        let max_relay_lifetime = overrides.max_relay_lifetime.or(settings.max_relay_lifetime);
+
+        let dial = entry.dial(async {
+            let dial = async {
+                match route.action {
+                    RouteAction::Direct => DirectDialer.connect(target_addr, None, None).await,
+                    _ => {
+                        SERVER
+                            .get()
+                            .unwrap()
+                            .dialer
+                            .connect(target_addr, Some(&sticky_key), route.egress.as_deref())
+                            .await
+                    }
+                }
+            };
+
+            match overrides.connect_timeout {
+                Some(timeout) => time::timeout(timeout, dial)
+                    .await
+                    .map_err(|_| Error::Timeout)?,
+                None => dial.await,
+            }
+        });
+
+        let (mut conn, relay) = match settings.reply_timing {
+            ReplyTiming::AfterConnect => match dial.await {
+                Ok(relay) => {
+                    let bind_addr =
+                        reply_bind_addr(settings.reply_bind_mode, &conn, &addr, relay.server_addr);
+
+                    match write_reply(
+                        conn,
+                        Reply::Succeeded,
+                        bind_addr,
+                        settings.abort_on_write_failure,
+                    )
+                    .await
+                    {
+                        Ok(conn) => {
+                            log_reply(
+                                peer,
+                                "connect",
+                                Some(&addr),
+                                Some(relay_id),
+                                Reply::Succeeded,
+                            );
+                            (conn, relay)
+                        }
                         Err(err) => {
-                            let _ = conn.shutdown().await;
+                            let mut relay = relay.stream;
                             let _ = relay.shutdown().await;
-                            Err(Error::from(err))
+                            return Err(Error::from(err));
                         }
-                    },
+                    }
+                }
+                Err(Error::Cancelled) => {
+                    log::info!(
+                        relay_id = relay_id;
+                        "[socks5] [{peer}] [connect] [{addr}] relay {relay_id} cancelled while dialing"
+                    );
+                    let mut conn = write_reply(
+                        conn,
+                        Reply::GeneralFailure,
+                        Address::unspecified(),
+                        settings.abort_on_write_failure,
+                    )
+                    .await?;
+                    log_refused(
+                        relay_id,
+                        peer,
+                        user.as_deref(),
+                        &addr,
+                        Reply::GeneralFailure,
+                        started,
+                    );
+                    let _ = conn.shutdown().await;
+                    return Ok(());
+                }
+                Err(relay_err) => {
+                    log::error!(relay_id = relay_id; "[connection] relay {relay_id}: {relay_err}");
+                    Diagnostics::record(Some(peer), Some(addr.to_string()), &relay_err);
+                    let reply = match relay_err {
+                        Error::TargetUnreachable(_) => Reply::HostUnreachable,
+                        _ => settings.tunnel_failure_reply,
+                    };
+                    let mut conn = write_reply(
+                        conn,
+                        reply,
+                        Address::unspecified(),
+                        settings.abort_on_write_failure,
+                    )
+                    .await?;
+                    log_refused(relay_id, peer, user.as_deref(), &addr, reply, started);
+                    let _ = conn.shutdown().await;
+                    return Ok(());
+                }
+            },
+            ReplyTiming::Immediate => {
+                let bind_addr = reply_bind_addr(settings.reply_bind_mode, &conn, &addr, None);
+                let mut conn = write_reply(
+                    conn,
+                    Reply::Succeeded,
+                    bind_addr,
+                    settings.abort_on_write_failure,
+                )
+                .await?;
+                log_reply(
+                    peer,
+                    "connect",
+                    Some(&addr),
+                    Some(relay_id),
+                    Reply::Succeeded,
+                );
+
+                // whatever the client sends meanwhile waits in the socket buffer until the relay starts reading it
+                match dial.await {
+                    Ok(relay) => (conn, relay),
                     Err(err) => {
-                        let _ = relay.shutdown().await;
-                        Err(Error::from(err))
+                        // the client was told the relay succeeded, closing the connection is the only way left to fail it
+                        match err {
+                            Error::Cancelled => log::info!(
+                                relay_id = relay_id;
+                                "[socks5] [{peer}] [connect] [{addr}] relay {relay_id} cancelled while dialing"
+                            ),
+                            err => {
+                                log::error!(relay_id = relay_id; "[connection] relay {relay_id}: {err}");
+                                Diagnostics::record(Some(peer), Some(addr.to_string()), &err);
+                            }
+                        }
+
+                        AccessLog::record(AccessLogEntry {
+                            peer,
+                            user: user.as_deref(),
+                            target: &entry.target,
+                            reply: Reply::Succeeded,
+                            started: entry.started,
+                            duration: entry.started.elapsed().ok(),
+                            bytes_up: 0,
+                            bytes_down: 0,
+                        });
+
+                        let _ = conn.shutdown().await;
+                        return Ok(());
                     }
                 }
             }
-            Err(relay_err) => {
-                log::error!("[connection] {relay_err}");
-                let mut conn = conn
-                    .reply(Reply::GeneralFailure, Address::unspecified())
-                    .await?;
-                let _ = conn.shutdown().await;
+        };
+
+        let Dialed {
+            stream: mut relay,
+            via,
+            compression,
+            timings,
+            ..
+        } = relay;
+
+        timings.observe();
+
+        // an empty relay is only told apart when it closes, so with `quiet_empty_connects` every start is quiet
+        log::log!(
+            if settings.quiet_empty_connects { Level::Debug } else { Level::Info },
+            event = "relay_started",
+            relay_id = relay_id,
+            peer:% = peer,
+            target:% = addr,
+            via:% = via,
+            acquire_ms = timings.acquire.map(|acquire| acquire.as_millis() as u64),
+            connection = timings.acquire.map(|_| if timings.handshake { "new" } else { "reused" }),
+            open_ms = timings.open.as_millis() as u64;
+            "[socks5] [{peer}] [connect] [{addr}] relay {relay_id} started via {via}"
+        );
+
+        let _ = entry.via.set(via);
+
+        // counted until this returns, `relay_closed` below is the matching end event
+        let _active = metrics::RELAYS_ACTIVE.track();
+        let opened = Instant::now();
+        let first_byte = OnceCell::new();
+
+        let (reason, res) = tokio::select! {
+            res = forward(
+                &mut conn,
+                &mut *relay,
+                relay_linger,
+                settings.write_buffer,
+                compression,
+                &entry.bytes_up,
+                &entry.bytes_down,
+                &first_byte,
+            ) => res,
+            () = entry.cancel.notified() => (CloseReason::Canceled, Ok(())),
+            () = lifetime(max_relay_lifetime) => {
+                (CloseReason::LifetimeExceeded, Ok(()))
+            }
+        };
+
+        if let CloseReason::LifetimeExceeded = reason {
+            let _ = relay.shutdown().await;
+            let _ = conn.shutdown().await;
+        }
+
+        let up = entry.bytes_up.load(Ordering::Relaxed);
+        let down = entry.bytes_down.load(Ordering::Relaxed);
+
+        // covers the server connecting to the target, which TUIC does not acknowledge, and the target's response
+        let first_byte = first_byte.get().map(|first_byte| *first_byte - opened);
+
+        if let Some(first_byte) = first_byte {
+            metrics::CONNECT_PHASE_FIRST_BYTE_SECONDS.observe(first_byte);
+        }
+
+        if let Some(user) = &user {
+            Quotas::add(user, up + down);
+        }
+
+        AccessLog::record(AccessLogEntry {
+            peer,
+            user: user.as_deref(),
+            target: &entry.target,
+            reply: Reply::Succeeded,
+            started: entry.started,
+            duration: entry.started.elapsed().ok(),
+            bytes_up: up,
+            bytes_down: down,
+        });
+
+        // the client went away right after the CONNECT succeeded, e.g. a scanner
+        let is_empty = up == 0 && matches!(reason, CloseReason::LocalEof | CloseReason::LocalError);
+        let is_quiet = is_empty && settings.quiet_empty_connects;
+
+        if is_empty {
+            // on a local error, the remote stream was not finished yet
+            let _ = relay.shutdown().await;
+        }
+
+        let quic = res.as_ref().err().and_then(QuicError::from_io);
+        let quic_detail = quic
+            .as_ref()
+            .map_or_else(String::new, |quic| format!(", {quic}"));
+
+        log::log!(
+            if is_quiet { Level::Debug } else { Level::Info },
+            event = "relay_closed",
+            relay_id = relay_id,
+            peer:% = peer,
+            target:% = addr,
+            reason:% = reason,
+            quic_error = quic.as_ref().map(|quic| quic.kind),
+            quic_code = quic.as_ref().and_then(|quic| quic.code),
+            quic_reason = quic.as_ref().and_then(|quic| quic.reason.as_deref()),
+            bytes_up = up,
+            bytes_down = down,
+            first_byte_ms = first_byte.map(|first_byte| first_byte.as_millis() as u64);
+            "[socks5] [{peer}] [connect] [{addr}] relay {relay_id} closed ({reason}{quic_detail}), {up} bytes up, {down} bytes down"
+        );
+
+        if let Some(option) = relay_warning(&settings, up, down) {
+            log::warn!(
+                event = "relay_traffic_warning",
+                relay_id = relay_id,
+                peer:% = peer,
+                target:% = addr,
+                option = option,
+                bytes_up = up,
+                bytes_down = down;
+                "[socks5] [{peer}] [connect] [{addr}] relay {relay_id} exceeded `{option}`, {up} bytes up, {down} bytes down"
+            );
+        }
+
+        match res {
+            Ok(()) => Ok(()),
+            Err(err) if is_quiet => {
+                log::debug!(
+                    relay_id = relay_id;
+                    "[socks5] [{peer}] [connect] [{addr}] relay {relay_id} closed without sending data: {err}"
+                );
                 Ok(())
             }
+            Err(err) => {
+                let _ = conn.shutdown().await;
+                Err(Error::from(err))
+            }
+        }
+    }
+
+    /// Returns a snapshot of the CONNECT relays currently being forwarded, oldest first
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn active_relays() -> Vec<RelayInfo> {
+        let Some(server) = SERVER.get() else {
+            return Vec::new();
+        };
+
+        let relays = server.relays.lock();
+        let mut infos = Vec::with_capacity(relays.len());
+
+        for (id, entry) in relays.iter() {
+            infos.push(RelayInfo {
+                id: *id,
+                peer: entry.peer,
+                target: entry.target.clone(),
+                via: entry.via.get().cloned(),
+                started: entry.started,
+                bytes_up: entry.bytes_up.load(Ordering::Relaxed),
+                bytes_down: entry.bytes_down.load(Ordering::Relaxed),
+            });
+        }
+
+        drop(relays);
+
+        infos.sort_unstable_by_key(|info| info.id);
+        infos
+    }
+
+    /// Closes the listed relay with the given ID. Returns `false` if there is no such relay
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn cancel_relay(id: u64) -> bool {
+        let Some(server) = SERVER.get() else {
+            return false;
+        };
+
+        let Some(entry) = server.relays.lock().get(&id).cloned() else {
+            return false;
+        };
+
+        entry.cancel.notify_one();
+        true
+    }
+
+    /// Returns a snapshot of the server counters
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn stats() -> ServerStats {
+        let server = SERVER.get().unwrap();
+
+        ServerStats {
+            connections: server.connections.count(),
+            relays: server.relays.lock().len(),
+            udp_sessions: udp::count(),
+            draining: server.draining.load(Ordering::Acquire),
         }
     }
 
     async fn send_pkt(
         mut assoc: Associate<associate::Ready>,
+        peer: SocketAddr,
+        declared_addr: Address,
         assoc_socket: Arc<AssociatedUdpSocket>,
     ) -> Result<(), Error> {
-        let assoc_id = SERVER
-            .get()
-            .unwrap()
-            .next_assoc_id
-            .fetch_add(1, Ordering::AcqRel);
-
-        SERVER
-            .get()
-            .unwrap()
-            .udp_sessions
-            .lock()
-            .insert(assoc_id, assoc_socket.clone());
+        let (sink, mut stream) = udp::associate()?;
+        let assoc_id = sink.id();
+        let stats = sink.stats().clone();
 
         let mut connected = None;
 
+        // a port of 0 accepts packets from any port
+        let expected_src = {
+            let (ip, port) = match declared_addr {
+                Address::SocketAddress(addr) if !addr.ip().is_unspecified() => {
+                    (addr.ip(), addr.port())
+                }
+                Address::SocketAddress(addr) => (peer.ip(), addr.port()),
+                Address::DomainAddress(_, port) => (peer.ip(), port),
+            };
+
+            let port = if Self::settings().udp_strict_source {
+                port
+            } else {
+                0
+            };
+
+            SocketAddr::from((canonical_ip(ip), port))
+        };
+
+        #[allow(clippy::io_other_error)]
         async fn accept_pkt(
             assoc_socket: &AssociatedUdpSocket,
             connected: &mut Option<SocketAddr>,
-            assoc_id: u16,
+            expected_src: SocketAddr,
+            sink: &PacketSink,
+            stats: &UdpStats,
         ) -> Result<(), Error> {
             let (pkt, frag, dst_addr, src_addr) = assoc_socket.recv_from().await?;
 
+            let src_ip_match = canonical_ip(src_addr.ip()) == expected_src.ip();
+            let src_port_match = expected_src.port() == 0 || expected_src.port() == src_addr.port();
+
+            if !src_ip_match || !src_port_match {
+                log::debug!("[socks5] [{src_addr}] [associate] packet from an undeclared source dropped, expecting {expected_src}");
+                stats.inc_dropped();
+                return Ok(());
+            }
+
             if let Some(connected) = connected {
                 if connected != &src_addr {
                     Err(IoError::new(
@@ -251,59 +1101,923 @@ impl Server {
                 ))?;
             }
 
-            let target_addr = match dst_addr {
-                Address::DomainAddress(domain, port) => TuicAddress::DomainAddress(domain, port),
-                Address::SocketAddress(addr) => TuicAddress::SocketAddress(addr),
-            };
+            let target_addr = tuic_address(dst_addr);
 
-            let res = match TuicConnection::get().await {
-                Ok(conn) => conn.packet(pkt, target_addr, assoc_id).await,
-                Err(err) => Err(err),
-            };
+            let requested_addr = target_addr.clone();
+            let target_addr = Resolver::resolve_addr(target_addr).await?;
 
-            match res {
-                Ok(()) => {}
-                Err(err) => log::error!("[connection] {err}"),
+            // there is no direct UDP relay, so only rejection is honored and `direct` packets still go through the tunnel
+            if let RouteAction::Reject = Router::route(&requested_addr, &target_addr).await.action {
+                log::debug!("[socks5] [{src_addr}] [associate] [{requested_addr}] packet rejected by routing rules");
+                stats.inc_dropped();
+                return Ok(());
+            }
+
+            match sink.send(pkt, target_addr).await {
+                Ok(true) => stats.inc_sent(),
+                Ok(false) => stats.inc_dropped(),
+                Err(err) => {
+                    log::error!("[connection] {err}");
+                    Diagnostics::record(Some(src_addr), Some(requested_addr.to_string()), &err);
+                    stats.inc_dropped();
+                }
             }
 
             Ok(())
         }
 
+        // the stream only ends once the association is dropped below
+        let relay_back = async {
+            while let Some((pkt, addr)) = stream.recv().await {
+                let addr = match addr {
+                    TuicAddress::None => unreachable!(),
+                    TuicAddress::DomainAddress(domain, port) => {
+                        Address::DomainAddress(domain, port)
+                    }
+                    TuicAddress::SocketAddress(addr) => Address::SocketAddress(addr),
+                };
+
+                match assoc_socket.send(pkt, 0, addr).await {
+                    Ok(_) => stats.inc_received(),
+                    Err(err) => {
+                        log::error!("[socks5] [send] {err}");
+                        stats.inc_dropped();
+                    }
+                }
+            }
+        };
+
         let res = tokio::select! {
             res = assoc.wait_until_closed() => res,
             _ = async { loop {
-                if let Err(err) = accept_pkt(&assoc_socket, &mut connected, assoc_id).await {
+                if let Err(err) = accept_pkt(&assoc_socket, &mut connected, expected_src, &sink, &stats).await {
                     log::warn!("[socks5] {err}");
+                    stats.inc_dropped();
                 }
             }} => unreachable!(),
+            () = relay_back => unreachable!(),
         };
 
         let _ = assoc.shutdown().await;
-        SERVER.get().unwrap().udp_sessions.lock().remove(&assoc_id);
+        drop((sink, stream));
+
+        let (sent, received, dropped) = stats.get();
+        log::info!(
+            "[socks5] [{peer}] [associate] [{assoc_id:#06x}] association closed, {sent} packets sent, {received} packets received, {dropped} packets dropped"
+        );
+
+        Ok(res?)
+    }
+}
+
+/// Unwraps IPv4-mapped IPv6 addresses, so that sources seen through a dual-stack socket compare equal to their IPv4 form
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// A CONNECT relay that is being forwarded
+#[cfg_attr(not(unix), allow(dead_code))]
+pub struct RelayInfo {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub target: String,
+    /// The server the relay goes through, or `direct`. `None` while still dialing
+    pub via: Option<Arc<str>>,
+    pub started: SystemTime,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+struct RelayEntry {
+    peer: SocketAddr,
+    target: String,
+    via: OnceCell<Arc<str>>,
+    started: SystemTime,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    cancel: Notify,
+}
+
+#[cfg_attr(not(feature = "admin"), allow(dead_code))]
+pub struct ServerStats {
+    /// Accepted socks5 connections that are still open, including the ones in handshake
+    pub connections: usize,
+    /// CONNECT relays listed in `Server::active_relays()`
+    pub relays: usize,
+    pub udp_sessions: usize,
+    pub draining: bool,
+}
+
+impl RelayEntry {
+    /// Runs `dial` unless the relay is cancelled first. A cancelled dial is dropped, and a relay stream it already opened, e.g. while waiting for the server to answer a compression request, is reset rather than finished, so the server gives up the target
+    async fn dial(
+        &self,
+        dial: impl Future<Output = Result<Dialed, Error>>,
+    ) -> Result<Dialed, Error> {
+        tokio::select! {
+            res = dial => res,
+            () = self.cancel.notified() => Err(Error::Cancelled),
+        }
+    }
+}
+
+/// Keeps a relay listed in `Server::active_relays()` until dropped
+struct RelayGuard(Option<u64>);
+
+impl RelayGuard {
+    fn register(id: u64, entry: Arc<RelayEntry>) -> Self {
+        let mut relays = SERVER.get().unwrap().relays.lock();
+
+        if relays.len() >= MAX_TRACKED_RELAYS {
+            return Self(None);
+        }
+
+        relays.insert(id, entry);
+        Self(Some(id))
+    }
+}
+
+impl Drop for RelayGuard {
+    fn drop(&mut self) {
+        if let Some(id) = self.0 {
+            SERVER.get().unwrap().relays.lock().remove(&id);
+        }
+    }
+}
+
+/// Open CONNECT relays by destination, for `max_per_destination`. Unlike `Server::relays`, every relay is counted
+#[derive(Default)]
+struct Destinations(Mutex<HashMap<String, usize>>);
+
+impl Destinations {
+    /// Counts a relay to `addr` until the returned guard is dropped, `None` if `limit` relays to it are already open
+    fn acquire(&self, addr: &Address, limit: usize) -> Option<DestinationGuard<'_>> {
+        // domains are case-insensitive
+        let destination = addr.to_string().to_ascii_lowercase();
+        let mut destinations = self.0.lock();
+        let count = destinations.get(&destination).copied().unwrap_or(0);
+
+        if count >= limit {
+            return None;
+        }
+
+        destinations.insert(destination.clone(), count + 1);
+
+        Some(DestinationGuard {
+            destinations: self,
+            destination,
+        })
+    }
+}
+
+struct DestinationGuard<'a> {
+    destinations: &'a Destinations,
+    destination: String,
+}
+
+impl Drop for DestinationGuard<'_> {
+    fn drop(&mut self) {
+        let mut destinations = self.destinations.0.lock();
+
+        if let Some(count) = destinations.get_mut(&self.destination) {
+            *count -= 1;
+
+            if *count == 0 {
+                destinations.remove(&self.destination);
+            }
+        }
+    }
+}
+
+/// Usernames and passwords
+type Credentials = Arc<[(Arc<str>, Vec<u8>)]>;
+
+/// Username / password authentication that compares the credentials in constant time, so response timing does not reveal how much of a guess matched
+struct Password {
+    /// Swapped as a whole on reload
+    credentials: RwLock<Credentials>,
+    sessions: Arc<Mutex<HashMap<SocketAddr, Arc<str>>>>,
+}
+
+impl Password {
+    fn new(
+        credentials: HashMap<String, String>,
+        sessions: Arc<Mutex<HashMap<SocketAddr, Arc<str>>>>,
+    ) -> Self {
+        Self {
+            credentials: RwLock::new(Self::collect(credentials)),
+            sessions,
+        }
+    }
+
+    /// Replaces the credentials for the handshakes to come. Connections already authenticated stay open
+    fn set_credentials(&self, credentials: HashMap<String, String>) {
+        *self.credentials.write() = Self::collect(credentials);
+    }
+
+    fn collect(credentials: HashMap<String, String>) -> Credentials {
+        credentials
+            .into_iter()
+            .map(|(username, password)| (Arc::from(username), password.into_bytes()))
+            .collect()
+    }
+
+    /// The user `username` / `password` belong to. Every credential is compared in full, so a wrong username takes as long as a wrong password
+    fn find(&self, username: &[u8], password: &[u8]) -> Option<Arc<str>> {
+        let credentials = self.credentials.read().clone();
+        let mut user = None;
+
+        for (name, pass) in credentials.iter() {
+            let is_valid = username.ct_eq(name.as_bytes()) & password.ct_eq(pass);
+
+            if bool::from(is_valid) {
+                user = Some(name.clone());
+            }
+        }
+
+        user
+    }
+}
+
+#[async_trait]
+impl Auth for Password {
+    fn as_handshake_method(&self) -> HandshakeMethod {
+        HandshakeMethod::Password
+    }
+
+    async fn execute(&self, stream: &mut TcpStream) -> IoResult<()> {
+        let req = PasswordRequest::read_from(stream).await?;
+
+        if let Some(user) = self.find(&req.username, &req.password) {
+            PasswordResponse::new(true).write_to(stream).await?;
+
+            if let Ok(peer) = stream.peer_addr() {
+                self.sessions.lock().insert(peer, user);
+            }
+
+            Ok(())
+        } else {
+            PasswordResponse::new(false).write_to(stream).await?;
+            Err(IoError::new(
+                ErrorKind::InvalidData,
+                "password authentication failed, wrong username or password",
+            ))
+        }
+    }
+}
+
+/// Wraps the configured authentication method to report the result of every negotiation
+struct ObservedAuth {
+    inner: Arc<dyn Auth + Send + Sync>,
+    method: &'static str,
+}
 
-        let dissoc_res = match TuicConnection::get().await {
-            Ok(conn) => conn.dissociate(assoc_id).await,
-            Err(err) => Err(err),
+impl ObservedAuth {
+    fn new(inner: Arc<dyn Auth + Send + Sync>) -> Self {
+        let method = match inner.as_handshake_method() {
+            HandshakeMethod::None => "none",
+            HandshakeMethod::Password => "password",
+            _ => "unknown",
         };
 
-        match dissoc_res {
-            Ok(()) => {}
-            Err(err) => log::error!("[connection] [dissociate] {err}"),
+        Self { inner, method }
+    }
+}
+
+#[async_trait]
+impl Auth for ObservedAuth {
+    fn as_handshake_method(&self) -> HandshakeMethod {
+        self.inner.as_handshake_method()
+    }
+
+    async fn execute(&self, stream: &mut TcpStream) -> IoResult<()> {
+        let res = self.inner.execute(stream).await;
+
+        let result = match (self.method, &res) {
+            ("none", _) => {
+                metrics::AUTH_NONE_TOTAL.inc();
+                "succeeded"
+            }
+            (_, Ok(())) => {
+                metrics::AUTH_PASSWORD_TOTAL.inc();
+                "succeeded"
+            }
+            (_, Err(_)) => {
+                metrics::AUTH_PASSWORD_FAIL_TOTAL.inc();
+                "failed"
+            }
+        };
+
+        if let Ok(peer) = stream.peer_addr() {
+            log_auth(peer, self.method, result);
         }
 
-        Ok(res?)
+        res
+    }
+}
+
+/// The socks5 credentials of `cfg` by username, checked against `reject_no_auth_clients` and `user_quotas`
+fn credentials(cfg: &Local) -> Result<HashMap<String, String>, Error> {
+    let mut credentials = cfg.users.clone();
+
+    match (&cfg.username, &cfg.password) {
+        (Some(username), Some(password)) => {
+            credentials.insert(username.clone(), password.clone());
+        }
+        (None, None) => {}
+        _ => return Err(Error::InvalidSocks5Auth),
     }
 
-    pub async fn recv_pkt(pkt: Bytes, addr: Address, assoc_id: u16) {
-        let assoc_socket = {
-            let sessions = SERVER.get().unwrap().udp_sessions.lock();
-            let Some(assoc_socket) = sessions.get(&assoc_id) else { unreachable!() };
-            assoc_socket.clone()
+    if cfg.reject_no_auth_clients && credentials.is_empty() {
+        return Err(Error::MissingSocks5Credentials);
+    }
+
+    if let Some(user) = cfg
+        .user_quotas
+        .keys()
+        .find(|user| !credentials.contains_key(*user))
+    {
+        return Err(Error::UnknownQuotaUser(user.clone()));
+    }
+
+    Ok(credentials)
+}
+
+/// Whether the handshake failed because the client went away, e.g. a port scanner resetting the connection. Not an error of this side or a protocol violation
+fn is_aborted_handshake(err: &IoError) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+/// Resolves once a relay has been open for `max_relay_lifetime`, never without it
+async fn lifetime(max_relay_lifetime: Option<Duration>) {
+    match max_relay_lifetime {
+        Some(lifetime) => time::sleep(lifetime).await,
+        None => future::pending().await,
+    }
+}
+
+/// Writes `port` to `path` aside and renames it, so a reader never sees a partial port. The file aside is named after the process, so instances writing to the same directory do not collide
+fn write_port(path: &Path, port: u16) -> IoResult<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", process::id()));
+    let tmp = PathBuf::from(tmp);
+
+    let res = fs::write(&tmp, format!("{port}\n")).and_then(|()| fs::rename(&tmp, path));
+
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+
+    res
+}
+
+/// Converts a target of a socks5 request. An IP literal sent as a domain, e.g. `::1` or `[::1]` by some clients, becomes an IP address rather than a name to resolve
+fn tuic_address(addr: Address) -> TuicAddress {
+    match addr {
+        Address::DomainAddress(domain, port) => {
+            let literal = domain
+                .strip_prefix('[')
+                .and_then(|domain| domain.strip_suffix(']'))
+                .unwrap_or(&domain);
+
+            match literal.parse::<IpAddr>() {
+                Ok(ip) => TuicAddress::SocketAddress(SocketAddr::new(ip, port)),
+                Err(_) => TuicAddress::DomainAddress(domain, port),
+            }
+        }
+        Address::SocketAddress(addr) => TuicAddress::SocketAddress(addr),
+    }
+}
+
+/// Writes the reply to a CONNECT request. With `abort`, the connection was accepted with `SO_LINGER` set to 0 from the listening socket, so a failed write closes it with a TCP RST as it is dropped
+async fn write_reply(
+    conn: Connect<connect::NeedReply>,
+    reply: Reply,
+    addr: Address,
+    abort: bool,
+) -> IoResult<Connect<connect::Ready>> {
+    let mut conn = conn.reply(reply, addr).await?;
+
+    // a relay that ends normally still closes gracefully
+    if abort {
+        let _ = SockRef::from(conn.split().0.as_ref()).set_linger(None);
+    }
+
+    Ok(conn)
+}
+
+/// The BND address of a successful CONNECT reply. `server_addr` is the TUIC server the relay goes through, if known yet
+fn reply_bind_addr(
+    mode: ReplyBindMode,
+    conn: &Connect<connect::NeedReply>,
+    target: &Address,
+    server_addr: Option<SocketAddr>,
+) -> Address {
+    match mode {
+        ReplyBindMode::Zero => Address::unspecified(),
+        ReplyBindMode::EchoPort => {
+            let port = match target {
+                Address::DomainAddress(_, port) => *port,
+                Address::SocketAddress(addr) => addr.port(),
+            };
+
+            Address::SocketAddress(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+        }
+        // an IPv4 client of a dual-stack listener sees an IPv4-mapped local address, which would be replied as IPv6
+        ReplyBindMode::RelaySocket => conn.local_addr().map_or_else(
+            |_| Address::unspecified(),
+            |addr| Address::SocketAddress(SocketAddr::from((canonical_ip(addr.ip()), addr.port()))),
+        ),
+        ReplyBindMode::Server => {
+            server_addr.map_or_else(Address::unspecified, Address::SocketAddress)
+        }
+        ReplyBindMode::Custom(addr) => Address::SocketAddress(addr),
+    }
+}
+
+/// Records a refused CONNECT request in the access log. Opened relays are recorded once they close
+/// The option, `relay_warn_bytes` or `relay_warn_ratio`, that the traffic of a closed relay exceeded, if any
+fn relay_warning(settings: &Settings, up: u64, down: u64) -> Option<&'static str> {
+    if settings
+        .relay_warn_bytes
+        .is_some_and(|limit| up > limit || down > limit)
+    {
+        return Some("relay_warn_bytes");
+    }
+
+    let ratio = settings.relay_warn_ratio?;
+    let total = up.saturating_add(down);
+
+    (total >= settings.relay_warn_ratio_min_bytes
+        && up.max(down) as f64 > ratio * up.min(down) as f64)
+        .then_some("relay_warn_ratio")
+}
+
+/// Logs the reply refusing a CONNECT and its access log line
+fn log_refused(
+    relay_id: u64,
+    peer: SocketAddr,
+    user: Option<&str>,
+    target: &Address,
+    reply: Reply,
+    started: SystemTime,
+) {
+    log_reply(peer, "connect", Some(target), Some(relay_id), reply);
+
+    AccessLog::record(AccessLogEntry {
+        peer,
+        user,
+        target: &target.to_string(),
+        reply,
+        started,
+        duration: None,
+        bytes_up: 0,
+        bytes_down: 0,
+    });
+}
+
+fn log_auth(peer: SocketAddr, method: &'static str, result: &'static str) {
+    log::debug!(
+        event = "auth",
+        peer:% = peer,
+        method = method,
+        result = result;
+        "[socks5] [{peer}] [auth] {method} authentication {result}"
+    );
+}
+
+fn log_handshake(peer: SocketAddr, command: &'static str, target: &Address) {
+    match command {
+        "connect" => metrics::REQUESTS_CONNECT_TOTAL.inc(),
+        "bind" => metrics::REQUESTS_BIND_TOTAL.inc(),
+        _ => metrics::REQUESTS_ASSOCIATE_TOTAL.inc(),
+    }
+
+    log::debug!(
+        event = "handshake",
+        peer:% = peer,
+        command = command,
+        target:% = target;
+        "[socks5] [{peer}] [{command}] [{target}] handshake completed"
+    );
+}
+
+fn log_reply(
+    peer: SocketAddr,
+    command: &'static str,
+    target: Option<&Address>,
+    relay_id: Option<u64>,
+    reply: Reply,
+) {
+    match reply {
+        Reply::Succeeded => metrics::REPLIES_SUCCEEDED_TOTAL.inc(),
+        Reply::GeneralFailure => metrics::REPLIES_GENERAL_FAILURE_TOTAL.inc(),
+        Reply::ConnectionNotAllowed => metrics::REPLIES_CONNECTION_NOT_ALLOWED_TOTAL.inc(),
+        Reply::NetworkUnreachable => metrics::REPLIES_NETWORK_UNREACHABLE_TOTAL.inc(),
+        Reply::HostUnreachable => metrics::REPLIES_HOST_UNREACHABLE_TOTAL.inc(),
+        Reply::ConnectionRefused => metrics::REPLIES_CONNECTION_REFUSED_TOTAL.inc(),
+        Reply::TtlExpired => metrics::REPLIES_TTL_EXPIRED_TOTAL.inc(),
+        Reply::CommandNotSupported => metrics::REPLIES_COMMAND_NOT_SUPPORTED_TOTAL.inc(),
+        Reply::AddressTypeNotSupported => metrics::REPLIES_ADDRESS_TYPE_NOT_SUPPORTED_TOTAL.inc(),
+    }
+
+    match (target, relay_id) {
+        (Some(target), Some(relay_id)) => log::debug!(
+            event = "reply",
+            relay_id = relay_id,
+            peer:% = peer,
+            command = command,
+            target:% = target,
+            reply:? = reply;
+            "[socks5] [{peer}] [{command}] [{target}] relay {relay_id} replied {reply:?}"
+        ),
+        (Some(target), None) => log::debug!(
+            event = "reply",
+            peer:% = peer,
+            command = command,
+            target:% = target,
+            reply:? = reply;
+            "[socks5] [{peer}] [{command}] [{target}] replied {reply:?}"
+        ),
+        (None, _) => log::debug!(
+            event = "reply",
+            peer:% = peer,
+            command = command,
+            reply:? = reply;
+            "[socks5] [{peer}] [{command}] replied {reply:?}"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socks5_proto::Response;
+    use std::net::Ipv6Addr;
+    use tokio::io::AsyncReadExt;
+
+    fn password(credentials: &[(&str, &str)]) -> Password {
+        Password::new(
+            credentials
+                .iter()
+                .map(|(username, password)| (username.to_string(), password.to_string()))
+                .collect(),
+            Arc::default(),
+        )
+    }
+
+    #[test]
+    fn password_accepts_matching_credentials() {
+        let auth = password(&[("alice", "secret"), ("bob", "hunter2")]);
+
+        assert_eq!(auth.find(b"alice", b"secret").as_deref(), Some("alice"));
+        assert_eq!(auth.find(b"bob", b"hunter2").as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn password_rejects_wrong_credentials() {
+        let auth = password(&[("alice", "secret"), ("bob", "hunter2")]);
+
+        assert_eq!(auth.find(b"alice", b"hunter2"), None);
+        assert_eq!(auth.find(b"alice", b"secre"), None);
+        assert_eq!(auth.find(b"alice", b"secret!"), None);
+        assert_eq!(auth.find(b"carol", b"secret"), None);
+        assert_eq!(auth.find(b"", b""), None);
+    }
+
+    #[test]
+    fn password_uses_reloaded_credentials() {
+        let auth = password(&[("alice", "secret")]);
+        auth.set_credentials(HashMap::from([("alice".to_owned(), "changed".to_owned())]));
+
+        assert_eq!(auth.find(b"alice", b"secret"), None);
+        assert_eq!(auth.find(b"alice", b"changed").as_deref(), Some("alice"));
+    }
+
+    fn local(json: &str) -> Local {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn reject_no_auth_clients_requires_credentials() {
+        let cfg = local(r#"{ "server": "127.0.0.1:1080", "reject_no_auth_clients": true }"#);
+        assert!(matches!(
+            credentials(&cfg),
+            Err(Error::MissingSocks5Credentials)
+        ));
+
+        let cfg = local(
+            r#"{ "server": "127.0.0.1:1080", "reject_no_auth_clients": true, "username": "alice", "password": "secret" }"#,
+        );
+        assert_eq!(credentials(&cfg).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn relay_write_buffer_is_capped() {
+        let cfg = local(r#"{ "server": "127.0.0.1:1080", "relay_write_buffer": 1048576 }"#);
+        assert!(Settings::new(&cfg).is_ok());
+
+        let cfg = local(r#"{ "server": "127.0.0.1:1080", "relay_write_buffer": 1048577 }"#);
+        assert!(matches!(
+            Settings::new(&cfg),
+            Err(Error::InvalidRelayWriteBuffer)
+        ));
+    }
+
+    #[test]
+    fn relay_warn_ratio_is_validated() {
+        let cfg = local(r#"{ "server": "127.0.0.1:1080", "relay_warn_ratio": 1.0 }"#);
+        assert!(Settings::new(&cfg).is_ok());
+
+        let cfg = local(r#"{ "server": "127.0.0.1:1080", "relay_warn_ratio": 0.5 }"#);
+        assert!(matches!(
+            Settings::new(&cfg),
+            Err(Error::InvalidRelayWarnRatio)
+        ));
+    }
+
+    #[test]
+    fn relay_warnings() {
+        let cfg = local(
+            r#"{ "server": "127.0.0.1:1080", "relay_warn_bytes": 10000000, "relay_warn_ratio": 100, "relay_warn_ratio_min_bytes": 100000 }"#,
+        );
+        let settings = Settings::new(&cfg).unwrap();
+
+        // a small lopsided relay is left alone
+        assert_eq!(relay_warning(&settings, 10, 50_000), None);
+        assert_eq!(relay_warning(&settings, 1_000, 99_000), None);
+
+        assert_eq!(
+            relay_warning(&settings, 1_000, 200_000),
+            Some("relay_warn_ratio")
+        );
+        assert_eq!(
+            relay_warning(&settings, 200_000, 0),
+            Some("relay_warn_ratio")
+        );
+        assert_eq!(
+            relay_warning(&settings, 10_000_001, 10_000_000),
+            Some("relay_warn_bytes")
+        );
+
+        let cfg = local(r#"{ "server": "127.0.0.1:1080" }"#);
+        let settings = Settings::new(&cfg).unwrap();
+        assert_eq!(relay_warning(&settings, 0, u64::MAX), None);
+    }
+
+    /// Offers `methods` to a socks5 server requiring a password, and returns the method it picked along with the result of the server side handshake
+    async fn negotiate(methods: &[u8]) -> (u8, IoResult<()>) {
+        let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Socks5Server::new(listener, Arc::new(password(&[("alice", "secret")])));
+
+        let client = async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(&[0x05, methods.len() as u8])
+                .await
+                .unwrap();
+            stream.write_all(methods).await.unwrap();
+
+            let mut resp = [0; 2];
+            stream.read_exact(&mut resp).await.unwrap();
+
+            if resp[1] == 0x02 {
+                stream
+                    .write_all(&[
+                        0x01, 5, b'a', b'l', b'i', b'c', b'e', 6, b's', b'e', b'c', b'r', b'e',
+                        b't',
+                    ])
+                    .await
+                    .unwrap();
+                stream.read_exact(&mut [0; 2]).await.unwrap();
+            }
+
+            resp[1]
         };
 
-        match assoc_socket.send(pkt, 0, addr).await {
-            Ok(_) => {}
-            Err(err) => log::error!("[socks5] [send] {err}"),
+        let server = async {
+            let (conn, _) = server.accept().await.unwrap();
+            conn.handshake().await.map(|_| ())
+        };
+
+        tokio::join!(client, server)
+    }
+
+    #[tokio::test]
+    async fn password_is_picked_over_no_authentication() {
+        let (method, _) = negotiate(&[0x00, 0x02]).await;
+        assert_eq!(method, 0x02);
+    }
+
+    #[tokio::test]
+    async fn client_without_password_authentication_is_rejected() {
+        let (method, res) = negotiate(&[0x00]).await;
+        assert_eq!(method, 0xff);
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    /// Sends `greeting` to a socks5 server, then closes the connection, resetting it if `reset`, and returns the result of the server side handshake
+    async fn interrupted_handshake(greeting: &[u8], reset: bool) -> IoResult<()> {
+        let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Socks5Server::new(listener, Arc::new(password(&[("alice", "secret")])));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let (conn, _) = server.accept().await.unwrap();
+
+        stream.write_all(greeting).await.unwrap();
+
+        if reset {
+            SockRef::from(&stream)
+                .set_linger(Some(Duration::ZERO))
+                .unwrap();
+        }
+
+        drop(stream);
+        conn.handshake().await.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn handshake_reset_by_the_client_is_aborted() {
+        // the version and the number of methods, but not the methods
+        let err = interrupted_handshake(&[0x05, 0x02], true)
+            .await
+            .unwrap_err();
+        assert!(is_aborted_handshake(&err), "{err:?}");
+
+        let err = interrupted_handshake(&[0x05, 0x02], false)
+            .await
+            .unwrap_err();
+        assert!(is_aborted_handshake(&err), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn handshake_protocol_error_is_not_aborted() {
+        // socks4
+        let err = interrupted_handshake(&[0x04, 0x01, 0x00], false)
+            .await
+            .unwrap_err();
+        assert!(!is_aborted_handshake(&err), "{err:?}");
+    }
+
+    /// Writes a successful reply with the BND address `addr`, returning the bytes written and the reply read back from them
+    async fn reply_round_trip(addr: SocketAddr) -> (Vec<u8>, Response) {
+        let mut buf = Vec::new();
+        Response::new(Reply::Succeeded, Address::SocketAddress(addr))
+            .write_to(&mut buf)
+            .await
+            .unwrap();
+
+        let resp = Response::read_from(&mut buf.as_slice()).await.unwrap();
+        (buf, resp)
+    }
+
+    #[tokio::test]
+    async fn ipv6_bind_address_is_replied_in_full() {
+        let addr = SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 1080));
+        let (buf, resp) = reply_round_trip(addr).await;
+
+        // VER, REP, RSV, ATYP of IPv6, 16 bytes of address and 2 of port
+        assert_eq!(buf[3], 0x04);
+        assert_eq!(buf.len(), 4 + 16 + 2);
+        assert_eq!(resp.address, Address::SocketAddress(addr));
+    }
+
+    #[tokio::test]
+    async fn ipv4_mapped_bind_address_is_replied_as_ipv4() {
+        let mapped = Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped();
+        let addr = SocketAddr::from((canonical_ip(IpAddr::V6(mapped)), 1080));
+        let (buf, resp) = reply_round_trip(addr).await;
+
+        assert_eq!(buf[3], 0x01);
+        assert_eq!(buf.len(), 4 + 4 + 2);
+        assert_eq!(
+            resp.address,
+            Address::SocketAddress(SocketAddr::from(([192, 0, 2, 1], 1080)))
+        );
+    }
+
+    #[test]
+    fn credentials_table_and_single_login_are_merged() {
+        let cfg = local(
+            r#"{ "server": "127.0.0.1:1080", "username": "alice", "password": "secret", "credentials": { "bob": "hunter2", "carol": "pa55" } }"#,
+        );
+        let auth = Password::new(credentials(&cfg).unwrap(), Arc::default());
+
+        assert_eq!(auth.find(b"alice", b"secret").as_deref(), Some("alice"));
+        assert_eq!(auth.find(b"bob", b"hunter2").as_deref(), Some("bob"));
+        assert_eq!(auth.find(b"carol", b"pa55").as_deref(), Some("carol"));
+        assert_eq!(auth.find(b"bob", b"pa55"), None);
+        assert_eq!(auth.find(b"dave", b"hunter2"), None);
+    }
+
+    #[test]
+    fn single_login_needs_both_username_and_password() {
+        let cfg = local(r#"{ "server": "127.0.0.1:1080", "username": "alice" }"#);
+        assert!(matches!(credentials(&cfg), Err(Error::InvalidSocks5Auth)));
+    }
+
+    fn destination(host: &str) -> Address {
+        Address::DomainAddress(host.to_owned(), 443)
+    }
+
+    #[test]
+    fn relays_to_a_destination_are_limited() {
+        let destinations = Destinations::default();
+
+        let first = destinations.acquire(&destination("example.com"), 2);
+        let second = destinations.acquire(&destination("EXAMPLE.com"), 2);
+        assert!(first.is_some() && second.is_some());
+        assert!(destinations
+            .acquire(&destination("example.com"), 2)
+            .is_none());
+
+        // other destinations, including another port, have a count of their own
+        assert!(destinations
+            .acquire(&destination("example.org"), 2)
+            .is_some());
+        assert!(destinations
+            .acquire(&Address::DomainAddress("example.com".to_owned(), 80), 2)
+            .is_some());
+    }
+
+    #[test]
+    fn closed_relays_are_no_longer_counted() {
+        let destinations = Destinations::default();
+
+        let first = destinations.acquire(&destination("example.com"), 1);
+        assert!(destinations
+            .acquire(&destination("example.com"), 1)
+            .is_none());
+
+        drop(first);
+        let second = destinations.acquire(&destination("example.com"), 1);
+        assert!(second.is_some());
+
+        drop(second);
+        assert!(destinations.0.lock().is_empty());
+    }
+
+    fn domain_target(domain: &str) -> TuicAddress {
+        tuic_address(Address::DomainAddress(domain.to_owned(), 443))
+    }
+
+    #[test]
+    fn ipv6_literal_domain_is_an_address() {
+        let addr = TuicAddress::SocketAddress(SocketAddr::from((Ipv6Addr::LOCALHOST, 443)));
+
+        assert_eq!(domain_target("::1"), addr);
+        assert_eq!(domain_target("[::1]"), addr);
+    }
+
+    #[test]
+    fn ipv4_literal_domain_is_an_address() {
+        assert_eq!(
+            domain_target("192.0.2.1"),
+            TuicAddress::SocketAddress(SocketAddr::from(([192, 0, 2, 1], 443)))
+        );
+    }
+
+    #[test]
+    fn names_stay_domains() {
+        for domain in ["example.com", "[example.com]", "[192.0.2.1", "::1]"] {
+            assert_eq!(
+                domain_target(domain),
+                TuicAddress::DomainAddress(domain.to_owned(), 443)
+            );
         }
     }
+
+    #[test]
+    fn port_file_holds_the_port() {
+        let dir = std::env::temp_dir().join(format!("tuic-client-port-file-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("port");
+
+        let listener = StdTcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        write_port(&path, 1080).unwrap();
+        write_port(&path, port).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{port}\n"));
+
+        // nothing is left aside
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,414 @@
+//! Session state carried over restarts in `state_file`: the address every server was last reached at, the TLS session tickets and the quota usage. It is loaded at startup and saved on shutdown
+//!
+//! With `state_key`, the file is `MAGIC`, a PBKDF2 salt, a nonce, then the JSON sealed with ChaCha20-Poly1305
+
+use crate::{
+    connection::Endpoint,
+    quota::{Quotas, Usage},
+    Error,
+};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    pbkdf2,
+};
+use rustls::client::StoresClientSessions;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    net::SocketAddr,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+static STATE: OnceCell<State> = OnceCell::new();
+
+/// Bumped on every incompatible change of `Snapshot`. Files of another version are ignored
+const VERSION: u32 = 1;
+
+/// Starts an encrypted file. A plaintext file starts with `{`
+const MAGIC: &[u8] = b"TUIC-STATE-ENC-1";
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+pub struct State {
+    path: PathBuf,
+    key: Option<String>,
+    /// Handed out piece by piece to the components restoring from it
+    loaded: Mutex<Snapshot>,
+    sessions: OnceCell<Arc<SessionCache>>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct Snapshot {
+    version: u32,
+    servers: HashMap<String, SocketAddr>,
+    sessions: Vec<(Vec<u8>, Vec<u8>)>,
+    quota: Option<Usage>,
+}
+
+/// Read ahead of the rest, which may have any layout in another version
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+impl State {
+    pub fn set_config(
+        path: Option<PathBuf>,
+        key: Option<String>,
+        key_file: Option<PathBuf>,
+        migrate_plaintext: bool,
+    ) -> Result<(), Error> {
+        let key = match (key, key_file) {
+            (key, None) => key,
+            // secret mounts and editors usually end the file with a line break
+            (None, Some(key_file)) => Some(
+                fs::read_to_string(key_file)?
+                    .trim_end_matches(['\r', '\n'])
+                    .to_owned(),
+            ),
+            (Some(_), Some(_)) => return Err(Error::InvalidStateKey),
+        };
+
+        let Some(path) = path else {
+            return Ok(());
+        };
+
+        let loaded = match fs::read(&path) {
+            Ok(buf) => {
+                let buf = unseal(buf, key.as_deref(), migrate_plaintext, &path)?;
+
+                match serde_json::from_slice::<Version>(&buf) {
+                    Ok(Version { version: VERSION }) => match serde_json::from_slice(&buf) {
+                        Ok(snapshot) => {
+                            log::info!("[state] restored from {}", path.display());
+                            snapshot
+                        }
+                        Err(err) => {
+                            log::warn!("[state] ignoring invalid {}: {err}", path.display());
+                            Snapshot::default()
+                        }
+                    },
+                    Ok(Version { version }) => {
+                        log::warn!(
+                            "[state] ignoring {} of version {version}, expecting {VERSION}",
+                            path.display()
+                        );
+                        Snapshot::default()
+                    }
+                    Err(err) => {
+                        log::warn!("[state] ignoring invalid {}: {err}", path.display());
+                        Snapshot::default()
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => Snapshot::default(),
+            Err(err) => {
+                log::warn!("[state] failed to read {}: {err}", path.display());
+                Snapshot::default()
+            }
+        };
+
+        let state = Self {
+            path,
+            key,
+            loaded: Mutex::new(loaded),
+            sessions: OnceCell::new(),
+        };
+
+        STATE
+            .set(state)
+            .map_err(|_| "state already initialized")
+            .unwrap();
+
+        Ok(())
+    }
+
+    /// The address every server was last reached at, by server name and port
+    pub fn take_servers() -> HashMap<String, SocketAddr> {
+        STATE.get().map_or_else(HashMap::new, |state| {
+            std::mem::take(&mut state.loaded.lock().servers)
+        })
+    }
+
+    /// A session cache holding the saved TLS session tickets, whose tickets are saved again on shutdown. `None` without a state file
+    pub fn session_cache(size: usize) -> Option<Arc<SessionCache>> {
+        let state = STATE.get()?;
+
+        let cache = state.sessions.get_or_init(|| {
+            let cache = SessionCache::new(size);
+
+            for (key, value) in std::mem::take(&mut state.loaded.lock().sessions) {
+                cache.put(key, value);
+            }
+
+            Arc::new(cache)
+        });
+
+        Some(cache.clone())
+    }
+
+    pub fn take_quota_usage() -> Option<Usage> {
+        STATE
+            .get()
+            .and_then(|state| state.loaded.lock().quota.take())
+    }
+
+    /// Whether there is a state file to save on shutdown
+    pub fn is_enabled() -> bool {
+        STATE.get().is_some()
+    }
+
+    /// Saves the state to the state file, if any
+    pub fn save_on_shutdown() {
+        let Some(state) = STATE.get() else {
+            return;
+        };
+
+        match state.save() {
+            Ok(()) => log::info!("[state] saved to {}", state.path.display()),
+            Err(err) => log::warn!("[state] failed to save to {}: {err}", state.path.display()),
+        }
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let snapshot = Snapshot {
+            version: VERSION,
+            servers: Endpoint::known_servers(),
+            sessions: self
+                .sessions
+                .get()
+                .map_or_else(Vec::new, |cache| cache.entries()),
+            quota: Quotas::usage(),
+        };
+
+        let mut buf = serde_json::to_vec(&snapshot).unwrap();
+
+        if let Some(key) = &self.key {
+            buf = seal(key, buf);
+        }
+
+        // written aside and renamed, so a crash never leaves a truncated file behind
+        let tmp = self.path.with_extension("tmp");
+        let mut opts = OpenOptions::new();
+        opts.write(true).create(true).truncate(true);
+
+        // the session tickets hold TLS resumption secrets
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+
+        opts.open(&tmp)?.write_all(&buf)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+/// TLS session tickets, bounded like rustls' `ClientSessionMemoryCache`, dropping the oldest first, but able to list its entries for saving
+pub struct SessionCache {
+    size: usize,
+    entries: Mutex<Sessions>,
+}
+
+struct Sessions {
+    values: HashMap<Vec<u8>, Vec<u8>>,
+    order: VecDeque<Vec<u8>>,
+}
+
+impl SessionCache {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            entries: Mutex::new(Sessions {
+                values: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let entries = self.entries.lock();
+
+        entries
+            .order
+            .iter()
+            .filter_map(|key| Some((key.clone(), entries.values.get(key)?.clone())))
+            .collect()
+    }
+}
+
+impl StoresClientSessions for SessionCache {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        let mut entries = self.entries.lock();
+
+        if entries.values.insert(key.clone(), value).is_none() {
+            entries.order.push_back(key);
+        }
+
+        while entries.values.len() > self.size {
+            let Some(oldest) = entries.order.pop_front() else {
+                break;
+            };
+
+            entries.values.remove(&oldest);
+        }
+
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.entries.lock().values.get(key).cloned()
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> LessSafeKey {
+    let mut key = [0; 32];
+
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap())
+}
+
+/// `MAGIC`, salt, nonce and the sealed `plaintext`. Every call draws a new salt, so a nonce is never reused under the same key
+fn seal(passphrase: &str, mut plaintext: Vec<u8>) -> Vec<u8> {
+    let salt = rand::random::<[u8; SALT_LEN]>();
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+
+    derive_key(passphrase, &salt)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(MAGIC),
+            &mut plaintext,
+        )
+        .unwrap();
+
+    [MAGIC, &salt[..], &nonce[..], &plaintext[..]].concat()
+}
+
+/// The JSON in the read `buf`, opened with the `passphrase` if any. A plaintext file is only accepted without a passphrase or while migrating, as it could have been put there by anyone able to write the file
+fn unseal(
+    buf: Vec<u8>,
+    passphrase: Option<&str>,
+    migrate_plaintext: bool,
+    path: &Path,
+) -> Result<Vec<u8>, Error> {
+    match (buf.starts_with(MAGIC), passphrase) {
+        (true, Some(passphrase)) => {
+            if migrate_plaintext {
+                log::warn!(
+                    "[state] {} is already encrypted, `state_migrate_plaintext` can be unset",
+                    path.display()
+                );
+            }
+
+            open(passphrase, &buf[MAGIC.len()..])
+                .ok_or_else(|| Error::StateDecrypt(path.to_owned()))
+        }
+        (true, None) => Err(Error::StateDecrypt(path.to_owned())),
+        (false, Some(_)) if migrate_plaintext => {
+            log::warn!(
+                "[state] reading plaintext {}, it is encrypted when saved",
+                path.display()
+            );
+            Ok(buf)
+        }
+        (false, Some(_)) => Err(Error::StatePlaintext(path.to_owned())),
+        (false, None) => Ok(buf),
+    }
+}
+
+/// Opens what follows `MAGIC`, `None` if the key is wrong or the data was altered
+fn open(passphrase: &str, sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < SALT_LEN + NONCE_LEN {
+        return None;
+    }
+
+    let (salt, rest) = sealed.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let mut buf = ciphertext.to_vec();
+
+    let plaintext = derive_key(passphrase, salt)
+        .open_in_place(
+            Nonce::try_assume_unique_for_key(nonce).ok()?,
+            Aad::from(MAGIC),
+            &mut buf,
+        )
+        .ok()?;
+
+    Some(plaintext.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAINTEXT: &[u8] = br#"{"version":1}"#;
+
+    fn path() -> PathBuf {
+        PathBuf::from("state.json")
+    }
+
+    #[test]
+    fn sealed_file_opens_with_its_key_only() {
+        let sealed = seal("secret", PLAINTEXT.to_vec());
+
+        assert_eq!(
+            unseal(sealed.clone(), Some("secret"), false, &path()).unwrap(),
+            PLAINTEXT
+        );
+        assert!(matches!(
+            unseal(sealed.clone(), Some("other"), false, &path()),
+            Err(Error::StateDecrypt(_))
+        ));
+        assert!(matches!(
+            unseal(sealed, None, false, &path()),
+            Err(Error::StateDecrypt(_))
+        ));
+    }
+
+    #[test]
+    fn altered_file_is_not_opened() {
+        let mut sealed = seal("secret", PLAINTEXT.to_vec());
+        *sealed.last_mut().unwrap() ^= 1;
+
+        assert!(matches!(
+            unseal(sealed, Some("secret"), false, &path()),
+            Err(Error::StateDecrypt(_))
+        ));
+    }
+
+    #[test]
+    fn plaintext_file_is_rejected_with_a_key() {
+        assert!(matches!(
+            unseal(PLAINTEXT.to_vec(), Some("secret"), false, &path()),
+            Err(Error::StatePlaintext(_))
+        ));
+    }
+
+    #[test]
+    fn plaintext_file_is_read_while_migrating() {
+        assert_eq!(
+            unseal(PLAINTEXT.to_vec(), Some("secret"), true, &path()).unwrap(),
+            PLAINTEXT
+        );
+    }
+
+    #[test]
+    fn plaintext_file_is_read_without_a_key() {
+        assert_eq!(
+            unseal(PLAINTEXT.to_vec(), None, false, &path()).unwrap(),
+            PLAINTEXT
+        );
+    }
+}
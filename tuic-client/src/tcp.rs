@@ -0,0 +1,233 @@
+//! TUIC over TLS over TCP, for networks that block UDP. Every relay opens a TLS connection of its own to the server, authenticates on it and sends a single `Connect`
+
+use crate::{
+    connection::Password,
+    dialer::{DialTimings, Dialed},
+    utils::{ServerAddr, StreamCompression, Transport},
+    Error,
+};
+use once_cell::sync::OnceCell;
+use rustls::{ClientConfig as RustlsClientConfig, ServerName};
+use std::{
+    io::{Error as IoError, ErrorKind},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{self, Instant},
+};
+use tokio_rustls::TlsConnector;
+use tuic::{Address, Authenticate, Connect, Header};
+use uuid::Uuid;
+
+static TCP_TRANSPORT: OnceCell<TcpTransport> = OnceCell::new();
+
+/// The status byte the server answers the `Connect` with once the target is connected
+const STATUS_CONNECTED: u8 = 0x00;
+/// The status byte the server answers the `Connect` with if the target address did not resolve
+const STATUS_RESOLVE_FAILED: u8 = 0x01;
+
+pub struct TcpTransport {
+    mode: Transport,
+    fallback_timeout: Duration,
+    server: ServerAddr,
+    server_name: ServerName,
+    tls: TlsConnector,
+    uuid: Uuid,
+    password: Password,
+    timeout: Duration,
+}
+
+impl TcpTransport {
+    pub fn set_config(
+        mode: Transport,
+        fallback_timeout: Duration,
+        server: ServerAddr,
+        tls: RustlsClientConfig,
+        uuid: Uuid,
+        password: Password,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let server_name = match server.server_name().parse::<IpAddr>() {
+            Ok(ip) => ServerName::IpAddress(ip),
+            Err(_) => ServerName::try_from(server.server_name())
+                .map_err(|err| IoError::new(ErrorKind::InvalidInput, err))?,
+        };
+
+        let transport = Self {
+            mode,
+            fallback_timeout,
+            server,
+            server_name,
+            tls: TlsConnector::from(Arc::new(tls)),
+            uuid,
+            password,
+            timeout,
+        };
+
+        TCP_TRANSPORT
+            .set(transport)
+            .map_err(|_| "TCP transport already initialized")
+            .unwrap();
+
+        Ok(())
+    }
+
+    /// What the relays are carried over, with how long QUIC is given to connect in `auto` mode
+    pub fn mode() -> (Transport, Duration) {
+        TCP_TRANSPORT
+            .get()
+            .map_or((Transport::Quic, Duration::ZERO), |transport| {
+                (transport.mode, transport.fallback_timeout)
+            })
+    }
+
+    pub async fn connect(addr: Address) -> Result<Dialed, Error> {
+        let transport = TCP_TRANSPORT.get().unwrap();
+
+        time::timeout(transport.timeout, transport.connect_inner(addr))
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    async fn connect_inner(&self, addr: Address) -> Result<Dialed, Error> {
+        let start = Instant::now();
+        let mut last_err = None;
+        let mut stream = None;
+
+        for server_addr in self.server.resolve().await? {
+            match TcpStream::connect(server_addr).await {
+                Ok(s) => {
+                    stream = Some((s, server_addr));
+                    break;
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        let Some((stream, server_addr)) = stream else {
+            return Err(last_err.map_or(Error::DnsResolve, Error::from));
+        };
+
+        stream.set_nodelay(true)?;
+
+        let mut stream = self.tls.connect(self.server_name.clone(), stream).await?;
+        let acquired = Instant::now();
+
+        // the token is exported from this TLS session, exactly like from the QUIC one
+        let password = self.password.load()?;
+        let mut token = [0; 32];
+
+        stream
+            .get_ref()
+            .1
+            .export_keying_material(&mut token, self.uuid.as_ref(), Some(&*password))
+            .map_err(IoError::other)?;
+
+        let mut buf = Vec::new();
+        Header::Authenticate(Authenticate::new(self.uuid, token)).marshal(&mut buf)?;
+        Header::Connect(Connect::new(addr)).marshal(&mut buf)?;
+        stream.write_all(&buf).await?;
+
+        // the server closes the connection without a status if the authentication failed
+        match stream.read_u8().await? {
+            STATUS_CONNECTED => {}
+            STATUS_RESOLVE_FAILED => return Err(Error::TargetUnreachable("cannot resolve")),
+            _ => return Err(Error::TargetUnreachable("cannot connect to")),
+        }
+
+        Ok(Dialed {
+            stream: Box::new(stream),
+            via: Arc::from(format!("{} (tcp)", self.server)),
+            server_addr: Some(server_addr),
+            compression: StreamCompression::None,
+            // every stream has a TLS connection of its own
+            timings: DialTimings {
+                acquire: Some(acquired - start),
+                handshake: true,
+                open: acquired.elapsed(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::tests::client_crypto;
+    use rustls::{Certificate, PrivateKey, ServerConfig as RustlsServerConfig};
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// Authentication and `Connect` to an IPv4 address
+    const COMMANDS_LEN: usize = 2 + 16 + 32 + 2 + 1 + 4 + 2;
+
+    /// A transport to a loopback TLS server that answers the commands with `status`
+    async fn transport(status: u8) -> TcpTransport {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_der = Certificate(cert.serialize_der().unwrap());
+        let key = PrivateKey(cert.serialize_private_key_der());
+
+        let crypto = RustlsServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(crypto));
+
+        let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut buf = [0; COMMANDS_LEN];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&[status]).await.unwrap();
+            let _ = stream.shutdown().await;
+        });
+
+        TcpTransport {
+            mode: Transport::TcpFallback,
+            fallback_timeout: Duration::ZERO,
+            server: ServerAddr::new(
+                "localhost".to_owned(),
+                port,
+                Some(IpAddr::from(Ipv4Addr::LOCALHOST)),
+                false,
+            ),
+            server_name: ServerName::try_from("localhost").unwrap(),
+            tls: TlsConnector::from(Arc::new(client_crypto(&cert_der))),
+            uuid: Uuid::nil(),
+            password: Password::Inline(Arc::from(&b"password"[..])),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn target() -> Address {
+        Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 80)))
+    }
+
+    #[tokio::test]
+    async fn connected_target_is_relayed() {
+        let transport = transport(STATUS_CONNECTED).await;
+        assert!(transport.connect_inner(target()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn unreachable_target_fails_the_relay() {
+        let resolve_failed = transport(STATUS_RESOLVE_FAILED).await;
+        let err = resolve_failed.connect_inner(target()).await.err().unwrap();
+        assert!(matches!(err, Error::TargetUnreachable("cannot resolve")));
+
+        let connect_failed = transport(0x02).await;
+        let err = connect_failed.connect_inner(target()).await.err().unwrap();
+        assert!(matches!(err, Error::TargetUnreachable("cannot connect to")));
+    }
+}
@@ -0,0 +1,265 @@
+//! UDP associations relayed through the TUIC connection, as a stream of the packets coming back from the server and a sink for the packets going out. The socks5 UDP associate is built on it, other consumers can inspect or rewrite payloads the same way, once [`Client::run()`](crate::Client::run) started the client
+//!
+//! A destination that is unreachable is never reported back: TUIC v5 has no command for the server to report a failed UDP send, e.g. an ICMP port unreachable, and socks5 has no error channel for UDP either, so the packets are silently lost. Applications have to detect a dead UDP endpoint with their own timeouts
+
+use crate::{connection::Connection as TuicConnection, metrics::UdpStats, utils, Error};
+use bytes::Bytes;
+use futures_core::Stream;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tuic::Address;
+
+static ASSOCIATIONS: Lazy<Mutex<HashMap<u16, PacketSender>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_ASSOC_ID: AtomicU16 = AtomicU16::new(0);
+
+/// Hands the packets received from the server to an association, along with the counters of the ones dropped
+type PacketSender = (Sender<(Bytes, Address)>, Arc<UdpStats>);
+
+/// Packets from the server waiting for the consumer of an association. Beyond it, packets are dropped like on a full socket buffer
+const QUEUE_SIZE: usize = 64;
+
+/// Opens a new association. It is closed on the server once both halves are dropped. Fails with `Error::AssociationsExhausted` if all 65536 association IDs are in use
+pub fn associate() -> Result<(PacketSink, PacketStream), Error> {
+    let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+    let mut assocs = ASSOCIATIONS.lock();
+
+    // IDs wrap around, skip the ones still held by a live association
+    let assoc_id = (0..=u16::MAX)
+        .map(|_| NEXT_ASSOC_ID.fetch_add(1, Ordering::AcqRel))
+        .find(|assoc_id| !assocs.contains_key(assoc_id))
+        .ok_or(Error::AssociationsExhausted)?;
+
+    let stats = UdpStats::register(assoc_id);
+    assocs.insert(assoc_id, (tx, stats.clone()));
+    drop(assocs);
+
+    let reg = Arc::new(Registration {
+        assoc_id,
+        conn: Mutex::new(None),
+        stats,
+    });
+
+    let sink = PacketSink {
+        assoc_id,
+        reg: reg.clone(),
+    };
+
+    let stream = PacketStream { rx, _reg: reg };
+
+    Ok((sink, stream))
+}
+
+/// Number of open associations
+pub(crate) fn count() -> usize {
+    ASSOCIATIONS.lock().len()
+}
+
+/// Hands a packet from the server to its association, dropping it if the association is closed or its queue is full. Packets dropped on a full queue count in the stats of the association
+pub(crate) fn deliver(pkt: Bytes, addr: Address, assoc_id: u16) {
+    // packets may still be in flight after the association is torn down
+    let Some((tx, stats)) = ASSOCIATIONS.lock().get(&assoc_id).cloned() else {
+        log::debug!("[udp] [{assoc_id:#06x}] dropped packet for closed association");
+        return;
+    };
+
+    match tx.try_send((pkt, addr)) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            log::debug!("[udp] [{assoc_id:#06x}] queue full, dropped packet");
+            stats.inc_dropped();
+        }
+        Err(TrySendError::Closed(_)) => {
+            log::debug!("[udp] [{assoc_id:#06x}] dropped packet for closed association")
+        }
+    }
+}
+
+/// Sends the packets of an association to the server
+pub struct PacketSink {
+    assoc_id: u16,
+    reg: Arc<Registration>,
+}
+
+impl PacketSink {
+    /// The association ID, as seen by the server
+    pub fn id(&self) -> u16 {
+        self.assoc_id
+    }
+
+    /// Packet counters of the association, exported with the metrics until both halves are dropped
+    pub(crate) fn stats(&self) -> &Arc<UdpStats> {
+        &self.reg.stats
+    }
+
+    /// Relays a packet to `addr` through the server, returning `false` if it was dropped because the datagram send buffer is full
+    pub async fn send(&self, pkt: Bytes, addr: Address) -> Result<bool, Error> {
+        let conn = TuicConnection::get().await?;
+        self.reg.bind(&conn);
+        conn.packet(pkt, addr, self.assoc_id).await
+    }
+}
+
+/// The packets the server relays back to an association, with the address each came from
+pub struct PacketStream {
+    rx: Receiver<(Bytes, Address)>,
+    _reg: Arc<Registration>,
+}
+
+impl PacketStream {
+    /// Waits for the next packet from the server and the address it came from, `None` once the association is closed
+    pub async fn recv(&mut self) -> Option<(Bytes, Address)> {
+        self.rx.recv().await
+    }
+}
+
+impl Stream for PacketStream {
+    type Item = (Bytes, Address);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+struct Registration {
+    assoc_id: u16,
+    /// The connection the packets of the association were last sent on, where the server keeps the association
+    conn: Mutex<Option<TuicConnection>>,
+    stats: Arc<UdpStats>,
+}
+
+impl Registration {
+    /// Records that the association is relayed on `conn`. If it moves over from another connection that is still open, e.g. a draining one, it is dissociated there
+    fn bind(&self, conn: &TuicConnection) {
+        let mut current = self.conn.lock();
+
+        if current
+            .as_ref()
+            .is_some_and(|current| current.is_same(conn))
+        {
+            return;
+        }
+
+        if let Some(prev) = current.replace(conn.clone()) {
+            dissociate(self.assoc_id, prev);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let assoc_id = self.assoc_id;
+        ASSOCIATIONS.lock().remove(&assoc_id);
+        UdpStats::unregister(assoc_id);
+
+        match self.conn.lock().take() {
+            Some(conn) => dissociate(assoc_id, conn),
+            None => {
+                log::debug!("[connection] [dissociate] [{assoc_id:#06x}] no packet sent, skipped")
+            }
+        }
+    }
+}
+
+/// Frees association `assoc_id` on the server of `conn`. If that connection is gone, so is the association on the server, and reconnecting just to dissociate would be pointless
+fn dissociate(assoc_id: u16, conn: TuicConnection) {
+    if conn.is_closed() {
+        log::debug!("[connection] [dissociate] [{assoc_id:#06x}] connection closed, skipped");
+        return;
+    }
+
+    utils::spawn(format_args!("dissociate {assoc_id:#06x}"), async move {
+        match conn.dissociate(assoc_id).await {
+            Ok(()) => log::debug!("[connection] [dissociate] [{assoc_id:#06x}] sent"),
+            Err(err) => log::debug!("[connection] [dissociate] [{assoc_id:#06x}] {err}"),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::tests::connect_loopback;
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::time;
+    use tuic_quinn::Task;
+
+    #[tokio::test]
+    async fn teardown_dissociates_on_the_owning_connection() {
+        let (conn, server, server_conn) = connect_loopback().await;
+        let (sink, stream) = associate().unwrap();
+        let assoc_id = sink.id();
+        sink.reg.bind(&conn);
+
+        // the association lives on while either half does
+        drop(sink);
+        let pending = time::timeout(Duration::from_millis(100), server_conn.accept_uni()).await;
+        assert!(pending.is_err());
+
+        drop(stream);
+
+        let recv = server_conn.accept_uni().await.unwrap();
+        let task = server.accept_uni_stream(recv).await.unwrap();
+        assert!(matches!(task, Task::Dissociate(id) if id == assoc_id));
+    }
+
+    #[tokio::test]
+    async fn moving_to_another_connection_dissociates_on_the_previous_one() {
+        let (prev, prev_server, prev_server_conn) = connect_loopback().await;
+        let (next, next_server, next_server_conn) = connect_loopback().await;
+        let (sink, stream) = associate().unwrap();
+        let assoc_id = sink.id();
+
+        sink.reg.bind(&prev);
+        sink.reg.bind(&prev);
+        sink.reg.bind(&next);
+
+        let recv = prev_server_conn.accept_uni().await.unwrap();
+        let task = prev_server.accept_uni_stream(recv).await.unwrap();
+        assert!(matches!(task, Task::Dissociate(id) if id == assoc_id));
+
+        drop((sink, stream));
+
+        let recv = next_server_conn.accept_uni().await.unwrap();
+        let task = next_server.accept_uni_stream(recv).await.unwrap();
+        assert!(matches!(task, Task::Dissociate(id) if id == assoc_id));
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_are_counted() {
+        let (sink, mut stream) = associate().unwrap();
+        let addr = Address::SocketAddress(SocketAddr::from(([127, 0, 0, 1], 53)));
+
+        for _ in 0..QUEUE_SIZE + 2 {
+            deliver(Bytes::from_static(b"pkt"), addr.clone(), sink.id());
+        }
+
+        assert_eq!(sink.stats().get(), (0, 0, 2));
+
+        // the queue holds the packets that fit
+        for _ in 0..QUEUE_SIZE {
+            assert!(stream.recv().await.is_some());
+        }
+    }
+
+    #[test]
+    fn wrapped_ids_skip_live_associations() {
+        let (live, _live_stream) = associate().unwrap();
+
+        // the next ID wraps onto the live association
+        NEXT_ASSOC_ID.store(live.id(), Ordering::Release);
+
+        let (next, _next_stream) = associate().unwrap();
+        assert_ne!(next.id(), live.id());
+    }
+}
@@ -0,0 +1,243 @@
+//! Reaching the server through the UDP associate of an upstream socks5 proxy, for networks that only let traffic out through it
+//!
+//! The QUIC endpoint is pointed at a loopback socket, which wraps every packet in a socks5 UDP header for the proxy and unwraps the packets coming back. The socket is connected to the endpoint, so other local processes can neither send through the association nor receive its packets
+
+use crate::{utils, Error};
+use bytes::BytesMut;
+use socks5_proto::{
+    handshake::{HandshakeRequest, HandshakeResponse},
+    Address, Command, HandshakeMethod, Reply, Request, Response, UdpHeader,
+};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::{
+    io::AsyncReadExt,
+    net::{self, TcpStream, UdpSocket},
+    task::JoinHandle,
+};
+
+/// A UDP association on the proxy, relaying to one server. It is released when dropped
+pub struct UdpRelay {
+    local_addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl UdpRelay {
+    /// Asks `proxy` for a UDP association and starts relaying packets between it and a new loopback socket, which only exchanges packets with `endpoint`, the address of the QUIC endpoint
+    pub async fn open(
+        proxy: SocketAddr,
+        server: SocketAddr,
+        endpoint: SocketAddr,
+    ) -> Result<Self, Error> {
+        let mut control = TcpStream::connect(proxy).await?;
+
+        HandshakeRequest::new(vec![HandshakeMethod::None])
+            .write_to(&mut control)
+            .await?;
+
+        let resp = HandshakeResponse::read_from(&mut control).await?;
+
+        if resp.method != HandshakeMethod::None {
+            return Err(Error::UpstreamProxy(String::from(
+                "proxy requires authentication",
+            )));
+        }
+
+        // the address the packets will come from is not known yet, so it is left unspecified
+        Request::new(Command::Associate, Address::unspecified())
+            .write_to(&mut control)
+            .await?;
+
+        let resp = Response::read_from(&mut control).await?;
+
+        if resp.reply != Reply::Succeeded {
+            return Err(Error::UpstreamProxy(format!(
+                "UDP associate refused with {:?}",
+                resp.reply
+            )));
+        }
+
+        let relay_addr = match resp.address {
+            // proxies commonly reply with an unspecified address, meaning their own
+            Address::SocketAddress(addr) if addr.ip().is_unspecified() => {
+                SocketAddr::from((proxy.ip(), addr.port()))
+            }
+            Address::SocketAddress(addr) => addr,
+            Address::DomainAddress(domain, port) => net::lookup_host((domain.as_str(), port))
+                .await?
+                .next()
+                .ok_or(Error::DnsResolve)?,
+        };
+
+        let local = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await?;
+        local.connect(endpoint).await?;
+        let local_addr = local.local_addr()?;
+
+        let bind_addr = if relay_addr.is_ipv4() {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+
+        let remote = UdpSocket::bind(bind_addr).await?;
+        remote.connect(relay_addr).await?;
+
+        log::info!("[upstream] [{proxy}] UDP associate established, relaying to {server} through {relay_addr}");
+
+        let task = utils::spawn(
+            format_args!("upstream {proxy}"),
+            relay(control, local, remote, server),
+        );
+
+        Ok(Self { local_addr, task })
+    }
+
+    /// The address the QUIC endpoint should send the packets for the server to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for UdpRelay {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Runs until the proxy closes the control connection, which ends the association
+async fn relay(mut control: TcpStream, local: UdpSocket, remote: UdpSocket, server: SocketAddr) {
+    let header = UdpHeader::new(0, Address::SocketAddress(server));
+    let mut up = vec![0; u16::MAX as usize];
+    let mut down = vec![0; u16::MAX as usize];
+    let mut control_buf = [0; 1];
+
+    loop {
+        tokio::select! {
+            res = local.recv(&mut up) => {
+                let len = match res {
+                    Ok(len) => len,
+                    Err(err) => {
+                        log::warn!("[upstream] failed to receive from the QUIC endpoint: {err}");
+                        continue;
+                    }
+                };
+
+                let mut pkt = BytesMut::with_capacity(header.serialized_len() + len);
+                header.write_to_buf(&mut pkt);
+                pkt.extend_from_slice(&up[..len]);
+
+                if let Err(err) = remote.send(&pkt).await {
+                    log::debug!("[upstream] failed to send to the proxy: {err}");
+                }
+            }
+            res = remote.recv(&mut down) => {
+                let len = match res {
+                    Ok(len) => len,
+                    Err(err) => {
+                        log::debug!("[upstream] failed to receive from the proxy: {err}");
+                        continue;
+                    }
+                };
+
+                let mut pkt = &down[..len];
+
+                let resp_header = match UdpHeader::read_from(&mut pkt).await {
+                    Ok(header) => header,
+                    Err(err) => {
+                        log::debug!("[upstream] dropped invalid packet from the proxy: {err}");
+                        continue;
+                    }
+                };
+
+                if resp_header.frag != 0 {
+                    log::debug!("[upstream] dropped fragmented packet from the proxy");
+                    continue;
+                }
+
+                if let Err(err) = local.send(pkt).await {
+                    log::debug!("[upstream] failed to send to the QUIC endpoint: {err}");
+                }
+            }
+            _ = control.read(&mut control_buf) => {
+                log::warn!("[upstream] proxy closed the UDP associate");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{net::TcpListener, time};
+
+    /// A socks5 proxy answering a single UDP associate, returning its address, the socket the association sends from and the control connection
+    async fn proxy() -> (SocketAddr, UdpSocket, JoinHandle<TcpStream>) {
+        let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let udp = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+        let udp_addr = udp.local_addr().unwrap();
+
+        let control = tokio::spawn(async move {
+            let (mut control, _) = listener.accept().await.unwrap();
+            HandshakeRequest::read_from(&mut control).await.unwrap();
+            HandshakeResponse::new(HandshakeMethod::None)
+                .write_to(&mut control)
+                .await
+                .unwrap();
+            Request::read_from(&mut control).await.unwrap();
+            Response::new(Reply::Succeeded, Address::SocketAddress(udp_addr))
+                .write_to(&mut control)
+                .await
+                .unwrap();
+            control
+        });
+
+        (addr, udp, control)
+    }
+
+    #[tokio::test]
+    async fn only_packets_of_the_endpoint_are_relayed() {
+        let (proxy_addr, proxy_udp, control) = proxy().await;
+        let server = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 443));
+
+        let endpoint = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+        let other = UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap();
+
+        let relay = UdpRelay::open(proxy_addr, server, endpoint.local_addr().unwrap())
+            .await
+            .unwrap();
+        let _control = control.await.unwrap();
+
+        other.send_to(b"other", relay.local_addr()).await.unwrap();
+        endpoint.send_to(b"quic", relay.local_addr()).await.unwrap();
+
+        // the packet of the other socket is dropped by the connected socket, so the first to arrive is the endpoint's
+        let mut buf = [0; 64];
+        let (len, from) = proxy_udp.recv_from(&mut buf).await.unwrap();
+        let mut pkt = &buf[..len];
+        let header = UdpHeader::read_from(&mut pkt).await.unwrap();
+        assert_eq!(header.address, Address::SocketAddress(server));
+        assert_eq!(pkt, b"quic");
+
+        let res = time::timeout(Duration::from_millis(100), proxy_udp.recv_from(&mut buf)).await;
+        assert!(res.is_err());
+
+        // and the packets coming back go to the endpoint
+        let mut resp = BytesMut::new();
+        UdpHeader::new(0, Address::SocketAddress(server)).write_to_buf(&mut resp);
+        resp.extend_from_slice(b"back");
+        proxy_udp.send_to(&resp, from).await.unwrap();
+
+        let len = endpoint.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"back");
+    }
+}
@@ -1,14 +1,78 @@
-use crate::Error;
+#[cfg(feature = "geoip")]
+use crate::geoip::GeoIp;
+use crate::{resolver::Resolver, Error};
+use env_logger::fmt::Formatter;
+use log::{
+    kv::{Error as KvError, Key, Value, VisitSource},
+    Record,
+};
 use rustls::{Certificate, RootCertStore};
 use rustls_pemfile::Item;
+use serde_json::{Map, Value as JsonValue};
+use socks5_proto::Reply;
+#[cfg(all(feature = "tokio-console", tokio_unstable))]
+use std::future;
 use std::{
+    fmt::{self, Display},
     fs::{self, File},
-    io::BufReader,
+    future::Future,
+    io::{BufReader, Error as IoError, Write},
     net::{IpAddr, SocketAddr},
     path::PathBuf,
     str::FromStr,
+    time::Duration,
 };
-use tokio::net;
+use tokio::{net, task::JoinHandle};
+#[cfg(all(feature = "tokio-console", tokio_unstable))]
+use tokio::{sync::oneshot, task};
+use tuic::Address;
+
+/// Spawns a task. With the `tokio-console` feature the task is named `name` so it can be told apart in tokio-console
+///
+/// Like tokio-console itself, naming tasks requires building with `RUSTFLAGS="--cfg tokio_unstable"`, without it tasks are spawned unnamed
+#[track_caller]
+pub fn spawn<F>(name: fmt::Arguments<'_>, fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "tokio-console", tokio_unstable))]
+    {
+        // the future is handed to the named task once it is spawned, so it is still spawned unnamed if that fails
+        let (tx, rx) = oneshot::channel::<F>();
+
+        let named = task::Builder::new()
+            .name(&name.to_string())
+            .spawn(async move {
+                match rx.await {
+                    Ok(fut) => fut.await,
+                    Err(_) => future::pending().await,
+                }
+            });
+
+        match named {
+            Ok(handle) => {
+                let _ = tx.send(fut);
+                handle
+            }
+            Err(err) => {
+                log::warn!("[task] failed to name task `{name}`: {err}");
+                tokio::spawn(fut)
+            }
+        }
+    }
+
+    #[cfg(not(all(feature = "tokio-console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::spawn(fut)
+    }
+}
+
+/// Spreads `interval` randomly by up to `jitter` times its length either way, so that clients started together do not stay in step
+pub fn jitter(interval: Duration, jitter: f64) -> Duration {
+    interval.mul_f64(1.0 + jitter * (2.0 * rand::random::<f64>() - 1.0))
+}
 
 pub fn load_certs(paths: Vec<PathBuf>, disable_native: bool) -> Result<RootCertStore, Error> {
     let mut certs = RootCertStore::empty();
@@ -42,11 +106,18 @@ pub struct ServerAddr {
     domain: String,
     port: u16,
     ip: Option<IpAddr>,
+    /// Discover the addresses through the `SRV` records of `_tuic._udp.<domain>` first
+    srv: bool,
 }
 
 impl ServerAddr {
-    pub fn new(domain: String, port: u16, ip: Option<IpAddr>) -> Self {
-        Self { domain, port, ip }
+    pub fn new(domain: String, port: u16, ip: Option<IpAddr>, srv: bool) -> Self {
+        Self {
+            domain,
+            port,
+            ip,
+            srv,
+        }
     }
 
     pub fn server_name(&self) -> &str {
@@ -55,13 +126,57 @@ impl ServerAddr {
 
     pub async fn resolve(&self) -> Result<impl Iterator<Item = SocketAddr>, Error> {
         if let Some(ip) = self.ip {
-            Ok(vec![SocketAddr::from((ip, self.port))].into_iter())
-        } else {
-            Ok(net::lookup_host((self.domain.as_str(), self.port))
-                .await?
-                .collect::<Vec<_>>()
-                .into_iter())
+            return Ok(vec![SocketAddr::from((ip, self.port))].into_iter());
         }
+
+        if self.srv {
+            if let Some(addrs) = self.resolve_srv().await {
+                return Ok(addrs.into_iter());
+            }
+        }
+
+        Ok(net::lookup_host((self.domain.as_str(), self.port))
+            .await?
+            .collect::<Vec<_>>()
+            .into_iter())
+    }
+
+    /// The addresses of the `SRV` targets, in the order to try them in. `None` if there are no usable records
+    async fn resolve_srv(&self) -> Option<Vec<SocketAddr>> {
+        let name = format!("_tuic._udp.{}", self.domain);
+
+        let records = match Resolver::lookup_srv(&name).await {
+            Ok(records) if !records.is_empty() => records,
+            Ok(_) => {
+                log::debug!("[connection] no SRV records for {name}, resolving {self}");
+                return None;
+            }
+            Err(err) => {
+                log::warn!("[connection] failed to look up SRV records for {name}: {err}, resolving {self}");
+                return None;
+            }
+        };
+
+        let mut addrs = Vec::new();
+
+        for record in records {
+            match net::lookup_host((record.target.as_str(), record.port)).await {
+                Ok(resolved) => addrs.extend(resolved),
+                Err(err) => log::warn!(
+                    "[connection] failed to resolve SRV target {}:{}: {err}",
+                    record.target,
+                    record.port
+                ),
+            }
+        }
+
+        (!addrs.is_empty()).then_some(addrs)
+    }
+}
+
+impl Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.domain, self.port)
     }
 }
 
@@ -85,6 +200,259 @@ impl FromStr for UdpRelayMode {
     }
 }
 
+/// A socks5 reply code other than `Succeeded`, named in snake case, e.g. `connection_refused`
+#[derive(Clone, Copy)]
+pub struct FailureReply(pub Reply);
+
+impl FromStr for FailureReply {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const REPLIES: [(&str, Reply); 8] = [
+            ("general_failure", Reply::GeneralFailure),
+            ("connection_not_allowed", Reply::ConnectionNotAllowed),
+            ("network_unreachable", Reply::NetworkUnreachable),
+            ("host_unreachable", Reply::HostUnreachable),
+            ("connection_refused", Reply::ConnectionRefused),
+            ("ttl_expired", Reply::TtlExpired),
+            ("command_not_supported", Reply::CommandNotSupported),
+            ("address_type_not_supported", Reply::AddressTypeNotSupported),
+        ];
+
+        REPLIES
+            .iter()
+            .find(|(name, _)| s.eq_ignore_ascii_case(name))
+            .map(|(_, reply)| Self(*reply))
+            .ok_or("invalid socks5 failure reply")
+    }
+}
+
+/// What the BND fields of a successful CONNECT reply carry
+#[derive(Clone, Copy)]
+pub enum ReplyBindMode {
+    /// `0.0.0.0:0`, which every client accepts
+    Zero,
+    /// `0.0.0.0` with the target port, for clients that expect a meaningful BND.PORT
+    EchoPort,
+    /// The local address of the socks5 connection, i.e. the proxy address the client connected to
+    RelaySocket,
+    /// The address of the TUIC server the relay goes through, `0.0.0.0:0` for relays routed directly
+    Server,
+    /// A fixed address
+    Custom(SocketAddr),
+}
+
+impl FromStr for ReplyBindMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("zero") {
+            Ok(Self::Zero)
+        } else if s.eq_ignore_ascii_case("echo_port") {
+            Ok(Self::EchoPort)
+        } else if s.eq_ignore_ascii_case("relay_socket") {
+            Ok(Self::RelaySocket)
+        } else if s.eq_ignore_ascii_case("server") {
+            Ok(Self::Server)
+        } else {
+            s.parse()
+                .map(Self::Custom)
+                .map_err(|_| "invalid reply bind mode")
+        }
+    }
+}
+
+/// When a CONNECT request is replied to
+#[derive(Clone, Copy)]
+pub enum ReplyTiming {
+    /// After the relay is opened, so that a failure can be replied
+    AfterConnect,
+    /// Right away, before the relay is opened
+    Immediate,
+}
+
+impl FromStr for ReplyTiming {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("after_connect") {
+            Ok(Self::AfterConnect)
+        } else if s.eq_ignore_ascii_case("immediate") {
+            Ok(Self::Immediate)
+        } else {
+            Err("invalid reply timing")
+        }
+    }
+}
+
+/// The line format of `access_log`
+#[derive(Clone, Copy)]
+pub enum AccessLogFormat {
+    /// The Common Log Format of web servers, with the upload size and the duration appended
+    Common,
+    /// The W3C Extended Log File Format, with a `#Fields` directive at the start of the file
+    W3c,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("common") {
+            Ok(Self::Common)
+        } else if s.eq_ignore_ascii_case("w3c") {
+            Ok(Self::W3c)
+        } else {
+            Err("invalid access log format")
+        }
+    }
+}
+
+/// How the client picks the server profile to connect to
+pub enum ProfileSelection {
+    /// Keep using the profile that last connected, moving on to the next one only on failure
+    Failover,
+    /// Move on to the next profile on every new connection
+    RoundRobin,
+}
+
+impl FromStr for ProfileSelection {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("failover") {
+            Ok(Self::Failover)
+        } else if s.eq_ignore_ascii_case("round_robin") {
+            Ok(Self::RoundRobin)
+        } else {
+            Err("invalid profile selection policy")
+        }
+    }
+}
+
+/// What the relays to the server are carried over
+#[derive(Clone, Copy)]
+pub enum Transport {
+    Quic,
+    /// TLS over TCP, one connection per relay
+    TcpFallback,
+    /// QUIC, switching to TCP when it does not connect in time
+    Auto,
+}
+
+impl FromStr for Transport {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("quic") {
+            Ok(Self::Quic)
+        } else if s.eq_ignore_ascii_case("tcp_fallback") {
+            Ok(Self::TcpFallback)
+        } else if s.eq_ignore_ascii_case("auto") {
+            Ok(Self::Auto)
+        } else {
+            Err("invalid transport")
+        }
+    }
+}
+
+/// Which address family to connect to first when the server resolves to both
+#[derive(Clone, Copy)]
+pub enum IpPreference {
+    /// The order the resolver returned
+    System,
+    V4,
+    V6,
+    /// Race the first address of each family, IPv6 first with a head start
+    HappyEyeballs,
+}
+
+impl IpPreference {
+    /// Orders `addrs` so the preferred family is tried first. The order within each family is kept
+    pub fn sort(self, addrs: &mut [SocketAddr]) {
+        match self {
+            Self::System | Self::HappyEyeballs => {}
+            Self::V4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            Self::V6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+        }
+    }
+}
+
+impl FromStr for IpPreference {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("system") {
+            Ok(Self::System)
+        } else if s.eq_ignore_ascii_case("v4") {
+            Ok(Self::V4)
+        } else if s.eq_ignore_ascii_case("v6") {
+            Ok(Self::V6)
+        } else if s.eq_ignore_ascii_case("happy_eyeballs") {
+            Ok(Self::HappyEyeballs)
+        } else {
+            Err("invalid IP preference")
+        }
+    }
+}
+
+/// What to do with a native mode UDP packet that does not fit in a single QUIC datagram
+#[derive(Clone, Copy)]
+pub enum UdpOversizePolicy {
+    Fragment,
+    Stream,
+}
+
+impl FromStr for UdpOversizePolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("fragment") {
+            Ok(Self::Fragment)
+        } else if s.eq_ignore_ascii_case("stream") {
+            Ok(Self::Stream)
+        } else {
+            Err("invalid UDP oversize policy")
+        }
+    }
+}
+
+/// Compression requested for relayed TCP streams, written as `none`, `zstd` or `zstd:<level>`
+#[derive(Clone, Copy)]
+pub enum StreamCompression {
+    None,
+    #[cfg(feature = "compression")]
+    Zstd(i8),
+}
+
+impl FromStr for StreamCompression {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algo, level) = match s.split_once(':') {
+            Some((algo, level)) => (algo, Some(level)),
+            None => (s, None),
+        };
+
+        if algo.eq_ignore_ascii_case("none") && level.is_none() {
+            Ok(Self::None)
+        } else if algo.eq_ignore_ascii_case("zstd") {
+            #[cfg(feature = "compression")]
+            return match level.map(str::parse::<i8>) {
+                None => Ok(Self::Zstd(3)),
+                Some(Ok(level)) if level <= 22 => Ok(Self::Zstd(level)),
+                Some(_) => Err("invalid zstd compression level"),
+            };
+
+            #[cfg(not(feature = "compression"))]
+            Err("stream compression requires the `compression` feature")
+        } else {
+            Err("invalid stream compression")
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
 pub enum CongestionControl {
     Cubic,
     NewReno,
@@ -106,3 +474,460 @@ impl FromStr for CongestionControl {
         }
     }
 }
+
+pub enum DnsUpstream {
+    System,
+    Plain(SocketAddr),
+    Https(String, u16, String),
+    Tls(String, u16),
+}
+
+impl FromStr for DnsUpstream {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn split_host_port(s: &str, default_port: u16) -> Result<(String, u16), &'static str> {
+            let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+                let (host, rest) = rest.split_once(']').ok_or("invalid DNS server address")?;
+                (host, rest.strip_prefix(':'))
+            } else if let Some((host, port)) = s.rsplit_once(':') {
+                (host, Some(port))
+            } else {
+                (s, None)
+            };
+
+            let port = match port {
+                Some(port) => port.parse().map_err(|_| "invalid DNS server port")?,
+                None => default_port,
+            };
+
+            if host.is_empty() {
+                return Err("invalid DNS server address");
+            }
+
+            Ok((host.to_owned(), port))
+        }
+
+        if s.eq_ignore_ascii_case("system") {
+            Ok(Self::System)
+        } else if let Some(rest) = s.strip_prefix("https://") {
+            let (authority, path) = match rest.find('/') {
+                Some(pos) => rest.split_at(pos),
+                None => (rest, "/dns-query"),
+            };
+            let (host, port) = split_host_port(authority, 443)?;
+            Ok(Self::Https(host, port, path.to_owned()))
+        } else if let Some(rest) = s.strip_prefix("tls://") {
+            let (host, port) = split_host_port(rest, 853)?;
+            Ok(Self::Tls(host, port))
+        } else {
+            let rest = s.strip_prefix("udp://").unwrap_or(s);
+            let (host, port) = split_host_port(rest, 53)?;
+            let ip = host.parse().map_err(|_| "invalid DNS server address")?;
+            Ok(Self::Plain(SocketAddr::new(ip, port)))
+        }
+    }
+}
+
+/// A destination that may bypass the tunnel: a domain with all its subdomains, or an IP CIDR block
+pub enum Bypass {
+    Domain(String),
+    Cidr(IpAddr, u8),
+}
+
+impl Bypass {
+    pub fn matches(&self, addr: &Address) -> bool {
+        match (self, addr) {
+            (Self::Domain(domain), Address::DomainAddress(target, _)) => {
+                domain_has_suffix(target, domain)
+            }
+            (Self::Cidr(net, prefix), Address::SocketAddress(target)) => {
+                ip_in_cidr(target.ip(), *net, *prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Bypass {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (s, None),
+        };
+
+        match (ip.parse::<IpAddr>(), prefix) {
+            (Ok(ip), prefix) => {
+                let max = if ip.is_ipv4() { 32 } else { 128 };
+
+                let prefix = match prefix {
+                    Some(prefix) => prefix.parse().map_err(|_| "invalid CIDR prefix")?,
+                    None => max,
+                };
+
+                if prefix > max {
+                    return Err("invalid CIDR prefix");
+                }
+
+                Ok(Self::Cidr(ip, prefix))
+            }
+            (Err(_), None) if !s.trim_matches('.').is_empty() => {
+                Ok(Self::Domain(s.trim_matches('.').to_owned()))
+            }
+            _ => Err("invalid bypass rule"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum RouteAction {
+    Tunnel,
+    Direct,
+    Reject,
+}
+
+impl FromStr for RouteAction {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("tunnel") {
+            Ok(Self::Tunnel)
+        } else if s.eq_ignore_ascii_case("direct") {
+            Ok(Self::Direct)
+        } else if s.eq_ignore_ascii_case("reject") {
+            Ok(Self::Reject)
+        } else {
+            Err("invalid routing action")
+        }
+    }
+}
+
+/// What routing does when a rule cannot be evaluated
+#[derive(Clone, Copy)]
+pub enum RuleEvalFailure {
+    /// Send the connection through the tunnel
+    FailOpen,
+    /// Reject the connection
+    FailClosed,
+}
+
+impl FromStr for RuleEvalFailure {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("fail_open") {
+            Ok(Self::FailOpen)
+        } else if s.eq_ignore_ascii_case("fail_closed") {
+            Ok(Self::FailClosed)
+        } else {
+            Err("invalid rule evaluation failure policy")
+        }
+    }
+}
+
+/// Destination matcher of a routing rule, written as `<type>:<value>`
+pub enum RouteMatcher {
+    Domain(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    IpCidr(IpAddr, u8),
+    #[cfg(feature = "geoip")]
+    GeoIp(String),
+    Port(u16, u16),
+}
+
+impl RouteMatcher {
+    /// Whether matching requires the IP address of the target
+    pub fn needs_ip(&self) -> bool {
+        match self {
+            Self::IpCidr(..) => true,
+            #[cfg(feature = "geoip")]
+            Self::GeoIp(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Domain matchers are checked against the requested domain, the others against the resolved socket address
+    ///
+    /// Fails if the rule cannot be evaluated: an IP-based rule for a target that could not be resolved, or a GeoIP rule without a usable database
+    pub fn matches(&self, requested: &Address, resolved: &Address) -> Result<bool, &'static str> {
+        match (self, requested, resolved) {
+            (Self::Domain(domain), Address::DomainAddress(target, _), _) => {
+                Ok(target.trim_end_matches('.').eq_ignore_ascii_case(domain))
+            }
+            (Self::DomainSuffix(suffix), Address::DomainAddress(target, _), _) => {
+                Ok(domain_has_suffix(target, suffix))
+            }
+            (Self::DomainKeyword(keyword), Address::DomainAddress(target, _), _) => {
+                Ok(target.to_ascii_lowercase().contains(keyword))
+            }
+            (Self::IpCidr(net, prefix), _, Address::SocketAddress(target)) => {
+                Ok(ip_in_cidr(target.ip(), *net, *prefix))
+            }
+            #[cfg(feature = "geoip")]
+            (Self::GeoIp(_), _, Address::SocketAddress(_)) if !GeoIp::is_available() => {
+                Err("GeoIP database unavailable")
+            }
+            #[cfg(feature = "geoip")]
+            (Self::GeoIp(code), _, Address::SocketAddress(target)) => {
+                Ok(GeoIp::country(target.ip()).as_deref() == Some(code.as_str()))
+            }
+            (Self::Port(start, end), _, Address::SocketAddress(target)) => {
+                Ok((*start..=*end).contains(&target.port()))
+            }
+            (Self::Port(start, end), _, Address::DomainAddress(_, port)) => {
+                Ok((*start..=*end).contains(port))
+            }
+            (_, _, Address::DomainAddress(..)) if self.needs_ip() => Err("target not resolved"),
+            _ => Ok(false),
+        }
+    }
+}
+
+impl FromStr for RouteMatcher {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s.split_once(':').ok_or("invalid routing matcher")?;
+        let domain = value.trim_matches('.').to_ascii_lowercase();
+
+        if kind.eq_ignore_ascii_case("domain") && !domain.is_empty() {
+            Ok(Self::Domain(domain))
+        } else if kind.eq_ignore_ascii_case("domain-suffix") && !domain.is_empty() {
+            Ok(Self::DomainSuffix(domain))
+        } else if kind.eq_ignore_ascii_case("domain-keyword") && !domain.is_empty() {
+            Ok(Self::DomainKeyword(domain))
+        } else if kind.eq_ignore_ascii_case("ip-cidr") {
+            match Bypass::from_str(value) {
+                Ok(Bypass::Cidr(net, prefix)) => Ok(Self::IpCidr(net, prefix)),
+                _ => Err("invalid CIDR in routing matcher"),
+            }
+        } else if kind.eq_ignore_ascii_case("geoip") {
+            #[cfg(feature = "geoip")]
+            return if value.len() == 2 {
+                Ok(Self::GeoIp(value.to_ascii_uppercase()))
+            } else {
+                Err("invalid country code in routing matcher")
+            };
+
+            #[cfg(not(feature = "geoip"))]
+            Err("geoip matchers require the `geoip` feature")
+        } else if kind.eq_ignore_ascii_case("port") {
+            let (start, end) = value.split_once('-').unwrap_or((value, value));
+
+            match (start.parse(), end.parse()) {
+                (Ok(start), Ok(end)) if start <= end => Ok(Self::Port(start, end)),
+                _ => Err("invalid port range in routing matcher"),
+            }
+        } else {
+            Err("invalid routing matcher")
+        }
+    }
+}
+
+fn domain_has_suffix(target: &str, suffix: &str) -> bool {
+    // compared as bytes, as the suffix may start inside a multibyte character of the target
+    let target = target.trim_end_matches('.').as_bytes();
+    let suffix = suffix.as_bytes();
+
+    target.len() >= suffix.len()
+        && target[target.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+        && (target.len() == suffix.len() || target[target.len() - suffix.len() - 1] == b'.')
+}
+
+fn ip_in_cidr(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("text") {
+            Ok(Self::Text)
+        } else if s.eq_ignore_ascii_case("json") {
+            Ok(Self::Json)
+        } else {
+            Err("invalid log format")
+        }
+    }
+}
+
+/// Formats a log record as a single-line JSON object. Key-values attached to the record (e.g. `peer`, `target`, `bytes_up`) are flattened into the object
+pub fn format_json_log(buf: &mut Formatter, record: &Record) -> Result<(), IoError> {
+    struct Fields<'a>(&'a mut Map<String, JsonValue>);
+
+    impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), KvError> {
+            if let Ok(value) = serde_json::to_value(value) {
+                self.0.insert(key.as_str().to_owned(), value);
+            }
+
+            Ok(())
+        }
+    }
+
+    let mut obj = Map::new();
+    obj.insert("time".to_owned(), buf.timestamp().to_string().into());
+    obj.insert("level".to_owned(), record.level().as_str().into());
+    obj.insert("message".to_owned(), record.args().to_string().into());
+
+    let _ = record.key_values().visit(&mut Fields(&mut obj));
+
+    writeln!(buf, "{}", JsonValue::Object(obj))
+}
+
+/// Waits for SIGINT or, on Unix, SIGTERM. Until it is called, the signals keep their default handling of ending the process
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{self, SignalKind};
+
+        match unix::signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = term.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+            Err(err) => {
+                log::warn!("[signal] failed to listen for SIGTERM: {err}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{net::Ipv6Addr, pin::pin};
+    use tokio::time::{self, Instant};
+
+    /// Runs `fut` to completion, returning its output and the time it took on the clock of the test, which under `start_paused` only moves when every task waits on a timer
+    pub(crate) async fn timed<F: Future>(fut: F) -> (F::Output, Duration) {
+        let start = Instant::now();
+        let output = fut.await;
+        (output, start.elapsed())
+    }
+
+    /// Asserts `fut` is still pending once `duration` has passed on the clock of the test
+    pub(crate) async fn assert_pending_for<F: Future + Unpin>(fut: &mut F, duration: Duration) {
+        tokio::select! {
+            biased;
+            _ = fut => panic!("completed within {duration:?}"),
+            () = time::sleep(duration) => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn server_without_srv_records_is_resolved_directly() {
+        // no `dns.resolver` to look up `SRV` records with
+        let server = ServerAddr::new("localhost".to_owned(), 443, None, true);
+        let addrs = server.resolve().await.unwrap().collect::<Vec<_>>();
+
+        assert!(!addrs.is_empty());
+        assert!(addrs
+            .iter()
+            .all(|addr| addr.ip().is_loopback() && addr.port() == 443));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_clock_drives_timeouts_without_waiting() {
+        let started = std::time::SystemTime::now();
+
+        let mut sleep = pin!(time::sleep(Duration::from_secs(3600)));
+        assert_pending_for(&mut sleep, Duration::from_secs(3599)).await;
+
+        let ((), elapsed) = timed(sleep).await;
+        assert_eq!(elapsed, Duration::from_secs(1));
+
+        let res = timed(time::timeout(
+            Duration::from_secs(60),
+            std::future::pending::<()>(),
+        ))
+        .await;
+        assert!(res.0.is_err());
+        assert_eq!(res.1, Duration::from_secs(60));
+
+        assert!(started.elapsed().unwrap() < Duration::from_secs(60));
+    }
+
+    fn suffix_matches(suffix: &str, target: &str) -> bool {
+        let matcher = RouteMatcher::from_str(&format!("domain-suffix:{suffix}")).unwrap();
+        let target = Address::DomainAddress(target.to_owned(), 443);
+        matcher.matches(&target, &target).unwrap()
+    }
+
+    #[test]
+    fn domain_suffix_matches_the_domain_and_its_subdomains() {
+        assert!(suffix_matches("example.com", "example.com"));
+        assert!(suffix_matches("example.com", "www.example.com"));
+        assert!(suffix_matches("example.com", "a.b.EXAMPLE.com"));
+        assert!(!suffix_matches("example.com", "notexample.com"));
+        assert!(!suffix_matches("example.com", "example.org"));
+        assert!(!suffix_matches("www.example.com", "example.com"));
+    }
+
+    #[test]
+    fn domain_suffix_ignores_trailing_dots() {
+        assert!(suffix_matches("example.com", "www.example.com."));
+        assert!(suffix_matches("example.com.", "www.example.com"));
+        assert!(suffix_matches(".example.com", "example.com.."));
+    }
+
+    #[test]
+    fn domain_suffix_handles_non_ascii_targets() {
+        // the suffix length falls inside a multibyte character
+        assert!(!suffix_matches("b.com", "ü.com"));
+        assert!(!suffix_matches("om", "例え.com"));
+        assert!(suffix_matches("com", "例え.com"));
+        assert!(suffix_matches("例え.jp", "www.例え.jp"));
+        assert!(!suffix_matches("例え.jp", "x例え.jp"));
+
+        let bypass = Bypass::from_str("example.com").unwrap();
+        assert!(!bypass.matches(&Address::DomainAddress("ééééé.com".to_owned(), 80)));
+    }
+
+    #[test]
+    fn ip_preference_puts_the_preferred_family_first() {
+        let v4_1 = SocketAddr::from(([192, 0, 2, 1], 443));
+        let v4_2 = SocketAddr::from(([192, 0, 2, 2], 443));
+        let v6_1 = SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 443));
+        let v6_2 = SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2), 443));
+        let resolved = [v6_1, v4_1, v6_2, v4_2];
+
+        let mut addrs = resolved;
+        IpPreference::V4.sort(&mut addrs);
+        assert_eq!(addrs, [v4_1, v4_2, v6_1, v6_2]);
+
+        let mut addrs = resolved;
+        IpPreference::V6.sort(&mut addrs);
+        assert_eq!(addrs, [v6_1, v6_2, v4_1, v4_2]);
+
+        let mut addrs = resolved;
+        IpPreference::System.sort(&mut addrs);
+        assert_eq!(addrs, resolved);
+    }
+}
@@ -5,6 +5,7 @@ use bytes::{BufMut, Bytes};
 use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use quinn::{
     Connection as QuinnConnection, ConnectionError, RecvStream, SendDatagramError, SendStream,
+    VarInt,
 };
 use std::{
     fmt::{Debug, Formatter, Result as FmtResult},
@@ -59,14 +60,26 @@ impl<Side> Connection<Side> {
         addr: Address,
         assoc_id: u16,
     ) -> Result<(), Error> {
-        let Some(max_pkt_size) = self.conn.max_datagram_size() else {
+        self.packet_native_clamped(pkt, addr, assoc_id, usize::MAX)
+    }
+
+    /// Sends a `Packet` using UDP relay mode `native`, fragmenting it into datagrams of at most `max_pkt_size` bytes, or of the max datagram size of the connection if smaller.
+    pub fn packet_native_clamped(
+        &self,
+        pkt: impl AsRef<[u8]>,
+        addr: Address,
+        assoc_id: u16,
+        max_pkt_size: usize,
+    ) -> Result<(), Error> {
+        let Some(max_datagram_size) = self.conn.max_datagram_size() else {
             return Err(Error::SendDatagram(SendDatagramError::Disabled));
         };
 
+        let max_pkt_size = max_pkt_size.min(max_datagram_size);
         let model = self.model.send_packet(assoc_id, addr, max_pkt_size);
 
         for (header, frag) in model.into_fragments(pkt) {
-            let mut buf = vec![0; header.len() + frag.len()];
+            let mut buf = Vec::with_capacity(header.len() + frag.len());
             header.write(&mut buf);
             buf.put_slice(frag);
             self.conn.send_datagram(Bytes::from(buf))?;
@@ -76,6 +89,8 @@ impl<Side> Connection<Side> {
     }
 
     /// Sends a `Packet` using UDP relay mode `quic`.
+    ///
+    /// Every fragment is sent on its own unidirectional stream, behind a header carrying its size, so the receiver reads exactly that many bytes however the stream is split into reads. Fragments hold at most 65535 bytes including the header, so a payload up to the UDP maximum of 65507 bytes is sent in at most two fragments and reassembled by the receiver.
     pub async fn packet_quic(
         &self,
         pkt: impl AsRef<[u8]>,
@@ -136,6 +151,33 @@ impl Connection<side::Client> {
         Ok(())
     }
 
+    /// Sends an `Authenticate` command followed by `label`, an opaque name of the client the server may use for logging or routing. A label longer than 255 bytes is truncated.
+    ///
+    /// The label is an extension of TUIC v5 that servers must support: the ones that do not may fail the authentication or close the connection on it. See the `Client label` section of the specification.
+    pub async fn authenticate_labeled(
+        &self,
+        uuid: Uuid,
+        password: impl AsRef<[u8]>,
+        label: impl AsRef<[u8]>,
+    ) -> Result<(), Error> {
+        let model = self
+            .model
+            .send_authenticate(uuid, password, &self.keying_material_exporter());
+
+        let label = label.as_ref();
+        let label = &label[..label.len().min(u8::MAX as usize)];
+
+        let mut buf = Vec::with_capacity(model.header().len() + 1 + label.len());
+        model.header().write(&mut buf);
+        buf.put_u8(label.len() as u8);
+        buf.put_slice(label);
+
+        let mut send = self.conn.open_uni().await?;
+        AsyncWriteExt::write_all(&mut send, &buf).await?;
+        send.close().await?;
+        Ok(())
+    }
+
     /// Sends a `Connect` command.
     pub async fn connect(&self, addr: Address) -> Result<Connect, Error> {
         let model = self.model.send_connect(addr);
@@ -144,6 +186,21 @@ impl Connection<side::Client> {
         Ok(Connect::new(Side::Client(model), send, recv))
     }
 
+    /// Sends a `Connect` command requesting stream compression with algorithm `algo` at `level`.
+    ///
+    /// The server answers with the algorithm it applies, see `Connect::recv_compression()`.
+    pub async fn connect_compressed(
+        &self,
+        addr: Address,
+        algo: u8,
+        level: i8,
+    ) -> Result<Connect, Error> {
+        let model = self.model.send_connect_compressed(addr, algo, level);
+        let (mut send, recv) = self.conn.open_bi().await?;
+        model.header().async_marshal(&mut send).await?;
+        Ok(Connect::new(Side::Client(model), send, recv))
+    }
+
     /// Sends a `Dissociate` command.
     pub async fn dissociate(&self, assoc_id: u16) -> Result<(), Error> {
         let model = self.model.send_dissociate(assoc_id);
@@ -232,7 +289,7 @@ impl Connection<side::Client> {
                 if let Some(pkt) = self.model.recv_packet(pkt) {
                     let pos = dg.position() as usize;
                     let mut buf = dg.into_inner();
-                    if (pos + pkt.size() as usize) < buf.len() {
+                    if (pos + pkt.size() as usize) <= buf.len() {
                         buf = buf.slice(pos..pos + pkt.size() as usize);
                         Ok(Task::Packet(Packet::new(pkt, PacketSource::Native(buf))))
                     } else {
@@ -274,6 +331,7 @@ impl Connection<side::Server> {
                 Ok(Task::Authenticate(Authenticate::new(
                     model,
                     self.keying_material_exporter(),
+                    recv,
                 )))
             }
             Header::Connect(_) => Err(Error::BadCommandUniStream("connect", recv)),
@@ -362,11 +420,20 @@ impl<Side> Debug for Connection<Side> {
 pub struct Authenticate {
     model: AuthenticateModel<Rx>,
     exporter: KeyingMaterialExporter,
+    recv: RecvStream,
 }
 
 impl Authenticate {
-    fn new(model: AuthenticateModel<Rx>, exporter: KeyingMaterialExporter) -> Self {
-        Self { model, exporter }
+    fn new(
+        model: AuthenticateModel<Rx>,
+        exporter: KeyingMaterialExporter,
+        recv: RecvStream,
+    ) -> Self {
+        Self {
+            model,
+            exporter,
+            recv,
+        }
     }
 
     /// The UUID of the client.
@@ -383,6 +450,20 @@ impl Authenticate {
     pub fn validate(&self, password: impl AsRef<[u8]>) -> bool {
         self.model.is_valid(password, &self.exporter)
     }
+
+    /// Reads the label the client sent after the command, `None` if it sent none. See `Connection::authenticate_labeled()`.
+    pub async fn label(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let mut len = [0; 1];
+
+        if AsyncReadExt::read(&mut self.recv, &mut len).await? == 0 {
+            return Ok(None);
+        }
+
+        let mut label = vec![0; len[0] as usize];
+        AsyncReadExt::read_exact(&mut self.recv, &mut label).await?;
+
+        Ok(Some(label))
+    }
 }
 
 /// A received `Connect` command.
@@ -401,6 +482,11 @@ impl Connect {
         Self { model, send, recv }
     }
 
+    /// Returns the streams the relay is carried on, e.g. to hand them to code that only knows quinn. The relay then no longer counts as a `Connect` task of the connection.
+    pub fn into_streams(self) -> (SendStream, RecvStream) {
+        (self.send, self.recv)
+    }
+
     /// Returns the `Connect` address
     pub fn addr(&self) -> &Address {
         match &self.model {
@@ -411,6 +497,47 @@ impl Connect {
             Side::Server(model) => model.addr(),
         }
     }
+
+    /// Returns the compression algorithm and level requested with the `Connect`, if any
+    pub fn compression(&self) -> Option<(u8, i8)> {
+        match &self.model {
+            Side::Client(model) => {
+                let Header::Connect(conn) = model.header() else { unreachable!() };
+                conn.compression()
+            }
+            Side::Server(model) => model.compression(),
+        }
+    }
+
+    /// Aborts the relay in both directions: resets the send stream and asks the peer to stop sending, both with `code`. The peer sees the relay as aborted instead of finished.
+    pub fn abort(&mut self, code: VarInt) {
+        // only fails once the stream is already finished, reset or stopped
+        let _ = self.send.reset(code);
+        let _ = self.recv.stop(code);
+    }
+
+    /// Sets the send priority of the stream. Data of streams with a higher priority is sent first, streams of the same priority are sent in turns. The default is 0.
+    pub fn set_priority(&self, priority: i32) {
+        // only fails once the stream is finished or reset, when there is nothing left to schedule
+        let _ = self.send.set_priority(priority);
+    }
+
+    /// Answers a `Connect` requesting compression with the algorithm the server applies to the stream, `0x00` for none.
+    ///
+    /// This must be called before any payload is sent.
+    pub async fn send_compression(&mut self, algo: u8) -> Result<(), Error> {
+        AsyncWriteExt::write_all(&mut self.send, &[algo]).await?;
+        Ok(())
+    }
+
+    /// Reads the answer of the server to a `Connect` requesting compression, i.e. the algorithm applied to the stream, `0x00` for none.
+    ///
+    /// This must be called before any payload is received.
+    pub async fn recv_compression(&mut self) -> Result<u8, Error> {
+        let mut buf = [0; 1];
+        AsyncReadExt::read_exact(&mut self.recv, &mut buf).await?;
+        Ok(buf[0])
+    }
 }
 
 impl AsyncRead for Connect {
@@ -552,3 +679,113 @@ pub enum Error {
     #[error("bad command `{0}` from datagram")]
     BadCommandDatagram(&'static str, Bytes),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::{ClientConfig, Endpoint, ServerConfig};
+    use rustls::{Certificate, PrivateKey, RootCertStore};
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tokio::time;
+
+    /// A QUIC connection over loopback, as the client and the server side of it
+    async fn connect() -> (QuinnConnection, QuinnConnection) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_der = Certificate(cert.serialize_der().unwrap());
+        let key = PrivateKey(cert.serialize_private_key_der());
+
+        let server_cfg = ServerConfig::with_single_cert(vec![cert_der.clone()], key).unwrap();
+        let server =
+            Endpoint::server(server_cfg, SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&cert_der).unwrap();
+        let client = Endpoint::client(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        let connecting = client
+            .connect_with(
+                ClientConfig::with_root_certificates(roots),
+                server.local_addr().unwrap(),
+                "localhost",
+            )
+            .unwrap();
+
+        let (client_conn, server_conn) =
+            tokio::join!(connecting, async { server.accept().await.unwrap().await });
+
+        (client_conn.unwrap(), server_conn.unwrap())
+    }
+
+    /// Accepts `Packet` streams on `conn` until a packet is fully assembled
+    async fn recv_packet_quic(conn: &QuinnConnection) -> (Bytes, Address, u16) {
+        let model = Connection::<side::Server>::new(conn.clone());
+
+        loop {
+            let recv = conn.accept_uni().await.unwrap();
+
+            match model.accept_uni_stream(recv).await.unwrap() {
+                Task::Packet(pkt) => {
+                    if let Some(res) = pkt.accept().await.unwrap() {
+                        return res;
+                    }
+                }
+                _ => panic!("expecting a packet"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn largest_udp_payload_over_quic() {
+        let (client_conn, server_conn) = connect().await;
+        let client = Connection::<side::Client>::new(client_conn);
+
+        let payload = (0..65507).map(|i| i as u8).collect::<Vec<_>>();
+        let addr = Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 53)));
+
+        let (sent, (pkt, recv_addr, assoc_id)) = tokio::join!(
+            client.packet_quic(&payload, addr.clone(), 0x1234),
+            recv_packet_quic(&server_conn),
+        );
+
+        sent.unwrap();
+        assert_eq!(&pkt[..], &payload[..]);
+        assert_eq!(recv_addr.to_string(), addr.to_string());
+        assert_eq!(assoc_id, 0x1234);
+    }
+
+    #[tokio::test]
+    async fn packet_over_quic_split_into_several_reads() {
+        let (client_conn, server_conn) = connect().await;
+
+        let payload = (0..4096).map(|i| i as u8).collect::<Vec<_>>();
+        let addr = Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 53)));
+        let header = Header::Packet(tuic::Packet::new(
+            0x1234,
+            0,
+            1,
+            0,
+            payload.len() as u16,
+            addr.clone(),
+        ));
+
+        let send = async {
+            let mut send = client_conn.open_uni().await.unwrap();
+            header.async_marshal(&mut send).await.unwrap();
+
+            // every chunk only arrives after the receiver has read the previous one
+            for chunk in payload.chunks(1000) {
+                AsyncWriteExt::write_all(&mut send, chunk).await.unwrap();
+                AsyncWriteExt::flush(&mut send).await.unwrap();
+                time::sleep(Duration::from_millis(20)).await;
+            }
+
+            // the receiver stops the stream once it read the payload, which may beat the finish
+            let _ = send.close().await;
+        };
+
+        let (_, (pkt, recv_addr, assoc_id)) = tokio::join!(send, recv_packet_quic(&server_conn));
+
+        assert_eq!(&pkt[..], &payload[..]);
+        assert_eq!(recv_addr.to_string(), addr.to_string());
+        assert_eq!(assoc_id, 0x1234);
+    }
+}
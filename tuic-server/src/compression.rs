@@ -0,0 +1,53 @@
+use async_compression::{
+    tokio::{bufread::ZstdDecoder, write::ZstdEncoder},
+    Level,
+};
+use std::io::Error as IoError;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// Highest zstd level granted to clients. Levels above it cost a lot more memory and CPU for little gain on relayed traffic
+pub const MAX_ZSTD_LEVEL: i32 = 19;
+
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Relays between a zstd compressed TUIC stream and a plain TCP stream until both directions are closed
+pub async fn relay_zstd<C, S>(conn: &mut C, stream: &mut S, level: i32) -> Result<(), IoError>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (conn_recv, conn_send) = io::split(conn);
+    let (mut stream_recv, mut stream_send) = io::split(stream);
+
+    let mut conn_recv = ZstdDecoder::new(BufReader::new(conn_recv));
+    let mut conn_send = ZstdEncoder::with_quality(conn_send, Level::Precise(level));
+
+    tokio::try_join!(
+        copy(&mut conn_recv, &mut stream_send),
+        copy(&mut stream_recv, &mut conn_send),
+    )?;
+
+    Ok(())
+}
+
+/// Copies until `reader` reaches EOF, then shuts `writer` down. `writer` is flushed after every write, so that an encoder does not hold back data the peer waits for
+async fn copy<R, W>(reader: &mut R, writer: &mut W) -> Result<(), IoError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; BUFFER_SIZE];
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+
+        if n == 0 {
+            break;
+        }
+
+        writer.write_all(&buf[..n]).await?;
+        writer.flush().await?;
+    }
+
+    writer.shutdown().await
+}
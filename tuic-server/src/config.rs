@@ -36,9 +36,15 @@ pub struct Config {
     pub alpn: Vec<String>,
     #[serde(default = "default::udp_relay_ipv6")]
     pub udp_relay_ipv6: bool,
+    /// Accept requests from clients to compress relayed TCP streams. Requires the `compression` feature
+    #[serde(default = "default::stream_compression")]
+    pub stream_compression: bool,
     #[serde(default = "default::zero_rtt_handshake")]
     pub zero_rtt_handshake: bool,
     pub dual_stack: Option<bool>,
+    /// Also listen on TCP at `server`, for clients whose network blocks UDP. Each TCP connection carries TLS with the same certificate and ALPN, then a single authenticated TCP relay. UDP relays are not available over it
+    #[serde(default = "default::tcp_fallback")]
+    pub tcp_fallback: bool,
     #[serde(default = "default::auth_timeout")]
     pub auth_timeout: Duration,
     #[serde(default = "default::max_idle_time")]
@@ -54,6 +60,7 @@ pub struct Config {
 }
 
 impl Config {
+    #[allow(clippy::collapsible_match)]
     pub fn parse(args: ArgsOs) -> Result<Self, ConfigError> {
         let mut parser = Parser::from_iter(args);
         let mut path = None;
@@ -101,10 +108,18 @@ mod default {
         true
     }
 
+    pub fn stream_compression() -> bool {
+        false
+    }
+
     pub fn zero_rtt_handshake() -> bool {
         false
     }
 
+    pub fn tcp_fallback() -> bool {
+        false
+    }
+
     pub fn auth_timeout() -> Duration {
         Duration::from_secs(3)
     }
@@ -7,12 +7,15 @@ use quinn::ConnectionError;
 use rustls::Error as RustlsError;
 use std::{env, io::Error as IoError, net::SocketAddr, process};
 use thiserror::Error;
-use tuic::Address;
+use tuic::{Address, UnmarshalError};
 use tuic_quinn::Error as ModelError;
 use uuid::Uuid;
 
+#[cfg(feature = "compression")]
+mod compression;
 mod config;
 mod server;
+mod tcp;
 mod utils;
 
 #[tokio::main]
@@ -52,14 +55,20 @@ pub enum Error {
     Connection(#[from] ConnectionError),
     #[error(transparent)]
     Model(#[from] ModelError),
+    #[error(transparent)]
+    Unmarshal(#[from] UnmarshalError),
     #[error("duplicated authentication")]
     DuplicatedAuth,
     #[error("token length too short")]
     ExportKeyingMaterial,
     #[error("authentication failed: {0}")]
     AuthFailed(Uuid),
-    #[error("received packet from unexpected source")]
-    UnexpectedPacketSource,
+    #[error("authentication timeout")]
+    AuthTimeout,
+    #[error("unexpected command on a TCP connection")]
+    UnexpectedTcpCommand,
+    #[error("no address resolved")]
+    NoAddressResolved,
     #[error("{0} resolved to {1} but IPv6 UDP relay disabled")]
     UdpRelayIpv6Disabled(Address, SocketAddr),
 }
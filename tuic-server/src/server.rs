@@ -1,5 +1,8 @@
+#[cfg(feature = "compression")]
+use crate::compression;
 use crate::{
     config::Config,
+    tcp,
     utils::{self, CongestionControl, UdpRelayMode},
     Error,
 };
@@ -18,7 +21,10 @@ use std::{
     collections::{hash_map::Entry, HashMap},
     future::Future,
     io::{Error as IoError, ErrorKind},
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket},
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener as StdTcpListener,
+        UdpSocket as StdUdpSocket,
+    },
     pin::Pin,
     sync::{
         atomic::{AtomicBool, AtomicUsize, Ordering},
@@ -29,7 +35,7 @@ use std::{
 };
 use tokio::{
     io::{self, AsyncWriteExt},
-    net::{self, TcpStream, UdpSocket},
+    net::{self, TcpListener, TcpStream, UdpSocket},
     sync::{
         oneshot::{self, Receiver, Sender},
         Mutex as AsyncMutex,
@@ -37,7 +43,7 @@ use tokio::{
     time,
 };
 use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tuic::Address;
+use tuic::{Address, Connect as ConnectHeader};
 use tuic_quinn::{side, Connect, Connection as Model, Packet, Task};
 use uuid::Uuid;
 
@@ -45,8 +51,10 @@ const DEFAULT_CONCURRENT_STREAMS: usize = 32;
 
 pub struct Server {
     ep: Endpoint,
+    tcp_fallback: Mutex<Option<(TcpListener, Arc<RustlsServerConfig>)>>,
     users: Arc<HashMap<Uuid, Vec<u8>>>,
     udp_relay_ipv6: bool,
+    stream_compression: bool,
     zero_rtt_handshake: bool,
     auth_timeout: Duration,
     max_external_pkt_size: usize,
@@ -71,6 +79,11 @@ impl Server {
         crypto.max_early_data_size = u32::MAX;
         crypto.send_half_rtt_data = cfg.zero_rtt_handshake;
 
+        // early data over TCP would have to be read separately, and the fallback is not latency-critical enough to bother
+        let mut tcp_crypto = crypto.clone();
+        tcp_crypto.max_early_data_size = 0;
+        tcp_crypto.send_half_rtt_data = false;
+
         let mut config = ServerConfig::with_crypto(Arc::new(crypto));
         let mut tp_cfg = TransportConfig::default();
 
@@ -109,6 +122,24 @@ impl Server {
         socket.bind(&SockAddr::from(cfg.server))?;
         let socket = StdUdpSocket::from(socket);
 
+        let tcp_fallback = if cfg.tcp_fallback {
+            let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+
+            if let Some(dual_stack) = cfg.dual_stack {
+                socket.set_only_v6(!dual_stack)?;
+            }
+
+            socket.set_reuse_address(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&SockAddr::from(cfg.server))?;
+            socket.listen(1024)?;
+
+            let listener = TcpListener::from_std(StdTcpListener::from(socket))?;
+            Some((listener, Arc::new(tcp_crypto)))
+        } else {
+            None
+        };
+
         let ep = Endpoint::new(
             EndpointConfig::default(),
             Some(config),
@@ -116,6 +147,11 @@ impl Server {
             TokioRuntime,
         )?;
 
+        #[cfg(not(feature = "compression"))]
+        if cfg.stream_compression {
+            eprintln!("built without the `compression` feature, stream compression requests will be declined");
+        }
+
         let users = cfg
             .users
             .into_iter()
@@ -124,8 +160,10 @@ impl Server {
 
         Ok(Self {
             ep,
+            tcp_fallback: Mutex::new(tcp_fallback),
             users: Arc::new(users),
             udp_relay_ipv6: cfg.udp_relay_ipv6,
+            stream_compression: cfg.stream_compression,
             zero_rtt_handshake: cfg.zero_rtt_handshake,
             auth_timeout: cfg.auth_timeout,
             max_external_pkt_size: cfg.max_external_packet_size,
@@ -135,6 +173,15 @@ impl Server {
     }
 
     pub async fn start(&self) {
+        if let Some((listener, crypto)) = self.tcp_fallback.lock().take() {
+            tokio::spawn(tcp::serve(
+                listener,
+                crypto,
+                self.users.clone(),
+                self.auth_timeout,
+            ));
+        }
+
         loop {
             let conn = self.ep.accept().await.unwrap();
 
@@ -142,6 +189,7 @@ impl Server {
                 conn,
                 self.users.clone(),
                 self.udp_relay_ipv6,
+                self.stream_compression,
                 self.zero_rtt_handshake,
                 self.auth_timeout,
                 self.max_external_pkt_size,
@@ -158,6 +206,7 @@ struct Connection {
     model: Model<side::Server>,
     users: Arc<HashMap<Uuid, Vec<u8>>>,
     udp_relay_ipv6: bool,
+    stream_compression: bool,
     is_authed: IsAuthed,
     udp_sessions: Arc<AsyncMutex<HashMap<u16, UdpSession>>>,
     udp_relay_mode: Arc<AtomicCell<Option<UdpRelayMode>>>,
@@ -174,6 +223,7 @@ impl Connection {
         conn: Connecting,
         users: Arc<HashMap<Uuid, Vec<u8>>>,
         udp_relay_ipv6: bool,
+        stream_compression: bool,
         zero_rtt_handshake: bool,
         auth_timeout: Duration,
         max_external_pkt_size: usize,
@@ -184,6 +234,7 @@ impl Connection {
             conn,
             users,
             udp_relay_ipv6,
+            stream_compression,
             zero_rtt_handshake,
             max_external_pkt_size,
         )
@@ -212,6 +263,7 @@ impl Connection {
         conn: Connecting,
         users: Arc<HashMap<Uuid, Vec<u8>>>,
         udp_relay_ipv6: bool,
+        stream_compression: bool,
         zero_rtt_handshake: bool,
         max_external_pkt_size: usize,
     ) -> Result<Self, Error> {
@@ -232,6 +284,7 @@ impl Connection {
             model: Model::<side::Server>::new(conn),
             users,
             udp_relay_ipv6,
+            stream_compression,
             is_authed: IsAuthed::new(),
             udp_sessions: Arc::new(AsyncMutex::new(HashMap::new())),
             udp_relay_mode: Arc::new(AtomicCell::new(None)),
@@ -267,6 +320,7 @@ impl Connection {
                 .set_max_concurrent_uni_streams(VarInt::from((max * 2) as u32));
         }
 
+        #[allow(clippy::unnecessary_map_or)]
         async fn pre_process(conn: &Connection, recv: RecvStream) -> Result<Task, Error> {
             let task = conn.model.accept_uni_stream(recv).await?;
 
@@ -289,19 +343,26 @@ impl Connection {
                 err = conn.inner.closed() => Err(err)?,
             };
 
-            let same_pkt_src = matches!(task, Task::Packet(_))
-                && matches!(conn.get_udp_relay_mode(), Some(UdpRelayMode::Native));
-            if same_pkt_src {
-                return Err(Error::UnexpectedPacketSource);
-            }
-
             Ok(task)
         }
 
         match pre_process(&self, recv).await {
-            Ok(Task::Authenticate(_)) => {}
+            Ok(Task::Authenticate(mut auth)) => match auth.label().await {
+                Ok(Some(label)) => eprintln!(
+                    "[{}] [{}] client label: {}",
+                    self.inner.remote_address(),
+                    auth.uuid(),
+                    String::from_utf8_lossy(&label)
+                ),
+                Ok(None) => {}
+                Err(err) => eprintln!("{err}"),
+            },
             Ok(Task::Packet(pkt)) => {
-                self.set_udp_relay_mode(UdpRelayMode::Quic);
+                // a native mode client may still send packets too large for a datagram over uni streams, so this must not override the native mode
+                if self.get_udp_relay_mode().is_none() {
+                    self.set_udp_relay_mode(UdpRelayMode::Quic);
+                }
+
                 match self.handle_packet(pkt).await {
                     Ok(()) => {}
                     Err(err) => eprintln!("{err}"),
@@ -367,12 +428,6 @@ impl Connection {
                 err = conn.inner.closed() => Err(err)?,
             };
 
-            let same_pkt_src = matches!(task, Task::Packet(_))
-                && matches!(conn.get_udp_relay_mode(), Some(UdpRelayMode::Quic));
-            if same_pkt_src {
-                return Err(Error::UnexpectedPacketSource);
-            }
-
             Ok(task)
         }
 
@@ -393,7 +448,22 @@ impl Connection {
         }
     }
 
-    async fn handle_connect(&self, conn: Connect) -> Result<(), Error> {
+    async fn handle_connect(&self, mut conn: Connect) -> Result<(), Error> {
+        // the answer is sent before dialing, so the client is not held up by the target
+        let compression = match conn.compression() {
+            Some((algo, level)) => {
+                let level = self.accept_compression(algo, level);
+                let answer = match level {
+                    Some(_) => algo,
+                    None => ConnectHeader::COMPRESSION_NONE,
+                };
+
+                conn.send_compression(answer).await?;
+                level
+            }
+            None => None,
+        };
+
         let mut stream = None;
         let mut last_err = None;
 
@@ -414,7 +484,15 @@ impl Connection {
 
         if let Some(mut stream) = stream {
             let mut conn = conn.compat();
-            let res = io::copy_bidirectional(&mut conn, &mut stream).await;
+
+            let res = match compression {
+                #[cfg(feature = "compression")]
+                Some(level) => compression::relay_zstd(&mut conn, &mut stream, level).await,
+                _ => io::copy_bidirectional(&mut conn, &mut stream)
+                    .await
+                    .map(|_| ()),
+            };
+
             let _ = conn.shutdown().await;
             let _ = stream.shutdown().await;
             res?;
@@ -426,6 +504,23 @@ impl Connection {
         }
     }
 
+    /// Returns the zstd level to compress the stream with, or `None` if the requested compression is declined
+    fn accept_compression(&self, algo: u8, level: i8) -> Option<i32> {
+        if !self.stream_compression {
+            return None;
+        }
+
+        #[cfg(feature = "compression")]
+        if algo == ConnectHeader::COMPRESSION_ZSTD {
+            return Some(i32::from(level).min(compression::MAX_ZSTD_LEVEL));
+        }
+
+        #[cfg(not(feature = "compression"))]
+        let _ = (algo, level);
+
+        None
+    }
+
     async fn handle_packet(&self, pkt: Packet) -> Result<(), Error> {
         let Some((pkt, addr, assoc_id)) = pkt.accept().await? else {
             return Ok(());
@@ -513,7 +608,7 @@ impl Connection {
     }
 }
 
-async fn resolve_dns(addr: &Address) -> Result<impl Iterator<Item = SocketAddr>, IoError> {
+pub async fn resolve_dns(addr: &Address) -> Result<impl Iterator<Item = SocketAddr>, IoError> {
     match addr {
         Address::None => Err(IoError::new(ErrorKind::InvalidInput, "empty address")),
         Address::DomainAddress(domain, port) => Ok(net::lookup_host((domain.as_str(), *port))
@@ -0,0 +1,142 @@
+//! TUIC over TLS over TCP, for clients whose network blocks UDP. Every TCP connection authenticates and then carries a single `Connect` relay
+
+use crate::{server, Error};
+use rustls::ServerConfig as RustlsServerConfig;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use tuic::Header;
+use uuid::Uuid;
+
+/// The status byte answering the `Connect`: the target is connected and the relay starts
+const STATUS_CONNECTED: u8 = 0x00;
+/// The status byte answering the `Connect`: the target address did not resolve to any address
+const STATUS_RESOLVE_FAILED: u8 = 0x01;
+/// The status byte answering the `Connect`: no resolved address of the target accepted the connection
+const STATUS_CONNECT_FAILED: u8 = 0x02;
+
+pub async fn serve(
+    listener: TcpListener,
+    crypto: Arc<RustlsServerConfig>,
+    users: Arc<HashMap<Uuid, Vec<u8>>>,
+    auth_timeout: Duration,
+) {
+    let acceptor = TlsAcceptor::from(crypto);
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(res) => res,
+            Err(err) => {
+                eprintln!("[tcp] {err}");
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let users = users.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, acceptor, users, auth_timeout).await {
+                eprintln!("[{addr}] [tcp] {err}");
+            }
+        });
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+    users: Arc<HashMap<Uuid, Vec<u8>>>,
+    auth_timeout: Duration,
+) -> Result<(), Error> {
+    stream.set_nodelay(true)?;
+
+    // the handshake, the authentication and the request must all arrive within the authentication timeout
+    let accept = async {
+        let mut stream = acceptor.accept(stream).await?;
+
+        let header = Header::async_unmarshal(&mut (&mut stream).compat()).await?;
+
+        let Header::Authenticate(auth) = header else {
+            return Err(Error::UnexpectedTcpCommand);
+        };
+
+        let mut token = [0; 32];
+
+        let is_valid = users.get(&auth.uuid()).is_some_and(|password| {
+            stream
+                .get_ref()
+                .1
+                .export_keying_material(&mut token, auth.uuid().as_ref(), Some(password.as_slice()))
+                .is_ok()
+                && token == auth.token()
+        });
+
+        if !is_valid {
+            return Err(Error::AuthFailed(auth.uuid()));
+        }
+
+        let header = Header::async_unmarshal(&mut (&mut stream).compat()).await?;
+
+        let Header::Connect(conn) = header else {
+            return Err(Error::UnexpectedTcpCommand);
+        };
+
+        Ok::<_, Error>((stream, conn))
+    };
+
+    let (mut stream, conn) = time::timeout(auth_timeout, accept)
+        .await
+        .map_err(|_| Error::AuthTimeout)??;
+
+    // compression is only negotiated over QUIC
+    if conn.compression().is_some() {
+        return Err(Error::UnexpectedTcpCommand);
+    }
+
+    let addrs = match server::resolve_dns(conn.addr()).await {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            let _ = stream.write_all(&[STATUS_RESOLVE_FAILED]).await;
+            let _ = stream.shutdown().await;
+            return Err(Error::from(err));
+        }
+    };
+
+    let mut target = None;
+    let mut last_err = None;
+
+    for addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(s) => {
+                target = Some(s);
+                break;
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    let Some(mut target) = target else {
+        let status = match last_err {
+            Some(_) => STATUS_CONNECT_FAILED,
+            None => STATUS_RESOLVE_FAILED,
+        };
+        let _ = stream.write_all(&[status]).await;
+        let _ = stream.shutdown().await;
+        return Err(last_err.map_or(Error::NoAddressResolved, Error::from));
+    };
+
+    stream.write_all(&[STATUS_CONNECTED]).await?;
+
+    let res = io::copy_bidirectional(&mut stream, &mut target).await;
+    let _ = stream.shutdown().await;
+    let _ = target.shutdown().await;
+    res?;
+
+    Ok(())
+}
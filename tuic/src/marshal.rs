@@ -10,7 +10,7 @@ impl Header {
     /// Marshals the header into an `AsyncWrite` stream
     #[cfg(feature = "async_marshal")]
     pub async fn async_marshal(&self, s: &mut (impl AsyncWrite + Unpin)) -> Result<(), IoError> {
-        let mut buf = vec![0; self.len()];
+        let mut buf = Vec::with_capacity(self.len());
         self.write(&mut buf);
         s.write_all(&buf).await
     }
@@ -18,7 +18,7 @@ impl Header {
     /// Marshals the header into a `Write` stream
     #[cfg(feature = "marshal")]
     pub fn marshal(&self, s: &mut impl Write) -> Result<(), IoError> {
-        let mut buf = vec![0; self.len()];
+        let mut buf = Vec::with_capacity(self.len());
         self.write(&mut buf);
         s.write_all(&buf)
     }
@@ -73,6 +73,11 @@ impl Authenticate {
 impl Connect {
     fn write(&self, buf: &mut impl BufMut) {
         self.addr().write(buf);
+
+        if let Some((algo, level)) = self.compression() {
+            buf.put_u8(algo);
+            buf.put_i8(level);
+        }
     }
 }
 
@@ -96,3 +101,18 @@ impl Dissociate {
 impl Heartbeat {
     fn write(&self, _buf: &mut impl BufMut) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marshal_writes_only_the_header() {
+        let header = Header::Dissociate(Dissociate::new(0x1234));
+        let mut buf = Vec::new();
+        header.marshal(&mut buf).unwrap();
+
+        assert_eq!(buf, [VERSION, Header::TYPE_CODE_DISSOCIATE, 0x12, 0x34]);
+        assert_eq!(buf.len(), header.len());
+    }
+}
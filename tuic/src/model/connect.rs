@@ -15,10 +15,10 @@ struct Tx {
 }
 
 impl Connect<side::Tx> {
-    pub(super) fn new(task_reg: Register, addr: Address) -> Self {
+    pub(super) fn new(task_reg: Register, header: ConnectHeader) -> Self {
         Self {
             inner: Side::Tx(Tx {
-                header: Header::Connect(ConnectHeader::new(addr)),
+                header: Header::Connect(header),
                 _task_reg: task_reg,
             }),
             _marker: side::Tx,
@@ -43,14 +43,16 @@ impl Debug for Connect<side::Tx> {
 
 struct Rx {
     addr: Address,
+    compression: Option<(u8, i8)>,
     _task_reg: Register,
 }
 
 impl Connect<side::Rx> {
-    pub(super) fn new(task_reg: Register, addr: Address) -> Self {
+    pub(super) fn new(task_reg: Register, addr: Address, compression: Option<(u8, i8)>) -> Self {
         Self {
             inner: Side::Rx(Rx {
                 addr,
+                compression,
                 _task_reg: task_reg,
             }),
             _marker: side::Rx,
@@ -62,11 +64,20 @@ impl Connect<side::Rx> {
         let Side::Rx(rx) = &self.inner else { unreachable!() };
         &rx.addr
     }
+
+    /// Returns the compression algorithm and level requested by the peer, if any
+    pub fn compression(&self) -> Option<(u8, i8)> {
+        let Side::Rx(rx) = &self.inner else { unreachable!() };
+        rx.compression
+    }
 }
 
 impl Debug for Connect<side::Rx> {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         let Side::Rx(rx) = &self.inner else { unreachable!() };
-        f.debug_struct("Connect").field("addr", &rx.addr).finish()
+        f.debug_struct("Connect")
+            .field("addr", &rx.addr)
+            .field("compression", &rx.compression)
+            .finish()
     }
 }
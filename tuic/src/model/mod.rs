@@ -75,13 +75,22 @@ where
 
     /// Sends a `Connect`
     pub fn send_connect(&self, addr: Address) -> Connect<side::Tx> {
-        Connect::<side::Tx>::new(self.task_connect_count.reg(), addr)
+        Connect::<side::Tx>::new(self.task_connect_count.reg(), ConnectHeader::new(addr))
+    }
+
+    /// Sends a `Connect` requesting stream compression with algorithm `algo` at `level`
+    pub fn send_connect_compressed(&self, addr: Address, algo: u8, level: i8) -> Connect<side::Tx> {
+        Connect::<side::Tx>::new(
+            self.task_connect_count.reg(),
+            ConnectHeader::with_compression(addr, algo, level),
+        )
     }
 
     /// Receives a `Connect`
     pub fn recv_connect(&self, header: ConnectHeader) -> Connect<side::Rx> {
+        let compression = header.compression();
         let (addr,) = header.into();
-        Connect::<side::Rx>::new(self.task_connect_count.reg(), addr)
+        Connect::<side::Rx>::new(self.task_connect_count.reg(), addr, compression)
     }
 
     /// Sends a `Packet`
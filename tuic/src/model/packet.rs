@@ -178,12 +178,13 @@ where
     P: AsRef<[u8]> + 'a,
 {
     fn new(assoc_id: u16, pkt_id: u16, addr: Address, max_pkt_size: usize, payload: P) -> Self {
-        let first_frag_size = max_pkt_size - PacketHeader::len_without_addr() - addr.len();
+        let first_frag_size = max_pkt_size - Self::header_len_without_addr() - addr.len();
         let frag_size_addr_none =
-            max_pkt_size - PacketHeader::len_without_addr() - Address::None.len();
+            max_pkt_size - Self::header_len_without_addr() - Address::None.len();
 
         let frag_total = if first_frag_size < payload.as_ref().len() {
-            (1 + (payload.as_ref().len() - first_frag_size) / frag_size_addr_none + 1) as u8
+            let rest = payload.as_ref().len() - first_frag_size;
+            (1 + (rest + frag_size_addr_none - 1) / frag_size_addr_none) as u8
         } else {
             1u8
         };
@@ -200,6 +201,11 @@ where
             _marker: PhantomData,
         }
     }
+
+    /// Length of the serialized `Header::Packet` without the address, including the version and command bytes
+    const fn header_len_without_addr() -> usize {
+        2 + PacketHeader::len_without_addr()
+    }
 }
 
 impl<'a, P> Iterator for Fragments<'a, P>
@@ -211,7 +217,7 @@ where
     fn next(&mut self) -> Option<Self::Item> {
         if self.next_frag_id < self.frag_total {
             let payload_size =
-                self.max_pkt_size - PacketHeader::len_without_addr() - self.addr.len();
+                self.max_pkt_size - Self::header_len_without_addr() - self.addr.len();
             let next_frag_end =
                 (self.next_frag_start + payload_size).min(self.payload.as_ref().len());
 
@@ -224,7 +230,8 @@ where
                 self.addr.take(),
             ));
 
-            let payload_ptr = &(self.payload.as_ref()[self.next_frag_start]) as *const u8;
+            // `add` instead of indexing, as the payload of an empty packet has no first byte
+            let payload_ptr = unsafe { self.payload.as_ref().as_ptr().add(self.next_frag_start) };
             let payload =
                 unsafe { slice::from_raw_parts(payload_ptr, next_frag_end - self.next_frag_start) };
 
@@ -12,17 +12,50 @@ use super::Address;
 /// where:
 ///
 /// - `ADDR` - target address
+///
+/// A `Connect` requesting stream compression is sent with its own type code and carries two more fields:
+///
+/// ```plain
+/// +----------+------+-------+
+/// |   ADDR   | ALGO | LEVEL |
+/// +----------+------+-------+
+/// | Variable |  1   |   1   |
+/// +----------+------+-------+
+/// ```
+///
+/// where:
+///
+/// - `ALGO` - the requested compression algorithm, never `0x00`
+/// - `LEVEL` - the compression level, signed
 #[derive(Clone, Debug)]
 pub struct Connect {
     addr: Address,
+    compression: Option<(u8, i8)>,
 }
 
 impl Connect {
     const TYPE_CODE: u8 = 0x01;
+    const TYPE_CODE_COMPRESSED: u8 = 0x81;
+
+    /// No compression, as answered by a server declining the request
+    pub const COMPRESSION_NONE: u8 = 0x00;
+    /// Zstandard compression
+    pub const COMPRESSION_ZSTD: u8 = 0x01;
 
     /// Creates a new `Connect` command
     pub const fn new(addr: Address) -> Self {
-        Self { addr }
+        Self {
+            addr,
+            compression: None,
+        }
+    }
+
+    /// Creates a new `Connect` command requesting stream compression with algorithm `algo` at `level`
+    pub const fn with_compression(addr: Address, algo: u8, level: i8) -> Self {
+        Self {
+            addr,
+            compression: Some((algo, level)),
+        }
     }
 
     /// Returns the address
@@ -30,15 +63,25 @@ impl Connect {
         &self.addr
     }
 
+    /// Returns the requested compression algorithm and level, if any
+    pub const fn compression(&self) -> Option<(u8, i8)> {
+        self.compression
+    }
+
     /// Returns the command type code
     pub const fn type_code() -> u8 {
         Self::TYPE_CODE
     }
 
+    /// Returns the command type code of a `Connect` requesting stream compression
+    pub const fn type_code_compressed() -> u8 {
+        Self::TYPE_CODE_COMPRESSED
+    }
+
     /// Returns the serialized length of the command
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
-        self.addr.len()
+        self.addr.len() + if self.compression.is_some() { 2 } else { 0 }
     }
 }
 
@@ -13,6 +13,7 @@ impl Heartbeat {
     const TYPE_CODE: u8 = 0x04;
 
     /// Creates a new `Heartbeat` command
+    #[allow(clippy::new_without_default)]
     pub const fn new() -> Self {
         Self
     }
@@ -43,6 +43,8 @@ pub const VERSION: u8 = 0x05;
 /// - `0x03` - `Dissociate` - for terminating a UDP relaying session
 /// - `0x04` - `Heartbeat` - for keeping the QUIC connection alive
 ///
+/// A `Connect` requesting stream compression uses type code `0x81`
+///
 /// Command `Connect` and `Packet` carry payload (stream / packet fragment)
 #[non_exhaustive]
 #[derive(Clone, Debug)]
@@ -57,6 +59,7 @@ pub enum Header {
 impl Header {
     pub const TYPE_CODE_AUTHENTICATE: u8 = Authenticate::type_code();
     pub const TYPE_CODE_CONNECT: u8 = Connect::type_code();
+    pub const TYPE_CODE_CONNECT_COMPRESSED: u8 = Connect::type_code_compressed();
     pub const TYPE_CODE_PACKET: u8 = Packet::type_code();
     pub const TYPE_CODE_DISSOCIATE: u8 = Dissociate::type_code();
     pub const TYPE_CODE_HEARTBEAT: u8 = Heartbeat::type_code();
@@ -65,6 +68,7 @@ impl Header {
     pub const fn type_code(&self) -> u8 {
         match self {
             Self::Authenticate(_) => Authenticate::type_code(),
+            Self::Connect(conn) if conn.compression().is_some() => Connect::type_code_compressed(),
             Self::Connect(_) => Connect::type_code(),
             Self::Packet(_) => Packet::type_code(),
             Self::Dissociate(_) => Dissociate::type_code(),
@@ -185,6 +189,7 @@ impl Display for Address {
     }
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for Address {
     fn default() -> Self {
         Self::None
@@ -29,6 +29,9 @@ impl Header {
                 Authenticate::async_read(s).await.map(Self::Authenticate)
             }
             Header::TYPE_CODE_CONNECT => Connect::async_read(s).await.map(Self::Connect),
+            Header::TYPE_CODE_CONNECT_COMPRESSED => {
+                Connect::async_read_compressed(s).await.map(Self::Connect)
+            }
             Header::TYPE_CODE_PACKET => Packet::async_read(s).await.map(Self::Packet),
             Header::TYPE_CODE_DISSOCIATE => Dissociate::async_read(s).await.map(Self::Dissociate),
             Header::TYPE_CODE_HEARTBEAT => Heartbeat::async_read(s).await.map(Self::Heartbeat),
@@ -54,6 +57,7 @@ impl Header {
         match cmd {
             Header::TYPE_CODE_AUTHENTICATE => Authenticate::read(s).map(Self::Authenticate),
             Header::TYPE_CODE_CONNECT => Connect::read(s).map(Self::Connect),
+            Header::TYPE_CODE_CONNECT_COMPRESSED => Connect::read_compressed(s).map(Self::Connect),
             Header::TYPE_CODE_PACKET => Packet::read(s).map(Self::Packet),
             Header::TYPE_CODE_DISSOCIATE => Dissociate::read(s).map(Self::Dissociate),
             Header::TYPE_CODE_HEARTBEAT => Heartbeat::read(s).map(Self::Heartbeat),
@@ -192,6 +196,32 @@ impl Connect {
     fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
         Ok(Self::new(Address::read(s)?))
     }
+
+    #[cfg(feature = "async_marshal")]
+    async fn async_read_compressed(
+        s: &mut (impl AsyncRead + Unpin),
+    ) -> Result<Self, UnmarshalError> {
+        let addr = Address::async_read(s).await?;
+        let mut buf = [0; 2];
+        s.read_exact(&mut buf).await?;
+        Self::from_compression(addr, buf)
+    }
+
+    #[cfg(feature = "marshal")]
+    fn read_compressed(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+        let addr = Address::read(s)?;
+        let mut buf = [0; 2];
+        s.read_exact(&mut buf)?;
+        Self::from_compression(addr, buf)
+    }
+
+    fn from_compression(addr: Address, [algo, level]: [u8; 2]) -> Result<Self, UnmarshalError> {
+        if algo == Self::COMPRESSION_NONE {
+            return Err(UnmarshalError::InvalidCompression(algo));
+        }
+
+        Ok(Self::with_compression(addr, algo, level as i8))
+    }
 }
 
 impl Packet {
@@ -257,6 +287,7 @@ impl Heartbeat {
 }
 
 /// Errors that can occur when unmarshalling a packet
+#[non_exhaustive]
 #[derive(Debug, Error)]
 pub enum UnmarshalError {
     #[error(transparent)]
@@ -267,6 +298,8 @@ pub enum UnmarshalError {
     InvalidCommand(u8),
     #[error("invalid UUID: {0}")]
     InvalidUuid(#[from] UuidError),
+    #[error("invalid compression algorithm: {0}")]
+    InvalidCompression(u8),
     #[error("invalid address type: {0}")]
     InvalidAddressType(u8),
     #[error("address parsing error: {0}")]